@@ -0,0 +1,43 @@
+//! Feeds arbitrary bytes to the decoders of packets and announcements, which parse whatever
+//! arrives from the network.
+//!
+//! The app is a binary crate, so the modules under test are compiled into the target along
+//! with the few items they use from the rest of the crate.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+mod protocol {
+    #[path = "../../../src/protocol/packet.rs"]
+    pub mod packet;
+
+    pub type PeerID = u64;
+    pub type PeerAddr = std::net::SocketAddr;
+}
+
+#[allow(dead_code)]
+#[path = "../../src/discovery/announcement.rs"]
+mod announcement;
+
+use announcement::Announcement;
+use protocol::packet::Packet;
+
+fuzz_target!(|bytes: &[u8]| {
+    if let Ok(packet) = Packet::from_bytes(bytes) {
+        for name in ["checksum", "hash", "nonce"] {
+            let _ = packet.get_header_bytes(name);
+        }
+        // Whatever was decoded must survive being encoded again.
+        let encoded = packet.as_bytes();
+        let decoded = Packet::from_bytes(&encoded).unwrap();
+        assert_eq!(decoded.get_payload(), packet.get_payload());
+    }
+    if let Ok(announcement) = Announcement::from_bytes(bytes) {
+        let _ = announcement.to_string();
+        let encoded = announcement.as_bytes();
+        let decoded = Announcement::from_bytes(&encoded).unwrap();
+        assert_eq!(decoded.as_bytes(), encoded);
+    }
+});
@@ -1,7 +1,14 @@
+use std::convert::Infallible;
 use std::fmt;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
 
-use crate::protocol::PeerID;
+use crate::protocol::{PeerAddr, PeerID};
+use crate::transfer::ByteRange;
+
+/// Prefix of the responses sent through [`Request::respond_error`], so that clients can tell
+/// failures apart from the responses of successful requests.
+pub const ERROR_PREFIX: &str = "ERR: ";
 
 /// The `ReadRequest` trait allows for reading a request from a connection.
 ///
@@ -20,13 +27,105 @@ pub trait ReadRequest {
     fn read_request(&self) -> io::Result<Request>;
 }
 
+/// Refers to a peer either by its identifier, by the name it announced itself with or by the
+/// index it was listed with, e.g., `#2`.
+#[derive(Clone)]
+pub enum PeerRef {
+    ID(PeerID),
+    Name(String),
+    Index(usize),
+}
+
+impl FromStr for PeerRef {
+    type Err = Infallible;
+
+    /// Parses an identifier or an index prefixed by `#`, or takes the whole string as a name if
+    /// it is neither.
+    fn from_str(s: &str) -> Result<PeerRef, Infallible> {
+        if let Some(index) = s.strip_prefix('#').and_then(|index| index.parse().ok()) {
+            return Ok(PeerRef::Index(index));
+        }
+        Ok(s.parse::<PeerID>()
+            .map_or_else(|_| PeerRef::Name(s.to_string()), PeerRef::ID))
+    }
+}
+
+impl fmt::Display for PeerRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerRef::ID(id) => write!(f, "{id}"),
+            PeerRef::Name(name) => write!(f, "{name}"),
+            PeerRef::Index(index) => write!(f, "#{index}"),
+        }
+    }
+}
+
 /// Represents a message sent to API.
 pub enum Message {
+    /// Checks that the daemon is alive; answered with `pong <pid>`.
+    Ping,
     MyID,
     MyAddr,
-    Peers,
-    Send(String),
-    SendTo(PeerID, String),
+    /// Lists the discovered peers, as JSON if `json`, checking whether each of them can be
+    /// connected to if `probe`.
+    Peers {
+        json: bool,
+        probe: bool,
+    },
+    /// Describes the peer that matches the given identifier, checking whether it can be
+    /// connected to.
+    PeerInfo(PeerID),
+    /// Sends files to all the peers; several files are sent over one connection per peer.
+    Send(Vec<String>),
+    /// Sends a directory to all the peers as a single archive.
+    SendArchive(String),
+    /// Sends a text to all the peers as a text file.
+    SendText(String),
+    SendTo(PeerRef, String),
+    SendToAddr(PeerAddr, String),
+    /// Asks a peer whether sending a file with the given name would replace one it has;
+    /// answered with `exists` or `missing`.
+    FileExists(PeerRef, String),
+    SendExcept(Vec<PeerID>, String),
+    /// Sends a file to the discovered members of the named group.
+    SendToGroup(String, String),
+    SendFilesTo(PeerID, Vec<String>),
+    Msg(PeerID, String),
+    Groups,
+    /// Creates a group of peers by their identifiers, replacing any group of the same name.
+    CreateGroup(String, Vec<PeerID>),
+    Discover,
+    /// Resolves the local address again and, if it changed, receives and announces on it.
+    Refresh,
+    /// Enumerates the interfaces again and lists all their addresses, marking the one the
+    /// local address is picked from.
+    Rescan,
+    /// Sends a small generated file to the peer itself and checks that it is saved intact;
+    /// answered with `pass` or `fail` along with the reason.
+    SelfTest,
+    /// Sends a multicast probe and waits a short while for the peers to echo it back; answered
+    /// with the peers that did.
+    McastTest,
+    Diagnostics,
+    DiscoveryDebug,
+    History(usize),
+    ListRemote(PeerID),
+    /// Pulls a shared file from a peer, or only the given range of it.
+    Pull(PeerID, String, Option<ByteRange>),
+    /// Reads back a file received into the save location, by its name, preceded by the
+    /// subdirectory of its sender if `save_subdir_by_peer` is enabled.
+    Cat(String),
+    /// Checks a file received into the save location, named like for [`Message::Cat`], against
+    /// a SHA-256 hash in hex.
+    Verify(String, String),
+    /// Shows the save location and the space left on its filesystem.
+    SaveInfo,
+    Metrics,
+    ClearPeers,
+    /// Subscribes to the transfer events, which are streamed until the client goes away.
+    Events,
+    /// Stops the app; `true` aborts the transfers in progress rather than waiting for them.
+    Shutdown(bool),
 }
 
 /// The `Api` structure allows for creating a different kind of APIs (e.g., IPC, HTTP, etc.).
@@ -45,7 +144,7 @@ impl<R: ReadRequest> Api<R> {
     }
 
     /// Returns an iterator over incoming requests.
-    pub fn incoming_requests(&self) -> IncomingRequests<R> {
+    pub fn incoming_requests(&self) -> IncomingRequests<'_, R> {
         IncomingRequests { api: self }
     }
 
@@ -71,12 +170,12 @@ impl<'a, R: ReadRequest> Iterator for IncomingRequests<'a, R> {
 /// Represents a request sent to an API.
 pub struct Request {
     message: Message,
-    response_writer: Box<dyn Write>,
+    response_writer: Box<dyn Write + Send>,
 }
 
 impl Request {
     /// Creates a new instance of a request.
-    pub fn new(message: Message, response_writer: Box<dyn Write>) -> Request {
+    pub fn new(message: Message, response_writer: Box<dyn Write + Send>) -> Request {
         Request {
             message,
             response_writer,
@@ -92,4 +191,87 @@ impl Request {
     pub fn response(&mut self, data: impl fmt::Display) -> io::Result<()> {
         write!(self.response_writer, "{data}")
     }
+
+    /// Sends a response telling why this request, or a part of it, failed, prefixed by
+    /// [`ERROR_PREFIX`].
+    pub fn respond_error(&mut self, reason: impl fmt::Display) -> io::Result<()> {
+        write!(self.response_writer, "{ERROR_PREFIX}{reason}")
+    }
+
+    /// Consumes the request and returns its response writer, so that the response can be
+    /// written from elsewhere, e.g., streamed from another thread.
+    pub fn into_response_writer(self) -> Box<dyn Write + Send> {
+        self.response_writer
+    }
+
+    /// Sends the bytes read from the `reader` as they are as a response to this request.
+    pub fn response_from(&mut self, mut reader: impl Read) -> io::Result<u64> {
+        io::copy(&mut reader, &mut self.response_writer)
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::{self, Error, ErrorKind, Write};
+    use std::sync::{Arc, Mutex};
+
+    use super::{Message, ReadRequest, Request};
+
+    /// A request reader that yields a preset queue of requests without any connection.
+    ///
+    /// Once the queue is drained, reading a request fails, which ends the iteration
+    /// over incoming requests.
+    #[derive(Default)]
+    pub struct MockRequestReader {
+        requests: RefCell<VecDeque<Request>>,
+    }
+
+    impl MockRequestReader {
+        /// Creates a new reader with an empty queue.
+        pub fn new() -> MockRequestReader {
+            MockRequestReader::default()
+        }
+
+        /// Queues a request with the given message and returns the buffer its response
+        /// will be written into.
+        pub fn push(&self, message: Message) -> ResponseBuffer {
+            let response = ResponseBuffer::default();
+            let request = Request::new(message, Box::new(response.clone()));
+            self.requests.borrow_mut().push_back(request);
+            response
+        }
+    }
+
+    impl ReadRequest for MockRequestReader {
+        fn read_request(&self) -> io::Result<Request> {
+            self.requests
+                .borrow_mut()
+                .pop_front()
+                .ok_or(Error::new(ErrorKind::UnexpectedEof, "no more requests"))
+        }
+    }
+
+    /// An in-memory response writer whose contents can be inspected after the request
+    /// is handled.
+    #[derive(Clone, Default)]
+    pub struct ResponseBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl ResponseBuffer {
+        /// Returns everything written into the buffer so far.
+        pub fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    impl Write for ResponseBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
 }
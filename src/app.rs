@@ -1,40 +1,104 @@
-use std::io::{self, Error};
-use std::time::Duration;
-use std::{fs, thread};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Error, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{mem, process, slice, thread};
 
-use crate::api::{Api, Message, Request};
+use crate::api::{Api, Message, PeerRef, ReadRequest, Request};
 use crate::config::Config;
-use crate::discovery::PeerDiscovery;
-use crate::elogln;
-use crate::ipc::IPCServer;
-use crate::protocol::{self, PeerAddr, PeerID};
-use crate::transfer::{receiver, sender};
+use crate::diagnostics::LastError;
+use crate::discovery::{PeerDiscovery, PeerInfo};
+use crate::group::Groups;
+use crate::http::HttpServer;
+use crate::ipc::{self, IPCServer};
+use crate::protocol::packet::Packet;
+use crate::protocol::{self, PeerAddr, PeerID, ShortAddr};
+use crate::transfer::sender::{self, FileResults, SendResults};
+use crate::transfer::{self, receiver};
+use crate::{digest, elogln, events, history, interface, json, logln, metrics, net};
 
 pub struct App {
     my_id: PeerID,
-    my_addr: PeerAddr,
+    /// Address the receiver is bound on and the peer is announced at; replaced once the local
+    /// address is refreshed.
+    my_addr: Mutex<PeerAddr>,
     peer_discovery: PeerDiscovery,
     config: Config,
+    receiver_last_error: LastError,
+    /// A clone of the listener the receiver is accepting on, to stop it when rebinding.
+    receiver_listener: Mutex<Option<TcpListener>>,
+    /// Number of transfer connections the receiver is handling.
+    active_transfers: Arc<AtomicUsize>,
+    /// Set once a shutdown is requested, to whether the transfers in progress are aborted.
+    shutdown: OnceLock<bool>,
+    /// Identifiers of the peers in the order they were first listed, so that the peer at
+    /// position `n` keeps the index `#n + 1` for the rest of the session.
+    peer_indices: Mutex<Vec<PeerID>>,
+    groups: Groups,
 }
 
+/// Interval between checks of whether the transfers in progress have finished on shutdown.
+const TRANSFER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Interval between checks of whether the app has been idle for the `idle_timeout`.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long probing whether the peers are reachable waits for each of them, unless the
+/// `peer_reachability_timeout` is configured.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Size of the file sent by the self-test.
+const SELF_TEST_FILE_LEN: usize = 64 * 1024;
+/// How long the self-test waits for its file to be saved.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the multicast test waits for the peers to echo its probe back.
+const MCAST_TEST_TIMEOUT: Duration = Duration::from_secs(2);
+
 impl App {
-    /// Creates a new instance of `App` with all the necessary setup.
-    pub fn new() -> App {
-        let my_id = protocol::get_my_id();
-        let my_addr = protocol::get_my_addr();
+    /// Creates a new instance of `App` that uses the given config.
+    pub fn with_config(mut config: Config) -> App {
+        if let Some(interface) = &config.interface {
+            config.discovery_interfaces = vec![interface.clone()];
+        }
+        let my_id = protocol::get_my_id(&config.data_location, &*config.id_strategy);
+        // An interface without an address is reported by `run`, which validates the config.
+        let my_addr = resolve_my_addr(&config).unwrap_or(PeerAddr::new(
+            Ipv4Addr::UNSPECIFIED.into(),
+            config.transfer_port,
+        ));
 
         App {
             my_id,
-            my_addr,
-            peer_discovery: PeerDiscovery::new(my_id, my_addr),
-            config: Config::default(),
+            my_addr: Mutex::new(my_addr),
+            groups: Groups::new(&config.groups),
+            peer_discovery: PeerDiscovery::new(
+                my_id,
+                my_addr,
+                protocol::get_my_name(),
+                protocol::count_launch(&config.data_location),
+                config.clone(),
+            ),
+            config,
+            receiver_last_error: LastError::default(),
+            receiver_listener: Mutex::default(),
+            active_transfers: Arc::default(),
+            shutdown: OnceLock::new(),
+            peer_indices: Mutex::default(),
         }
     }
 
     /// Starts the main event loop.
     ///
-    /// **NOTE:** This function always blocks the current thread.
+    /// **NOTE:** This function blocks the current thread until a shutdown is requested. Unless
+    /// it asks to abort them, the transfers in progress are waited for before returning; the
+    /// discovery and receiver threads end along with the process.
     pub fn run(&mut self) -> io::Result<()> {
+        self.config.validate()?;
+        // Claim the socket first, so that a second instance fails before it gets in the way of
+        // the running one.
+        let ipc_server = IPCServer::new()?;
         let save_location_exists = self.config.save_location.try_exists().map_err(|err| {
             Error::new(err.kind(), "failed to check the existence of save location")
         })?;
@@ -42,56 +106,1245 @@ impl App {
         if !save_location_exists {
             fs::create_dir(&self.config.save_location)?;
         }
-        self.spawn_file_receiver()?;
+        self.spawn_file_receiver(self.my_addr())?;
         self.peer_discovery.spawn()?;
         self.peer_discovery.announce_peer()?;
 
         // Wait for a short duration to allow other threads to fully start up.
         thread::sleep(Duration::from_millis(20));
-        let api = Api::new(IPCServer::new()?);
+        let ipc_api = Api::new(ipc_server);
+        let http_api = match self.config.http_api_addr {
+            Some(addr) => Some(Api::new(HttpServer::bind(addr)?)),
+            None => None,
+        };
+
+        let app = &*self;
+        thread::scope(|scope| {
+            if let Some(timeout) = self.config.idle_timeout {
+                thread::Builder::new()
+                    .name(String::from("idle_watchdog"))
+                    .spawn_scoped(scope, move || app.shut_down_once_idle(timeout))?;
+            }
+            if let Some(interval) = self.config.peer_snapshot_interval {
+                thread::Builder::new()
+                    .name(String::from("peer_snapshot"))
+                    .spawn_scoped(scope, move || app.snapshot_peers(interval))?;
+            }
+            if let Some(http_api) = http_api {
+                thread::Builder::new()
+                    .name(String::from("http_api"))
+                    .spawn_scoped(scope, move || {
+                        if app.serve(&http_api) {
+                            app.wake_ipc_api();
+                        }
+                    })?;
+            }
+            if app.serve(&ipc_api) {
+                app.wake_http_api();
+            }
+            io::Result::Ok(())
+        })?;
 
+        if self.shutdown.get() == Some(&false) {
+            logln!("Waiting for the transfers in progress to finish");
+            while self.active_transfers.load(Ordering::Acquire) > 0 {
+                thread::sleep(TRANSFER_POLL_INTERVAL);
+            }
+        }
+        logln!("Shut down");
+        Ok(())
+    }
+
+    /// Handles the incoming requests of the `api` until it stops yielding them or a shutdown
+    /// is requested.
+    ///
+    /// Returns `true` if the shutdown was requested through this `api`.
+    fn serve<R: ReadRequest>(&self, api: &Api<R>) -> bool {
         for request in api.incoming_requests() {
+            let is_shutdown = matches!(request.message(), Message::Shutdown(_));
             if let Err(e) = self.handle_api_request(request) {
                 elogln!("Failed to handle an api request: {e}");
             };
+            if self.shutdown.get().is_some() {
+                return is_shutdown;
+            }
+        }
+        false
+    }
+
+    /// Requests a shutdown once the app has been idle for the `timeout`, see
+    /// [`Config::idle_timeout`]; any transfer or newly discovered peer starts it over.
+    ///
+    /// Returns once a shutdown is requested, by this or any other means.
+    fn shut_down_once_idle(&self, timeout: Duration) {
+        let events = events::subscribe();
+        let mut peer_count = self.peer_discovery.peer_count();
+        let mut last_active = Instant::now();
+
+        while self.shutdown.get().is_none() {
+            let transferred = events.recv_timeout(IDLE_POLL_INTERVAL.min(timeout)).is_ok()
+                || self.active_transfers.load(Ordering::Acquire) > 0;
+            let previous_peer_count =
+                mem::replace(&mut peer_count, self.peer_discovery.peer_count());
+            let discovered = peer_count > previous_peer_count
+                || (self.config.idle_only_without_peers && peer_count > 0);
+
+            if transferred || discovered {
+                last_active = Instant::now();
+            } else if last_active.elapsed() >= timeout {
+                if self.shutdown.set(false).is_ok() {
+                    logln!("Shutting down after being idle for {timeout:?}");
+                    self.wake_ipc_api();
+                    self.wake_http_api();
+                }
+                return;
+            }
+        }
+    }
+
+    /// Writes the discovered peers into the file at [`peer_snapshot_path`] every `interval`,
+    /// see [`Config::peer_snapshot_interval`].
+    ///
+    /// Returns once a shutdown is requested, removing the file.
+    fn snapshot_peers(&self, interval: Duration) {
+        let path = peer_snapshot_path();
+        let mut last_written = None::<Instant>;
+
+        while self.shutdown.get().is_none() {
+            if last_written.is_none_or(|at| at.elapsed() >= interval) {
+                let peers = self.peer_discovery.get_discovered_peer_infos();
+                if let Err(e) = write_peer_snapshot(&path, &peers) {
+                    elogln!("Failed to write the peers into `{}`: {e}", path.display());
+                }
+                last_written = Some(Instant::now());
+            }
+            thread::sleep(IDLE_POLL_INTERVAL.min(interval));
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    /// Sends a no-op request to the IPC API, so that it notices the shutdown.
+    fn wake_ipc_api(&self) {
+        let _ = UnixStream::connect(ipc::socket_path()).and_then(|mut stream| {
+            // Don't wait for the response for long, the API may have stopped already.
+            stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+            stream.write_all(b"/myid")?;
+            stream.shutdown(Shutdown::Write)?;
+            stream.read_to_end(&mut Vec::new())
+        });
+    }
+
+    /// Sends a no-op request to the HTTP API, if it is enabled, so that it notices the shutdown.
+    fn wake_http_api(&self) {
+        if let Some(addr) = self.config.http_api_addr {
+            let _ = TcpStream::connect(addr)
+                .and_then(|mut stream| stream.write_all(b"GET /myid HTTP/1.1\r\n\r\n"));
         }
-        Ok(())
     }
 
-    fn spawn_file_receiver(&self) -> io::Result<()> {
-        let receiving_addr = self.my_addr;
-        let save_location = self.config.save_location.clone();
+    fn spawn_file_receiver(&self, addr: PeerAddr) -> io::Result<()> {
+        // Bind on the current thread so that the failure is surfaced to the caller.
+        let listener = receiver::bind(addr, self.config.transfer_backlog)?;
+        *lock(&self.receiver_listener) = Some(listener.try_clone()?);
+        let config = self.config.clone();
+        let my_id = self.my_id;
+        let active_transfers = Arc::clone(&self.active_transfers);
+        let last_error = self.receiver_last_error.clone();
 
         thread::Builder::new()
             .name(String::from("file_receiver"))
-            .spawn(move || receiver::receive_files(receiving_addr, save_location))?;
+            .spawn(move || {
+                receiver::receive_files(
+                    listener,
+                    config,
+                    my_id,
+                    active_transfers,
+                    last_error.clone(),
+                )
+                .inspect_err(|e| last_error.record(format!("Receiver stopped: {e}")))
+            })?;
 
         Ok(())
     }
 
+    /// Stops the receiver from accepting new connections, if it is running.
+    fn stop_file_receiver(&self) -> io::Result<()> {
+        match lock(&self.receiver_listener).take() {
+            Some(listener) => receiver::stop(&listener),
+            None => Ok(()),
+        }
+    }
+
+    fn my_addr(&self) -> PeerAddr {
+        *lock(&self.my_addr)
+    }
+
+    /// Resolves the local address again, e.g., after a network change, and if it changed,
+    /// rebinds the receiver on the new address and announces it.
+    ///
+    /// Returns the new address, or `None` if it hasn't changed.
+    fn refresh_addr(&self) -> io::Result<Option<PeerAddr>> {
+        let mut my_addr = lock(&self.my_addr);
+        let new_addr = resolve_my_addr(&self.config)?;
+        if new_addr == *my_addr {
+            return Ok(None);
+        }
+
+        self.stop_file_receiver()?;
+        if let Err(e) = self.spawn_file_receiver(new_addr) {
+            // Keep receiving on the old address rather than not at all.
+            if let Err(e) = self.spawn_file_receiver(*my_addr) {
+                elogln!("Failed to rebind the receiver on `{my_addr}`: {e}");
+            }
+            return Err(e);
+        }
+        *my_addr = new_addr;
+        self.peer_discovery.set_addr(new_addr);
+        logln!("The local address changed to `{new_addr}`");
+        self.peer_discovery.announce_peer()?;
+        Ok(Some(new_addr))
+    }
+
     fn handle_api_request(&self, mut req: Request) -> io::Result<()> {
         match req.message() {
+            Message::Ping => req.response(format!("pong {}", process::id())),
             Message::MyID => req.response(self.my_id),
-            Message::MyAddr => req.response(self.my_addr),
-            Message::Peers => match self.peer_discovery.get_discovered_peer_ids() {
-                Some(ids) => {
-                    let ids = ids.iter().map(|&id| format!("{id}\n")).collect::<String>();
-                    req.response(ids)
+            Message::MyAddr => req.response(self.my_addr()),
+            Message::Peers { json, probe } => {
+                self.wait_for_any_peer();
+                let peers = self.peer_discovery.get_discovered_peer_infos();
+                let indices = self.index_peers(&peers);
+                let reachable = probe.then(|| {
+                    let addrs = peers.iter().map(|(_, peer)| peer.addr).collect::<Vec<_>>();
+                    let timeout = self.config.peer_reachability_timeout;
+                    net::probe_all(&addrs, timeout.unwrap_or(PROBE_TIMEOUT))
+                });
+
+                match json {
+                    true => {
+                        req.response(peers_as_json(&peers, Some(&indices), reachable.as_deref()))
+                    }
+                    false if peers.is_empty() => req.respond_error("No peers found"),
+                    false => req.response(peers_as_text(&peers, &indices, reachable.as_deref())),
+                }
+            }
+            Message::PeerInfo(peer_id) => {
+                let Some(peer) = self.peer_discovery.find_peer_info_by_id(*peer_id) else {
+                    return req.respond_error(self.peer_not_found());
+                };
+                let timeout = self.config.peer_reachability_timeout;
+                let reachable = net::probe_all(&[peer.addr], timeout.unwrap_or(PROBE_TIMEOUT));
+                req.response(peer_info_as_text(*peer_id, &peer, reachable[0]))
+            }
+            Message::Send(file_paths) => {
+                let Some(addrs) = self.peer_discovery.get_discovered_peer_addrs() else {
+                    return req.respond_error("No peers found");
+                };
+                match file_paths.as_slice() {
+                    [] => req.respond_error("No files given"),
+                    [file_path] => {
+                        let results =
+                            sender::send_file_to_all(&addrs, file_path, self.my_id, &self.config);
+                        respond_to_sends(&mut req, "", "Failed to send file", results)
+                    }
+                    file_paths => {
+                        let file_paths = file_paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+                        let results = sender::send_files_to_all(
+                            &addrs,
+                            &file_paths,
+                            self.my_id,
+                            &self.config,
+                        );
+                        let failed = results
+                            .iter()
+                            .flat_map(|(_, results)| results)
+                            .any(|(_, result)| result.is_err());
+                        let text = results
+                            .into_iter()
+                            .map(|(addr, results)| {
+                                format!("`{addr}`:\n{}", file_results_as_text(results))
+                            })
+                            .collect::<String>();
+                        respond_once(&mut req, text, failed)
+                    }
                 }
-                None => req.response("No peers found"),
+            }
+            Message::SendText(text) => match self.peer_discovery.get_discovered_peer_addrs() {
+                Some(addrs) => {
+                    let results = sender::send_text_to_all(&addrs, text, self.my_id, &self.config);
+                    respond_to_sends(&mut req, "", "Failed to send the text", results)
+                }
+                None => req.respond_error("No peers found"),
             },
-            Message::Send(file_path) => match self.peer_discovery.get_discovered_peer_addrs() {
-                Some(addrs) => sender::send_file_to_all(&addrs, file_path)
-                    .or_else(|_| req.response("Failed to send file")),
-                None => req.response("No peers found"),
+            Message::SendArchive(dir_path) => {
+                match self.peer_discovery.get_discovered_peer_addrs() {
+                    Some(addrs) => {
+                        let results =
+                            sender::send_archive_to_all(&addrs, dir_path, self.my_id, &self.config);
+                        respond_to_sends(&mut req, "", "Failed to send the directory", results)
+                    }
+                    None => req.respond_error("No peers found"),
+                }
+            }
+            Message::SendTo(peer, file_path) => match self.find_peer_addr(peer) {
+                Ok(addr) => sender::send_file_to(addr, file_path, self.my_id, &self.config)
+                    .or_else(|e| req.respond_error(format!("Failed to send file: {e}"))),
+                Err(e) => req.respond_error(e),
             },
-            Message::SendTo(peer_id, file_path) => {
+            Message::FileExists(peer, file_name) => {
+                let addr = match self.find_peer_addr(peer) {
+                    Ok(addr) => addr,
+                    Err(e) => return req.respond_error(e),
+                };
+                match sender::peer_has_file_named(addr, file_name, self.my_id, &self.config) {
+                    Ok(true) => req.response("exists"),
+                    Ok(false) => req.response("missing"),
+                    Err(e) => req.respond_error(format!("Failed to ask the peer: {e}")),
+                }
+            }
+            Message::SendToAddr(peer_addr, file_path) => {
+                // Connect directly, the peer may not have been discovered (e.g., it lives on a
+                // different subnet).
+                sender::send_file_to(*peer_addr, file_path, self.my_id, &self.config)
+                    .or_else(|e| req.respond_error(format!("Failed to send file: {e}")))
+            }
+            Message::SendExcept(excluded_ids, file_path) => {
+                let Some(peers) = self.peer_discovery.get_discovered_peers() else {
+                    return req.respond_error("No peers found");
+                };
+                let addrs = peers
+                    .into_iter()
+                    .filter(|(id, _)| !excluded_ids.contains(id))
+                    .map(|(_, addr)| addr)
+                    .collect::<Vec<_>>();
+
+                if addrs.is_empty() {
+                    return req
+                        .respond_error("No eligible peers; all the discovered peers are excluded");
+                }
+                let results = sender::send_file_to_all(&addrs, file_path, self.my_id, &self.config);
+                respond_to_sends(&mut req, "", "Failed to send file", results)
+            }
+            Message::SendToGroup(group, file_path) => {
+                let Some(members) = self.groups.members(group) else {
+                    return req.respond_error(format!("No group named `{group}`"));
+                };
+                let mut addrs = Vec::new();
+                let mut skipped = Vec::new();
+                for id in members {
+                    match self.peer_discovery.find_peer_addr_by_id(id) {
+                        Some(addr) => addrs.push(addr),
+                        None => skipped.push(id.to_string()),
+                    }
+                }
+
+                if addrs.is_empty() {
+                    return req
+                        .respond_error(format!("None of the members of `{group}` are discovered"));
+                }
+                let results = sender::send_file_to_all(&addrs, file_path, self.my_id, &self.config);
+                let notice = match skipped.is_empty() {
+                    true => String::new(),
+                    false => format!(
+                        "Skipped the members that aren't discovered: {}\n",
+                        skipped.join(", ")
+                    ),
+                };
+                respond_to_sends(&mut req, &notice, "Failed to send file", results)
+            }
+            Message::SendFilesTo(peer_id, file_paths) => {
+                let Some(addr) = self.peer_discovery.find_peer_addr_by_id(*peer_id) else {
+                    return req.respond_error(self.peer_not_found());
+                };
+                let file_paths = file_paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+                let results = sender::send_files_to(addr, &file_paths, self.my_id, &self.config);
+                let failed = results.iter().any(|(_, result)| result.is_err());
+                respond_once(&mut req, file_results_as_text(results), failed)
+            }
+            Message::Msg(peer_id, text) => {
                 match self.peer_discovery.find_peer_addr_by_id(*peer_id) {
-                    Some(addr) => sender::send_file_to(addr, file_path)
-                        .or_else(|_| req.response("Failed to send file: {e}")),
-                    None => req.response("No peers found that matches the given identifier"),
+                    Some(addr) => sender::send_message_to(addr, text, self.my_id, &self.config)
+                        .or_else(|e| req.respond_error(format!("Failed to send message: {e}"))),
+                    None => req.respond_error(self.peer_not_found()),
                 }
             }
+            Message::Groups => {
+                let groups = self.groups.list();
+                if groups.is_empty() {
+                    return req.response("No groups defined");
+                }
+                let groups = groups
+                    .iter()
+                    .map(|(name, members)| {
+                        let members = members.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+                        format!("{name}: {}\n", members.join(", "))
+                    })
+                    .collect::<String>();
+                req.response(groups)
+            }
+            Message::CreateGroup(name, members) => {
+                match self.groups.create(name, members.clone()) {
+                    Ok(false) => req.response(format!("Created the group `{name}`")),
+                    Ok(true) => req.response(format!("Replaced the group `{name}`")),
+                    Err(e) => req.respond_error(format!("Failed to create the group: {e}")),
+                }
+            }
+            Message::Discover => match self
+                .peer_discovery
+                .announce_peer()
+                .and_then(|_| self.peer_discovery.request_announcements())
+            {
+                Ok(_) => req.response("Requested announcements from peers"),
+                Err(e) => req.respond_error(format!("Failed to request announcements: {e}")),
+            },
+            Message::Refresh => match self.refresh_addr() {
+                Ok(Some(addr)) => req.response(format!("The address changed to {addr}")),
+                Ok(None) => req.response(format!("The address is unchanged ({})", self.my_addr())),
+                Err(e) => req.respond_error(format!("Failed to refresh the address: {e}")),
+            },
+            Message::Rescan => {
+                let addresses = interface::all_addresses();
+                if addresses.is_empty() {
+                    return req.respond_error("Failed to enumerate the interfaces");
+                }
+                let selected = interface::local_ipv4_address();
+                let mut listing = String::new();
+                for address in addresses {
+                    let mut line = match (address.family, address.address) {
+                        (Some(family), Some(ip)) => format!("{}: {family} {ip}", address.name),
+                        (Some(family), None) => format!("{}: {family}", address.name),
+                        (None, _) => format!("{}: no address", address.name),
+                    };
+                    if address
+                        .address
+                        .is_some_and(|ip| Some(ip) == selected.map(IpAddr::V4))
+                    {
+                        line += " (selected)";
+                    }
+                    logln!("{line}");
+                    listing += &line;
+                    listing.push('\n');
+                }
+                if selected.is_none() {
+                    // Only private addresses in `192.168.0.0/16` are picked.
+                    listing += "No address was selected; the peer announces itself at 0.0.0.0\n";
+                }
+                req.response(listing)
+            }
+            Message::SelfTest => match self.self_test() {
+                Ok(_) => req.response("pass"),
+                Err(e) => req.respond_error(format!("fail: {e}")),
+            },
+            Message::McastTest => match self.peer_discovery.probe_multicast(MCAST_TEST_TIMEOUT) {
+                Ok(echoes) if echoes.is_empty() => req.respond_error(format!(
+                    "No peers echoed the multicast probe within {}s; either multicast is blocked \
+                     on the network or no other peers are running",
+                    MCAST_TEST_TIMEOUT.as_secs()
+                )),
+                Ok(echoes) => {
+                    let mut listing = format!(
+                        "Multicast works; {} peer(s) echoed the probe:\n",
+                        echoes.len()
+                    );
+                    for (id, addr) in echoes {
+                        listing += &format!("{id} {}\n", addr.ip());
+                    }
+                    req.response(listing)
+                }
+                Err(e) => req.respond_error(format!("Failed to send the multicast probe: {e}")),
+            },
+            Message::Diagnostics => req.response(format!(
+                "discovery: {}\nreceiver: {}\n",
+                self.peer_discovery.last_error(),
+                self.receiver_last_error
+            )),
+            Message::DiscoveryDebug => {
+                let datagrams = self.peer_discovery.recent_datagrams().get();
+                if datagrams.is_empty() {
+                    return req.response("No datagrams received yet");
+                }
+                let datagrams = datagrams
+                    .iter()
+                    .map(|(at, datagram)| format!("{}s ago: {datagram}\n", at.elapsed().as_secs()))
+                    .collect::<String>();
+                req.response(datagrams)
+            }
+            Message::History(count) => match history::last(&self.config.data_location, *count) {
+                Ok(records) if records.is_empty() => req.response("No transfers yet"),
+                Ok(records) => req.response(records.join("\n") + "\n"),
+                Err(e) => req.respond_error(format!("Failed to read the history: {e}")),
+            },
+            Message::ListRemote(peer_id) => {
+                let Some(addr) = self.peer_discovery.find_peer_addr_by_id(*peer_id) else {
+                    return req.respond_error(self.peer_not_found());
+                };
+                match sender::list_remote(addr, self.my_id, &self.config) {
+                    Ok(files) if files.is_empty() => req.response("The peer shares no files"),
+                    Ok(files) => {
+                        let files = files
+                            .iter()
+                            .map(|(name, size)| format!("{name} ({size} bytes)\n"))
+                            .collect::<String>();
+                        req.response(files)
+                    }
+                    Err(e) => req.respond_error(format!("Failed to list the files: {e}")),
+                }
+            }
+            Message::Pull(peer_id, file_name, range) => {
+                let Some(addr) = self.peer_discovery.find_peer_addr_by_id(*peer_id) else {
+                    return req.respond_error(self.peer_not_found());
+                };
+                match sender::pull_from(addr, file_name, *range, self.my_id, &self.config) {
+                    Ok(_) if range.is_some() => {
+                        req.response(format!("Pulled the range of `{file_name}`"))
+                    }
+                    Ok(_) => req.response(format!("Pulled `{file_name}`")),
+                    Err(e) => req.respond_error(format!("Failed to pull the file: {e}")),
+                }
+            }
+            Message::Cat(file_name) => {
+                let Some(path) = self.received_file_path(file_name) else {
+                    return req.respond_error("Invalid file name");
+                };
+                match File::open(&path) {
+                    Ok(file) if path.is_file() => req.response_from(file).map(|_| ()),
+                    _ => req.respond_error(format!("No received file named `{file_name}`")),
+                }
+            }
+            Message::Verify(file_name, hash) => {
+                let Some(path) = self.received_file_path(file_name) else {
+                    return req.respond_error("Invalid file name");
+                };
+                let hash = hash.trim();
+                if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return req.respond_error(format!("Invalid SHA-256 hash `{hash}`"));
+                }
+                if !path.is_file() {
+                    return req.respond_error(format!("No received file named `{file_name}`"));
+                }
+                match digest::sha256_file(&path) {
+                    Ok(actual) if digest::to_hex(&actual).eq_ignore_ascii_case(hash) => {
+                        req.response(format!("`{file_name}` matches the hash"))
+                    }
+                    Ok(actual) => req.response(format!(
+                        "`{file_name}` doesn't match the hash; its hash is {}",
+                        digest::to_hex(&actual)
+                    )),
+                    Err(e) => req.respond_error(format!("Failed to hash `{file_name}`: {e}")),
+                }
+            }
+            Message::SaveInfo => {
+                let save_location = self.config.save_location.display();
+                match receiver::available_space(&self.config.save_location) {
+                    Ok(bytes) => req.response(format!("{save_location} ({bytes} bytes free)")),
+                    Err(e) => req.respond_error(format!(
+                        "{save_location} (failed to get the free space: {e})"
+                    )),
+                }
+            }
+            Message::Metrics => req.response(metrics::render(self.peer_discovery.peer_count())),
+            Message::ClearPeers => match self.peer_discovery.clear() {
+                0 => req.response("No peers found"),
+                count => req.response(format!("Forgot {count} peers")),
+            },
+            Message::Events => {
+                let events = events::subscribe();
+                let mut writer = req.into_response_writer();
+                // A client that went away is only noticed on the next event.
+                thread::Builder::new()
+                    .name(String::from("api_events"))
+                    .spawn(move || {
+                        for event in events {
+                            if writeln!(writer, "{}", event.to_json())
+                                .and_then(|_| writer.flush())
+                                .is_err()
+                            {
+                                break;
+                            }
+                        }
+                    })?;
+                Ok(())
+            }
+            Message::Shutdown(abort) => match self.shutdown.set(*abort) {
+                Ok(_) => req.response("Shutting down"),
+                Err(_) => req.respond_error("Already shutting down"),
+            },
         }
     }
+
+    /// Returns the address of the peer the `peer` refers to, or the response to send if it
+    /// can't be found.
+    fn find_peer_addr(&self, peer: &PeerRef) -> Result<PeerAddr, String> {
+        match peer {
+            PeerRef::ID(id) => self.wait_for_peer_addr(*id).ok_or(self.peer_not_found()),
+            PeerRef::Name(name) => self
+                .peer_discovery
+                .find_peer_addr_by_name(name)
+                .map_err(|e| e.to_string()),
+            PeerRef::Index(index) => self.find_peer_addr_by_index(*index),
+        }
+    }
+
+    /// Returns the address of the peer that matches the given identifier.
+    ///
+    /// If the peer hasn't been discovered yet, e.g., right after the app started, it is waited
+    /// for until the configured `peer_wait_timeout` elapses.
+    fn wait_for_peer_addr(&self, id: PeerID) -> Option<PeerAddr> {
+        match self.config.peer_wait_timeout {
+            Some(timeout) => self.peer_discovery.wait_for_peer(id, timeout),
+            None => self.peer_discovery.find_peer_addr_by_id(id),
+        }
+    }
+
+    /// Waits for a peer to be discovered, if none have been yet, until the configured
+    /// `peer_list_wait` elapses; the peers are asked to announce themselves first.
+    fn wait_for_any_peer(&self) {
+        let Some(timeout) = self.config.peer_list_wait else {
+            return;
+        };
+        if !self.peer_discovery.is_empty() {
+            return;
+        }
+        if let Err(e) = self.peer_discovery.request_announcements() {
+            elogln!("Failed to request announcements: {e}");
+        }
+        self.peer_discovery.wait_for_any_peer(timeout);
+    }
+
+    /// Returns the index each of the `peers` is listed with, giving the next free index to
+    /// those listed for the first time.
+    fn index_peers(&self, peers: &[(PeerID, PeerInfo)]) -> Vec<usize> {
+        let mut peer_indices = self.peer_indices.lock().unwrap_or_else(|p| p.into_inner());
+        peers
+            .iter()
+            .map(
+                |(id, _)| match peer_indices.iter().position(|known| known == id) {
+                    Some(position) => position + 1,
+                    None => {
+                        peer_indices.push(*id);
+                        peer_indices.len()
+                    }
+                },
+            )
+            .collect()
+    }
+
+    /// Returns the address of the peer listed with the given index, see [`App::index_peers`].
+    fn find_peer_addr_by_index(&self, index: usize) -> Result<PeerAddr, String> {
+        let peer_indices = self.peer_indices.lock().unwrap_or_else(|p| p.into_inner());
+        let id = index
+            .checked_sub(1)
+            .and_then(|position| peer_indices.get(position))
+            .ok_or(format!("No peer has been listed with the index #{index}"))?;
+        self.peer_discovery.find_peer_addr_by_id(*id).ok_or(format!(
+            "The peer #{index} (`{id}`) is no longer discovered; list the peers again"
+        ))
+    }
+
+    /// Sends a small generated file straight to the app's own receiver, bypassing the
+    /// discovery, and checks that it is saved into the save location with the same bytes.
+    ///
+    /// This exercises the encoding of the packet, the transfer and the saving end to end.
+    fn self_test(&self) -> Result<(), String> {
+        let mut addr = match &*lock(&self.receiver_listener) {
+            Some(listener) => listener.local_addr().map_err(|e| e.to_string())?,
+            None => return Err(String::from("the receiver isn't running")),
+        };
+        if addr.ip().is_unspecified() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+
+        // A name of its own keeps the file from replacing or being renamed after another.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_nanos());
+        let file_name = format!("self-test-{nanos}.bin");
+        let contents = (0..SELF_TEST_FILE_LEN)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let path = self.config.data_location.join(&file_name);
+        fs::create_dir_all(&self.config.data_location)
+            .and_then(|_| fs::write(&path, &contents))
+            .map_err(|e| format!("failed to create the file to send: {e}"))?;
+        let sent = sender::send_file_to(addr, &path, self.my_id, &self.config);
+        let _ = fs::remove_file(&path);
+        sent.map_err(|e| format!("failed to send to `{addr}`: {e}"))?;
+
+        let received_path = match self.config.content_addressed {
+            true => receiver::object_path(&self.config.save_location, &digest::sha256(&contents)),
+            false => {
+                // Saved where the files from this peer are, like those of any other sender.
+                let mut headers = Packet::new();
+                headers.set_header("sender_name", protocol::get_my_name());
+                receiver::save_dir(&headers, &self.config).join(&file_name)
+            }
+        };
+        // Sending completes once the file is written out, which may be before it is saved.
+        let deadline = Instant::now() + SELF_TEST_TIMEOUT;
+        while fs::read(&received_path).ok().as_ref() != Some(&contents) {
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "`{}` wasn't saved with the sent bytes",
+                    received_path.display()
+                ));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // An object may be shared with other names in the index.
+        if !self.config.content_addressed {
+            let _ = fs::remove_file(&received_path);
+        }
+        Ok(())
+    }
+
+    /// Returns the path of a received file by its name, or `None` if the name isn't a plain
+    /// file name; only the files right inside the save location can be read back.
+    ///
+    /// If `save_subdir_by_peer` is enabled, the name may be preceded by the subdirectory of the
+    /// sender, e.g., `laptop/notes.txt`.
+    fn received_file_path(&self, file_name: &str) -> Option<PathBuf> {
+        let (subdir, name) = match file_name.split_once('/') {
+            Some((subdir, name)) if self.config.save_subdir_by_peer => (Some(subdir), name),
+            _ => (None, file_name),
+        };
+        if !transfer::is_plain_file_name(name)
+            || subdir.is_some_and(|subdir| !transfer::is_plain_file_name(subdir))
+        {
+            return None;
+        }
+        let save_dir = match subdir {
+            Some(subdir) => self.config.save_location.join(subdir),
+            None => self.config.save_location.clone(),
+        };
+        Some(save_dir.join(name))
+    }
+
+    /// Returns the response to a request whose target peer couldn't be found.
+    fn peer_not_found(&self) -> String {
+        match self.peer_discovery.is_empty() {
+            true => String::from("No peers found"),
+            false => String::from("No peers found that matches the given identifier"),
+        }
+    }
+}
+
+/// Responds with the `notice`, followed by each peer a file failed to be sent to, or by why it
+/// couldn't be sent at all; only the `notice` is responded if it was sent to every peer.
+fn respond_to_sends(
+    req: &mut Request,
+    notice: &str,
+    failure: &str,
+    results: io::Result<SendResults>,
+) -> io::Result<()> {
+    let failures = match results {
+        Ok(results) => results
+            .iter()
+            .filter_map(|(addr, result)| {
+                let e = result.as_ref().err()?;
+                Some(format!("{failure} to `{addr}`: {e}\n"))
+            })
+            .collect::<String>(),
+        Err(e) => format!("{failure}: {e}"),
+    };
+    let failed = !failures.is_empty();
+    respond_once(req, format!("{notice}{failures}"), failed)
+}
+
+/// Responds with the `text`, as an error if `failed`, so that the error prefix is written once
+/// at its start however many of its lines tell of a failure.
+fn respond_once(req: &mut Request, text: String, failed: bool) -> io::Result<()> {
+    match failed {
+        true => req.respond_error(text),
+        false => req.response(text),
+    }
+}
+
+/// Returns a line for each file telling whether it was sent or why it failed to be.
+fn file_results_as_text(results: FileResults) -> String {
+    results
+        .into_iter()
+        .map(|(path, result)| match result {
+            Ok(_) => format!("{}: sent\n", path.display()),
+            Err(e) => format!("{}: failed: {e}\n", path.display()),
+        })
+        .collect()
+}
+
+/// Returns the address to receive files on and announce the peer at, as per the `config`.
+///
+/// Fails if the single `interface` to run on has no IPv4 address, e.g., once it went down.
+fn resolve_my_addr(config: &Config) -> io::Result<PeerAddr> {
+    let ip = match (&config.interface, config.discovery_interfaces.is_empty()) {
+        // Failing rather than falling back to all the interfaces, which the app was asked not
+        // to run on.
+        (Some(interface), _) => interface::resolve_ipv4_addresses(slice::from_ref(interface))?[0],
+        (None, true) => return Ok(protocol::get_my_addr(config.transfer_port)),
+        // Receive on all the interfaces, since the peer can't be reached at a single one.
+        (None, false) => Ipv4Addr::UNSPECIFIED,
+    };
+    Ok(PeerAddr::new(ip.into(), config.transfer_port))
+}
+
+/// Returns the path of the file the peers are written into every `peer_snapshot_interval`.
+fn peer_snapshot_path() -> PathBuf {
+    ipc::runtime_path("-peers.json")
+}
+
+/// Writes the `peers` as a JSON array of objects with their `id`, `addr` and `name` into the
+/// file at the `path`, replacing it at once by renaming a fully written temporary file over it.
+///
+/// The temporary file is given a name of its own and created anew, so that a file or symlink
+/// someone else planted in a shared temp dir is never written through.
+fn write_peer_snapshot(path: &Path, peers: &[(PeerID, PeerInfo)]) -> io::Result<()> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".{}-{nanos}.tmp", process::id()));
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .and_then(|mut file| file.write_all(peers_as_json(peers, None, None).as_bytes()))
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Locks the `mutex`, ignoring poisoning since the values it guards are replaced at once.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|p| p.into_inner())
+}
+
+/// Lists the peers one per line, each preceded by its index and followed by its address and
+/// whether it is `reachable`, if probed.
+fn peers_as_text(
+    peers: &[(PeerID, PeerInfo)],
+    indices: &[usize],
+    reachable: Option<&[bool]>,
+) -> String {
+    peers
+        .iter()
+        .enumerate()
+        .map(|(i, (id, peer))| {
+            let addr = ShortAddr(peer.addr);
+            match reachable {
+                Some(reachable) => {
+                    format!("#{} {id} {addr} reachable: {}\n", indices[i], reachable[i])
+                }
+                None => format!("#{} {id} {addr}\n", indices[i]),
+            }
+        })
+        .collect()
+}
+
+/// Describes a peer one field per line, along with whether it is `reachable`.
+fn peer_info_as_text(id: PeerID, peer: &PeerInfo, reachable: bool) -> String {
+    let unknown = || String::from("unknown");
+    format!(
+        "id: {id}\naddr: {}\nname: {}\nversion: {}\nlast seen: {}s ago\nreachable: {reachable}\n",
+        peer.addr,
+        peer.name.clone().unwrap_or_else(unknown),
+        peer.version.clone().unwrap_or_else(unknown),
+        peer.last_seen.elapsed().as_secs()
+    )
+}
+
+/// Lists the peers as a JSON array of objects with their `index`, if `indices` are given,
+/// `id`, `addr` and `name`, along with whether each is `reachable`, if probed.
+fn peers_as_json(
+    peers: &[(PeerID, PeerInfo)],
+    indices: Option<&[usize]>,
+    reachable: Option<&[bool]>,
+) -> String {
+    let peers = peers
+        .iter()
+        .enumerate()
+        .map(|(i, (id, peer))| {
+            let name = peer
+                .name
+                .as_deref()
+                .map_or(String::from("null"), json::string);
+            let index = indices.map_or(String::new(), |indices| {
+                format!("\"index\":{},", indices[i])
+            });
+            let mut object = format!(
+                "{{{index}\"id\":{id},\"addr\":{},\"name\":{name}",
+                json::string(&peer.addr.to_string())
+            );
+            if let Some(reachable) = reachable {
+                object.push_str(&format!(",\"reachable\":{}", reachable[i]));
+            }
+            object + "}"
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]\n", peers.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::api::testing::{MockRequestReader, ResponseBuffer};
+    use crate::api::ERROR_PREFIX;
+
+    fn test_app(name: &str) -> App {
+        let root = env::temp_dir().join(format!("redtooth-{name}-{}", process::id()));
+        App::with_config(Config {
+            save_location: root.join("files"),
+            data_location: root.join("data"),
+            ..Config::default()
+        })
+    }
+
+    fn handle_all(app: &App, reader: MockRequestReader) {
+        for request in Api::new(reader).incoming_requests() {
+            app.handle_api_request(request).unwrap();
+        }
+    }
+
+    #[test]
+    fn peers_without_discovered_peers() {
+        let app = test_app("peers");
+        let reader = MockRequestReader::new();
+        let response = reader.push(Message::Peers {
+            json: false,
+            probe: false,
+        });
+
+        handle_all(&app, reader);
+        assert_eq!(response.contents(), "ERR: No peers found");
+    }
+
+    #[test]
+    fn writes_peer_snapshots_at_once() {
+        let dir = env::temp_dir().join(format!("redtooth-snapshot-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("redtooth-peers.json");
+        let peer = PeerInfo {
+            addr: "192.168.1.5:25802".parse().unwrap(),
+            name: Some(String::from("pc")),
+            seq: None,
+            version: None,
+            last_seen: Instant::now(),
+        };
+
+        fs::write(&path, "stale").unwrap();
+        write_peer_snapshot(&path, &[(7, peer)]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[{\"id\":7,\"addr\":\"192.168.1.5:25802\",\"name\":\"pc\"}]\n"
+        );
+        write_peer_snapshot(&path, &[]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[]\n");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // A symlink planted at a predictable temporary name isn't written through.
+        let target = dir.join("target");
+        fs::write(&target, "kept").unwrap();
+        std::os::unix::fs::symlink(&target, dir.join("redtooth-peers.json.tmp")).unwrap();
+        write_peer_snapshot(&path, &[]).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "kept");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lists_peers_as_json() {
+        let peer = |name: Option<&str>| PeerInfo {
+            addr: "192.168.1.5:25802".parse().unwrap(),
+            name: name.map(String::from),
+            seq: None,
+            version: None,
+            last_seen: Instant::now(),
+        };
+        let peers = [(1, peer(Some("say \"hi\""))), (2, peer(None))];
+
+        assert_eq!(
+            peers_as_json(&peers, Some(&[1, 3]), Some(&[true, false])),
+            "[{\"index\":1,\"id\":1,\"addr\":\"192.168.1.5:25802\",\"name\":\"say \\\"hi\\\"\",\
+             \"reachable\":true},\
+             {\"index\":3,\"id\":2,\"addr\":\"192.168.1.5:25802\",\"name\":null,\"reachable\":false}]\n"
+        );
+        assert_eq!(peers_as_json(&[], Some(&[]), None), "[]\n");
+        assert_eq!(
+            peers_as_text(&peers, &[1, 3], Some(&[true, false])),
+            "#1 1 192.168.1.5 reachable: true\n#3 2 192.168.1.5 reachable: false\n"
+        );
+        let other_port = [(
+            4,
+            PeerInfo {
+                addr: "192.168.1.6:8000".parse().unwrap(),
+                name: None,
+                seq: None,
+                version: None,
+                last_seen: Instant::now(),
+            },
+        )];
+        assert_eq!(
+            peers_as_text(&other_port, &[2], None),
+            "#2 4 192.168.1.6:8000\n"
+        );
+    }
+
+    #[test]
+    fn describes_a_single_peer() {
+        let app = test_app("peer-info");
+        let reader = MockRequestReader::new();
+        let response = reader.push(Message::PeerInfo(7));
+        handle_all(&app, reader);
+        assert_eq!(response.contents(), "ERR: No peers found");
+
+        let peer = PeerInfo {
+            addr: "192.168.1.5:25802".parse().unwrap(),
+            name: Some(String::from("laptop")),
+            seq: None,
+            version: None,
+            last_seen: Instant::now(),
+        };
+        assert_eq!(
+            peer_info_as_text(7, &peer, false),
+            "id: 7\naddr: 192.168.1.5:25802\nname: laptop\nversion: unknown\nlast seen: 0s ago\n\
+             reachable: false\n"
+        );
+    }
+
+    #[test]
+    fn keeps_peer_indices_for_the_session() {
+        let app = test_app("indices");
+        let peer = || PeerInfo {
+            addr: "192.168.1.5:25802".parse().unwrap(),
+            name: None,
+            seq: None,
+            version: None,
+            last_seen: Instant::now(),
+        };
+
+        assert_eq!(app.index_peers(&[(5, peer()), (3, peer())]), [1, 2]);
+        assert_eq!(app.index_peers(&[(3, peer()), (7, peer())]), [2, 3]);
+        assert_eq!(
+            app.find_peer_addr_by_index(4),
+            Err(String::from("No peer has been listed with the index #4"))
+        );
+        // The peer was listed but isn't among the discovered peers anymore.
+        assert!(app
+            .find_peer_addr_by_index(1)
+            .is_err_and(|e| e.contains("no longer discovered")));
+    }
+
+    #[test]
+    fn responds_to_each_queued_request() {
+        let app = test_app("queue");
+        let reader = MockRequestReader::new();
+        let id_response = reader.push(Message::MyID);
+        let addr_response = reader.push(Message::MyAddr);
+
+        handle_all(&app, reader);
+        assert_eq!(id_response.contents(), app.my_id.to_string());
+        assert_eq!(addr_response.contents(), app.my_addr().to_string());
+    }
+
+    #[test]
+    fn keeps_the_address_if_unchanged() {
+        let app = test_app("refresh");
+        let reader = MockRequestReader::new();
+        let response = reader.push(Message::Refresh);
+
+        handle_all(&app, reader);
+        assert_eq!(
+            response.contents(),
+            format!("The address is unchanged ({})", app.my_addr())
+        );
+    }
+
+    #[test]
+    fn refuses_to_run_on_an_interface_without_an_address() {
+        let config = Config {
+            interface: Some(String::from("missing0")),
+            ..Config::default()
+        };
+        let err = resolve_my_addr(&config).unwrap_err();
+        assert!(err.to_string().contains("no interface named `missing0`"));
+    }
+
+    #[test]
+    fn lists_the_addresses_of_all_interfaces() {
+        let app = test_app("rescan");
+        let reader = MockRequestReader::new();
+        let response = reader.push(Message::Rescan);
+
+        handle_all(&app, reader);
+        let listing = response.contents();
+        assert!(listing.lines().any(|line| line == "lo: IPv4 127.0.0.1"));
+        // The loopback address is never the one picked.
+        assert!(!listing.contains("127.0.0.1 (selected)"));
+    }
+
+    #[test]
+    fn passes_the_self_test() {
+        let app = test_app("self-test");
+        fs::create_dir_all(&app.config.save_location).unwrap();
+        let reader = MockRequestReader::new();
+        let stopped_response = reader.push(Message::SelfTest);
+        handle_all(&app, reader);
+        assert_eq!(
+            stopped_response.contents(),
+            "ERR: fail: the receiver isn't running"
+        );
+
+        app.spawn_file_receiver("127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let reader = MockRequestReader::new();
+        let response = reader.push(Message::SelfTest);
+        handle_all(&app, reader);
+        assert_eq!(response.contents(), "pass");
+        // Nothing is left behind.
+        assert_eq!(fs::read_dir(&app.config.save_location).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn sends_to_groups_of_discovered_peers() {
+        let app = test_app("groups");
+        let reader = MockRequestReader::new();
+        let empty_response = reader.push(Message::Groups);
+        let create_response = reader.push(Message::CreateGroup(String::from("work"), vec![1, 2]));
+        let replace_response = reader.push(Message::CreateGroup(String::from("work"), vec![3]));
+        let invalid_response = reader.push(Message::CreateGroup(String::from("a,b"), vec![1]));
+        let list_response = reader.push(Message::Groups);
+        let send_response = reader.push(Message::SendToGroup(
+            String::from("work"),
+            String::from("notes.txt"),
+        ));
+        let unknown_response = reader.push(Message::SendToGroup(
+            String::from("home"),
+            String::from("notes.txt"),
+        ));
+
+        handle_all(&app, reader);
+        assert_eq!(empty_response.contents(), "No groups defined");
+        assert_eq!(create_response.contents(), "Created the group `work`");
+        assert_eq!(replace_response.contents(), "Replaced the group `work`");
+        assert_eq!(
+            invalid_response.contents(),
+            "ERR: Failed to create the group: invalid group name `a,b`"
+        );
+        assert_eq!(list_response.contents(), "work: 3\n");
+        assert_eq!(
+            send_response.contents(),
+            "ERR: None of the members of `work` are discovered"
+        );
+        assert_eq!(unknown_response.contents(), "ERR: No group named `home`");
+    }
+
+    #[test]
+    fn prefixes_failed_sends_once() {
+        let addrs: [PeerAddr; 3] = ["10.0.0.1:25802", "10.0.0.2:25802", "10.0.0.3:25802"]
+            .map(|addr| addr.parse().unwrap());
+        let refused = || Err(Error::from(io::ErrorKind::ConnectionRefused));
+        let respond = |notice: &str, results| {
+            let response = ResponseBuffer::default();
+            let mut req = Request::new(Message::Ping, Box::new(response.clone()));
+            respond_to_sends(&mut req, notice, "Failed to send file", results).unwrap();
+            response.contents()
+        };
+
+        let response = respond(
+            "Skipped the members that aren't discovered: 4\n",
+            Ok(vec![
+                (addrs[0], refused()),
+                (addrs[1], Ok(())),
+                (addrs[2], refused()),
+            ]),
+        );
+        assert!(response.starts_with("ERR: Skipped the members"));
+        assert_eq!(response.matches(ERROR_PREFIX).count(), 1);
+        assert_eq!(response.lines().count(), 3);
+        assert_eq!(respond("", Ok(vec![(addrs[1], Ok(()))])), "");
+    }
+
+    #[test]
+    fn shuts_down_once_idle() {
+        let app = test_app("idle");
+        let timeout = Duration::from_millis(100);
+        let started = Instant::now();
+
+        app.shut_down_once_idle(timeout);
+        assert!(started.elapsed() >= timeout);
+        assert_eq!(app.shutdown.get(), Some(&false));
+    }
+
+    #[test]
+    fn reads_back_received_files() {
+        let app = test_app("cat");
+        fs::create_dir_all(&app.config.save_location).unwrap();
+        fs::write(app.config.save_location.join("notes.txt"), b"some notes").unwrap();
+        let reader = MockRequestReader::new();
+        let file_response = reader.push(Message::Cat(String::from("notes.txt")));
+        let missing_response = reader.push(Message::Cat(String::from("missing.txt")));
+        let escaping_response = reader.push(Message::Cat(String::from("../data/id")));
+
+        handle_all(&app, reader);
+        assert_eq!(file_response.contents(), "some notes");
+        assert_eq!(
+            missing_response.contents(),
+            "ERR: No received file named `missing.txt`"
+        );
+        assert_eq!(escaping_response.contents(), "ERR: Invalid file name");
+
+        // Files saved apart by their sender are read back by their path.
+        let app = App::with_config(Config {
+            save_subdir_by_peer: true,
+            ..app.config.clone()
+        });
+        fs::create_dir_all(app.config.save_location.join("laptop")).unwrap();
+        fs::write(
+            app.config.save_location.join("laptop/notes.txt"),
+            b"laptop notes",
+        )
+        .unwrap();
+        let reader = MockRequestReader::new();
+        let file_response = reader.push(Message::Cat(String::from("laptop/notes.txt")));
+        let escaping_response = reader.push(Message::Cat(String::from("../data/id")));
+
+        handle_all(&app, reader);
+        assert_eq!(file_response.contents(), "laptop notes");
+        assert_eq!(escaping_response.contents(), "ERR: Invalid file name");
+    }
+
+    #[test]
+    fn verifies_received_files() {
+        let app = test_app("verify");
+        fs::create_dir_all(&app.config.save_location).unwrap();
+        fs::write(app.config.save_location.join("notes.txt"), b"some notes").unwrap();
+        let hash = digest::to_hex(&digest::sha256(b"some notes"));
+        let other_hash = digest::to_hex(&digest::sha256(b"other notes"));
+        let verify = |file_name: &str, hash: &str| {
+            Message::Verify(String::from(file_name), String::from(hash))
+        };
+        let reader = MockRequestReader::new();
+        let match_response = reader.push(verify("notes.txt", &hash.to_uppercase()));
+        let mismatch_response = reader.push(verify("notes.txt", &other_hash));
+        let invalid_response = reader.push(verify("notes.txt", "abc"));
+        let missing_response = reader.push(verify("missing.txt", &hash));
+
+        handle_all(&app, reader);
+        assert_eq!(match_response.contents(), "`notes.txt` matches the hash");
+        assert_eq!(
+            mismatch_response.contents(),
+            format!("`notes.txt` doesn't match the hash; its hash is {hash}")
+        );
+        assert_eq!(
+            invalid_response.contents(),
+            "ERR: Invalid SHA-256 hash `abc`"
+        );
+        assert_eq!(
+            missing_response.contents(),
+            "ERR: No received file named `missing.txt`"
+        );
+    }
 }
@@ -1,37 +1,715 @@
-use std::io;
-use std::io::BufRead;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::path::{self, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::protocol::PeerID;
+use crate::api::{Message, PeerRef};
+use crate::config::Config;
+use crate::ipc;
+use crate::protocol::{id, PeerAddr, PeerID};
+use crate::transfer::confirm::ConfirmCommand;
+use crate::transfer::ByteRange;
+
+/// Time the command set by `--confirm-command` has to decide on a file; below the default
+/// `transfer_timeout`, so that the file is declined before its sender gives up waiting.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(20);
 
 pub enum Command<'buf> {
     /// Unknown or unrecognized command
     Unknown,
     /// Display the IP address of the current device.
     MyIp,
-    /// Display the identifiers of all the discovered peers.
-    List,
-    /// Send a file to all the peers.
-    Send(&'buf str),
-    /// Send a file to the peer that matches the given identifier.
-    SendTo(PeerID, &'buf str),
+    /// Display the identifiers of all the discovered peers, as JSON if `json`, along with
+    /// whether each of them can be connected to if `probe`.
+    List { json: bool, probe: bool },
+    /// Send files to all the peers, e.g., `send a.txt "my notes.txt"`.
+    Send(Vec<PathBuf>),
+    /// Send a directory to all the peers as a single archive.
+    SendArchive(&'buf str),
+    /// Send a text to all the peers as a text file, e.g., `send --text "hello world"`.
+    SendText(&'buf str),
+    /// Send a file to the peer that matches the given identifier, name or listed index.
+    SendTo(PeerRef, &'buf str),
+    /// Set whether files are sent to a peer without asking when it already has a file of the
+    /// same name (`on`, the default) or only once confirmed, see [`confirm_overwrite`].
+    Overwrite(bool),
+    /// Send a file to the given address, whether or not its peer has been discovered.
+    SendToAddr(PeerAddr, &'buf str),
+    /// Send a file to all the peers except the ones that match the given identifiers.
+    SendExcept(Vec<PeerID>, &'buf str),
+    /// Send a file to the discovered members of the named group.
+    SendToGroup(&'buf str, &'buf str),
+    /// Display the groups of peers along with their members.
+    Groups,
+    /// Create a group of peers by their identifiers, replacing any group of the same name.
+    CreateGroup(&'buf str, Vec<PeerID>),
+    /// Send a short text message to the peer that matches the given identifier.
+    Msg(PeerID, &'buf str),
+    /// Announce to the peers and ask them to announce themselves.
+    Discover,
+    /// Re-resolve the local address, e.g., after switching networks, and announce it if it
+    /// changed.
+    Refresh,
+    /// Enumerate the interfaces again and display all their addresses, marking the one the
+    /// local address is picked from.
+    Rescan,
+    /// Send a small generated file to the peer itself to check that files are encoded, sent
+    /// and saved intact.
+    SelfTest,
+    /// Send a multicast probe and display the peers that echoed it back, to check that
+    /// multicast works on the network.
+    McastTest,
+    /// Display the last error hit by each background component.
+    Diagnostics,
+    /// Display the most recent datagrams received by the discovery and what became of them.
+    DiscoveryDebug,
+    /// Display the given number of most recent transfers.
+    History(usize),
+    /// Display the files shared by the peer that matches the given identifier.
+    ListRemote(PeerID),
+    /// Display everything known about the peer that matches the given identifier.
+    PeerInfo(PeerID),
+    /// Fetch a shared file, or only the given range of it, from the peer that matches the given
+    /// identifier.
+    Pull(PeerID, &'buf str, Option<ByteRange>),
+    /// Display the contents of a received file, by its name.
+    Cat(&'buf str),
+    /// Check a received file, by its name, against the given SHA-256 hash.
+    Verify(&'buf str, &'buf str),
+    /// Display where received files are saved and how much space is left there.
+    SaveInfo,
+    /// Display the transfer counters in the Prometheus text format.
+    Metrics,
+    /// Forget all the discovered peers, so that they have to be discovered again.
+    ClearPeers,
+    /// Display the transfer events as they happen.
+    Events,
+    /// Stop the daemon, aborting the transfers in progress if `true`.
+    Shutdown(bool),
+}
+
+/// Represents an error that can occur when reading a command.
+pub enum CommandError {
+    Io(io::Error),
+    /// The command lacks the named argument.
+    MissingArgument(&'static str),
+    /// The named argument isn't valid; holds the given value.
+    InvalidArgument(&'static str, String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Io(e) => write!(f, "failed to read the command: {e}"),
+            CommandError::MissingArgument(name) => write!(f, "missing {name}"),
+            CommandError::InvalidArgument(name, value) => write!(f, "invalid {name} `{value}`"),
+        }
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(err: io::Error) -> CommandError {
+        CommandError::Io(err)
+    }
+}
+
+impl Command<'_> {
+    /// Returns the API message the command is sent to the daemon as, or `None` if the command
+    /// is unknown.
+    ///
+    /// File paths are made absolute, since the daemon doesn't share the working directory.
+    fn into_message(self) -> Option<Message> {
+        let message = match self {
+            Command::Unknown | Command::Overwrite(_) => return None,
+            Command::MyIp => Message::MyAddr,
+            Command::List { json, probe } => Message::Peers { json, probe },
+            Command::Send(paths) => Message::Send(paths.iter().map(absolute_path).collect()),
+            Command::SendArchive(path) => Message::SendArchive(absolute_path(path)),
+            Command::SendText(text) => Message::SendText(text.to_string()),
+            Command::SendTo(peer, path) => Message::SendTo(peer, absolute_path(path)),
+            Command::SendToAddr(peer_addr, path) => {
+                Message::SendToAddr(peer_addr, absolute_path(path))
+            }
+            Command::SendExcept(peer_ids, path) => {
+                Message::SendExcept(peer_ids, absolute_path(path))
+            }
+            Command::SendToGroup(group, path) => {
+                Message::SendToGroup(group.to_string(), absolute_path(path))
+            }
+            Command::Groups => Message::Groups,
+            Command::CreateGroup(name, peer_ids) => {
+                Message::CreateGroup(name.to_string(), peer_ids)
+            }
+            Command::Msg(peer_id, text) => Message::Msg(peer_id, text.to_string()),
+            Command::Discover => Message::Discover,
+            Command::Refresh => Message::Refresh,
+            Command::Rescan => Message::Rescan,
+            Command::SelfTest => Message::SelfTest,
+            Command::McastTest => Message::McastTest,
+            Command::Diagnostics => Message::Diagnostics,
+            Command::DiscoveryDebug => Message::DiscoveryDebug,
+            Command::History(count) => Message::History(count),
+            Command::ListRemote(peer_id) => Message::ListRemote(peer_id),
+            Command::PeerInfo(peer_id) => Message::PeerInfo(peer_id),
+            Command::Pull(peer_id, file_name, range) => {
+                Message::Pull(peer_id, file_name.to_string(), range)
+            }
+            Command::Cat(file_name) => Message::Cat(file_name.to_string()),
+            Command::Verify(file_name, hash) => {
+                Message::Verify(file_name.to_string(), hash.to_string())
+            }
+            Command::SaveInfo => Message::SaveInfo,
+            Command::Metrics => Message::Metrics,
+            Command::ClearPeers => Message::ClearPeers,
+            Command::Events => Message::Events,
+            Command::Shutdown(abort) => Message::Shutdown(abort),
+        };
+        Some(message)
+    }
+}
+
+/// Reads commands from the standard input and sends them to the running daemon, writing its
+/// responses to the standard output, until the input ends or the daemon is stopped.
+///
+/// Started by running the app as `redtooth shell`.
+pub fn run_shell() -> io::Result<()> {
+    let mut stdout = io::stdout();
+    let mut overwrite = true;
+
+    loop {
+        write!(stdout, "> ")?;
+        stdout.flush()?;
+        let mut input = String::new();
+        // The input ended, e.g., on Ctrl-D.
+        if io::stdin().lock().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
+        let command = match parse_command(&input) {
+            Ok(command) => command,
+            Err(e) => {
+                writeln!(stdout, "{e}")?;
+                continue;
+            }
+        };
+        match &command {
+            Command::Overwrite(on) => {
+                overwrite = *on;
+                let status = if overwrite { "on" } else { "off" };
+                writeln!(stdout, "Overwriting files on the peers is {status}")?;
+                continue;
+            }
+            Command::SendTo(peer, file_path) if !overwrite => {
+                let mut stdin = io::stdin().lock();
+                if !confirm_overwrite(peer, file_path, &mut stdin, &mut stdout)? {
+                    writeln!(stdout, "Not sent")?;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        let stops = matches!(command, Command::Shutdown(_));
+        let Some(message) = command.into_message() else {
+            writeln!(stdout, "Unknown command `{}`", input.trim())?;
+            continue;
+        };
+        match ipc::stream_request(message, &mut stdout) {
+            Ok(_) => writeln!(stdout)?,
+            Err(e) => writeln!(stdout, "{e}")?,
+        }
+        if stops {
+            return Ok(());
+        }
+    }
+}
+
+/// Asks the daemon whether the `peer` already has a file named like the one at the
+/// `file_path` and, if so, whether to overwrite it; returns whether to send the file.
+///
+/// Used by the shell before sending to a single peer while [`Command::Overwrite`] is off.
+pub fn confirm_overwrite(
+    peer: &PeerRef,
+    file_path: &str,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> io::Result<bool> {
+    let file_name = Path::new(file_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let answer = ipc::send_request(Message::FileExists(peer.clone(), file_name.clone()))?;
+    match answer.as_str() {
+        "exists" => ask_overwrite(&file_name, input, output),
+        "missing" => Ok(true),
+        // E.g., the peer couldn't be found; sending reports it again.
+        _ => {
+            writeln!(output, "{answer}")?;
+            Ok(false)
+        }
+    }
+}
+
+/// Prompts whether to overwrite the file with the given name on the peer; anything but a yes
+/// is a no.
+fn ask_overwrite(
+    file_name: &str,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> io::Result<bool> {
+    write!(
+        output,
+        "`{file_name}` exists on the peer, overwrite? [y/N] "
+    )?;
+    output.flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Applies the command-line `args` the app was started with to the `config`, e.g.,
+/// `--interface eth0` or `--peer-timeout <secs>`.
+///
+/// Each flag sets a field of the config:
+///
+/// | Flag                                | Field                     |
+/// |-------------------------------------|---------------------------|
+/// | `--interface <name or address>`     | `interface`               |
+/// | `--save-subdir-by-peer`             | `save_subdir_by_peer`     |
+/// | `--id-strategy <name>`              | `id_strategy`             |
+/// | `--confirm-command <template>`      | `confirm_incoming`        |
+/// | `--peer-timeout <secs>`             | `peer_wait_timeout`       |
+/// | `--http-api <address>`              | `http_api_addr`           |
+/// | `--mdns`                            | `mdns_announcements`      |
+/// | `--no-multicast`                    | `multicast_announcements` |
+/// | `--unicast-announce <ip>`           | `unicast_announce_addrs`  |
+/// | `--bootstrap-server <address>`      | `bootstrap_server`        |
+/// | `--bootstrap-interval <secs>`       | `bootstrap_interval`      |
+/// | `--content-addressed`               | `content_addressed`       |
+/// | `--post-receive-command <template>` | `post_receive_command`    |
+/// | `--rename-template <template>`      | `rename_template`         |
+/// | `--idle-timeout <secs>`             | `idle_timeout`            |
+/// | `--idle-only-without-peers`         | `idle_only_without_peers` |
+/// | `--peer-snapshot-interval <secs>`   | `peer_snapshot_interval`  |
+///
+/// `--unicast-announce` may be given several times, once per address. A duration of 0 seconds
+/// disables what it times, where the field is optional.
+pub fn apply_args(
+    args: impl IntoIterator<Item = String>,
+    config: &mut Config,
+) -> Result<(), CommandError> {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interface" => config.interface = Some(next_value(&mut args, "interface name")?),
+            "--save-subdir-by-peer" => config.save_subdir_by_peer = true,
+            "--id-strategy" => {
+                let name = next_value::<String>(&mut args, "id strategy")?;
+                config.id_strategy = id::strategy_by_name(&name)
+                    .ok_or(CommandError::InvalidArgument("id strategy", name))?;
+            }
+            "--confirm-command" => {
+                let template = next_value(&mut args, "confirmation command")?;
+                config.confirm_incoming = Some(Arc::new(ConfirmCommand {
+                    template,
+                    timeout: CONFIRM_TIMEOUT,
+                }));
+            }
+            "--peer-timeout" => config.peer_wait_timeout = next_secs(&mut args, "peer timeout")?,
+            "--http-api" => config.http_api_addr = Some(next_value(&mut args, "api address")?),
+            "--mdns" => config.mdns_announcements = true,
+            "--no-multicast" => config.multicast_announcements = false,
+            "--unicast-announce" => {
+                let addr = next_value(&mut args, "announce address")?;
+                config.unicast_announce_addrs.push(addr);
+            }
+            "--bootstrap-server" => {
+                config.bootstrap_server = Some(next_value(&mut args, "bootstrap server")?);
+            }
+            "--bootstrap-interval" => {
+                config.bootstrap_interval = next_secs(&mut args, "bootstrap interval")?.ok_or(
+                    CommandError::InvalidArgument("bootstrap interval", String::from("0")),
+                )?;
+            }
+            "--content-addressed" => config.content_addressed = true,
+            "--post-receive-command" => {
+                let template = next_value(&mut args, "post-receive command")?;
+                config.post_receive_command = Some(template);
+            }
+            "--rename-template" => {
+                config.rename_template = Some(next_value(&mut args, "rename template")?);
+            }
+            "--idle-timeout" => config.idle_timeout = next_secs(&mut args, "idle timeout")?,
+            "--idle-only-without-peers" => config.idle_only_without_peers = true,
+            "--peer-snapshot-interval" => {
+                config.peer_snapshot_interval = next_secs(&mut args, "snapshot interval")?;
+            }
+            _ => return Err(CommandError::InvalidArgument("flag", arg)),
+        }
+    }
+    Ok(())
 }
 
-pub fn read_command(input_buffer: &mut String) -> io::Result<Command> {
-    let mut stdin = io::stdin().lock();
-    stdin.read_line(input_buffer)?;
+/// Takes the value of a command-line flag from the `args` and parses it.
+fn next_value<T: FromStr>(
+    args: &mut impl Iterator<Item = String>,
+    name: &'static str,
+) -> Result<T, CommandError> {
+    let value = args.next().ok_or(CommandError::MissingArgument(name))?;
+    parse_value(&value, name)
+}
+
+/// Takes a number of seconds from the `args` like [`next_value`]; 0 seconds is `None`.
+fn next_secs(
+    args: &mut impl Iterator<Item = String>,
+    name: &'static str,
+) -> Result<Option<Duration>, CommandError> {
+    let secs = next_value(args, name)?;
+    Ok(Some(Duration::from_secs(secs)).filter(|duration| !duration.is_zero()))
+}
 
-    let mut it = input_buffer.split(' ');
+fn parse_command(input: &str) -> Result<Command<'_>, CommandError> {
+    let mut it = input.split(' ');
     let command = it.next().unwrap_or_default().trim();
     let command = match command {
         "myip" => Command::MyIp,
-        "list" => Command::List,
-        "send" => Command::Send(it.next().unwrap().trim()),
+        "list" => {
+            let flags = it.map(str::trim).collect::<Vec<_>>();
+            Command::List {
+                json: flags.contains(&"--json"),
+                probe: flags.contains(&"--probe"),
+            }
+        }
+        "send" => match next_arg(&mut it, "file path")? {
+            "--archive" => Command::SendArchive(next_arg(&mut it, "directory path")?),
+            "--text" => {
+                let text = input.splitn(3, ' ').nth(2).unwrap_or_default().trim();
+                let text = text
+                    .strip_prefix('"')
+                    .and_then(|text| text.strip_suffix('"'))
+                    .unwrap_or(text);
+                match text {
+                    "" => return Err(CommandError::MissingArgument("text")),
+                    text => Command::SendText(text),
+                }
+            }
+            index if index.starts_with('#') => {
+                let index = parse_value(&index[1..], "peer index")?;
+                Command::SendTo(PeerRef::Index(index), next_arg(&mut it, "file path")?)
+            }
+            group if group.starts_with('@') => {
+                Command::SendToGroup(&group[1..], next_arg(&mut it, "file path")?)
+            }
+            _ => Command::Send(parse_paths(input)?),
+        },
         "sendto" => {
-            let peer_id = it.next().unwrap().trim().parse::<PeerID>().unwrap();
-            let file_path = it.next().unwrap().trim();
-            Command::SendTo(peer_id, file_path)
+            let Ok(peer) = next_arg(&mut it, "peer id or name")?.parse::<PeerRef>();
+            Command::SendTo(peer, next_arg(&mut it, "file path")?)
+        }
+        "overwrite" => match next_arg(&mut it, "on or off")? {
+            "on" => Command::Overwrite(true),
+            "off" => Command::Overwrite(false),
+            value => {
+                return Err(CommandError::InvalidArgument(
+                    "on or off",
+                    value.to_string(),
+                ))
+            }
+        },
+        "sendtoaddr" => {
+            let peer_addr = parse_arg(&mut it, "peer address")?;
+            Command::SendToAddr(peer_addr, next_arg(&mut it, "file path")?)
+        }
+        "send_all" => match next_arg(&mut it, "file path")? {
+            "--except" => {
+                let peer_ids = next_arg(&mut it, "peer ids")?
+                    .split(',')
+                    .map(|id| parse_value(id.trim(), "peer id"))
+                    .collect::<Result<_, _>>()?;
+                Command::SendExcept(peer_ids, next_arg(&mut it, "file path")?)
+            }
+            _ => Command::Send(parse_paths(input)?),
+        },
+        "msg" => {
+            let peer_id = parse_arg(&mut it, "peer id")?;
+            let text = input.splitn(3, ' ').nth(2).unwrap_or_default().trim();
+            Command::Msg(peer_id, text)
+        }
+        "groups" => Command::Groups,
+        "group" => {
+            let name = next_arg(&mut it, "group name")?;
+            let peer_ids = next_arg(&mut it, "peer ids")?
+                .split(',')
+                .map(|id| parse_value(id.trim(), "peer id"))
+                .collect::<Result<_, _>>()?;
+            Command::CreateGroup(name, peer_ids)
         }
+        "discover" => Command::Discover,
+        "refresh" => Command::Refresh,
+        "rescan" => Command::Rescan,
+        "self_test" => Command::SelfTest,
+        "mcast_test" => Command::McastTest,
+        "diagnostics" => Command::Diagnostics,
+        "discovery_debug" => Command::DiscoveryDebug,
+        "history" => match it.next().map(str::trim).filter(|count| !count.is_empty()) {
+            Some(count) => Command::History(parse_value(count, "count")?),
+            None => Command::History(10),
+        },
+        "list_remote" => Command::ListRemote(parse_arg(&mut it, "peer id")?),
+        "peer_info" => Command::PeerInfo(parse_arg(&mut it, "peer id")?),
+        "pull" => {
+            let peer_id = parse_arg(&mut it, "peer id")?;
+            match next_arg(&mut it, "file name")? {
+                "--range" => {
+                    let range = parse_arg(&mut it, "byte range")?;
+                    Command::Pull(peer_id, next_arg(&mut it, "file name")?, Some(range))
+                }
+                file_name => Command::Pull(peer_id, file_name, None),
+            }
+        }
+        "cat" => Command::Cat(next_arg(&mut it, "file name")?),
+        "verify" => {
+            let file_name = next_arg(&mut it, "file name")?;
+            Command::Verify(file_name, next_arg(&mut it, "hash")?)
+        }
+        "save_info" => Command::SaveInfo,
+        "metrics" => Command::Metrics,
+        "clear_peers" => Command::ClearPeers,
+        "events" => Command::Events,
+        "stop" | "quit" => Command::Shutdown(it.next().is_some_and(|arg| arg.trim() == "--abort")),
         _ => Command::Unknown,
     };
     Ok(command)
 }
+
+/// Returns the `path` made absolute against the working directory, or as is if it can't be.
+fn absolute_path(path: impl AsRef<Path>) -> String {
+    let path = path.as_ref();
+    path::absolute(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Returns the next argument, named `name` in the error if it is missing.
+fn next_arg<'a>(
+    it: &mut impl Iterator<Item = &'a str>,
+    name: &'static str,
+) -> Result<&'a str, CommandError> {
+    it.next()
+        .map(str::trim)
+        .filter(|arg| !arg.is_empty())
+        .ok_or(CommandError::MissingArgument(name))
+}
+
+/// Parses the file paths following the command in the `input`, where paths containing spaces
+/// are quoted.
+fn parse_paths(input: &str) -> Result<Vec<PathBuf>, CommandError> {
+    let paths = input.split_once(' ').unwrap_or_default().1;
+    let paths = ipc::split_quoted(paths)
+        .ok_or_else(|| CommandError::InvalidArgument("file paths", paths.trim().to_string()))?;
+    Ok(paths.into_iter().map(PathBuf::from).collect())
+}
+
+/// Parses the next argument, named `name` in the error if it is missing or invalid.
+fn parse_arg<'a, T: FromStr>(
+    it: &mut impl Iterator<Item = &'a str>,
+    name: &'static str,
+) -> Result<T, CommandError> {
+    parse_value(next_arg(it, name)?, name)
+}
+
+fn parse_value<T: FromStr>(value: &str, name: &'static str) -> Result<T, CommandError> {
+    value
+        .parse()
+        .map_err(|_| CommandError::InvalidArgument(name, value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::IpAddr;
+
+    use super::*;
+
+    #[test]
+    fn reports_missing_and_invalid_arguments() {
+        for input in [
+            "send\n",
+            "send \n",
+            "sendto 42\n",
+            "send --archive\n",
+            "pull 42\n",
+            "group work\n",
+        ] {
+            assert!(matches!(
+                parse_command(input),
+                Err(CommandError::MissingArgument(_))
+            ));
+        }
+        let Err(err) = parse_command("sendtoaddr nowhere notes.txt\n") else {
+            panic!("expected an invalid address");
+        };
+        assert_eq!(err.to_string(), "invalid peer address `nowhere`");
+        assert!(parse_command("send_all --except 1,x notes.txt\n").is_err());
+
+        let Ok(Command::SendTo(PeerRef::ID(42), "notes.txt")) =
+            parse_command("sendto 42 notes.txt\n")
+        else {
+            panic!("expected a sendto command");
+        };
+        assert!(matches!(
+            parse_command("history\n"),
+            Ok(Command::History(10))
+        ));
+        assert!(matches!(
+            parse_command("send #2 notes.txt\n"),
+            Ok(Command::SendTo(PeerRef::Index(2), "notes.txt"))
+        ));
+        assert!(matches!(
+            parse_command("send @work notes.txt\n"),
+            Ok(Command::SendToGroup("work", "notes.txt"))
+        ));
+        let Ok(Command::Send(paths)) = parse_command("send a.txt \"my notes.txt\" c.txt\n") else {
+            panic!("expected a send command");
+        };
+        assert_eq!(paths, ["a.txt", "my notes.txt", "c.txt"].map(PathBuf::from));
+        assert!(parse_command("send \"unclosed.txt\n").is_err());
+        let Ok(Command::CreateGroup("work", peer_ids)) = parse_command("group work 1,2\n") else {
+            panic!("expected a group command");
+        };
+        assert_eq!(peer_ids, [1, 2]);
+    }
+
+    #[test]
+    fn sends_file_paths_as_absolute() {
+        let Some(Message::SendTo(PeerRef::ID(42), path)) = parse_command("sendto 42 notes.txt\n")
+            .ok()
+            .and_then(Command::into_message)
+        else {
+            panic!("expected a send_to message");
+        };
+        assert_eq!(
+            PathBuf::from(path),
+            env::current_dir().unwrap().join("notes.txt")
+        );
+        assert!(Command::Unknown.into_message().is_none());
+    }
+
+    #[test]
+    fn asks_before_overwriting() {
+        let mut output = Vec::new();
+        assert!(ask_overwrite("notes.txt", &mut "y\n".as_bytes(), &mut output).unwrap());
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "`notes.txt` exists on the peer, overwrite? [y/N] "
+        );
+        for answer in ["\n", "n\n", "whatever\n", ""] {
+            assert!(!ask_overwrite("notes.txt", &mut answer.as_bytes(), &mut Vec::new()).unwrap());
+        }
+        assert!(matches!(
+            parse_command("overwrite off\n"),
+            Ok(Command::Overwrite(false))
+        ));
+    }
+
+    #[test]
+    fn applies_command_line_args() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let mut config = Config::default();
+
+        assert!(apply_args(args(&["--interface", "eth0"]), &mut config).is_ok());
+        assert!(!config.save_subdir_by_peer);
+        assert!(apply_args(args(&["--save-subdir-by-peer"]), &mut config).is_ok());
+        assert!(config.save_subdir_by_peer);
+        assert_eq!(config.interface.as_deref(), Some("eth0"));
+        assert!(apply_args(args(&["--id-strategy", "random"]), &mut config).is_ok());
+        assert!(matches!(
+            apply_args(args(&["--id-strategy", "mac"]), &mut config),
+            Err(CommandError::InvalidArgument("id strategy", _))
+        ));
+        assert!(apply_args(
+            args(&["--confirm-command", "confirm-file {name}"]),
+            &mut config
+        )
+        .is_ok());
+        assert!(config.confirm_incoming.is_some());
+        assert!(apply_args(args(&["--peer-timeout", "15"]), &mut config).is_ok());
+        assert_eq!(config.peer_wait_timeout, Some(Duration::from_secs(15)));
+        assert!(apply_args(args(&["--peer-timeout", "0"]), &mut config).is_ok());
+        assert_eq!(config.peer_wait_timeout, None);
+        assert!(matches!(
+            apply_args(args(&["--peer-timeout", "soon"]), &mut config),
+            Err(CommandError::InvalidArgument("peer timeout", _))
+        ));
+
+        let flags = [
+            "--http-api",
+            "127.0.0.1:8080",
+            "--mdns",
+            "--no-multicast",
+            "--unicast-announce",
+            "10.0.0.2",
+            "--unicast-announce",
+            "10.0.0.3",
+            "--bootstrap-server",
+            "10.0.0.1:25900",
+            "--bootstrap-interval",
+            "60",
+            "--content-addressed",
+            "--post-receive-command",
+            "notify-send {path}",
+            "--rename-template",
+            "{stem} ({n}){ext}",
+            "--idle-timeout",
+            "300",
+            "--idle-only-without-peers",
+            "--peer-snapshot-interval",
+            "5",
+        ];
+        assert!(apply_args(args(&flags), &mut config).is_ok());
+        assert_eq!(
+            config.http_api_addr,
+            Some("127.0.0.1:8080".parse().unwrap())
+        );
+        assert!(config.mdns_announcements);
+        assert!(!config.multicast_announcements);
+        assert_eq!(
+            config.unicast_announce_addrs,
+            [
+                "10.0.0.2".parse::<IpAddr>().unwrap(),
+                "10.0.0.3".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            config.bootstrap_server,
+            Some("10.0.0.1:25900".parse().unwrap())
+        );
+        assert_eq!(config.bootstrap_interval, Duration::from_secs(60));
+        assert!(config.content_addressed);
+        assert_eq!(
+            config.post_receive_command.as_deref(),
+            Some("notify-send {path}")
+        );
+        assert_eq!(config.rename_template.as_deref(), Some("{stem} ({n}){ext}"));
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(300)));
+        assert!(config.idle_only_without_peers);
+        assert_eq!(config.peer_snapshot_interval, Some(Duration::from_secs(5)));
+        assert!(matches!(
+            apply_args(args(&["--bootstrap-interval", "0"]), &mut config),
+            Err(CommandError::InvalidArgument("bootstrap interval", _))
+        ));
+        assert!(matches!(
+            apply_args(args(&["--http-api", "localhost"]), &mut config),
+            Err(CommandError::InvalidArgument("api address", _))
+        ));
+        assert!(matches!(
+            apply_args(args(&["--interface"]), &mut config),
+            Err(CommandError::MissingArgument("interface name"))
+        ));
+        let Err(err) = apply_args(args(&["--verbose"]), &mut config) else {
+            panic!("expected an unknown flag");
+        };
+        assert_eq!(err.to_string(), "invalid flag `--verbose`");
+    }
+}
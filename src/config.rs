@@ -1,5 +1,16 @@
+use std::collections::HashMap;
 use std::env;
-use std::path::{Path, PathBuf};
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::slice;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::protocol::id::{AddrBased, IdStrategy};
+use crate::protocol::{PeerID, DEFAULT_PEER_PORT};
+use crate::transfer::confirm::Confirm;
+use crate::{elogln, interface};
 
 #[cfg(not(windows))]
 const HOME_ENV_KEY: &str = "HOME";
@@ -8,18 +19,287 @@ const HOME_ENV_KEY: &str = "USERPROFILE";
 /// Directory where all the received files will live.
 const DIR_NAME: &str = env!("CARGO_PKG_NAME");
 
+#[derive(Clone)]
 pub struct Config {
     /// Path where the received file will be saved.
     pub save_location: PathBuf,
+    /// Path of the directory whose files the peers may list and pull.
+    pub shared_location: PathBuf,
+    /// Path where the app keeps its own state (e.g., the persisted peer id).
+    pub data_location: PathBuf,
+    /// How the peer id is generated when no id was persisted by a previous launch, e.g.,
+    /// `--id-strategy random`; see [`strategy_by_name`](crate::protocol::id::strategy_by_name).
+    pub id_strategy: Arc<dyn IdStrategy>,
+    /// Port on which the files are received.
+    pub transfer_port: u16,
+    /// Maximum duration a read or write on a transfer connection may stall before failing
+    /// with [`TimedOut`](std::io::ErrorKind::TimedOut); `None` waits indefinitely.
+    pub transfer_timeout: Option<Duration>,
+    /// Whether to enable `SO_KEEPALIVE` on transfer connections.
+    pub transfer_keepalive: bool,
+    /// How long closing a transfer connection may block to deliver unsent data;
+    /// `None` leaves the OS default.
+    pub transfer_linger: Option<Duration>,
+    /// Number of times sending to a peer is retried after a transient network error, e.g., a
+    /// refused or reset connection, before the send is failed.
+    pub send_retries: u32,
+    /// Delay before retrying a failed send.
+    pub send_retry_delay: Duration,
+    /// Whether to have the peer tell how many bytes of each file sent on its own it saved, and
+    /// fail the send unless it saved them all. Peers running versions that don't confirm make
+    /// such sends time out.
+    pub confirm_received_length: bool,
+    /// Maximum number of peers a file is sent to at once; the sends to further peers are
+    /// queued until one of them completes.
+    pub max_concurrent_sends: usize,
+    /// Size of the buffers transfer connections and the files sent over them are read and
+    /// written through, so that large files take fewer, larger syscalls.
+    pub transfer_buffer_size: usize,
+    /// Maximum number of connections the OS queues for the receiver until they are accepted.
+    pub transfer_backlog: u32,
+    /// Maximum number of transfer connections handled at once; further connections are
+    /// rejected until one of them ends.
+    pub max_transfer_connections: usize,
+    /// Whether to apply the permissions and modification time a received file had on the
+    /// sender, rather than the defaults of a newly created file.
+    pub preserve_file_metadata: bool,
+    /// Whether to store the received files under `objects/<sha256 of the contents>` in the save
+    /// location, rather than under their names, so that identical files are stored only once.
+    /// The names are mapped to the hashes in the `index` file next to the objects.
+    pub content_addressed: bool,
+    /// Command to run after a file is received and saved, e.g., `notify-send Received {path}`;
+    /// `None` runs nothing. It isn't run through a shell: the template is split at whitespace
+    /// into the program and its arguments, and `{path}` is replaced by the saved file's path.
+    pub post_receive_command: Option<String>,
+    /// Template of the name a received file is saved under when a file with its name exists
+    /// already, e.g., `{stem} ({n}){ext}`; `None` overwrites the existing file.
+    ///
+    /// `{stem}` is replaced by the name without its extension, `{ext}` by the extension along
+    /// with its dot, and `{n}` by the smallest number, from 1, that makes the name free.
+    pub rename_template: Option<String>,
+    /// Decides whether to receive each incoming file once its headers arrive, e.g., by running
+    /// a [`ConfirmCommand`](crate::transfer::confirm::ConfirmCommand); `None` receives every
+    /// file. A declined file is refused as soon as its headers arrive and the rest of it is
+    /// skipped, so that the files sent after it on the same connection are still received.
+    pub confirm_incoming: Option<Arc<dyn Confirm>>,
+    /// Whether to save the received files in a subdirectory of the save location named after
+    /// their sender, e.g., `<save location>/laptop/notes.txt`, rather than all in the save
+    /// location itself; ignored if `content_addressed` is enabled. A pulled file is saved in
+    /// the subdirectory of the peer it was pulled from, and received files are read back by
+    /// their path in the save location, e.g., `laptop/notes.txt`.
+    pub save_subdir_by_peer: bool,
+    /// Whether to skip saving received files that are empty, rather than creating them.
+    pub skip_empty_files: bool,
+    /// Whether to extract received directory archives into the save location, next to the
+    /// saved archive; ignored if `content_addressed` is enabled.
+    pub auto_extract: bool,
+    /// How long sending to a peer that hasn't been discovered yet waits for it to announce
+    /// itself; `None` fails right away.
+    pub peer_wait_timeout: Option<Duration>,
+    /// How long listing the peers waits for one to be discovered if none have been yet, e.g.,
+    /// right after the app started, asking the peers to announce themselves first; `None`
+    /// lists them right away.
+    pub peer_list_wait: Option<Duration>,
+    /// How long to wait for a connection to the transfer port of a newly announced peer, or of
+    /// a known peer announcing another address, before skipping it as unreachable; `None`
+    /// trusts the announced address. Checking delays the handling of other announcements by up
+    /// to this long.
+    pub peer_reachability_timeout: Option<Duration>,
+    /// Size of the buffer an incoming announcement is read into; larger announcements are
+    /// truncated on platforms where their size can't be known up front.
+    pub discovery_buffer_size: usize,
+    /// Whether to log every datagram the discovery receives, along with what became of it.
+    pub trace_discovery: bool,
+    /// Interfaces, given by their name (e.g., `eth0`) or IPv4 address, to discover peers and
+    /// announce on; empty uses the default interface. With any given, files are received on all
+    /// the interfaces and the peers take the address an announcement came from as the peer's
+    /// address, since it can't be reached at a single one.
+    pub discovery_interfaces: Vec<String>,
+    /// The single interface, given by its name or IPv4 address, to run on: peers are only
+    /// discovered on it and files are only received on its address. Overrides the
+    /// `discovery_interfaces`; set by the `--interface` flag.
+    pub interface: Option<String>,
+    /// Whether to announce over multicast. On networks that block multicast, disable it and
+    /// list the addresses of the peers in `unicast_announce_addrs` instead.
+    pub multicast_announcements: bool,
+    /// Number of routers the multicast announcements may cross, from 1 (the default), which
+    /// keeps them on the local link, up to 255. Routers have to forward multicast between the
+    /// subnets for more to help, and many never forward groups in `224.0.0.0/24`, such as the
+    /// one the announcements are sent to; mDNS announcements always stay on the local link.
+    pub multicast_ttl: u32,
+    /// Addresses of the peers to also announce to over unicast, on their discovery port.
+    pub unicast_announce_addrs: Vec<IpAddr>,
+    /// Address of a bootstrap server to register the peer with and fetch the other registered
+    /// peers from, for networks announcements can't cross; `None` disables it. The
+    /// `discovery::bootstrap` module describes what the server has to do.
+    pub bootstrap_server: Option<SocketAddr>,
+    /// How often to register with the `bootstrap_server` and fetch the peers from it.
+    pub bootstrap_interval: Duration,
+    /// Port the announcements are sent from, so that firewalls can allow them by it; `None`
+    /// uses a random port. If the port is busy, a random one is used instead.
+    pub announce_source_port: Option<u16>,
+    /// Whether to ask the peer for the content hash of a file before sending it, and skip the
+    /// file if the peer already has an identical copy. This costs an extra round-trip per peer.
+    pub skip_duplicate_files: bool,
+    /// Whether to also advertise the peer as a `_redtooth._tcp` DNS-SD service over mDNS and
+    /// discover peers advertised that way, so tools like `avahi-browse` can see it. This needs
+    /// the mDNS port, which a system mDNS responder may already be using.
+    pub mdns_announcements: bool,
+    /// Address on which the API is also served over HTTP; `None` disables it. The API isn't
+    /// authenticated, so it shouldn't be reachable from outside the host.
+    pub http_api_addr: Option<SocketAddr>,
+    /// Peers allowed to send files and messages, list and pull the shared files, or probe for
+    /// files, by their identifier or name; empty allows every peer that isn't denied. Peers
+    /// sending from versions that don't identify themselves are only allowed if this is empty.
+    ///
+    /// **NOTE:** Senders identify themselves, so the lists keep out mistakes rather than
+    /// attackers; see the note on [`transfer`](crate::transfer).
+    pub allowed_peers: Vec<String>,
+    /// Peers refused to send files and messages, or anything else, by their identifier or name,
+    /// even if they are allowed. They are also left out of the discovered peers.
+    pub denied_peers: Vec<String>,
+    /// Named groups of peers, by their identifiers, that files can be sent to at once; more
+    /// can be created through the API while the app runs.
+    pub groups: HashMap<String, Vec<PeerID>>,
+    /// How long the app may stay idle, i.e., without any transfer and without discovering a
+    /// new peer, before it shuts down; `None` keeps it running.
+    pub idle_timeout: Option<Duration>,
+    /// Whether the app only counts as idle while no peers are discovered.
+    pub idle_only_without_peers: bool,
+    /// How often to write the discovered peers as JSON into `redtooth-peers.json` in
+    /// `$XDG_RUNTIME_DIR`, or a per-user file in the temp dir, for tools that don't speak the
+    /// API; `None` disables it. The file is replaced at once, so it is never read half written,
+    /// and removed on shutdown.
+    pub peer_snapshot_interval: Option<Duration>,
 }
 
 impl Default for Config {
     fn default() -> Config {
-        let home = env::var(HOME_ENV_KEY)
-            .unwrap_or_else(|_| panic!("your OS should set env variable {HOME_ENV_KEY}"));
+        // Running under a minimal environment (e.g., a service or a container) may leave the
+        // env variable unset; that shouldn't prevent the app from starting.
+        let home = env::var_os(HOME_ENV_KEY)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                let fallback = env::current_dir().unwrap_or_else(|_| env::temp_dir());
+                elogln!(
+                    "Env variable {HOME_ENV_KEY} isn't set; falling back to `{}`",
+                    fallback.display()
+                );
+                fallback
+            });
 
         Config {
-            save_location: Path::new(&home).join(DIR_NAME),
+            save_location: home.join(DIR_NAME),
+            shared_location: home.join(format!("{DIR_NAME}-shared")),
+            data_location: home.join(format!(".{DIR_NAME}")),
+            id_strategy: Arc::new(AddrBased),
+            transfer_port: DEFAULT_PEER_PORT,
+            transfer_timeout: Some(Duration::from_secs(30)),
+            transfer_keepalive: true,
+            transfer_linger: None,
+            send_retries: 2,
+            send_retry_delay: Duration::from_millis(500),
+            confirm_received_length: false,
+            max_concurrent_sends: 4,
+            transfer_buffer_size: 64 * 1024,
+            transfer_backlog: 128,
+            max_transfer_connections: 8,
+            preserve_file_metadata: false,
+            content_addressed: false,
+            post_receive_command: None,
+            rename_template: None,
+            confirm_incoming: None,
+            save_subdir_by_peer: false,
+            skip_empty_files: false,
+            auto_extract: false,
+            peer_wait_timeout: None,
+            peer_list_wait: None,
+            peer_reachability_timeout: None,
+            discovery_buffer_size: 8192,
+            trace_discovery: false,
+            discovery_interfaces: Vec::new(),
+            interface: None,
+            multicast_announcements: true,
+            multicast_ttl: 1,
+            unicast_announce_addrs: Vec::new(),
+            bootstrap_server: None,
+            bootstrap_interval: Duration::from_secs(30),
+            announce_source_port: None,
+            skip_duplicate_files: false,
+            mdns_announcements: false,
+            http_api_addr: None,
+            allowed_peers: Vec::new(),
+            denied_peers: Vec::new(),
+            groups: HashMap::new(),
+            idle_timeout: None,
+            idle_only_without_peers: false,
+            peer_snapshot_interval: None,
+        }
+    }
+}
+
+impl Config {
+    /// Checks the settings whose values can be invalid, so that they are reported at startup
+    /// rather than when they are first used.
+    pub fn validate(&self) -> io::Result<()> {
+        if let Some(template) = &self.rename_template {
+            if !template.contains("{n}") {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("`rename_template` must contain `{{n}}`, got `{template}`"),
+                ));
+            }
+        }
+        for (name, value) in [
+            ("transfer_buffer_size", self.transfer_buffer_size),
+            ("max_concurrent_sends", self.max_concurrent_sends),
+        ] {
+            if value == 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("`{name}` must be greater than 0"),
+                ));
+            }
+        }
+        if self.bootstrap_server.is_some() && self.bootstrap_interval.is_zero() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "`bootstrap_interval` must be greater than 0",
+            ));
+        }
+        if self
+            .peer_snapshot_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "`peer_snapshot_interval` must be greater than 0",
+            ));
+        }
+        if !(1..=255).contains(&self.multicast_ttl) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "`multicast_ttl` must be between 1 and 255, got {}",
+                    self.multicast_ttl
+                ),
+            ));
+        }
+        if let Some(interface) = &self.interface {
+            interface::resolve_ipv4_addresses(slice::from_ref(interface))?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the peer with the given identifier and name may send files and
+    /// messages, as per the `allowed_peers` and `denied_peers`.
+    pub fn allows_peer(&self, id: Option<PeerID>, name: Option<&str>) -> bool {
+        let matches = |entry: &String| {
+            id.is_some_and(|id| *entry == id.to_string()) || name == Some(entry.as_str())
+        };
+        if self.denied_peers.iter().any(matches) {
+            return false;
         }
+        self.allowed_peers.is_empty() || self.allowed_peers.iter().any(matches)
     }
 }
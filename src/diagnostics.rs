@@ -0,0 +1,61 @@
+//! Diagnostics recorded by the background components.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Maximum number of events kept by [`RecentEvents`].
+const MAX_RECENT_EVENTS: usize = 64;
+
+/// The last error hit by a background component.
+///
+/// Clones share the same slot, so the component can record into it while the API reads it.
+#[derive(Clone, Default)]
+pub struct LastError(Arc<Mutex<Option<(Instant, String)>>>);
+
+impl LastError {
+    /// Records the error message along with the time it occurred, replacing the previous one.
+    pub fn record(&self, err: impl ToString) {
+        let mut last_error = self.0.lock().unwrap_or_else(|p| p.into_inner());
+        *last_error = Some((Instant::now(), err.to_string()));
+    }
+
+    /// Returns the last recorded error message along with the time it occurred, if any.
+    pub fn get(&self) -> Option<(Instant, String)> {
+        self.0.lock().unwrap_or_else(|p| p.into_inner()).clone()
+    }
+}
+
+impl fmt::Display for LastError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some((at, err)) => write!(f, "{}s ago: {err}", at.elapsed().as_secs()),
+            None => write!(f, "no errors"),
+        }
+    }
+}
+
+/// The most recent events seen by a background component, oldest first.
+///
+/// Clones share the same events, like the clones of [`LastError`].
+#[derive(Clone, Default)]
+pub struct RecentEvents(Arc<Mutex<VecDeque<(Instant, String)>>>);
+
+impl RecentEvents {
+    /// Records the event along with the time it occurred, dropping the oldest one if there are
+    /// already [`MAX_RECENT_EVENTS`].
+    pub fn record(&self, event: impl ToString) {
+        let mut events = self.0.lock().unwrap_or_else(|p| p.into_inner());
+        if events.len() == MAX_RECENT_EVENTS {
+            events.pop_front();
+        }
+        events.push_back((Instant::now(), event.to_string()));
+    }
+
+    /// Returns the recorded events along with the time each of them occurred.
+    pub fn get(&self) -> Vec<(Instant, String)> {
+        let events = self.0.lock().unwrap_or_else(|p| p.into_inner());
+        events.iter().cloned().collect()
+    }
+}
@@ -1,12 +1,36 @@
+use std::error::Error;
 use std::fmt;
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::num::ParseIntError;
+use std::str::{self, FromStr};
 
 use crate::protocol::packet::{InvalidHeaderSequence, Packet};
 use crate::protocol::{PeerAddr, PeerID};
 
+/// Value of the `kind` header of a packet that asks peers to announce themselves.
+const REQUEST_KIND: &str = "who_is_there";
+/// Value of the `kind` header of a packet that asks peers to echo it back, to test whether
+/// multicast datagrams reach them.
+const PROBE_KIND: &str = "mcast_probe";
+/// Value of the `kind` header of the answer to a multicast probe.
+const PROBE_ECHO_KIND: &str = "mcast_probe_echo";
+/// Bytes a binary announcement starts with: `RTA` followed by the version of the encoding.
+const BINARY_MAGIC: &[u8; 4] = b"RTA\x01";
+/// Maximum size of an encoded announcement.
+///
+/// Datagrams up to this size are delivered without being fragmented on any IPv4 network,
+/// since every host must accept datagrams of 576 bytes including the IP and UDP headers.
+pub const MAX_LEN: usize = 508;
+
+#[derive(Debug)]
 pub enum InvalidAnnouncement {
     MissingPeerID,
+    InvalidPeerID(ParseIntError),
     MissingPeerAddr,
+    InvalidPeerAddr(AddrParseError),
     InvalidPacket(InvalidHeaderSequence),
+    /// A binary announcement ended early or holds an unknown address family.
+    Malformed,
 }
 
 impl fmt::Display for InvalidAnnouncement {
@@ -15,41 +39,434 @@ impl fmt::Display for InvalidAnnouncement {
 
         match self {
             MissingPeerID => write!(f, "missing peer id"),
+            InvalidPeerID(e) => write!(f, "invalid peer id: {e}"),
             MissingPeerAddr => write!(f, "missing peer address"),
+            InvalidPeerAddr(e) => write!(f, "invalid peer address: {e}"),
             InvalidPacket(e) => write!(f, "invalid packet: {e}"),
+            Malformed => write!(f, "malformed binary announcement"),
+        }
+    }
+}
+
+impl Error for InvalidAnnouncement {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        use self::InvalidAnnouncement::*;
+
+        match self {
+            InvalidPeerID(e) => Some(e),
+            InvalidPeerAddr(e) => Some(e),
+            InvalidPacket(e) => Some(e),
+            MissingPeerID | MissingPeerAddr | Malformed => None,
         }
     }
 }
 
+/// Represents a peer announcing itself to the other peers.
+///
+/// It is sent in a compact binary encoding, see [`Announcement::as_bytes`], so that it stays
+/// within [`MAX_LEN`]. Earlier versions encoded it as the headers of a [`Packet`], which is
+/// still produced through its [`Display`](fmt::Display) implementation and understood through
+/// its [`FromStr`] implementation. The `name` and `version` are optional so that announcements
+/// from peers that don't send them are still understood.
+#[derive(Debug, PartialEq)]
 pub struct Announcement {
     pub peer_id: PeerID,
     pub peer_addr: PeerAddr,
+    /// Human-readable name of the peer, usually its hostname.
+    pub name: Option<String>,
+    /// Version of the app the peer is running.
+    pub version: Option<String>,
+    /// Sequence number of the announcement; a later announcement of the same peer has a
+    /// greater one, even across restarts of the peer.
+    pub seq: Option<u64>,
 }
 
 impl Announcement {
-    pub fn new(peer_id: PeerID, peer_addr: PeerAddr) -> Announcement {
-        Announcement { peer_id, peer_addr }
+    pub fn new(peer_id: PeerID, peer_addr: PeerAddr, name: String) -> Announcement {
+        Announcement {
+            peer_id,
+            peer_addr,
+            name: Some(name),
+            version: Some(String::from(env!("CARGO_PKG_VERSION"))),
+            seq: None,
+        }
+    }
+
+    /// Returns `true` if the `other` announcement of the same peer was made after this one.
+    ///
+    /// Announcements that don't carry a sequence number, e.g., from older peers, are never
+    /// considered outdated.
+    pub fn is_older_than(&self, other: Option<u64>) -> bool {
+        matches!((self.seq, other), (Some(seq), Some(other)) if seq < other)
     }
 
+    /// Decodes an announcement in either the binary or the packet encoding.
     pub fn from_bytes(bytes: &[u8]) -> Result<Announcement, InvalidAnnouncement> {
-        let packet = Packet::from_bytes(bytes).map_err(InvalidAnnouncement::InvalidPacket)?;
+        if let Some(bytes) = bytes.strip_prefix(BINARY_MAGIC) {
+            return Announcement::from_binary(bytes).ok_or(InvalidAnnouncement::Malformed);
+        }
+        str::from_utf8(bytes)
+            .map_err(|e| InvalidAnnouncement::InvalidPacket(e.into()))?
+            .parse()
+    }
+
+    /// Encodes the announcement as [`BINARY_MAGIC`] followed by:
+    ///
+    /// - the id as a big-endian `u64`,
+    /// - the address family (`4` or `6`), the IP address bytes and the port as a big-endian `u16`,
+    /// - the name and the version, each prefixed by its length as a big-endian `u16`; a length of
+    ///   zero means it is absent,
+    /// - the sequence number as a big-endian `u64`, if any.
+    ///
+    /// Fields added later are appended, so that peers decoding fewer fields still understand it.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = BINARY_MAGIC.to_vec();
+        bytes.extend(self.peer_id.to_be_bytes());
+
+        match self.peer_addr.ip() {
+            IpAddr::V4(ip) => {
+                bytes.push(4);
+                bytes.extend(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                bytes.push(6);
+                bytes.extend(ip.octets());
+            }
+        }
+        bytes.extend(self.peer_addr.port().to_be_bytes());
+
+        for field in [&self.name, &self.version] {
+            let field = field.as_deref().unwrap_or_default();
+            // Longer fields are cut short, at a character boundary; they wouldn't fit in
+            // `MAX_LEN` anyway.
+            let mut len = field.len().min(u16::MAX as usize);
+            while !field.is_char_boundary(len) {
+                len -= 1;
+            }
+            bytes.extend((len as u16).to_be_bytes());
+            bytes.extend(&field.as_bytes()[..len]);
+        }
+        if let Some(seq) = self.seq {
+            bytes.extend(seq.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes the fields of a binary announcement, i.e., the bytes after its magic.
+    fn from_binary(bytes: &[u8]) -> Option<Announcement> {
+        let mut reader = Reader(bytes);
+        let peer_id = PeerID::from_be_bytes(reader.take()?);
+        let ip = match reader.take::<1>()? {
+            [4] => IpAddr::V4(Ipv4Addr::from(reader.take::<4>()?)),
+            [6] => IpAddr::V6(Ipv6Addr::from(reader.take::<16>()?)),
+            _ => return None,
+        };
+        let port = u16::from_be_bytes(reader.take()?);
+
+        let name = reader.take_field()?;
+        let version = reader.take_field()?;
+        let seq = match reader.0.is_empty() {
+            true => None,
+            false => Some(u64::from_be_bytes(reader.take()?)),
+        };
+        Some(Announcement {
+            peer_id,
+            peer_addr: PeerAddr::new(ip, port),
+            name,
+            version,
+            seq,
+        })
+    }
+
+    /// Encodes the announcement as the headers of a packet, in the encoding earlier versions
+    /// announced in.
+    pub fn as_packet(&self) -> Packet<'_> {
+        let mut packet = Packet::new();
+        packet.set_header("id", self.peer_id);
+        packet.set_header("addr", self.peer_addr);
 
+        if let Some(name) = &self.name {
+            packet.set_header("name", name);
+        }
+        if let Some(version) = &self.version {
+            packet.set_header("version", version);
+        }
+        if let Some(seq) = self.seq {
+            packet.set_header("seq", seq);
+        }
+        packet
+    }
+
+    /// Decodes an announcement from the headers of a packet, as encoded by
+    /// [`Announcement::as_packet`].
+    pub fn from_packet(packet: &Packet) -> Result<Announcement, InvalidAnnouncement> {
         let peer_id = packet
             .get_header("id")
-            .and_then(|id| id.parse::<PeerID>().ok())
-            .ok_or(InvalidAnnouncement::MissingPeerID)?;
+            .ok_or(InvalidAnnouncement::MissingPeerID)?
+            .parse::<PeerID>()
+            .map_err(InvalidAnnouncement::InvalidPeerID)?;
         let peer_addr = packet
             .get_header("addr")
-            .and_then(|addr| addr.parse::<PeerAddr>().ok())
-            .ok_or(InvalidAnnouncement::MissingPeerAddr)?;
+            .ok_or(InvalidAnnouncement::MissingPeerAddr)?
+            .parse::<PeerAddr>()
+            .map_err(InvalidAnnouncement::InvalidPeerAddr)?;
 
-        Ok(Announcement { peer_id, peer_addr })
+        Ok(Announcement {
+            peer_id,
+            peer_addr,
+            name: packet.get_header("name").map(String::from),
+            version: packet.get_header("version").map(String::from),
+            seq: packet.get_header("seq").and_then(|seq| seq.parse().ok()),
+        })
     }
+}
 
-    pub fn as_bytes(&self) -> Vec<u8> {
-        let mut packet = Packet::new();
-        packet.set_header("id", self.peer_id);
-        packet.set_header("addr", self.peer_addr);
-        packet.as_bytes()
+impl fmt::Display for Announcement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // A header only packet is always valid UTF-8.
+        f.write_str(&String::from_utf8_lossy(&self.as_packet().as_bytes()))
+    }
+}
+
+impl FromStr for Announcement {
+    type Err = InvalidAnnouncement;
+
+    fn from_str(s: &str) -> Result<Announcement, InvalidAnnouncement> {
+        let packet =
+            Packet::from_bytes(s.as_bytes()).map_err(InvalidAnnouncement::InvalidPacket)?;
+        Announcement::from_packet(&packet)
+    }
+}
+
+/// A cursor over the bytes of a binary announcement.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (taken, rest) = self.0.split_first_chunk::<N>()?;
+        self.0 = rest;
+        Some(*taken)
+    }
+
+    /// Takes a length-prefixed field; `Some(None)` means the field is absent.
+    fn take_field(&mut self) -> Option<Option<String>> {
+        let len = u16::from_be_bytes(self.take()?) as usize;
+        let field = self.0.get(..len)?;
+        self.0 = &self.0[len..];
+        Some((len > 0).then(|| String::from_utf8_lossy(field).into_owned()))
+    }
+}
+
+/// A way of encoding announcements and the requests for them into datagrams, independent of how
+/// the datagrams are sent.
+pub trait AnnounceCodec: Send + Sync {
+    fn encode(&self, announcement: &Announcement) -> Vec<u8>;
+    /// Decodes an announcement; `None` means the datagram isn't meant for the app and should be
+    /// ignored silently.
+    fn decode(&self, bytes: &[u8]) -> Option<Result<Announcement, InvalidAnnouncement>>;
+    /// Returns a datagram that asks all the peers to announce themselves.
+    fn request(&self) -> Vec<u8>;
+    fn is_request(&self, bytes: &[u8]) -> bool;
+}
+
+/// The app's own encoding: announcements in the binary encoding, while those in the packet
+/// encoding are still understood, and requests as packets.
+pub struct NativeCodec;
+
+impl AnnounceCodec for NativeCodec {
+    fn encode(&self, announcement: &Announcement) -> Vec<u8> {
+        announcement.as_bytes()
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<Result<Announcement, InvalidAnnouncement>> {
+        Some(Announcement::from_bytes(bytes))
+    }
+
+    fn request(&self) -> Vec<u8> {
+        request_as_bytes()
+    }
+
+    fn is_request(&self, bytes: &[u8]) -> bool {
+        is_request(bytes)
+    }
+}
+
+/// Returns the bytes of a packet that asks all the peers to announce themselves.
+pub fn request_as_bytes() -> Vec<u8> {
+    let mut packet = Packet::new();
+    packet.set_header("kind", REQUEST_KIND);
+    packet.as_bytes()
+}
+
+/// Returns `true` if the given bytes are a packet asking peers to announce themselves.
+pub fn is_request(bytes: &[u8]) -> bool {
+    Packet::from_bytes(bytes).is_ok_and(|packet| packet.get_header("kind") == Some(REQUEST_KIND))
+}
+
+/// Returns the bytes of a packet that asks the peers receiving it to echo the `tag` back.
+pub fn probe_as_bytes(tag: &str) -> Vec<u8> {
+    let mut packet = Packet::new();
+    packet.set_header("kind", PROBE_KIND);
+    packet.set_header("tag", tag);
+    packet.as_bytes()
+}
+
+/// Returns the tag of the multicast probe in the given bytes, if they are one.
+pub fn probe_tag(bytes: &[u8]) -> Option<String> {
+    let packet = Packet::from_bytes(bytes).ok()?;
+    match packet.get_header("kind") {
+        Some(PROBE_KIND) => packet.get_header("tag").map(String::from),
+        _ => None,
+    }
+}
+
+/// Returns the bytes of a packet that echoes the `tag` of a multicast probe back on behalf of
+/// the peer with the `peer_id`.
+pub fn probe_echo_as_bytes(tag: &str, peer_id: PeerID) -> Vec<u8> {
+    let mut packet = Packet::new();
+    packet.set_header("kind", PROBE_ECHO_KIND);
+    packet.set_header("tag", tag);
+    packet.set_header("id", peer_id);
+    packet.as_bytes()
+}
+
+/// Returns the identifier of the peer that echoed the multicast probe with the `tag` back, if
+/// the given bytes are such an echo.
+pub fn probe_echo_peer_id(bytes: &[u8], tag: &str) -> Option<PeerID> {
+    let packet = Packet::from_bytes(bytes).ok()?;
+    if packet.get_header("kind") != Some(PROBE_ECHO_KIND) || packet.get_header("tag") != Some(tag) {
+        return None;
+    }
+    packet.get_header("id")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echoes_multicast_probes() {
+        let probe = probe_as_bytes("1-2");
+        assert_eq!(probe_tag(&probe).as_deref(), Some("1-2"));
+        assert!(!is_request(&probe));
+        assert_eq!(probe_tag(&request_as_bytes()), None);
+
+        let echo = probe_echo_as_bytes("1-2", 7);
+        assert_eq!(probe_echo_peer_id(&echo, "1-2"), Some(7));
+        assert_eq!(probe_echo_peer_id(&echo, "1-3"), None);
+        assert_eq!(probe_echo_peer_id(&probe, "1-2"), None);
+        assert_eq!(probe_tag(&echo), None);
+    }
+
+    #[test]
+    fn encodes_through_the_native_codec() {
+        let addr = "192.168.1.5:25802".parse().unwrap();
+        let announcement = Announcement::new(42, addr, String::from("laptop"));
+        let codec: &dyn AnnounceCodec = &NativeCodec;
+
+        let encoded = codec.encode(&announcement);
+        assert!(!codec.is_request(&encoded));
+        assert_eq!(codec.decode(&encoded).unwrap().ok(), Some(announcement));
+        assert!(codec.is_request(&codec.request()));
+        assert!(matches!(codec.decode(b"garbage"), Some(Err(_))));
+    }
+
+    #[test]
+    fn round_trips_through_the_wire_format() {
+        let addr = "192.168.1.5:25802".parse().unwrap();
+        let announcement = Announcement::new(42, addr, String::from("laptop"));
+
+        let parsed = Announcement::from_bytes(&announcement.as_bytes())
+            .ok()
+            .unwrap();
+        assert_eq!(parsed, announcement);
+        assert_eq!(parsed.name.as_deref(), Some("laptop"));
+        assert_eq!(parsed.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn understands_the_packet_encoding() {
+        let addr = "192.168.1.5:25802".parse().unwrap();
+        let announcement = Announcement::new(42, addr, String::from("laptop"));
+
+        let parsed = Announcement::from_bytes(announcement.to_string().as_bytes())
+            .ok()
+            .unwrap();
+        assert_eq!(parsed, announcement);
+        assert!(announcement.as_bytes().len() < announcement.to_string().len());
+    }
+
+    #[test]
+    fn tells_outdated_announcements_apart() {
+        let addr = "192.168.1.5:25802".parse().unwrap();
+        let mut announcement = Announcement::new(42, addr, String::from("laptop"));
+        let seq = 7;
+        announcement.seq = Some(seq);
+        assert!(announcement.is_older_than(Some(seq + 1)));
+        assert!(!announcement.is_older_than(Some(seq)));
+        assert!(!announcement.is_older_than(None));
+
+        // Announcements of older peers carry no sequence number.
+        announcement.seq = None;
+        let parsed = Announcement::from_bytes(&announcement.as_bytes())
+            .ok()
+            .unwrap();
+        assert_eq!(parsed.seq, None);
+        assert!(!parsed.is_older_than(Some(seq)));
+    }
+
+    #[test]
+    fn rejects_truncated_binary_announcement() {
+        let addr = "[::1]:25802".parse().unwrap();
+        let bytes = Announcement::new(42, addr, String::from("laptop")).as_bytes();
+
+        for len in [BINARY_MAGIC.len() + 3, bytes.len() - 1] {
+            assert!(matches!(
+                Announcement::from_bytes(&bytes[..len]),
+                Err(InvalidAnnouncement::Malformed)
+            ));
+        }
+    }
+
+    #[test]
+    fn parses_announcement_without_name_and_version() {
+        let parsed = "id=42\naddr=192.168.1.5:25802\n"
+            .parse::<Announcement>()
+            .ok()
+            .unwrap();
+
+        assert_eq!(parsed.peer_id, 42);
+        assert_eq!(parsed.name, None);
+        assert_eq!(parsed.version, None);
+    }
+
+    #[test]
+    fn rejects_announcement_without_id() {
+        let parsed = "addr=192.168.1.5:25802\n".parse::<Announcement>();
+        assert!(matches!(parsed, Err(InvalidAnnouncement::MissingPeerID)));
+    }
+
+    #[test]
+    fn tells_malformed_fields_apart_from_missing_ones() {
+        let parsed = "id=42\naddr=not-an-addr\n".parse::<Announcement>();
+        assert!(matches!(
+            parsed,
+            Err(InvalidAnnouncement::InvalidPeerAddr(_))
+        ));
+
+        let parsed = "id=abc\naddr=192.168.1.5:25802\n".parse::<Announcement>();
+        assert!(matches!(parsed, Err(InvalidAnnouncement::InvalidPeerID(_))));
+    }
+
+    #[test]
+    fn exposes_the_cause_of_invalid_announcements() {
+        let err = "id=abc\n".parse::<Announcement>().unwrap_err();
+        assert!(err.source().is_some_and(|e| e.is::<ParseIntError>()));
+
+        let err: Box<dyn Error> = Announcement::from_bytes(b"id=\xff").unwrap_err().into();
+        let packet_err = err.source().unwrap();
+        assert!(packet_err
+            .source()
+            .is_some_and(|e| e.is::<str::Utf8Error>()));
     }
 }
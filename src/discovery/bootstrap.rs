@@ -0,0 +1,190 @@
+//! A peer discoverer that goes through a bootstrap server, for networks where announcements
+//! can't reach the peers, e.g., because multicast is blocked or the peers aren't on the same
+//! network.
+//!
+//! Every `bootstrap_interval`, the peer connects to the server over TCP and sends a packet of
+//! kind `register` carrying its announcement, in the packet encoding of
+//! [`Announcement::as_packet`], then shuts down its side of the connection. The server
+//! registers the peer and replies with a packet in the same encoding for each registered peer,
+//! then closes the connection. Packets are framed as on a transfer connection, see
+//! [`transfer::write_packet`].
+//!
+//! The server is expected to replace an unspecified address (e.g., `0.0.0.0`) with the one
+//! the registration came from and to forget peers that stopped registering.
+
+use std::io::{self, Error, ErrorKind};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::announcement::{AnnounceCodec, Announcement, NativeCodec};
+use super::{lock_pkt, Diagnostics, PeerInfo, Peers, SharedPkt};
+use crate::config::Config;
+use crate::protocol::{self, PeerAddr};
+use crate::{elogln, logln, transfer};
+
+const THREAD_NAME: &str = "bootstrap_discovery";
+/// Value of the `kind` header of the packet registering the peer with the server.
+const REGISTER_KIND: &str = "register";
+/// How long to wait for the server to accept the connection and for each of its replies.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Spawns a discoverer that registers the peer announced by the `announcement_pkt`, as it is
+/// at the time, with the bootstrap `server` and adds the peers registered with it.
+///
+/// Errors are logged and recorded into the last error of the `diagnostics`; the discoverer
+/// keeps going and tries again after the `bootstrap_interval`. Since it never stops, it isn't
+/// watched like the local discoverers.
+pub fn spawn(
+    server: SocketAddr,
+    peers: Arc<Peers>,
+    announcement_pkt: SharedPkt,
+    diagnostics: Diagnostics,
+    config: &Config,
+) -> io::Result<()> {
+    let config = config.clone();
+
+    thread::Builder::new()
+        .name(String::from(THREAD_NAME))
+        .spawn(move || loop {
+            let exchanged = current_announcement(&announcement_pkt)
+                .and_then(|announcement| exchange(server, &announcement));
+            match exchanged {
+                Ok(found) => add_peers(&peers, found, &config),
+                Err(e) => {
+                    let err = format!("Failed to discover peers through `{server}`; {e}");
+                    elogln!("{err}");
+                    diagnostics.last_error.record(err);
+                }
+            }
+            thread::sleep(config.bootstrap_interval);
+        })?;
+    Ok(())
+}
+
+/// Decodes the announcement the peer currently announces itself with.
+fn current_announcement(announcement_pkt: &SharedPkt) -> io::Result<Announcement> {
+    match NativeCodec.decode(&lock_pkt(announcement_pkt)) {
+        Some(Ok(announcement)) => Ok(announcement),
+        Some(Err(e)) => Err(Error::new(ErrorKind::InvalidData, e)),
+        None => Err(Error::new(ErrorKind::InvalidData, "no announcement")),
+    }
+}
+
+/// Registers the `announcement` with the `server` and returns the peers registered with it.
+fn exchange(server: SocketAddr, announcement: &Announcement) -> io::Result<Vec<Announcement>> {
+    let mut stream = TcpStream::connect_timeout(&server, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut registration = announcement.as_packet();
+    registration.set_header("kind", REGISTER_KIND);
+    transfer::write_packet(&mut stream, &registration)?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut found = Vec::new();
+    let mut buf = Vec::new();
+    while let Some(packet) = transfer::read_packet(&mut stream, &mut buf)? {
+        let peer = Announcement::from_packet(&packet).map_err(|e| {
+            let err = format!("server sent an invalid peer; {e}");
+            Error::new(ErrorKind::InvalidData, err)
+        })?;
+        found.push(peer);
+    }
+    Ok(found)
+}
+
+/// Adds the peers `found` through the server to the discovered ones, leaving out the peer
+/// itself, the peers with an unspecified address, the denied ones and announcements older
+/// than what is already known.
+fn add_peers(peers: &Peers, found: Vec<Announcement>, config: &Config) {
+    let my_id = protocol::get_my_id(&config.data_location, &*config.id_strategy);
+    let mut peer_map = peers.map.lock().unwrap_or_else(|p| p.into_inner());
+    let mut inserted = false;
+
+    for announcement in found {
+        if announcement.peer_id == my_id
+            || announcement.peer_addr.ip().is_unspecified()
+            || !config.allows_peer(Some(announcement.peer_id), announcement.name.as_deref())
+        {
+            continue;
+        }
+        let known = peer_map.get(&announcement.peer_id);
+        if known.is_some_and(|known| announcement.is_older_than(known.seq)) {
+            continue;
+        }
+        if known.is_none() {
+            log_discovered(announcement.name.as_deref(), announcement.peer_addr);
+        }
+        let peer = PeerInfo {
+            addr: announcement.peer_addr,
+            name: announcement.name,
+            seq: announcement.seq,
+            version: announcement.version,
+            last_seen: Instant::now(),
+        };
+        peer_map.insert(announcement.peer_id, peer);
+        inserted = true;
+    }
+    if inserted {
+        peers.inserted.notify_all();
+    }
+}
+
+fn log_discovered(name: Option<&str>, addr: PeerAddr) {
+    match name {
+        Some(name) => logln!("Discovered `{name}` at `{addr}` through the bootstrap server"),
+        None => logln!("Discovered `{addr}` through the bootstrap server"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::{env, process};
+
+    use super::*;
+    use crate::transfer::{read_packet, write_packet};
+
+    #[test]
+    fn registers_with_the_server_and_adds_the_peers() {
+        let config = Config {
+            data_location: env::temp_dir().join(format!("redtooth-bootstrap-{}", process::id())),
+            ..Config::default()
+        };
+        let my_id = protocol::get_my_id(&config.data_location, &*config.id_strategy);
+        let me = Announcement::new(my_id, "0.0.0.0:25802".parse().unwrap(), "me".into());
+        let other = Announcement::new(my_id + 1, "10.0.0.2:25802".parse().unwrap(), "pc".into());
+        let hidden = Announcement::new(my_id + 2, "0.0.0.0:25802".parse().unwrap(), "x".into());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let registered = [me.to_string(), other.to_string(), hidden.to_string()];
+        let serve = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            let registration = read_packet(&mut stream, &mut buf).unwrap().unwrap();
+            assert_eq!(registration.get_header("kind"), Some(REGISTER_KIND));
+            let registration = Announcement::from_packet(&registration).unwrap();
+            assert!(read_packet(&mut stream, &mut Vec::new()).unwrap().is_none());
+
+            for peer in &registered {
+                let peer = peer.parse::<Announcement>().unwrap();
+                write_packet(&mut stream, &peer.as_packet()).unwrap();
+            }
+            registration
+        });
+
+        let found = exchange(server, &me).unwrap();
+        assert_eq!(serve.join().unwrap(), me);
+        assert_eq!(found.len(), 3);
+
+        let peers = Peers::default();
+        add_peers(&peers, found, &config);
+        let peer_map = peers.map.lock().unwrap();
+        assert_eq!(peer_map.len(), 1);
+        assert_eq!(peer_map[&other.peer_id].addr, other.peer_addr);
+        assert_eq!(peer_map[&other.peer_id].name.as_deref(), Some("pc"));
+    }
+}
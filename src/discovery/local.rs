@@ -1,49 +1,343 @@
 //! A local peer discoverer.
 
-use std::net::{Ipv4Addr, UdpSocket};
-use std::sync::{Arc, Mutex, TryLockError};
-use std::{io, thread};
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::os::fd::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::ptr;
+use std::sync::{Arc, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use super::{Announcement, PeerMap, ThreadHandle};
-use crate::{elogln, logln};
+use super::announcement::{self, AnnounceCodec, NativeCodec};
+use super::mdns::{self, MdnsCodec};
+use super::{lock_pkt, Diagnostics, PeerInfo, Peers, SharedPkt, ThreadHandle};
+use crate::config::Config;
+use crate::net::multicast;
+use crate::protocol::{PeerAddr, PeerID};
+use crate::{elogln, interface, logln};
 
 // Range between `224.0.0.0` to `224.0.0.250` is reserved or use by routing and maintenance
 // protocols inside a network.
 const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
 const MULTICAST_PORT: u16 = 20581;
+/// Delay before receiving again after a transient error.
+const RECV_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Wire format of the announcements exchanged by a local server.
+#[derive(Clone, Copy)]
+pub enum Format {
+    /// The app's own packet based format, on its own port.
+    Native,
+    /// DNS-SD records over mDNS, understood by tools like `avahi-browse` and `dns-sd`.
+    Mdns,
+}
+
+impl Format {
+    fn port(self) -> u16 {
+        match self {
+            Format::Native => MULTICAST_PORT,
+            Format::Mdns => mdns::MDNS_PORT,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Format::Native => "native",
+            Format::Mdns => "mDNS",
+        }
+    }
+
+    /// Returns the number of routers the multicast datagrams may cross.
+    fn multicast_ttl(self, config: &Config) -> u32 {
+        match self {
+            Format::Native => config.multicast_ttl,
+            // mDNS is link-local by definition.
+            Format::Mdns => 1,
+        }
+    }
+
+    pub fn thread_name(self) -> &'static str {
+        match self {
+            Format::Native => "local_discovery",
+            Format::Mdns => "mdns_discovery",
+        }
+    }
+
+    /// Returns the codec the announcements of the format are encoded with.
+    pub fn codec(self) -> &'static dyn AnnounceCodec {
+        match self {
+            Format::Native => &NativeCodec,
+            Format::Mdns => &MdnsCodec,
+        }
+    }
+}
+
+/// Spawns a local server that exchanges announcements in the given format.
+///
+/// The server answers requests for announcements by announcing the `announcement_pkt`, as it
+/// is at the time.
+/// Errors are logged and recorded into the last error of the `diagnostics`, and each received
+/// datagram into its recent datagrams.
+pub fn spawn(
+    peers: Arc<Peers>,
+    announcement_pkt: SharedPkt,
+    format: Format,
+    diagnostics: Diagnostics,
+    config: &Config,
+) -> io::Result<ThreadHandle> {
+    // Bind on the current thread so that the failure is surfaced to the caller.
+    let interfaces = interface::resolve_ipv4_addresses(&config.discovery_interfaces)?;
+    let socket = bind_socket(format, &interfaces, config)?;
+    let config = config.clone();
 
-/// Spawns a local server.
-pub fn spawn(peer_map: Arc<Mutex<PeerMap>>) -> io::Result<ThreadHandle> {
     thread::Builder::new()
-        .name(String::from("local_discovery"))
-        .spawn(move || discover_peers(peer_map))
+        .name(String::from(format.thread_name()))
+        .spawn(move || {
+            discover_peers(
+                socket,
+                peers,
+                &announcement_pkt,
+                format,
+                &interfaces,
+                &diagnostics,
+                &config,
+            )
+        })
 }
 
-/// Announces the peer to other instances of the local server.
-pub fn announce_peer(pkt: &[u8]) -> io::Result<()> {
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    // Don't announce to the current instance of the server.
-    socket.set_multicast_loop_v4(false)?;
-    socket.send_to(pkt, (MULTICAST_ADDR, MULTICAST_PORT))?;
+/// Announces the peer to other instances of the local server on each of the `interfaces`, or
+/// on the default one if none are given, as per [`send_announcement`].
+pub fn announce_peer(
+    pkt: &[u8],
+    format: Format,
+    interfaces: &[Ipv4Addr],
+    config: &Config,
+) -> io::Result<()> {
+    let ttl = format.multicast_ttl(config);
+    let socket = match config.announce_source_port {
+        Some(port) => multicast::announce_socket(port, ttl).or_else(|e| {
+            elogln!("Failed to announce from port {port}: {e}; using a random port instead");
+            multicast::announce_socket(0, ttl)
+        })?,
+        None => multicast::announce_socket(0, ttl)?,
+    };
+    send_announcement(&socket, pkt, format, interfaces, config)
+}
+
+/// Sends a multicast probe tagged with the `tag` out of each of the `interfaces`, or out of the
+/// default one if none are given, and returns the peers that echoed it back, with the
+/// addresses they echoed from, within the `timeout`.
+///
+/// The probe is sent over multicast regardless of `multicast_announcements`, and never to the
+/// `unicast_announce_addrs`, so that the echoes show whether multicast reaches the peers.
+pub fn probe_multicast(
+    tag: &str,
+    interfaces: &[Ipv4Addr],
+    timeout: Duration,
+    config: &Config,
+) -> io::Result<Vec<(PeerID, SocketAddr)>> {
+    let socket = multicast::announce_socket(0, config.multicast_ttl)?;
+    let probe = announcement::probe_as_bytes(tag);
+    multicast::send(&socket, &probe, MULTICAST_ADDR, MULTICAST_PORT, interfaces)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut echoes = Vec::new();
+    let mut raw_pkt = vec![0; config.discovery_buffer_size];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(echoes);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (pkt_len, addr) = match socket.recv_from(&mut raw_pkt) {
+            Ok(received) => received,
+            Err(e) if RecvError::classify(&e) == RecvError::Fatal => return Err(e),
+            Err(_) => continue,
+        };
+        if let Some(id) = announcement::probe_echo_peer_id(&raw_pkt[..pkt_len], tag) {
+            if !echoes.iter().any(|&(echoed, _)| echoed == id) {
+                echoes.push((id, addr));
+            }
+        }
+    }
+}
+
+/// Returns the echo of the multicast probe in the `pkt`, if it is one, on behalf of the peer
+/// announced by the `announcement_pkt`.
+fn echo_probe(pkt: &[u8], announcement_pkt: &SharedPkt) -> Option<Vec<u8>> {
+    let tag = announcement::probe_tag(pkt)?;
+    let announcement = NativeCodec.decode(&lock_pkt(announcement_pkt))?.ok()?;
+    Some(announcement::probe_echo_as_bytes(
+        &tag,
+        announcement.peer_id,
+    ))
+}
+
+/// Sends the `pkt` to the multicast group as per [`multicast::send`] unless
+/// `multicast_announcements` is disabled, and in the native format also to each of the
+/// `unicast_announce_addrs`, for networks that block multicast.
+fn send_announcement(
+    socket: &UdpSocket,
+    pkt: &[u8],
+    format: Format,
+    interfaces: &[Ipv4Addr],
+    config: &Config,
+) -> io::Result<()> {
+    if let Format::Native = format {
+        if pkt.len() > announcement::MAX_LEN {
+            let err = format!(
+                "Refused to announce {} bytes, more than the maximum of {}; try a shorter name",
+                pkt.len(),
+                announcement::MAX_LEN
+            );
+            elogln!("{err}");
+            return Err(Error::new(ErrorKind::InvalidInput, err));
+        }
+    }
+    // mDNS is multicast only.
+    if config.multicast_announcements || matches!(format, Format::Mdns) {
+        multicast::send(socket, pkt, MULTICAST_ADDR, format.port(), interfaces)?;
+    }
+    if let Format::Native = format {
+        for &ip in &config.unicast_announce_addrs {
+            socket.send_to(pkt, (ip, format.port()))?;
+        }
+    }
     Ok(())
 }
 
+/// Binds a socket to the multicast port of the format and joins the multicast group on each
+/// of the `interfaces`, or on the default one if none are given.
+fn bind_socket(format: Format, interfaces: &[Ipv4Addr], config: &Config) -> io::Result<UdpSocket> {
+    let hint = match format {
+        Format::Native => "another program may be using it",
+        Format::Mdns => "another mDNS responder (e.g., avahi-daemon) may be using it",
+    };
+    // Answers are sent from this socket too; they aren't received back.
+    let ttl = format.multicast_ttl(config);
+    multicast::bind_receiver(MULTICAST_ADDR, format.port(), interfaces, ttl, hint)
+}
+
 /// Starts listening for an **announcement** a packet on the local network.
-fn discover_peers(peer_map: Arc<Mutex<PeerMap>>) -> io::Result<()> {
-    let socket = UdpSocket::bind(("0.0.0.0", MULTICAST_PORT))?;
-    socket.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+///
+/// Announcements sent directly to the discovery port over unicast are received alike.
+fn discover_peers(
+    socket: UdpSocket,
+    peers: Arc<Peers>,
+    announcement_pkt: &SharedPkt,
+    format: Format,
+    interfaces: &[Ipv4Addr],
+    diagnostics: &Diagnostics,
+    config: &Config,
+) -> io::Result<()> {
+    let last_error = &diagnostics.last_error;
+    let codec = format.codec();
+    let buffer_size = config.discovery_buffer_size;
     logln!("Listening for new announcement on {}", socket.local_addr()?);
+    let mut raw_pkt = vec![0; buffer_size];
+
+    // mDNS responders only trust records sent from the mDNS port, so they are announced from
+    // the bound socket rather than through `announce_peer`.
+    if let Format::Mdns = format {
+        if let Err(e) = multicast::send(
+            &socket,
+            &lock_pkt(announcement_pkt),
+            MULTICAST_ADDR,
+            mdns::MDNS_PORT,
+            interfaces,
+        ) {
+            let err = format!("Failed to announce over mDNS; {e}");
+            elogln!("{err}");
+            last_error.record(err);
+        }
+    }
 
     loop {
-        let mut raw_pkt = [0; 4096];
-        let Ok((pkt_len, announcement_addr)) = socket.recv_from(&mut raw_pkt) else {
-            continue;
+        // Make room for the whole packet where its size can be known up front. The extra byte
+        // tells a packet that fits exactly apart from a truncated one.
+        if let Some(pkt_len) = peek_pkt_len(&socket) {
+            raw_pkt.resize(buffer_size.max(pkt_len + 1), 0);
+        }
+        let (pkt_len, announcement_addr) = match socket.recv_from(&mut raw_pkt) {
+            Ok(received) => received,
+            Err(e) => match RecvError::classify(&e) {
+                RecvError::Retry => continue,
+                RecvError::Transient => {
+                    let err = format!("Failed to receive an announcement; {e}");
+                    elogln!("{err}");
+                    last_error.record(err);
+                    // Don't spin on an error that persists for a while, e.g., a lack of memory.
+                    thread::sleep(RECV_RETRY_DELAY);
+                    continue;
+                }
+                // Leave it to the watchdog to restart the server on a fresh socket.
+                RecvError::Fatal => {
+                    return Err(Error::new(
+                        e.kind(),
+                        format!("failed to receive announcements; {e}"),
+                    ))
+                }
+            },
+        };
+        let trace = |outcome: &dyn fmt::Display| {
+            let datagram = format!(
+                "{} datagram from `{announcement_addr}` ({pkt_len} bytes): {outcome}",
+                format.name()
+            );
+            if config.trace_discovery {
+                logln!("{datagram}");
+            }
+            diagnostics.datagrams.record(datagram);
         };
 
-        let mut announcement = match Announcement::from_bytes(&raw_pkt[..pkt_len]) {
-            Ok(a) => a,
-            Err(e) => {
-                elogln!("Received a badly formatted packet; {e}");
+        if pkt_len == raw_pkt.len() {
+            trace(&"truncated");
+            let err = format!(
+                "Ignored a packet from `{announcement_addr}` that may have been truncated \
+                 to {pkt_len} bytes; consider increasing `discovery_buffer_size`"
+            );
+            elogln!("{err}");
+            last_error.record(err);
+            continue;
+        }
+
+        if let Format::Native = format {
+            if let Some(echo) = echo_probe(&raw_pkt[..pkt_len], announcement_pkt) {
+                trace(&"multicast probe");
+                if let Err(e) = socket.send_to(&echo, announcement_addr) {
+                    let err = format!("Failed to echo the multicast probe back; {e}");
+                    elogln!("{err}");
+                    last_error.record(err);
+                }
+                continue;
+            }
+        }
+
+        if codec.is_request(&raw_pkt[..pkt_len]) {
+            trace(&"request for announcements");
+            logln!("`{announcement_addr}` requested announcements");
+            let pkt = lock_pkt(announcement_pkt).clone();
+            if let Err(e) = send_announcement(&socket, &pkt, format, interfaces, config) {
+                let err = format!("Failed to answer the request for announcements; {e}");
+                elogln!("{err}");
+                last_error.record(err);
+            }
+            continue;
+        }
+
+        let mut announcement = match codec.decode(&raw_pkt[..pkt_len]) {
+            Some(Ok(a)) => a,
+            None => {
+                trace(&"not meant for the app");
+                continue;
+            }
+            Some(Err(e)) => {
+                trace(&format_args!("invalid announcement; {e}"));
+                let err = format!("Received a badly formatted packet; {e}");
+                elogln!("{err}");
+                last_error.record(err);
                 continue;
             }
         };
@@ -52,19 +346,192 @@ fn discover_peers(peer_map: Arc<Mutex<PeerMap>>) -> io::Result<()> {
         if announcement.peer_addr.ip().is_unspecified() {
             announcement.peer_addr.set_ip(announcement_addr.ip());
         }
+        trace(&format_args!(
+            "announcement of `{}` at `{}`",
+            announcement.peer_id, announcement.peer_addr
+        ));
+
+        if !config.allows_peer(Some(announcement.peer_id), announcement.name.as_deref()) {
+            trace(&"denied peer");
+            continue;
+        }
+
+        // Only new peers and peers that moved are probed, so that the repeated announcements
+        // of known peers don't stall the loop.
+        if let Some(timeout) = config
+            .peer_reachability_timeout
+            .filter(|_| !is_known_at(&peers, announcement.peer_id, announcement.peer_addr))
+        {
+            if let Err(e) = TcpStream::connect_timeout(&announcement.peer_addr, timeout) {
+                let err = format!(
+                    "Skipped the peer at `{}`, it isn't reachable; {e}",
+                    announcement.peer_addr
+                );
+                elogln!("{err}");
+                last_error.record(err);
+                continue;
+            }
+        }
 
         // Unlock the map's lock ASAP using inner block.
         {
-            let mut peer_map = match peer_map.try_lock() {
+            let mut peer_map = match peers.map.try_lock() {
                 Ok(guard) => guard,
                 Err(TryLockError::Poisoned(p)) => p.into_inner(),
                 Err(TryLockError::WouldBlock) => {
-                    elogln!("Peer map's lock is currently acquired by some other component");
+                    let err = "Peer map's lock is currently acquired by some other component";
+                    elogln!("{err}");
+                    last_error.record(err);
+                    continue;
+                }
+            };
+            // Peers are keyed by their identifiers alone, so a peer announcing a known
+            // identifier from elsewhere replaces the one already known.
+            if let Some(known) = peer_map.get(&announcement.peer_id) {
+                // Announcements may arrive out of order, e.g., right after the peer moved.
+                if announcement.is_older_than(known.seq) {
+                    trace(&"outdated announcement");
                     continue;
                 }
+                if known.addr != announcement.peer_addr {
+                    trace(&format_args!(
+                        "identifier already known at `{}`",
+                        known.addr
+                    ));
+                    let err = format!(
+                        "The peer `{}` announced itself at `{}` but is known at `{}`; either it \
+                         moved or two peers share the identifier, in which case delete the `id` \
+                         file in the data location of one of them and restart it",
+                        announcement.peer_id, announcement.peer_addr, known.addr
+                    );
+                    elogln!("{err}");
+                    last_error.record(err);
+                }
+            }
+            let peer = PeerInfo {
+                addr: announcement.peer_addr,
+                name: announcement.name.clone(),
+                seq: announcement.seq,
+                version: announcement.version.clone(),
+                last_seen: Instant::now(),
             };
-            peer_map.insert(announcement.peer_id, announcement.peer_addr);
+            peer_map.insert(announcement.peer_id, peer);
+            peers.inserted.notify_all();
         }
-        logln!("Discovered `{}`", announcement.peer_addr);
+        match &announcement.name {
+            Some(name) => logln!("Discovered `{name}` at `{}`", announcement.peer_addr),
+            None => logln!("Discovered `{}`", announcement.peer_addr),
+        }
+    }
+}
+
+/// Returns `true` if the peer with the `id` is already known at the `addr`.
+fn is_known_at(peers: &Peers, id: PeerID, addr: PeerAddr) -> bool {
+    let peer_map = peers.map.lock().unwrap_or_else(|p| p.into_inner());
+    peer_map.get(&id).is_some_and(|known| known.addr == addr)
+}
+
+/// How the server goes on after failing to receive a datagram.
+#[derive(Debug, PartialEq)]
+enum RecvError {
+    /// The read timed out or was interrupted; receive again right away.
+    Retry,
+    /// The error may go away on its own, e.g., an ICMP error queued on the socket by an
+    /// earlier answer or a temporary lack of buffers; receive again after a delay.
+    Transient,
+    /// The socket is unusable, e.g., it was closed; stop the server.
+    Fatal,
+}
+
+impl RecvError {
+    fn classify(err: &Error) -> RecvError {
+        match err.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => {
+                RecvError::Retry
+            }
+            ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::OutOfMemory => RecvError::Transient,
+            _ if err.raw_os_error() == Some(libc::ENOBUFS) => RecvError::Transient,
+            _ => RecvError::Fatal,
+        }
+    }
+}
+
+/// Returns the size of the next packet waiting on the socket without consuming it.
+///
+/// This function blocks the calling thread until a packet arrives.
+#[cfg(target_os = "linux")]
+fn peek_pkt_len(socket: &UdpSocket) -> Option<usize> {
+    // With `MSG_TRUNC`, the real size of the packet is returned even though nothing is read.
+    let pkt_len = unsafe {
+        libc::recv(
+            socket.as_raw_fd(),
+            ptr::null_mut(),
+            0,
+            libc::MSG_PEEK | libc::MSG_TRUNC,
+        )
+    };
+    usize::try_from(pkt_len).ok()
+}
+
+/// Returns the size of the next packet waiting on the socket without consuming it.
+#[cfg(not(target_os = "linux"))]
+fn peek_pkt_len(_socket: &UdpSocket) -> Option<usize> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::discovery::announcement::Announcement;
+
+    #[test]
+    fn echoes_probes_on_behalf_of_the_peer() {
+        let announcement = Announcement::new(7, "10.0.0.2:25802".parse().unwrap(), "pc".into());
+        let announcement_pkt = Arc::new(Mutex::new(NativeCodec.encode(&announcement)));
+
+        let echo = echo_probe(&announcement::probe_as_bytes("1-2"), &announcement_pkt).unwrap();
+        assert_eq!(announcement::probe_echo_peer_id(&echo, "1-2"), Some(7));
+        assert!(echo_probe(&NativeCodec.request(), &announcement_pkt).is_none());
+    }
+
+    #[test]
+    fn tells_known_peers_apart_from_moved_ones() {
+        let addr = "10.0.0.2:25802".parse().unwrap();
+        let peers = Peers::default();
+        peers.map.lock().unwrap().insert(
+            7,
+            PeerInfo {
+                addr,
+                name: None,
+                seq: None,
+                version: None,
+                last_seen: Instant::now(),
+            },
+        );
+
+        assert!(is_known_at(&peers, 7, addr));
+        assert!(!is_known_at(&peers, 7, "10.0.0.3:25802".parse().unwrap()));
+        assert!(!is_known_at(&peers, 8, addr));
+    }
+
+    #[test]
+    fn stops_only_on_fatal_receive_errors() {
+        let classify = |kind| RecvError::classify(&Error::from(kind));
+        assert_eq!(classify(ErrorKind::WouldBlock), RecvError::Retry);
+        assert_eq!(classify(ErrorKind::ConnectionRefused), RecvError::Transient);
+        assert_eq!(
+            RecvError::classify(&Error::from_raw_os_error(libc::ENOBUFS)),
+            RecvError::Transient
+        );
+        assert_eq!(
+            RecvError::classify(&Error::from_raw_os_error(libc::EBADF)),
+            RecvError::Fatal
+        );
     }
 }
@@ -0,0 +1,414 @@
+//! A minimal mDNS encoding of an announcement.
+//!
+//! The peer is advertised as a DNS-SD service of type `_redtooth._tcp` so that tools like
+//! `avahi-browse` and `dns-sd` can see it. Only what that needs is implemented: a response
+//! carrying the PTR, SRV, TXT and A records of the service, and a query for the service.
+//! Everything else sent to the mDNS group is ignored.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Range;
+
+use super::announcement::{AnnounceCodec, Announcement, InvalidAnnouncement};
+use crate::protocol::PeerID;
+
+pub const MDNS_PORT: u16 = 5353;
+/// Name under which the instances of the service are listed.
+const SERVICE: &str = "_redtooth._tcp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Tells the receivers to replace the records they have cached for the same name and type.
+const CACHE_FLUSH: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+/// Time in seconds the receivers may cache the records for.
+const TTL: u32 = 120;
+const MAX_LABEL_LEN: usize = 63;
+/// Maximum number of compression pointers followed while reading a name, so that a pointer
+/// loop can't hang the parser.
+const MAX_POINTERS: usize = 16;
+
+/// Announcements encoded as DNS-SD records, see [`encode`] and [`decode`]; requests are mDNS
+/// queries for the service.
+pub struct MdnsCodec;
+
+impl AnnounceCodec for MdnsCodec {
+    fn encode(&self, announcement: &Announcement) -> Vec<u8> {
+        encode(announcement)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<Result<Announcement, InvalidAnnouncement>> {
+        decode(bytes).map(Ok)
+    }
+
+    fn request(&self) -> Vec<u8> {
+        query()
+    }
+
+    fn is_request(&self, bytes: &[u8]) -> bool {
+        is_query(bytes)
+    }
+}
+
+/// Encodes the announcement as an mDNS response advertising the service.
+///
+/// The peer id, name, version and sequence number are carried by the TXT record. The A record is
+/// left out if the address is unspecified, in which case the receiver uses the address of the
+/// sender.
+pub fn encode(announcement: &Announcement) -> Vec<u8> {
+    let name = announcement
+        .name
+        .as_deref()
+        .unwrap_or(env!("CARGO_PKG_NAME"));
+    let instance = format!(
+        "{}.{SERVICE}",
+        label(&format!("{name}-{}", announcement.peer_id))
+    );
+    let host = format!("{}.local", label(name));
+
+    let mut txt = vec![
+        format!("id={}", announcement.peer_id),
+        format!("name={name}"),
+    ];
+    if let Some(version) = &announcement.version {
+        txt.push(format!("version={version}"));
+    }
+    if let Some(seq) = announcement.seq {
+        txt.push(format!("seq={seq}"));
+    }
+    let ipv4 = match announcement.peer_addr.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    };
+
+    let mut msg = Vec::new();
+    write_header(
+        &mut msg,
+        FLAG_RESPONSE | FLAG_AUTHORITATIVE,
+        0,
+        3 + ipv4.is_some() as u16,
+    );
+
+    let mut ptr = Vec::new();
+    write_name(&mut ptr, &instance);
+    write_record(&mut msg, SERVICE, TYPE_PTR, CLASS_IN, &ptr);
+
+    let mut srv = Vec::new();
+    srv.extend(0_u16.to_be_bytes()); // Priority
+    srv.extend(0_u16.to_be_bytes()); // Weight
+    srv.extend(announcement.peer_addr.port().to_be_bytes());
+    write_name(&mut srv, &host);
+    write_record(&mut msg, &instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, &srv);
+
+    let mut txt_data = Vec::new();
+    for entry in &txt {
+        let entry = &entry.as_bytes()[..entry.len().min(u8::MAX as usize)];
+        txt_data.push(entry.len() as u8);
+        txt_data.extend(entry);
+    }
+    write_record(
+        &mut msg,
+        &instance,
+        TYPE_TXT,
+        CLASS_IN | CACHE_FLUSH,
+        &txt_data,
+    );
+
+    if let Some(ip) = ipv4 {
+        write_record(
+            &mut msg,
+            &host,
+            TYPE_A,
+            CLASS_IN | CACHE_FLUSH,
+            &ip.octets(),
+        );
+    }
+    msg
+}
+
+/// Decodes an mDNS response advertising the service.
+///
+/// Returns `None` if the message doesn't advertise an instance of the service with a peer id,
+/// which is the case for most of the traffic on the mDNS group.
+pub fn decode(bytes: &[u8]) -> Option<Announcement> {
+    let msg = Message::parse(bytes)?;
+    if msg.flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+
+    let ptr = msg.find_record(SERVICE, TYPE_PTR)?;
+    let (instance, _) = read_name(bytes, ptr.data.start)?;
+
+    let srv = msg.find_record(&instance, TYPE_SRV)?;
+    let port = read_u16(bytes, srv.data.start + 4)?;
+    let (host, _) = read_name(bytes, srv.data.start + 6)?;
+
+    let txt = msg
+        .find_record(&instance, TYPE_TXT)
+        .map(|txt| read_txt(&bytes[txt.data.clone()]))
+        .unwrap_or_default();
+    let txt_value = |key: &str| {
+        txt.iter()
+            .find_map(|entry| entry.strip_prefix(key)?.strip_prefix('='))
+            .map(String::from)
+    };
+    let peer_id = txt_value("id")?.parse::<PeerID>().ok()?;
+
+    let ip = msg
+        .find_record(&host, TYPE_A)
+        .and_then(|a| <[u8; 4]>::try_from(&bytes[a.data.clone()]).ok())
+        .map_or(Ipv4Addr::UNSPECIFIED, Ipv4Addr::from);
+
+    Some(Announcement {
+        peer_id,
+        peer_addr: SocketAddr::new(IpAddr::V4(ip), port),
+        name: txt_value("name"),
+        version: txt_value("version"),
+        seq: txt_value("seq").and_then(|seq| seq.parse().ok()),
+    })
+}
+
+/// Returns an mDNS query asking for the instances of the service.
+pub fn query() -> Vec<u8> {
+    let mut msg = Vec::new();
+    write_header(&mut msg, 0, 1, 0);
+    write_name(&mut msg, SERVICE);
+    msg.extend(TYPE_PTR.to_be_bytes());
+    msg.extend(CLASS_IN.to_be_bytes());
+    msg
+}
+
+/// Returns `true` if the given bytes are an mDNS query asking for the instances of the service.
+pub fn is_query(bytes: &[u8]) -> bool {
+    Message::parse(bytes).is_some_and(|msg| {
+        msg.flags & FLAG_RESPONSE == 0
+            && msg.questions.iter().any(|(name, qtype)| {
+                name.eq_ignore_ascii_case(SERVICE) && matches!(*qtype, TYPE_PTR | TYPE_ANY)
+            })
+    })
+}
+
+/// A parsed DNS message; the data of the records is kept as a range into the message, since
+/// the names inside it may point anywhere in the message.
+struct Message {
+    flags: u16,
+    questions: Vec<(String, u16)>,
+    records: Vec<ResourceRecord>,
+}
+
+struct ResourceRecord {
+    name: String,
+    rtype: u16,
+    data: Range<usize>,
+}
+
+impl Message {
+    fn parse(bytes: &[u8]) -> Option<Message> {
+        let flags = read_u16(bytes, 2)?;
+        let question_count = read_u16(bytes, 4)?;
+        // Answers, authority and additional records are all treated alike.
+        let record_count = (6..12)
+            .step_by(2)
+            .map(|pos| read_u16(bytes, pos).map(usize::from))
+            .sum::<Option<usize>>()?;
+        let mut pos = 12;
+
+        let mut questions = Vec::new();
+        for _ in 0..question_count {
+            let (name, end) = read_name(bytes, pos)?;
+            questions.push((name, read_u16(bytes, end)?));
+            pos = end + 4;
+        }
+
+        let mut records = Vec::new();
+        for _ in 0..record_count {
+            let (name, end) = read_name(bytes, pos)?;
+            let rtype = read_u16(bytes, end)?;
+            let data_len = usize::from(read_u16(bytes, end + 8)?);
+            let data = end + 10..end + 10 + data_len;
+            if data.end > bytes.len() {
+                return None;
+            }
+            pos = data.end;
+            records.push(ResourceRecord { name, rtype, data });
+        }
+
+        Some(Message {
+            flags,
+            questions,
+            records,
+        })
+    }
+
+    fn find_record(&self, name: &str, rtype: u16) -> Option<&ResourceRecord> {
+        self.records
+            .iter()
+            .find(|record| record.rtype == rtype && record.name.eq_ignore_ascii_case(name))
+    }
+}
+
+fn write_header(msg: &mut Vec<u8>, flags: u16, question_count: u16, answer_count: u16) {
+    msg.extend(0_u16.to_be_bytes()); // Id, always zero for mDNS
+    msg.extend(flags.to_be_bytes());
+    msg.extend(question_count.to_be_bytes());
+    msg.extend(answer_count.to_be_bytes());
+    msg.extend(0_u16.to_be_bytes()); // Authority records
+    msg.extend(0_u16.to_be_bytes()); // Additional records
+}
+
+fn write_record(msg: &mut Vec<u8>, name: &str, rtype: u16, class: u16, data: &[u8]) {
+    write_name(msg, name);
+    msg.extend(rtype.to_be_bytes());
+    msg.extend(class.to_be_bytes());
+    msg.extend(TTL.to_be_bytes());
+    msg.extend((data.len() as u16).to_be_bytes());
+    msg.extend(data);
+}
+
+/// Writes a dotted name without compression.
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        buf.push(label.len() as u8);
+        buf.extend(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+/// Turns an arbitrary string into a single label, which can't contain dots and is limited
+/// to 63 bytes.
+fn label(s: &str) -> String {
+    let mut label = s.replace('.', "-");
+    if label.len() > MAX_LABEL_LEN {
+        let end = (0..=MAX_LABEL_LEN)
+            .rev()
+            .find(|&i| label.is_char_boundary(i))
+            .unwrap_or(0);
+        label.truncate(end);
+    }
+    label
+}
+
+/// Reads a possibly compressed name starting at `pos`.
+///
+/// Returns the dotted name and the position right after it in the message.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = usize::from(*msg.get(pos)?);
+        match len {
+            0 => return Some((labels.join("."), end.unwrap_or(pos + 1))),
+            len if len & 0xC0 == 0xC0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(pos + 2);
+                pos = usize::from(read_u16(msg, pos)? & 0x3FFF);
+            }
+            len if len & 0xC0 != 0 => return None,
+            len => {
+                let label = msg.get(pos + 1..pos + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+        }
+    }
+}
+
+/// Reads the length-prefixed strings of a TXT record.
+fn read_txt(mut data: &[u8]) -> Vec<String> {
+    let mut entries = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let Some(entry) = rest.get(..usize::from(len)) else {
+            break;
+        };
+        entries.push(String::from_utf8_lossy(entry).into_owned());
+        data = &rest[usize::from(len)..];
+    }
+    entries
+}
+
+fn read_u16(bytes: &[u8], pos: usize) -> Option<u16> {
+    let bytes = bytes.get(pos..pos + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_an_announcement() {
+        let addr = "192.168.1.5:25802".parse().unwrap();
+        let announcement = Announcement::new(42, addr, String::from("laptop.lan"));
+
+        assert_eq!(decode(&encode(&announcement)), Some(announcement));
+    }
+
+    #[test]
+    fn leaves_the_address_unspecified_without_an_a_record() {
+        let addr = "0.0.0.0:25802".parse().unwrap();
+        let announcement = Announcement::new(42, addr, String::from("laptop"));
+
+        let decoded = decode(&encode(&announcement)).unwrap();
+        assert!(decoded.peer_addr.ip().is_unspecified());
+        assert_eq!(decoded.peer_addr.port(), 25802);
+    }
+
+    #[test]
+    fn follows_compressed_names() {
+        let mut msg = Vec::new();
+        write_header(&mut msg, FLAG_RESPONSE, 0, 3);
+        // PTR record whose data points back at the name of the record itself, prefixed by
+        // the instance label.
+        write_name(&mut msg, SERVICE);
+        msg.extend(TYPE_PTR.to_be_bytes());
+        msg.extend(CLASS_IN.to_be_bytes());
+        msg.extend(TTL.to_be_bytes());
+        msg.extend(6_u16.to_be_bytes());
+        msg.extend([3, b'p', b'c', b'1', 0xC0, 12]);
+        // SRV and TXT records named by a pointer to the PTR data.
+        let instance_ptr = [0xC0, (msg.len() - 6) as u8];
+        msg.extend(instance_ptr);
+        msg.extend(TYPE_SRV.to_be_bytes());
+        msg.extend(CLASS_IN.to_be_bytes());
+        msg.extend(TTL.to_be_bytes());
+        msg.extend(8_u16.to_be_bytes());
+        msg.extend([0, 0, 0, 0, 0x64, 0xCA, 0xC0, 12]);
+        msg.extend(instance_ptr);
+        msg.extend(TYPE_TXT.to_be_bytes());
+        msg.extend(CLASS_IN.to_be_bytes());
+        msg.extend(TTL.to_be_bytes());
+        msg.extend(5_u16.to_be_bytes());
+        msg.extend([4, b'i', b'd', b'=', b'7']);
+
+        let decoded = decode(&msg).unwrap();
+        assert_eq!(decoded.peer_id, 7);
+        assert_eq!(decoded.peer_addr.port(), 25802);
+    }
+
+    #[test]
+    fn ignores_unrelated_traffic() {
+        let mut msg = Vec::new();
+        write_header(&mut msg, FLAG_RESPONSE, 0, 1);
+        write_record(
+            &mut msg,
+            "printer.local",
+            TYPE_A,
+            CLASS_IN,
+            &[192, 168, 1, 9],
+        );
+
+        assert_eq!(decode(&msg), None);
+        assert!(!is_query(&msg));
+        assert!(is_query(&query()));
+        assert_eq!(decode(&[0xC0; 40]), None);
+    }
+}
@@ -1,61 +1,476 @@
 mod announcement;
+mod bootstrap;
 mod local;
+mod mdns;
+mod watchdog;
 
 use std::collections::HashMap;
-use std::io;
-use std::sync::{Arc, Mutex};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
 
 use self::announcement::Announcement;
+use self::local::Format;
+use crate::config::Config;
+use crate::diagnostics::{LastError, RecentEvents};
 use crate::protocol::{PeerAddr, PeerID};
+use crate::{elogln, interface, logln};
 
-type PeerMap = HashMap<PeerID, PeerAddr>;
+type PeerMap = HashMap<PeerID, PeerInfo>;
 type ThreadHandle = JoinHandle<io::Result<()>>;
+/// An announcement packet shared with the discoverers, so that it can be replaced while they
+/// run.
+type SharedPkt = Arc<Mutex<Vec<u8>>>;
+
+/// The discovered peers, shared between the discoverers and their users.
+#[derive(Default)]
+struct Peers {
+    map: Mutex<PeerMap>,
+    /// Notified whenever a peer is inserted into the map.
+    inserted: Condvar,
+}
+
+/// Where the discoverers report what they run into.
+#[derive(Clone, Default)]
+struct Diagnostics {
+    last_error: LastError,
+    /// The most recent datagrams received, along with what became of them.
+    datagrams: RecentEvents,
+}
+
+/// What is known about a discovered peer.
+#[derive(Clone)]
+pub struct PeerInfo {
+    pub addr: PeerAddr,
+    /// Human-readable name the peer announced itself with, usually its hostname.
+    pub name: Option<String>,
+    /// Sequence number of the announcement the peer was last discovered through, if any.
+    pub seq: Option<u64>,
+    /// Version of the app the peer runs, if it announced it.
+    pub version: Option<String>,
+    /// When the peer was last discovered.
+    pub last_seen: Instant,
+}
+
+/// Represents an error that can occur when looking up a peer by its name.
+pub enum NameLookupError {
+    /// None of the discovered peers has the name.
+    NotFound(String),
+    /// Multiple peers have the name; holds their identifiers.
+    Ambiguous(String, Vec<PeerID>),
+}
+
+impl fmt::Display for NameLookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameLookupError::NotFound(name) => write!(f, "No peers found with the name `{name}`"),
+            NameLookupError::Ambiguous(name, ids) => {
+                let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+                write!(
+                    f,
+                    "Multiple peers are named `{name}` ({}); use an identifier instead",
+                    ids.join(", ")
+                )
+            }
+        }
+    }
+}
+
+/// Initial delay before restarting a discoverer thread that died.
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
 
 pub struct PeerDiscovery {
-    peers: Arc<Mutex<PeerMap>>,
-    announcement_pkt: Vec<u8>,
+    id: PeerID,
+    name: String,
+    peers: Arc<Peers>,
+    announcement_pkt: SharedPkt,
+    /// Announcement encoded as mDNS records; present only if mDNS announcements are enabled.
+    mdns_announcement_pkt: Option<SharedPkt>,
+    /// Sequence number of the next announcement; absent if the launch count is unknown.
+    next_seq: Option<AtomicU64>,
+    diagnostics: Diagnostics,
+    config: Config,
 }
 
 impl PeerDiscovery {
-    pub fn new(id: PeerID, addr: PeerAddr) -> PeerDiscovery {
+    /// Creates the discovery of a peer launched for the `launch`th time.
+    ///
+    /// The sequence numbers of the announcements start past those of the previous launches, so
+    /// other peers never take the announcements of this launch for outdated ones. Without the
+    /// `launch` count, the announcements carry no sequence number.
+    pub fn new(
+        id: PeerID,
+        addr: PeerAddr,
+        name: String,
+        launch: Option<u64>,
+        config: Config,
+    ) -> PeerDiscovery {
+        let next_seq = launch.map(|launch| AtomicU64::new(launch << 32));
+        let mut announcement = Announcement::new(id, addr, name.clone());
+        announcement.seq = take_seq(&next_seq);
+
         Self {
-            peers: Arc::new(Mutex::new(PeerMap::new())),
-            announcement_pkt: Announcement::new(id, addr).as_bytes(),
+            id,
+            name,
+            peers: Arc::default(),
+            announcement_pkt: Arc::new(Mutex::new(Format::Native.codec().encode(&announcement))),
+            mdns_announcement_pkt: config
+                .mdns_announcements
+                .then(|| Arc::new(Mutex::new(Format::Mdns.codec().encode(&announcement)))),
+            next_seq,
+            diagnostics: Diagnostics::default(),
+            config,
         }
     }
 
     /// Spawns a discoverer for discovering peers on either local or global or both networks.
+    ///
+    /// The discoverer is watched and restarted if it ever dies, e.g., when its socket gets
+    /// closed by the OS on a network change.
+    ///
+    /// If mDNS announcements are enabled, a second discoverer exchanges them alongside; failing
+    /// to start it only disables them, since the system's mDNS responder may own the port. If
+    /// a `bootstrap_server` is set, another discoverer fetches the peers registered with it;
+    /// it retries on its own, so it isn't watched.
     pub fn spawn(&mut self) -> io::Result<()> {
-        local::spawn(Arc::clone(&self.peers))?;
+        self.spawn_local(Arc::clone(&self.announcement_pkt), Format::Native)?;
+
+        if let Some(pkt) = self.mdns_announcement_pkt.clone() {
+            if let Err(e) = self.spawn_local(pkt, Format::Mdns) {
+                let err = format!("mDNS announcements are disabled; {e}");
+                elogln!("{err}");
+                self.diagnostics.last_error.record(err);
+            }
+        }
+        if let Some(server) = self.config.bootstrap_server {
+            self.spawn_bootstrap(server)?;
+        }
         Ok(())
     }
 
+    fn spawn_bootstrap(&self, server: SocketAddr) -> io::Result<()> {
+        bootstrap::spawn(
+            server,
+            Arc::clone(&self.peers),
+            Arc::clone(&self.announcement_pkt),
+            self.diagnostics.clone(),
+            &self.config,
+        )
+    }
+
+    fn spawn_local(&self, announcement_pkt: SharedPkt, format: Format) -> io::Result<()> {
+        let spawn_local = {
+            let peers = Arc::clone(&self.peers);
+            let diagnostics = self.diagnostics.clone();
+            let config = self.config.clone();
+
+            move || {
+                local::spawn(
+                    Arc::clone(&peers),
+                    Arc::clone(&announcement_pkt),
+                    format,
+                    diagnostics.clone(),
+                    &config,
+                )
+            }
+        };
+        let handle = spawn_local()?;
+
+        watchdog::spawn(
+            format.thread_name(),
+            handle,
+            spawn_local,
+            RESTART_BACKOFF,
+            self.diagnostics.last_error.clone(),
+        )?;
+        Ok(())
+    }
+
+    /// Returns the last error hit while discovering peers.
+    pub fn last_error(&self) -> &LastError {
+        &self.diagnostics.last_error
+    }
+
+    /// Returns the most recent datagrams received by the discoverers, with what became of them.
+    pub fn recent_datagrams(&self) -> &RecentEvents {
+        &self.diagnostics.datagrams
+    }
+
+    /// Announces the peer at the `addr` from now on, e.g., once the local address changed.
+    ///
+    /// The running discoverers answer requests for announcements with the new address right
+    /// away; announcing it is left to the caller.
+    pub fn set_addr(&self, addr: PeerAddr) {
+        let mut announcement = Announcement::new(self.id, addr, self.name.clone());
+        announcement.seq = take_seq(&self.next_seq);
+        *lock_pkt(&self.announcement_pkt) = Format::Native.codec().encode(&announcement);
+        if let Some(pkt) = &self.mdns_announcement_pkt {
+            *lock_pkt(pkt) = Format::Mdns.codec().encode(&announcement);
+        }
+    }
+
     /// Announces the peer to other instances of the server.
     pub fn announce_peer(&self) -> io::Result<()> {
-        local::announce_peer(&self.announcement_pkt)
+        local::announce_peer(
+            &lock_pkt(&self.announcement_pkt),
+            Format::Native,
+            &self.interfaces()?,
+            &self.config,
+        )
     }
 
-    /// Returns the identifiers of all the discovered peers.
-    pub fn get_discovered_peer_ids(&self) -> Option<Vec<PeerID>> {
-        self.peers
-            .lock()
-            .ok()
-            .and_then(|peer_map| (!peer_map.is_empty()).then(|| peer_map.keys().copied().collect()))
+    /// Asks other instances of the server to announce themselves, so they can be discovered
+    /// without waiting for their next announcement.
+    pub fn request_announcements(&self) -> io::Result<()> {
+        let interfaces = self.interfaces()?;
+        let mut formats = vec![Format::Native];
+        if self.mdns_announcement_pkt.is_some() {
+            formats.push(Format::Mdns);
+        }
+        for format in formats {
+            let request = format.codec().request();
+            local::announce_peer(&request, format, &interfaces, &self.config)?;
+        }
+        Ok(())
+    }
+
+    /// Tests whether multicast reaches the peers, by sending a probe that the peers echo back
+    /// and returning those that did within the `timeout`, with the addresses they echoed from.
+    pub fn probe_multicast(&self, timeout: Duration) -> io::Result<Vec<(PeerID, SocketAddr)>> {
+        // A tag of its own keeps late echoes of an earlier probe from counting.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_nanos());
+        let tag = format!("{}-{nanos}", self.id);
+        local::probe_multicast(&tag, &self.interfaces()?, timeout, &self.config)
+    }
+
+    /// Returns the addresses of the interfaces to discover peers on; empty means the default.
+    fn interfaces(&self) -> io::Result<Vec<Ipv4Addr>> {
+        interface::resolve_ipv4_addresses(&self.config.discovery_interfaces)
+    }
+
+    /// Forgets all the discovered peers and returns how many there were.
+    ///
+    /// Discovery keeps running, so the peers are added back once they announce themselves.
+    pub fn clear(&self) -> usize {
+        let mut peer_map = self.peers.map.lock().unwrap_or_else(|p| p.into_inner());
+        let count = peer_map.len();
+        peer_map.clear();
+        logln!("Cleared {count} discovered peers");
+        count
+    }
+
+    /// Returns the number of discovered peers.
+    pub fn peer_count(&self) -> usize {
+        self.peers.map.lock().map_or(0, |peer_map| peer_map.len())
+    }
+
+    /// Returns `true` if no peers have been discovered yet.
+    pub fn is_empty(&self) -> bool {
+        self.peer_count() == 0
     }
 
     /// Returns a list of addresses for all the discovered peers.
     pub fn get_discovered_peer_addrs(&self) -> Option<Vec<PeerAddr>> {
-        self.peers.lock().ok().and_then(|peer_map| {
-            (!peer_map.is_empty()).then(|| peer_map.values().copied().collect())
+        self.peers.map.lock().ok().and_then(|peer_map| {
+            (!peer_map.is_empty()).then(|| peer_map.values().map(|peer| peer.addr).collect())
         })
     }
 
+    /// Returns the identifiers along with the addresses of all the discovered peers.
+    pub fn get_discovered_peers(&self) -> Option<Vec<(PeerID, PeerAddr)>> {
+        self.peers.map.lock().ok().and_then(|peer_map| {
+            (!peer_map.is_empty())
+                .then(|| peer_map.iter().map(|(&id, peer)| (id, peer.addr)).collect())
+        })
+    }
+
+    /// Returns the identifiers along with what is known about all the discovered peers, sorted
+    /// by identifier.
+    pub fn get_discovered_peer_infos(&self) -> Vec<(PeerID, PeerInfo)> {
+        let peer_map = self.peers.map.lock().unwrap_or_else(|p| p.into_inner());
+        let mut peers = peer_map
+            .iter()
+            .map(|(&id, peer)| (id, peer.clone()))
+            .collect::<Vec<_>>();
+        peers.sort_unstable_by_key(|&(id, _)| id);
+        peers
+    }
+
     /// Returns the address of a specific peer that matches the given identifier.
     pub fn find_peer_addr_by_id(&self, id: PeerID) -> Option<PeerAddr> {
         self.peers
+            .map
             .lock()
             .ok()
-            .and_then(|peer_map| peer_map.get(&id).copied())
+            .and_then(|peer_map| peer_map.get(&id).map(|peer| peer.addr))
+    }
+
+    /// Returns everything known about a specific peer that matches the given identifier.
+    pub fn find_peer_info_by_id(&self, id: PeerID) -> Option<PeerInfo> {
+        self.peers
+            .map
+            .lock()
+            .ok()
+            .and_then(|peer_map| peer_map.get(&id).cloned())
+    }
+
+    /// Returns the address of the peer that matches the given identifier, waiting for up to
+    /// the `timeout` for it to be discovered if it hasn't been yet.
+    pub fn wait_for_peer(&self, id: PeerID, timeout: Duration) -> Option<PeerAddr> {
+        let peer_map = self.peers.map.lock().unwrap_or_else(|p| p.into_inner());
+        let (peer_map, _) = self
+            .peers
+            .inserted
+            .wait_timeout_while(peer_map, timeout, |peer_map| !peer_map.contains_key(&id))
+            .unwrap_or_else(|p| p.into_inner());
+        peer_map.get(&id).map(|peer| peer.addr)
+    }
+
+    /// Waits for up to the `timeout` for any peer to be discovered, unless one has been
+    /// already; returns whether one has.
+    pub fn wait_for_any_peer(&self, timeout: Duration) -> bool {
+        let peer_map = self.peers.map.lock().unwrap_or_else(|p| p.into_inner());
+        let (peer_map, _) = self
+            .peers
+            .inserted
+            .wait_timeout_while(peer_map, timeout, |peer_map| peer_map.is_empty())
+            .unwrap_or_else(|p| p.into_inner());
+        !peer_map.is_empty()
+    }
+
+    /// Returns the address of the only peer whose name matches the given one, ignoring case.
+    pub fn find_peer_addr_by_name(&self, name: &str) -> Result<PeerAddr, NameLookupError> {
+        let peer_map = self.peers.map.lock().unwrap_or_else(|p| p.into_inner());
+        let mut matches = peer_map
+            .iter()
+            .filter(|(_, peer)| {
+                peer.name
+                    .as_deref()
+                    .is_some_and(|peer_name| peer_name.eq_ignore_ascii_case(name))
+            })
+            .map(|(&id, peer)| (id, peer.addr))
+            .collect::<Vec<_>>();
+
+        match matches.as_slice() {
+            [] => Err(NameLookupError::NotFound(name.to_string())),
+            [(_, addr)] => Ok(*addr),
+            _ => {
+                matches.sort_unstable_by_key(|&(id, _)| id);
+                let ids = matches.into_iter().map(|(id, _)| id).collect();
+                Err(NameLookupError::Ambiguous(name.to_string(), ids))
+            }
+        }
+    }
+}
+
+/// Locks the shared packet, which can't be left half written, ignoring poisoning.
+fn lock_pkt(pkt: &SharedPkt) -> MutexGuard<'_, Vec<u8>> {
+    pkt.lock().unwrap_or_else(|p| p.into_inner())
+}
+
+/// Returns the `next_seq` and advances it.
+fn take_seq(next_seq: &Option<AtomicU64>) -> Option<u64> {
+    next_seq
+        .as_ref()
+        .map(|next_seq| next_seq.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn finds_peers_by_name() {
+        let config = Config::default();
+        let discovery =
+            PeerDiscovery::new(1, "0.0.0.0:1".parse().unwrap(), "me".into(), None, config);
+        {
+            let mut peers = discovery.peers.map.lock().unwrap();
+            for (id, name) in [(3, "laptop"), (2, "desktop"), (4, "Desktop")] {
+                let addr = format!("10.0.0.{id}:25802").parse().unwrap();
+                let name = Some(String::from(name));
+                peers.insert(
+                    id,
+                    PeerInfo {
+                        addr,
+                        name,
+                        seq: None,
+                        version: None,
+                        last_seen: Instant::now(),
+                    },
+                );
+            }
+        }
+
+        assert_eq!(
+            discovery.find_peer_addr_by_name("LAPTOP").ok(),
+            Some("10.0.0.3:25802".parse().unwrap())
+        );
+        assert!(matches!(
+            discovery.find_peer_addr_by_name("phone"),
+            Err(NameLookupError::NotFound(_))
+        ));
+        let Err(NameLookupError::Ambiguous(_, ids)) = discovery.find_peer_addr_by_name("desktop")
+        else {
+            panic!("expected an ambiguous name");
+        };
+        assert_eq!(ids, [2, 4]);
+    }
+
+    #[test]
+    fn numbers_announcements_past_previous_launches() {
+        let addr: PeerAddr = "10.0.0.1:25802".parse().unwrap();
+        let first = PeerDiscovery::new(1, addr, "me".into(), Some(1), Config::default());
+        for _ in 0..3 {
+            first.set_addr(addr);
+        }
+        let second = PeerDiscovery::new(1, addr, "me".into(), Some(2), Config::default());
+
+        let last_of_first = take_seq(&first.next_seq).unwrap();
+        assert_eq!(last_of_first, (1 << 32) + 4);
+        assert!(take_seq(&second.next_seq).unwrap() > last_of_first);
+
+        let unnumbered = PeerDiscovery::new(1, addr, "me".into(), None, Config::default());
+        assert_eq!(take_seq(&unnumbered.next_seq), None);
+    }
+
+    #[test]
+    fn waits_for_a_peer_to_be_discovered() {
+        let config = Config::default();
+        let discovery =
+            PeerDiscovery::new(1, "0.0.0.0:1".parse().unwrap(), "me".into(), None, config);
+        let addr = "10.0.0.2:25802".parse().unwrap();
+
+        assert_eq!(discovery.wait_for_peer(2, Duration::from_millis(10)), None);
+        assert!(!discovery.wait_for_any_peer(Duration::from_millis(10)));
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                let peer = PeerInfo {
+                    addr,
+                    name: None,
+                    seq: None,
+                    version: None,
+                    last_seen: Instant::now(),
+                };
+                discovery.peers.map.lock().unwrap().insert(2, peer);
+                discovery.peers.inserted.notify_all();
+            });
+            assert_eq!(
+                discovery.wait_for_peer(2, Duration::from_secs(5)),
+                Some(addr)
+            );
+        });
+        assert!(discovery.wait_for_any_peer(Duration::ZERO));
+
+        assert_eq!(discovery.clear(), 1);
+        assert!(discovery.is_empty());
     }
 }
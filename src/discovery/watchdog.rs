@@ -0,0 +1,111 @@
+//! A watchdog that keeps a discoverer thread running.
+
+use std::io;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::ThreadHandle;
+use crate::diagnostics::LastError;
+use crate::{elogln, logln};
+
+/// Longest duration to wait before attempting to restart the thread.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A thread that ran for at least this long is considered healthy, so its restart starts
+/// over from the initial backoff.
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+/// Spawns a watchdog that waits for the thread behind the `handle` to end, for whatever reason,
+/// and restarts it using `respawn`.
+///
+/// Restarts are delayed by a backoff starting at `initial_backoff` that doubles (up to
+/// [`MAX_BACKOFF`]) as long as the thread keeps dying quickly or fails to restart. Every
+/// restart is logged and recorded into the `last_error`.
+pub fn spawn<F>(
+    name: &'static str,
+    handle: ThreadHandle,
+    respawn: F,
+    initial_backoff: Duration,
+    last_error: LastError,
+) -> io::Result<JoinHandle<()>>
+where
+    F: Fn() -> io::Result<ThreadHandle> + Send + 'static,
+{
+    thread::Builder::new()
+        .name(format!("{name}_watchdog"))
+        .spawn(move || {
+            let mut handle = handle;
+            let mut backoff = initial_backoff;
+
+            loop {
+                let started_at = Instant::now();
+                let reason = match handle.join() {
+                    Ok(Ok(_)) => String::from("stopped"),
+                    Ok(Err(e)) => format!("failed: {e}"),
+                    Err(_) => String::from("panicked"),
+                };
+                if started_at.elapsed() >= HEALTHY_RUN {
+                    backoff = initial_backoff;
+                }
+
+                handle = loop {
+                    let err = format!("Thread `{name}` {reason}; restarting in {backoff:?}");
+                    elogln!("{err}");
+                    last_error.record(err);
+
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+
+                    match respawn() {
+                        Ok(handle) => break handle,
+                        Err(e) => {
+                            let err = format!("Failed to restart thread `{name}`: {e}");
+                            elogln!("{err}");
+                            last_error.record(err);
+                        }
+                    }
+                };
+                logln!("Restarted thread `{name}`");
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn restarts_a_thread_that_returns_early() {
+        let spawn_count = Arc::new(AtomicUsize::new(0));
+        let respawn = {
+            let spawn_count = Arc::clone(&spawn_count);
+            move || {
+                spawn_count.fetch_add(1, Ordering::SeqCst);
+                Ok(thread::spawn(|| Err(Error::other("socket closed"))))
+            }
+        };
+        let handle = respawn().unwrap();
+        let last_error = LastError::default();
+
+        spawn(
+            "test",
+            handle,
+            respawn,
+            Duration::from_millis(1),
+            last_error.clone(),
+        )
+        .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while spawn_count.load(Ordering::SeqCst) < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(spawn_count.load(Ordering::SeqCst) >= 3);
+        assert!(last_error
+            .get()
+            .is_some_and(|(_, err)| err.contains("socket closed")));
+    }
+}
@@ -0,0 +1,187 @@
+//! Transfer events, published to the API clients that subscribe to them, e.g., a GUI showing
+//! the transfers in progress.
+
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::history::{Direction, Record};
+use crate::json;
+use crate::protocol::PeerAddr;
+
+/// Minimum interval between two progress events of the same transfer.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Number of events a subscriber can fall behind by before it misses the next ones.
+const SUBSCRIBER_CAPACITY: usize = 256;
+
+/// Channels of the subscribers; a subscriber that went away is dropped on the next event.
+static SUBSCRIBERS: Mutex<Vec<SyncSender<Event>>> = Mutex::new(Vec::new());
+
+/// Describes a transfer an event is about.
+#[derive(Clone)]
+pub struct Transfer {
+    pub direction: Direction,
+    /// Address of the peer on the other end of the transfer, if known.
+    pub peer_addr: Option<PeerAddr>,
+    pub file_name: String,
+    /// Number of bytes to transfer.
+    pub total: u64,
+}
+
+#[derive(Clone)]
+pub enum Event {
+    Started(Transfer),
+    /// Holds the number of bytes transferred so far.
+    Progress(Transfer, u64),
+    Completed(Transfer),
+    /// Holds the reason the transfer failed.
+    Failed(Transfer, String),
+}
+
+impl Event {
+    /// Serializes the event as a single line JSON object, with its kind in the `event` field.
+    pub fn to_json(&self) -> String {
+        let (kind, transfer, extra) = match self {
+            Event::Started(transfer) => ("transfer_started", transfer, String::new()),
+            Event::Progress(transfer, bytes) => {
+                ("transfer_progress", transfer, format!(",\"bytes\":{bytes}"))
+            }
+            Event::Completed(transfer) => ("transfer_completed", transfer, String::new()),
+            Event::Failed(transfer, error) => (
+                "transfer_failed",
+                transfer,
+                format!(",\"error\":{}", json::string(error)),
+            ),
+        };
+        let peer = transfer
+            .peer_addr
+            .map_or(String::from("null"), |addr| json::string(&addr.to_string()));
+
+        format!(
+            "{{\"event\":\"{kind}\",\"direction\":\"{}\",\"peer\":{peer},\"file_name\":{},\
+             \"total\":{}{extra}}}",
+            transfer.direction,
+            json::string(&transfer.file_name),
+            transfer.total
+        )
+    }
+}
+
+impl From<&Record<'_>> for Event {
+    /// Turns a completed transfer into the event that reports it.
+    fn from(record: &Record) -> Event {
+        let transfer = Transfer {
+            direction: record.direction,
+            peer_addr: record.peer_addr,
+            file_name: record
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            total: record.bytes,
+        };
+        match &record.error {
+            Some(error) => Event::Failed(transfer, error.clone()),
+            None => Event::Completed(transfer),
+        }
+    }
+}
+
+/// Returns a channel that receives the events published from now on.
+///
+/// Events published while [`SUBSCRIBER_CAPACITY`] of them are waiting in the channel are
+/// dropped, so that a subscriber that stopped reading doesn't hold them forever.
+pub fn subscribe() -> Receiver<Event> {
+    let (sender, events) = mpsc::sync_channel(SUBSCRIBER_CAPACITY);
+    SUBSCRIBERS
+        .lock()
+        .unwrap_or_else(|p| p.into_inner())
+        .push(sender);
+    events
+}
+
+/// Sends the event to all the subscribers, without waiting for those that fell behind.
+pub fn publish(event: Event) {
+    SUBSCRIBERS
+        .lock()
+        .unwrap_or_else(|p| p.into_inner())
+        .retain(|subscriber| {
+            !matches!(
+                subscriber.try_send(event.clone()),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+}
+
+/// Reports the progress of a transfer, publishing [`Event::Started`] once created.
+pub struct Progress {
+    transfer: Transfer,
+    last_published: Instant,
+}
+
+impl Progress {
+    pub fn start(transfer: Transfer) -> Progress {
+        publish(Event::Started(transfer.clone()));
+        Progress {
+            transfer,
+            last_published: Instant::now(),
+        }
+    }
+
+    /// Publishes that `bytes` were transferred so far, unless progress was published less
+    /// than [`PROGRESS_INTERVAL`] ago.
+    pub fn update(&mut self, bytes: u64) {
+        if self.last_published.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last_published = Instant::now();
+        let bytes = bytes.min(self.transfer.total);
+        publish(Event::Progress(self.transfer.clone(), bytes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publishes_events_to_subscribers() {
+        let events = subscribe();
+        let transfer = Transfer {
+            direction: Direction::Sent,
+            peer_addr: Some("192.168.1.5:25802".parse().unwrap()),
+            file_name: String::from("notes \"1\".txt"),
+            total: 42,
+        };
+        publish(Event::Failed(transfer, String::from("refused")));
+
+        // Other tests may publish events meanwhile.
+        let event = events
+            .iter()
+            .find(|event| matches!(event, Event::Failed(_, error) if error == "refused"))
+            .unwrap();
+        assert_eq!(
+            event.to_json(),
+            "{\"event\":\"transfer_failed\",\"direction\":\"sent\",\"peer\":\"192.168.1.5:25802\",\
+             \"file_name\":\"notes \\\"1\\\".txt\",\"total\":42,\"error\":\"refused\"}"
+        );
+    }
+
+    #[test]
+    fn drops_events_for_subscribers_that_fell_behind() {
+        let events = subscribe();
+        let transfer = Transfer {
+            direction: Direction::Received,
+            peer_addr: None,
+            file_name: String::from("notes.txt"),
+            total: 42,
+        };
+        // Would block forever if publishing waited for the subscriber.
+        for _ in 0..=SUBSCRIBER_CAPACITY {
+            publish(Event::Completed(transfer.clone()));
+        }
+
+        assert_eq!(events.try_iter().count(), SUBSCRIBER_CAPACITY);
+    }
+}
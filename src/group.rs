@@ -0,0 +1,103 @@
+//! Named groups of peers that files can be sent to at once, e.g., `send @work <path>`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::elogln;
+use crate::protocol::PeerID;
+
+/// Represents a reason a group can't be created.
+#[derive(Debug, PartialEq)]
+pub enum InvalidGroup {
+    /// The name is empty or contains whitespace, `@`, `/` or `,`, so it couldn't be referred to
+    /// from every API.
+    InvalidName(String),
+    NoMembers,
+}
+
+impl fmt::Display for InvalidGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidGroup::InvalidName(name) => write!(f, "invalid group name `{name}`"),
+            InvalidGroup::NoMembers => write!(f, "a group needs at least one member"),
+        }
+    }
+}
+
+/// The groups defined in the config along with the ones created while the app runs.
+pub struct Groups(Mutex<BTreeMap<String, Vec<PeerID>>>);
+
+impl Groups {
+    /// Creates the groups defined in the config; invalid ones are left out.
+    pub fn new(defined: &HashMap<String, Vec<PeerID>>) -> Groups {
+        let groups = Groups(Mutex::default());
+        for (name, members) in defined {
+            if let Err(e) = groups.create(name, members.clone()) {
+                elogln!("Ignored the group `{name}` from the config; {e}");
+            }
+        }
+        groups
+    }
+
+    /// Creates a group with the given members, replacing any group of the same name.
+    ///
+    /// Returns `true` if a group was replaced.
+    pub fn create(&self, name: &str, members: Vec<PeerID>) -> Result<bool, InvalidGroup> {
+        let is_invalid = |c: char| c.is_whitespace() || matches!(c, '@' | '/' | ',');
+        if name.is_empty() || name.contains(is_invalid) {
+            return Err(InvalidGroup::InvalidName(name.to_string()));
+        }
+        if members.is_empty() {
+            return Err(InvalidGroup::NoMembers);
+        }
+        let mut groups = self.0.lock().unwrap_or_else(|p| p.into_inner());
+        Ok(groups.insert(name.to_string(), members).is_some())
+    }
+
+    /// Returns the identifiers of the members of the group with the given name.
+    pub fn members(&self, name: &str) -> Option<Vec<PeerID>> {
+        let groups = self.0.lock().unwrap_or_else(|p| p.into_inner());
+        groups.get(name).cloned()
+    }
+
+    /// Returns all the groups along with their members, sorted by name.
+    pub fn list(&self) -> Vec<(String, Vec<PeerID>)> {
+        let groups = self.0.lock().unwrap_or_else(|p| p.into_inner());
+        groups
+            .iter()
+            .map(|(name, members)| (name.clone(), members.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_and_replaces_groups() {
+        let defined = HashMap::from([
+            (String::from("work"), vec![1, 2]),
+            (String::from("bad name"), vec![3]),
+        ]);
+        let groups = Groups::new(&defined);
+        assert_eq!(groups.list(), [(String::from("work"), vec![1, 2])]);
+
+        assert_eq!(groups.create("home", vec![4]), Ok(false));
+        assert_eq!(groups.create("work", vec![2, 5]), Ok(true));
+        assert_eq!(groups.members("work"), Some(vec![2, 5]));
+        assert_eq!(groups.members("school"), None);
+
+        for name in ["", "a,b", "@work", "a/b"] {
+            assert_eq!(
+                groups.create(name, vec![1]),
+                Err(InvalidGroup::InvalidName(name.to_string()))
+            );
+        }
+        assert_eq!(
+            groups.create("empty", Vec::new()),
+            Err(InvalidGroup::NoMembers)
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! An append-only log of the completed transfers.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::events::{self, Event};
+use crate::protocol::PeerAddr;
+use crate::{elogln, metrics};
+
+/// Name of the file, inside the data location, where the records are appended.
+const HISTORY_FILE_NAME: &str = "history";
+
+/// Represents the direction of a transfer.
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Sent => write!(f, "sent"),
+            Direction::Received => write!(f, "received"),
+        }
+    }
+}
+
+/// Represents a single completed transfer.
+pub struct Record<'a> {
+    pub direction: Direction,
+    /// Address of the peer on the other end of the transfer, if known.
+    pub peer_addr: Option<PeerAddr>,
+    /// Path of the sent file, or the path where the received file was saved.
+    pub path: &'a Path,
+    /// Size of the file contents.
+    pub bytes: u64,
+    /// `None` if the transfer succeeded; otherwise the reason it failed.
+    pub error: Option<String>,
+}
+
+/// Appends the record to the history kept in the `data_location`.
+///
+/// The transfer is counted in the [metrics](crate::metrics) and published as an
+/// [event](crate::events) as well. Failing to record a
+/// transfer shouldn't fail the transfer itself, so errors are only logged.
+pub fn append(data_location: &Path, record: Record) {
+    metrics::count_transfer(&record);
+    events::publish(Event::from(&record));
+
+    if let Err(e) = try_append(data_location, &record) {
+        elogln!("Failed to record the transfer into the history: {e}");
+    }
+}
+
+/// Returns the last `count` records of the history kept in the `data_location`, one per line.
+///
+/// An absent history, e.g., before the first transfer, has no records.
+pub fn last(data_location: &Path, count: usize) -> io::Result<Vec<String>> {
+    let history = match fs::read_to_string(history_path(data_location)) {
+        Ok(history) => history,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let lines = history.lines().collect::<Vec<_>>();
+    let skip_len = lines.len().saturating_sub(count);
+    Ok(lines[skip_len..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect())
+}
+
+fn try_append(data_location: &Path, record: &Record) -> io::Result<()> {
+    fs::create_dir_all(data_location)?;
+    let mut history = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_path(data_location))?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let peer = record
+        .peer_addr
+        .map_or(String::from("unknown"), |addr| addr.to_string());
+    let result = record.error.as_deref().unwrap_or("ok");
+
+    // Written with a single call, so concurrent appends don't interleave within a record.
+    let line = format!(
+        "{timestamp}\t{}\t{peer}\t{}\t{result}\t{}\n",
+        record.direction,
+        record.bytes,
+        record.path.display()
+    );
+    history.write_all(line.as_bytes())
+}
+
+fn history_path(data_location: &Path) -> PathBuf {
+    data_location.join(HISTORY_FILE_NAME)
+}
@@ -0,0 +1,354 @@
+//! An HTTP/1.1 transport for the API, so that it can be driven by `curl` or a browser.
+//!
+//! The endpoints mirror the IPC commands: requests that only read state use `GET`, the rest
+//! use `POST` with the file path or text in the body, e.g., `POST /send_to/<id>`.
+//! `GET /events` streams the transfer events as server-sent events.
+//! `POST /send?archive` sends a directory as a single archive and `POST /shutdown?abort` stops
+//! the app without waiting for the transfers in progress.
+//! Requests that fail are answered with `422 Unprocessable Entity` and the reason in the body.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+use crate::api::{Message, PeerRef, ReadRequest, Request, ERROR_PREFIX};
+use crate::ipc::DEFAULT_HISTORY_COUNT;
+use crate::net;
+use crate::protocol::{PeerAddr, PeerID};
+use crate::transfer::ByteRange;
+
+/// Maximum size of the request line and headers altogether.
+const MAX_HEAD_LEN: u64 = 8 * 1024;
+/// Maximum size of a request body.
+const MAX_BODY_LEN: usize = 64 * 1024;
+/// Duration a client may take to send its request, so that a stalled client can't block the
+/// API for good.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+const BAD_REQUEST: &str = "400 Bad Request";
+const NOT_FOUND: &str = "404 Not Found";
+/// Status of the response to a valid request that failed, e.g., because the peer wasn't found.
+const UNPROCESSABLE: &str = "422 Unprocessable Entity";
+
+/// A structure representing an HTTP server.
+pub struct HttpServer(TcpListener);
+
+impl HttpServer {
+    /// Creates a new [HttpServer] bound to the `addr`.
+    pub fn bind(addr: SocketAddr) -> io::Result<HttpServer> {
+        TcpListener::bind(addr).map(HttpServer).map_err(|e| {
+            net::bind_error(e, addr.port(), "try changing `http_api_addr` in the config")
+        })
+    }
+}
+
+impl ReadRequest for HttpServer {
+    /// Accepts new incoming connections until one carries a valid request and returns it.
+    ///
+    /// Invalid requests are answered with an error status right away, so that they don't end
+    /// the iteration over incoming requests.
+    fn read_request(&self) -> io::Result<Request> {
+        loop {
+            let (mut stream, _) = self.0.accept()?;
+            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+            match read_message(&stream) {
+                // Events are streamed rather than sent as a single response.
+                Ok(Message::Events) => match EventStream::new(stream) {
+                    Ok(events) => return Ok(Request::new(Message::Events, Box::new(events))),
+                    Err(_) => continue,
+                },
+                Ok(message) => return Ok(Request::new(message, Box::new(Response::new(stream)))),
+                Err(status) => {
+                    let _ = write_response(&mut stream, status, status.as_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Reads an HTTP request and parses it into a message.
+///
+/// On failure, returns the status the request should be answered with.
+fn read_message(reader: impl Read) -> Result<Message, &'static str> {
+    let mut reader = BufReader::new(reader);
+    let mut head = (&mut reader).take(MAX_HEAD_LEN);
+
+    let mut request_line = String::new();
+    head.read_line(&mut request_line).map_err(|_| BAD_REQUEST)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(BAD_REQUEST);
+    };
+
+    let mut content_len = 0;
+    loop {
+        let mut header = String::new();
+        head.read_line(&mut header).map_err(|_| BAD_REQUEST)?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':').ok_or(BAD_REQUEST)?;
+        if name.eq_ignore_ascii_case("content-length") {
+            content_len = value.trim().parse().map_err(|_| BAD_REQUEST)?;
+        }
+    }
+
+    if content_len > MAX_BODY_LEN {
+        return Err("413 Payload Too Large");
+    }
+    let mut body = vec![0; content_len];
+    reader.read_exact(&mut body).map_err(|_| BAD_REQUEST)?;
+    let body = String::from_utf8(body).map_err(|_| BAD_REQUEST)?;
+
+    route(method, target, body.trim())
+}
+
+/// Maps the request to the message of the endpoint it targets.
+fn route(method: &str, target: &str, body: &str) -> Result<Message, &'static str> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+    let peer_id = |id: &str| id.parse::<PeerID>().map_err(|_| BAD_REQUEST);
+    let body = || match body {
+        "" => Err(BAD_REQUEST),
+        body => Ok(body.to_string()),
+    };
+
+    let message = match (method, segments.as_slice()) {
+        ("GET", ["ping"]) => Message::Ping,
+        ("GET", ["myid"]) => Message::MyID,
+        ("GET", ["myaddr"]) => Message::MyAddr,
+        ("GET", ["peers"]) => Message::Peers {
+            json: query.split('&').any(|p| p == "json"),
+            probe: query.split('&').any(|p| p == "probe"),
+        },
+        ("GET", ["diagnostics"]) => Message::Diagnostics,
+        ("GET", ["rescan"]) => Message::Rescan,
+        ("GET", ["discovery_debug"]) => Message::DiscoveryDebug,
+        ("GET", ["save_info"]) => Message::SaveInfo,
+        ("GET", ["metrics"]) => Message::Metrics,
+        ("GET", ["events"]) => Message::Events,
+        ("GET", ["groups"]) => Message::Groups,
+        ("GET", ["history"]) => {
+            let count = match query.split('&').find_map(|p| p.strip_prefix("count=")) {
+                Some(count) => count.parse().map_err(|_| BAD_REQUEST)?,
+                None => DEFAULT_HISTORY_COUNT,
+            };
+            Message::History(count)
+        }
+        ("GET", ["list_remote", id]) => Message::ListRemote(peer_id(id)?),
+        ("GET", ["peer_info", id]) => Message::PeerInfo(peer_id(id)?),
+        ("GET", ["cat", file_name]) => Message::Cat(file_name.to_string()),
+        ("GET", ["cat", subdir, file_name]) => Message::Cat(format!("{subdir}/{file_name}")),
+        ("GET", ["exists", peer, file_name]) => {
+            let Ok(peer) = peer.parse::<PeerRef>();
+            Message::FileExists(peer, file_name.to_string())
+        }
+        ("POST", ["verify", file_name]) => Message::Verify(file_name.to_string(), body()?),
+        ("POST", ["verify", subdir, file_name]) => {
+            Message::Verify(format!("{subdir}/{file_name}"), body()?)
+        }
+        ("POST", ["discover"]) => Message::Discover,
+        ("POST", ["refresh"]) => Message::Refresh,
+        ("POST", ["self_test"]) => Message::SelfTest,
+        ("POST", ["mcast_test"]) => Message::McastTest,
+        ("POST", ["clear_peers"]) => Message::ClearPeers,
+        ("POST", ["send"]) if query.split('&').any(|p| p == "archive") => {
+            Message::SendArchive(body()?)
+        }
+        ("POST", ["send"]) if query.split('&').any(|p| p == "text") => Message::SendText(body()?),
+        // One file path per line.
+        ("POST", ["send"]) => {
+            let body = body()?;
+            let paths = body.lines().filter(|path| !path.is_empty());
+            Message::Send(paths.map(String::from).collect())
+        }
+        ("POST", ["send_to", peer]) => {
+            let Ok(peer) = peer.parse::<PeerRef>();
+            Message::SendTo(peer, body()?)
+        }
+        ("POST", ["send_to_addr", addr]) => {
+            let addr = addr.parse::<PeerAddr>().map_err(|_| BAD_REQUEST)?;
+            Message::SendToAddr(addr, body()?)
+        }
+        ("POST", ["send_except", ids]) => {
+            let ids = ids.split(',').map(peer_id).collect::<Result<_, _>>()?;
+            Message::SendExcept(ids, body()?)
+        }
+        ("POST", ["send_to_group", group]) => Message::SendToGroup(group.to_string(), body()?),
+        // The identifiers of the members, separated by commas.
+        ("POST", ["groups", name]) => {
+            let ids = body()?
+                .split(',')
+                .map(|id| peer_id(id.trim()))
+                .collect::<Result<_, _>>()?;
+            Message::CreateGroup(name.to_string(), ids)
+        }
+        // One file path per line.
+        ("POST", ["send_files_to", id]) => {
+            let paths = body()?.lines().map(String::from).collect();
+            Message::SendFilesTo(peer_id(id)?, paths)
+        }
+        ("POST", ["msg", id]) => Message::Msg(peer_id(id)?, body()?),
+        ("POST", ["pull", id]) => {
+            let range = match query.split('&').find_map(|p| p.strip_prefix("range=")) {
+                Some(range) => Some(range.parse::<ByteRange>().map_err(|_| BAD_REQUEST)?),
+                None => None,
+            };
+            Message::Pull(peer_id(id)?, body()?, range)
+        }
+        ("POST", ["shutdown"]) => Message::Shutdown(query.split('&').any(|p| p == "abort")),
+        _ => return Err(NOT_FOUND),
+    };
+    Ok(message)
+}
+
+/// A response writer that buffers the body, so that it can be sent along with its length
+/// once the request is handled, i.e., when the writer is dropped.
+struct Response {
+    stream: TcpStream,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(stream: TcpStream) -> Response {
+        Response {
+            stream,
+            body: Vec::new(),
+        }
+    }
+}
+
+impl Write for Response {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.body.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Response {
+    /// Sends the response, with an error status if it starts with an error, see
+    /// [`Request::respond_error`](crate::api::Request::respond_error); the status replaces the
+    /// prefix of the error.
+    fn drop(&mut self) {
+        let _ = match self.body.strip_prefix(ERROR_PREFIX.as_bytes()) {
+            Some(reason) => write_response(&mut self.stream, UNPROCESSABLE, reason),
+            None => write_response(&mut self.stream, "200 OK", &self.body),
+        };
+    }
+}
+
+/// A response writer that streams each line written to it as a server-sent event.
+struct EventStream(TcpStream);
+
+impl EventStream {
+    /// Sends the head of the response right away, so that the client starts listening.
+    fn new(mut stream: TcpStream) -> io::Result<EventStream> {
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+             Connection: close\r\n\r\n"
+        )?;
+        Ok(EventStream(stream))
+    }
+}
+
+impl Write for EventStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for line in String::from_utf8_lossy(buf).lines() {
+            write!(self.0, "data: {line}\n\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(request: &str) -> Result<Message, &'static str> {
+        read_message(request.as_bytes())
+    }
+
+    #[test]
+    fn parses_requests_into_messages() {
+        assert!(matches!(
+            parse("GET /peers HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            Ok(Message::Peers { json: false, .. })
+        ));
+        assert!(matches!(
+            parse("GET /history?count=3 HTTP/1.1\r\n\r\n"),
+            Ok(Message::History(3))
+        ));
+
+        let request = "POST /send_to/42 HTTP/1.1\r\nContent-Length: 14\r\n\r\n/tmp/notes.txt";
+        let Ok(Message::SendTo(PeerRef::ID(42), path)) = parse(request) else {
+            panic!("expected a send_to message");
+        };
+        assert_eq!(path, "/tmp/notes.txt");
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        assert!(matches!(
+            parse("GET /unknown HTTP/1.1\r\n\r\n"),
+            Err("404 Not Found")
+        ));
+        assert!(matches!(
+            parse("POST /send HTTP/1.1\r\n\r\n"),
+            Err("400 Bad Request")
+        ));
+        assert!(matches!(
+            parse("POST /send HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"),
+            Err("400 Bad Request")
+        ));
+        assert!(matches!(
+            parse("POST /send HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n"),
+            Err("413 Payload Too Large")
+        ));
+    }
+
+    #[test]
+    fn answers_failed_requests_with_an_error_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let respond = |error: bool| {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            let mut req = Request::new(Message::MyID, Box::new(Response::new(stream)));
+            match error {
+                true => req.respond_error("No peers found").unwrap(),
+                false => req.response("42").unwrap(),
+            }
+            drop(req);
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let failed = respond(true);
+        assert!(failed.starts_with("HTTP/1.1 422 Unprocessable Entity\r\n"));
+        assert!(failed.ends_with("\r\n\r\nNo peers found"));
+        let succeeded = respond(false);
+        assert!(succeeded.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(succeeded.ends_with("\r\n\r\n42"));
+    }
+}
@@ -1,5 +1,7 @@
-use std::net::{IpAddr, Ipv4Addr};
-use std::ptr;
+use std::ffi::CStr;
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::{fmt, ptr};
 
 pub struct InterfaceAddresses {
     /// A linked list containing interfaces of the system.
@@ -24,6 +26,10 @@ impl InterfaceAddresses {
 
     fn get_interface_ip_address(interface: *mut libc::ifaddrs) -> Option<IpAddr> {
         let interface_address = unsafe { *interface }.ifa_addr;
+        // Some interfaces, e.g., tunnels without a peer, have no address at all.
+        if interface_address.is_null() {
+            return None;
+        }
         let address_family = unsafe { *interface_address }.sa_family;
 
         match address_family as libc::c_int {
@@ -41,7 +47,13 @@ impl InterfaceAddresses {
                 let address_bytes = unsafe { *socket_address }.sin_addr.s_addr.to_be();
                 Some(IpAddr::V4(Ipv4Addr::from(address_bytes)))
             }
-            // Not using ipv6 (currently) and packet.
+            libc::AF_INET6 => {
+                // The address of `sockaddr_in6` is kept as bytes in network byte order already.
+                let socket_address = interface_address as *mut libc::sockaddr_in6;
+                let address_bytes = unsafe { *socket_address }.sin6_addr.s6_addr;
+                Some(IpAddr::V6(Ipv6Addr::from(address_bytes)))
+            }
+            // Not using packet.
             _ => None,
         }
     }
@@ -68,6 +80,46 @@ impl Drop for InterfaceAddresses {
     }
 }
 
+/// Returns the hardware (MAC) address of the first non-loopback interface, if any.
+#[cfg(target_os = "linux")]
+pub fn mac_address() -> Option<[u8; 6]> {
+    let interface_addresses = InterfaceAddresses::new()?;
+    let mut interface = interface_addresses.interfaces;
+
+    while !interface.is_null() {
+        let current_interface = unsafe { *interface };
+        interface = current_interface.ifa_next;
+
+        let interface_address = current_interface.ifa_addr;
+        let is_loopback = current_interface.ifa_flags & libc::IFF_LOOPBACK as libc::c_uint != 0;
+
+        if interface_address.is_null()
+            || is_loopback
+            || unsafe { *interface_address }.sa_family as libc::c_int != libc::AF_PACKET
+        {
+            continue;
+        }
+        // Interfaces of the `AF_PACKET` family carry their link-layer address in `sockaddr_ll`.
+        let link_address = unsafe { *(interface_address as *mut libc::sockaddr_ll) };
+        if link_address.sll_halen != 6 {
+            continue;
+        }
+        let mut mac_address = [0; 6];
+        mac_address.copy_from_slice(&link_address.sll_addr[..6]);
+
+        if mac_address != [0; 6] {
+            return Some(mac_address);
+        }
+    }
+    None
+}
+
+/// Returns the hardware (MAC) address of the first non-loopback interface, if any.
+#[cfg(not(target_os = "linux"))]
+pub fn mac_address() -> Option<[u8; 6]> {
+    None
+}
+
 pub fn local_ipv4_address() -> Option<Ipv4Addr> {
     InterfaceAddresses::new()?.find_map(|ip_address| {
         let IpAddr::V4(address) = ip_address else {
@@ -76,3 +128,127 @@ pub fn local_ipv4_address() -> Option<Ipv4Addr> {
         (address.is_private() && address.octets().starts_with(&[192, 168])).then_some(address)
     })
 }
+
+/// Address family of an [`InterfaceAddress`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+    /// A link-layer address, e.g., the MAC address on Linux.
+    Link,
+    Other(libc::c_int),
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Family::Ipv4 => write!(f, "IPv4"),
+            Family::Ipv6 => write!(f, "IPv6"),
+            Family::Link => write!(f, "link"),
+            Family::Other(family) => write!(f, "family {family}"),
+        }
+    }
+}
+
+/// An address of an interface as listed by the system, see [`all_addresses`].
+pub struct InterfaceAddress {
+    pub name: String,
+    /// `None` for an interface listed without any address.
+    pub family: Option<Family>,
+    /// The IP address, for the IPv4 and IPv6 families.
+    pub address: Option<IpAddr>,
+}
+
+/// Enumerates the interfaces again and returns every address of each of them, whatever its
+/// family, in the order the system lists them.
+pub fn all_addresses() -> Vec<InterfaceAddress> {
+    let Some(interface_addresses) = InterfaceAddresses::new() else {
+        return Vec::new();
+    };
+    let mut interface = interface_addresses.interfaces;
+    let mut addresses = Vec::new();
+
+    while !interface.is_null() {
+        let current_interface = interface;
+        interface = unsafe { *current_interface }.ifa_next;
+
+        let interface_address = unsafe { *current_interface }.ifa_addr;
+        let family = (!interface_address.is_null()).then(|| {
+            match unsafe { *interface_address }.sa_family as libc::c_int {
+                libc::AF_INET => Family::Ipv4,
+                libc::AF_INET6 => Family::Ipv6,
+                #[cfg(target_os = "linux")]
+                libc::AF_PACKET => Family::Link,
+                #[cfg(not(target_os = "linux"))]
+                libc::AF_LINK => Family::Link,
+                family => Family::Other(family),
+            }
+        });
+        let name = unsafe { CStr::from_ptr((*current_interface).ifa_name) };
+        addresses.push(InterfaceAddress {
+            name: name.to_string_lossy().into_owned(),
+            family,
+            address: InterfaceAddresses::get_interface_ip_address(current_interface),
+        });
+    }
+    addresses
+}
+
+/// Returns the name and IPv4 address of each interface that has one.
+pub fn named_ipv4_addresses() -> Vec<(String, Ipv4Addr)> {
+    let Some(interface_addresses) = InterfaceAddresses::new() else {
+        return Vec::new();
+    };
+    let mut interface = interface_addresses.interfaces;
+    let mut addresses = Vec::new();
+
+    while !interface.is_null() {
+        let current_interface = interface;
+        interface = unsafe { *current_interface }.ifa_next;
+
+        let Some(IpAddr::V4(address)) =
+            InterfaceAddresses::get_interface_ip_address(current_interface)
+        else {
+            continue;
+        };
+        let name = unsafe { CStr::from_ptr((*current_interface).ifa_name) };
+        addresses.push((name.to_string_lossy().into_owned(), address));
+    }
+    addresses
+}
+
+/// Resolves each of the `interfaces`, given either by its name (e.g., `eth0`) or by its IPv4
+/// address, to its IPv4 address.
+pub fn resolve_ipv4_addresses(interfaces: &[String]) -> io::Result<Vec<Ipv4Addr>> {
+    if interfaces.is_empty() {
+        return Ok(Vec::new());
+    }
+    let named_addresses = named_ipv4_addresses();
+
+    interfaces
+        .iter()
+        .map(|interface| {
+            if let Ok(address) = interface.parse::<Ipv4Addr>() {
+                return Ok(address);
+            }
+            named_addresses
+                .iter()
+                .find(|(name, _)| name == interface)
+                .map(|&(_, address)| address)
+                .ok_or_else(|| {
+                    let available = named_addresses
+                        .iter()
+                        .map(|(name, address)| format!("{name} ({address})"))
+                        .collect::<Vec<_>>();
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!(
+                            "no interface named `{interface}` has an IPv4 address; available \
+                             interfaces: {}",
+                            available.join(", ")
+                        ),
+                    )
+                })
+        })
+        .collect()
+}
@@ -1,46 +1,188 @@
-use std::fs;
+use std::fs::{self, Permissions};
 use std::io::{self, Error, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+use std::{env, thread};
 
-use crate::api::{Message, ReadRequest, Request};
-use crate::protocol::PeerID;
+use crate::api::{Message, PeerRef, ReadRequest, Request};
+use crate::elogln;
+use crate::protocol::{PeerAddr, PeerID};
+use crate::transfer::ByteRange;
 
-pub const SOCK_FILE_PATH: &str = "/tmp/rapi.sock";
+/// Env variable that overrides the path of the socket file.
+pub const SOCK_PATH_ENV_KEY: &str = "REDTOOTH_SOCKET";
+/// Number of history records returned when the request doesn't specify it.
+pub const DEFAULT_HISTORY_COUNT: usize = 10;
+/// Duration a client may take to send its request, so that a stalled client doesn't keep its
+/// thread around for good.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// A structure representing an IPC socket server.
-pub struct IPCServer(UnixListener);
+///
+/// Connections are accepted on a thread of their own and each of them is read on its own
+/// short-lived thread, so a client that is slow to send its request doesn't stall the others.
+/// The socket file is removed once the server is dropped.
+pub struct IPCServer {
+    requests: Receiver<(Message, UnixStream)>,
+    path: PathBuf,
+    /// Device and inode numbers of the socket file created by this server.
+    file_id: Option<(u64, u64)>,
+}
 
 impl IPCServer {
-    /// Creates a new [IPCServer] bound to the [`SOCK_FILE_PATH`].
+    /// Creates a new [IPCServer] bound to the path returned by [`socket_path`].
+    ///
+    /// The socket file is only accessible by the current user. Fails with
+    /// [`AddrInUse`](ErrorKind::AddrInUse) if another daemon is serving it already.
     pub fn new() -> io::Result<IPCServer> {
-        let listener = UnixListener::bind(SOCK_FILE_PATH);
+        let path = socket_path();
+        let listener = UnixListener::bind(&path);
 
-        if !listener
+        let listener = if !listener
             .as_ref()
             .is_err_and(|e| e.kind() == ErrorKind::AddrInUse)
         {
-            Ok(IPCServer(listener?))
+            listener?
         } else {
-            // Delete the old socket file and create the new one.
-            fs::remove_file(SOCK_FILE_PATH)?;
-            Ok(IPCServer(UnixListener::bind(SOCK_FILE_PATH)?))
+            if let Some(pid) = running_daemon_pid(&path) {
+                return Err(Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("redtooth is already running (pid {pid})"),
+                ));
+            }
+            // Nothing serves the socket; delete the file left behind and create a new one.
+            fs::remove_file(&path)?;
+            UnixListener::bind(&path)?
+        };
+
+        fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+
+        let (sender, requests) = mpsc::channel();
+        thread::Builder::new()
+            .name(String::from("ipc_api"))
+            .spawn(move || accept_connections(listener, sender))?;
+
+        Ok(IPCServer {
+            requests,
+            file_id: file_id(&path),
+            path,
+        })
+    }
+}
+
+impl Drop for IPCServer {
+    fn drop(&mut self) {
+        // Another instance may have replaced the socket file since; leave its file alone.
+        if self.file_id.is_some() && file_id(&self.path) == self.file_id {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Returns the path of the socket file, which both the server and its clients use.
+///
+/// It is the value of the [`SOCK_PATH_ENV_KEY`] env variable if set; otherwise a file in
+/// `$XDG_RUNTIME_DIR`, which is private to the user, or else a per-user file in the temp dir.
+pub fn socket_path() -> PathBuf {
+    match env::var_os(SOCK_PATH_ENV_KEY) {
+        Some(path) => PathBuf::from(path),
+        None => runtime_path(".sock"),
+    }
+}
+
+/// Returns the path of the runtime file ending with the `suffix`: `redtooth<suffix>` in
+/// `$XDG_RUNTIME_DIR`, which is private to the user, or else `redtooth-<uid><suffix>` in the
+/// temp dir, which other users can write to as well.
+pub fn runtime_path(suffix: &str) -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join(format!("redtooth{suffix}")),
+        None => {
+            let uid = unsafe { libc::getuid() };
+            env::temp_dir().join(format!("redtooth-{uid}{suffix}"))
+        }
+    }
+}
+
+/// Pings the daemon serving the socket at the `path` and returns its pid, or `unknown` if it
+/// doesn't tell, e.g., because it runs an older version.
+///
+/// Returns `None` if no daemon serves the socket, e.g., because the one that created it
+/// crashed and left it behind.
+fn running_daemon_pid(path: &Path) -> Option<String> {
+    let mut stream = UnixStream::connect(path).ok()?;
+    let mut response = String::new();
+    // Whatever answers is alive, even if it doesn't understand the ping.
+    let _ = stream
+        .set_read_timeout(Some(READ_TIMEOUT))
+        .and_then(|_| stream.write_all(b"/ping"))
+        .and_then(|_| stream.shutdown(Shutdown::Write))
+        .and_then(|_| stream.read_to_string(&mut response));
+    let pid = response
+        .strip_prefix("pong ")
+        .and_then(|pid| pid.trim().parse::<u32>().ok());
+    Some(pid.map_or(String::from("unknown"), |pid| pid.to_string()))
+}
+
+/// Returns the device and inode numbers that identify the file at the `path`.
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+/// Accepts incoming connections and reads each of them on a new thread, passing the valid
+/// requests to the `sender` until the server is dropped.
+fn accept_connections(listener: UnixListener, sender: Sender<(Message, UnixStream)>) {
+    for stream in listener.incoming().flatten() {
+        let sender = sender.clone();
+        let spawned = thread::Builder::new()
+            .name(String::from("ipc_client"))
+            .spawn(move || {
+                if let Some(request) = read_connection(stream) {
+                    let _ = sender.send(request);
+                }
+            });
+
+        if let Err(e) = spawned {
+            elogln!("Failed to handle an ipc connection: {e}");
+        }
+    }
+}
+
+/// Reads the request sent on the `stream`.
+///
+/// An invalid request is answered right away, so that it doesn't reach the server.
+fn read_connection(mut stream: UnixStream) -> Option<(Message, UnixStream)> {
+    let mut request = String::new();
+    if let Err(e) = stream
+        .set_read_timeout(Some(READ_TIMEOUT))
+        .and_then(|_| stream.read_to_string(&mut request))
+    {
+        elogln!("Failed to read an ipc request: {e}");
+        return None;
+    }
+
+    match parse_request(&request) {
+        Some(message) => Some((message, stream)),
+        None => {
+            let _ = write!(stream, "Invalid command");
+            None
         }
     }
 }
 
 impl ReadRequest for IPCServer {
-    /// Accepts a new incoming connection and returns a new request received from it.
+    /// Returns the next request received from any of the connected clients.
     ///
-    /// This function will block the calling thread until a new connection is established.
-    /// When established, it reads the request and returns it.
+    /// This function will block the calling thread until a client sends a valid request.
     fn read_request(&self) -> io::Result<Request> {
-        let mut stream = self.0.accept().map(|(stream, _)| stream)?;
-        let mut request = String::new();
-        stream.read_to_string(&mut request)?;
-
-        let command =
-            parse_request(&request).ok_or(Error::new(ErrorKind::Other, "invalid command"))?;
-        Ok(Request::new(command, Box::new(stream)))
+        let (message, stream) = self
+            .requests
+            .recv()
+            .map_err(|_| Error::other("ipc server stopped accepting connections"))?;
+        Ok(Request::new(message, Box::new(stream)))
     }
 }
 
@@ -50,31 +192,342 @@ fn parse_request(req: &str) -> Option<Message> {
         .and_then(|v| v.split_once(' ').or(Some((v, ""))))?;
 
     match cmd {
+        "ping" => Some(Message::Ping),
         "myid" => Some(Message::MyID),
         "myaddr" => Some(Message::MyAddr),
-        "peers" => Some(Message::Peers),
-        "send" => Some(Message::Send(args.to_string())),
+        "peers" => {
+            let (mut json, mut probe) = (false, false);
+            for flag in args.split_whitespace() {
+                match flag {
+                    "--json" => json = true,
+                    "--probe" => probe = true,
+                    _ => return None,
+                }
+            }
+            Some(Message::Peers { json, probe })
+        }
+        "send" => match args.strip_prefix("--archive ") {
+            Some(dir_path) => Some(Message::SendArchive(dir_path.to_string())),
+            // `/send --text "hello world"`; the quotes are optional.
+            None if args.starts_with("--text ") => {
+                Some(Message::SendText(unquote(&args["--text ".len()..])?))
+            }
+            // `/send @work <path>` sends to the members of the group.
+            None if args.starts_with('@') => {
+                let (group, file_name) = args[1..].split_once(' ')?;
+                Some(Message::SendToGroup(
+                    group.to_string(),
+                    file_name.to_string(),
+                ))
+            }
+            // `/send #2 <path>` sends to the peer listed with the index.
+            None if args.starts_with('#') => {
+                let (peer, file_name) = args.split_once(' ')?;
+                let peer @ PeerRef::Index(_) = peer.parse::<PeerRef>().ok()? else {
+                    return None;
+                };
+                Some(Message::SendTo(peer, file_name.to_string()))
+            }
+            // `/send a.txt "my notes.txt"`; paths containing spaces are quoted.
+            None => Some(Message::Send(split_quoted(args).filter(|p| !p.is_empty())?)),
+        },
         "send_to" => {
-            let args = args.split_once(' ')?;
-            let peer_id = args.0.parse::<PeerID>().ok()?;
-            let file_name = args.1.to_string();
-            Some(Message::SendTo(peer_id, file_name))
+            let (peer, file_name) = args.split_once(' ')?;
+            let peer = peer.parse::<PeerRef>().ok()?;
+            Some(Message::SendTo(peer, file_name.to_string()))
+        }
+        "exists" => {
+            let (peer, file_name) = args.split_once(' ')?;
+            let peer = peer.parse::<PeerRef>().ok()?;
+            Some(Message::FileExists(peer, file_name.to_string()))
+        }
+        "send_to_addr" => {
+            let (peer_addr, file_name) = args.split_once(' ')?;
+            let peer_addr = peer_addr.parse::<PeerAddr>().ok()?;
+            Some(Message::SendToAddr(peer_addr, file_name.to_string()))
+        }
+        "send_except" => {
+            let (peer_ids, file_name) = args.split_once(' ')?;
+            let peer_ids = parse_peer_ids(peer_ids)?;
+            Some(Message::SendExcept(peer_ids, file_name.to_string()))
+        }
+        "send_files_to" => {
+            let (peer_id, file_names) = args.split_once(' ')?;
+            let peer_id = peer_id.parse::<PeerID>().ok()?;
+            let file_names = file_names.split(' ').map(String::from).collect();
+            Some(Message::SendFilesTo(peer_id, file_names))
+        }
+        "msg" => {
+            let (peer_id, text) = args.split_once(' ')?;
+            let peer_id = peer_id.parse::<PeerID>().ok()?;
+            Some(Message::Msg(peer_id, text.to_string()))
+        }
+        "groups" => Some(Message::Groups),
+        "group" => {
+            let (name, peer_ids) = args.split_once(' ')?;
+            Some(Message::CreateGroup(
+                name.to_string(),
+                parse_peer_ids(peer_ids)?,
+            ))
+        }
+        "discover" => Some(Message::Discover),
+        "refresh" => Some(Message::Refresh),
+        "rescan" => Some(Message::Rescan),
+        "self_test" => Some(Message::SelfTest),
+        "mcast_test" => Some(Message::McastTest),
+        "diagnostics" => Some(Message::Diagnostics),
+        "discovery_debug" => Some(Message::DiscoveryDebug),
+        "history" => match args {
+            "" => Some(Message::History(DEFAULT_HISTORY_COUNT)),
+            count => Some(Message::History(count.parse().ok()?)),
+        },
+        "list_remote" => Some(Message::ListRemote(args.parse().ok()?)),
+        "peer_info" => Some(Message::PeerInfo(args.parse().ok()?)),
+        "save_info" => Some(Message::SaveInfo),
+        "metrics" => Some(Message::Metrics),
+        "clear_peers" => Some(Message::ClearPeers),
+        "events" => Some(Message::Events),
+        "shutdown" => match args {
+            "" => Some(Message::Shutdown(false)),
+            "abort" => Some(Message::Shutdown(true)),
+            _ => None,
+        },
+        // `/pull <id> --range <start>-<end> <file name>` pulls only the range of the file.
+        "pull" => {
+            let (peer_id, file_name) = args.split_once(' ')?;
+            let peer_id = peer_id.parse::<PeerID>().ok()?;
+            match file_name.strip_prefix("--range ") {
+                Some(args) => {
+                    let (range, file_name) = args.split_once(' ')?;
+                    let range = range.parse::<ByteRange>().ok()?;
+                    Some(Message::Pull(peer_id, file_name.to_string(), Some(range)))
+                }
+                None => Some(Message::Pull(peer_id, file_name.to_string(), None)),
+            }
+        }
+        "cat" if !args.is_empty() => Some(Message::Cat(args.to_string())),
+        // The hash comes last, since the file name may contain spaces.
+        "verify" => {
+            let (file_name, hash) = args.rsplit_once(' ')?;
+            Some(Message::Verify(file_name.to_string(), hash.to_string()))
         }
         _ => None,
     }
 }
 
+/// Returns the text inside double quotes, where `\"` and `\\` stand for a quote and
+/// a backslash, or the text as is if it isn't quoted; `None` if the quotes aren't closed.
+fn unquote(text: &str) -> Option<String> {
+    let Some(quoted) = text.strip_prefix('"') else {
+        return Some(text.to_string());
+    };
+    let mut unquoted = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.push(chars.next()?),
+            // Nothing may follow the closing quote.
+            '"' => return chars.as_str().is_empty().then_some(unquoted),
+            c => unquoted.push(c),
+        }
+    }
+    None
+}
+
+/// Returns the `text` inside double quotes, escaped to be read back by [`unquote`].
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Splits the `text` at whitespace, except inside double quotes, where `\"` and `\\` stand for
+/// a quote and a backslash as in [`unquote`]; `None` if the quotes aren't closed.
+pub fn split_quoted(text: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\\' => word.push(chars.next()?),
+                        '"' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Some(words)
+}
+
+/// Quotes the `text` as per [`quote`] if [`split_quoted`] would split it or strip its quotes.
+fn quote_word(text: &str) -> String {
+    match text.is_empty() || text.contains(|c: char| c.is_whitespace() || c == '"') {
+        true => quote(text),
+        false => text.to_string(),
+    }
+}
+
+/// Parses a comma-separated list of peer identifiers.
+fn parse_peer_ids(ids: &str) -> Option<Vec<PeerID>> {
+    ids.split(',').map(|id| id.parse::<PeerID>().ok()).collect()
+}
+
+/// Sends the request for the `msg` to the running daemon and returns its response.
 pub fn send_request(msg: Message) -> io::Result<String> {
-    let mut stream = UnixStream::connect(SOCK_FILE_PATH)?;
+    let mut stream = write_request(msg)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+/// Sends the request for the `msg` to the running daemon and copies its response into the
+/// `output` as it arrives, e.g., the events streamed until the client goes away.
+pub fn stream_request(msg: Message, output: &mut impl Write) -> io::Result<u64> {
+    let mut stream = write_request(msg)?;
+    io::copy(&mut stream, output)
+}
+
+/// Connects to the running daemon and writes the request for the `msg`, shutting down the
+/// write side so that the daemon knows the request is complete.
+fn write_request(msg: Message) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(socket_path()).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("failed to connect to the daemon: {e}; is it running?"),
+        )
+    })?;
 
     match msg {
+        Message::Ping => write!(stream, "/ping")?,
         Message::MyID => write!(stream, "/myid")?,
         Message::MyAddr => write!(stream, "/myaddr")?,
-        Message::Peers => write!(stream, "/peers")?,
-        Message::Send(file_name) => write!(stream, "/send {file_name}")?,
-        Message::SendTo(peer_id, file_name) => write!(stream, "/send_to {peer_id} {file_name}")?,
+        Message::Peers { json, probe } => {
+            write!(stream, "/peers")?;
+            if json {
+                write!(stream, " --json")?;
+            }
+            if probe {
+                write!(stream, " --probe")?;
+            }
+        }
+        Message::Send(file_names) => {
+            let file_names = file_names.iter().map(|name| quote_word(name));
+            write!(stream, "/send {}", file_names.collect::<Vec<_>>().join(" "))?
+        }
+        Message::SendArchive(dir_path) => write!(stream, "/send --archive {dir_path}")?,
+        Message::SendText(text) => write!(stream, "/send --text {}", quote(&text))?,
+        Message::SendTo(peer, file_name) => write!(stream, "/send_to {peer} {file_name}")?,
+        Message::FileExists(peer, file_name) => write!(stream, "/exists {peer} {file_name}")?,
+        Message::SendToAddr(peer_addr, file_name) => {
+            write!(stream, "/send_to_addr {peer_addr} {file_name}")?
+        }
+        Message::SendExcept(peer_ids, file_name) => {
+            let peer_ids = peer_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+            write!(stream, "/send_except {} {file_name}", peer_ids.join(","))?
+        }
+        Message::SendToGroup(group, file_name) => write!(stream, "/send @{group} {file_name}")?,
+        Message::SendFilesTo(peer_id, file_names) => {
+            write!(stream, "/send_files_to {peer_id} {}", file_names.join(" "))?
+        }
+        Message::Msg(peer_id, text) => write!(stream, "/msg {peer_id} {text}")?,
+        Message::Groups => write!(stream, "/groups")?,
+        Message::CreateGroup(name, peer_ids) => {
+            let peer_ids = peer_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+            write!(stream, "/group {name} {}", peer_ids.join(","))?
+        }
+        Message::Discover => write!(stream, "/discover")?,
+        Message::Refresh => write!(stream, "/refresh")?,
+        Message::Rescan => write!(stream, "/rescan")?,
+        Message::SelfTest => write!(stream, "/self_test")?,
+        Message::McastTest => write!(stream, "/mcast_test")?,
+        Message::Diagnostics => write!(stream, "/diagnostics")?,
+        Message::DiscoveryDebug => write!(stream, "/discovery_debug")?,
+        Message::History(count) => write!(stream, "/history {count}")?,
+        Message::ListRemote(peer_id) => write!(stream, "/list_remote {peer_id}")?,
+        Message::PeerInfo(peer_id) => write!(stream, "/peer_info {peer_id}")?,
+        Message::Pull(peer_id, file_name, None) => write!(stream, "/pull {peer_id} {file_name}")?,
+        Message::Pull(peer_id, file_name, Some(range)) => {
+            write!(stream, "/pull {peer_id} --range {range} {file_name}")?
+        }
+        Message::Cat(file_name) => write!(stream, "/cat {file_name}")?,
+        Message::Verify(file_name, hash) => write!(stream, "/verify {file_name} {hash}")?,
+        Message::SaveInfo => write!(stream, "/save_info")?,
+        Message::Metrics => write!(stream, "/metrics")?,
+        Message::ClearPeers => write!(stream, "/clear_peers")?,
+        Message::Events => write!(stream, "/events")?,
+        Message::Shutdown(false) => write!(stream, "/shutdown")?,
+        Message::Shutdown(true) => write!(stream, "/shutdown abort")?,
     };
-    let mut response = String::new();
-    stream.read_to_string(&mut response)?;
-    Ok(response)
+    stream.shutdown(Shutdown::Write)?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quoted_text() {
+        let text = "say \"hi\" \\ bye";
+        let Some(Message::SendText(parsed)) =
+            parse_request(&format!("/send --text {}", quote(text)))
+        else {
+            panic!("expected a send_text message");
+        };
+        assert_eq!(parsed, text);
+
+        assert_eq!(unquote("hello world").as_deref(), Some("hello world"));
+        assert_eq!(unquote("\"unclosed"), None);
+        assert_eq!(unquote("\"closed\" early"), None);
+    }
+
+    #[test]
+    fn parses_quoted_file_paths() {
+        let paths = ["a.txt", "my notes.txt", "say \"hi\".txt", "back\\slash"];
+        let quoted = paths.map(quote_word).join(" ");
+        assert_eq!(
+            quoted,
+            r#"a.txt "my notes.txt" "say \"hi\".txt" back\slash"#
+        );
+        let Some(Message::Send(parsed)) = parse_request(&format!("/send {quoted}")) else {
+            panic!("expected a send message");
+        };
+        assert_eq!(parsed, paths);
+
+        assert_eq!(
+            split_quoted("  a  b\t"),
+            Some(vec![String::from("a"), String::from("b")])
+        );
+        assert_eq!(split_quoted("\"unclosed b"), None);
+        assert!(parse_request("/send ").is_none());
+    }
+
+    #[test]
+    fn tells_running_daemons_apart_from_stale_sockets() {
+        let path = env::temp_dir().join(format!("redtooth-ping-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            stream.read_to_string(&mut request).unwrap();
+            assert_eq!(
+                parse_request(&request).map(|m| matches!(m, Message::Ping)),
+                Some(true)
+            );
+            write!(stream, "pong 42").unwrap();
+        });
+        assert_eq!(running_daemon_pid(&path).as_deref(), Some("42"));
+
+        // The listener is gone, but its socket file is left behind.
+        assert!(path.exists());
+        assert_eq!(running_daemon_pid(&path), None);
+        fs::remove_file(&path).unwrap();
+    }
 }
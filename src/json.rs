@@ -0,0 +1,15 @@
+//! Helpers for writing JSON responses by hand.
+
+/// Quotes the string as a JSON string, escaping what has to be.
+pub fn string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted + "\""
+}
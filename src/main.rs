@@ -2,16 +2,50 @@ mod api;
 mod app;
 mod cli;
 mod config;
+mod diagnostics;
+mod digest;
 mod discovery;
+mod events;
+mod group;
+mod history;
+mod http;
 mod interface;
 mod ipc;
+mod json;
 mod macros;
+mod metrics;
+mod net;
 mod protocol;
 mod transfer;
 
+use std::env;
+use std::process::ExitCode;
+
 use crate::app::App;
+use crate::config::Config;
 
-fn main() -> std::io::Result<()> {
-    let mut app = App::new();
-    app.run()
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("shell") {
+        return match cli::run_shell() {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                elogln!("{e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+    let mut config = Config::default();
+    if let Err(e) = cli::apply_args(args, &mut config) {
+        elogln!("{e}");
+        return ExitCode::FAILURE;
+    }
+    let mut app = App::with_config(config);
+    match app.run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            elogln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,84 @@
+//! Process-wide counters, exposed in the Prometheus text exposition format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::history::{Direction, Record};
+
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static TRANSFERS_FAILED: AtomicU64 = AtomicU64::new(0);
+
+/// Counts the completed transfer; only the bytes of successful transfers are counted.
+pub fn count_transfer(record: &Record) {
+    if record.error.is_some() {
+        TRANSFERS_FAILED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let bytes = match record.direction {
+        Direction::Sent => &BYTES_SENT,
+        Direction::Received => &BYTES_RECEIVED,
+    };
+    bytes.fetch_add(record.bytes, Ordering::Relaxed);
+}
+
+/// Renders the counters, along with the given number of discovered peers, in the Prometheus
+/// text exposition format.
+pub fn render(peer_count: usize) -> String {
+    let metrics = [
+        (
+            "redtooth_peers",
+            "gauge",
+            "Number of discovered peers.",
+            peer_count as u64,
+        ),
+        (
+            "redtooth_bytes_sent_total",
+            "counter",
+            "Bytes of the files sent successfully.",
+            BYTES_SENT.load(Ordering::Relaxed),
+        ),
+        (
+            "redtooth_bytes_received_total",
+            "counter",
+            "Bytes of the files received successfully.",
+            BYTES_RECEIVED.load(Ordering::Relaxed),
+        ),
+        (
+            "redtooth_transfers_failed_total",
+            "counter",
+            "Transfers in either direction that failed.",
+            TRANSFERS_FAILED.load(Ordering::Relaxed),
+        ),
+    ];
+
+    let mut output = String::new();
+    for (name, kind, help, value) in metrics {
+        // Writing into a `String` never fails.
+        let _ = write!(
+            output,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        );
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_every_metric_with_its_type() {
+        let output = render(3);
+
+        assert!(output.contains("# TYPE redtooth_peers gauge\nredtooth_peers 3\n"));
+        for name in [
+            "redtooth_bytes_sent_total",
+            "redtooth_bytes_received_total",
+            "redtooth_transfers_failed_total",
+        ] {
+            assert!(output.contains(&format!("# TYPE {name} counter\n{name} ")));
+        }
+        assert!(output.ends_with('\n'));
+    }
+}
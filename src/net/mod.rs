@@ -0,0 +1,37 @@
+//! Helpers shared by the networking components.
+
+pub mod multicast;
+
+use std::io::{self, Error, ErrorKind};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// Adds context to an error that occurred while binding a socket to the `port`.
+///
+/// The `hint` should tell the user how to get around the error.
+pub fn bind_error(err: io::Error, port: u16, hint: &str) -> io::Error {
+    let reason = match err.kind() {
+        ErrorKind::AddrInUse => format!(
+            "port {port} is already in use, most likely by another running instance of redtooth"
+        ),
+        ErrorKind::PermissionDenied => format!("permission denied to bind port {port}"),
+        _ => format!("failed to bind port {port}: {err}"),
+    };
+    Error::new(err.kind(), format!("{reason}; {hint}"))
+}
+
+/// Tries to connect to all the addresses at once and returns, for each of them, whether it
+/// accepted a connection within the `timeout`.
+pub fn probe_all(addrs: &[SocketAddr], timeout: Duration) -> Vec<bool> {
+    thread::scope(|scope| {
+        let probes = addrs
+            .iter()
+            .map(|addr| scope.spawn(move || TcpStream::connect_timeout(addr, timeout).is_ok()))
+            .collect::<Vec<_>>();
+        probes
+            .into_iter()
+            .map(|probe| probe.join().unwrap_or(false))
+            .collect()
+    })
+}
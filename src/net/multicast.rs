@@ -0,0 +1,178 @@
+//! Setup of the UDP sockets that exchange multicast datagrams, shared by all the discovery
+//! paths so that they configure them the same way.
+
+use std::io::{self, Error};
+use std::mem;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd};
+
+/// Binds a socket to the `port` on all the interfaces and joins the multicast `group` on each
+/// of the `interfaces`, or on the default one if none are given.
+///
+/// Multicast loopback is disabled, so that what is sent from the socket isn't received back,
+/// and what is sent crosses up to `ttl` routers.
+/// A failure to bind is described by [`bind_error`](super::bind_error) along with the `hint`.
+///
+/// The port can be shared by several instances on the same host, see [`bind_reusable`].
+pub fn bind_receiver(
+    group: Ipv4Addr,
+    port: u16,
+    interfaces: &[Ipv4Addr],
+    ttl: u32,
+    hint: &str,
+) -> io::Result<UdpSocket> {
+    let socket = bind_reusable(port).map_err(|e| super::bind_error(e, port, hint))?;
+    socket.set_multicast_loop_v4(false)?;
+    socket.set_multicast_ttl_v4(ttl)?;
+
+    let default_interface = [Ipv4Addr::UNSPECIFIED];
+    let interfaces = match interfaces.is_empty() {
+        true => &default_interface[..],
+        false => interfaces,
+    };
+    for interface in interfaces {
+        socket.join_multicast_v4(&group, interface).map_err(|e| {
+            let hint = "make sure the network interface supports multicast";
+            Error::new(
+                e.kind(),
+                format!("failed to join the multicast group {group} on {interface}: {e}; {hint}"),
+            )
+        })?;
+    }
+    Ok(socket)
+}
+
+/// Binds a socket to the `port` on all the interfaces with `SO_REUSEADDR`, and `SO_REUSEPORT`
+/// where available, set beforehand, so that other instances on the same host can bind it too.
+///
+/// **NOTE:** Every instance bound to the port receives all the datagrams sent to the multicast
+/// group from other hosts. Since loopback is disabled, instances on the same host don't receive
+/// each other's multicast datagrams, and a unicast datagram is delivered to only one of them.
+fn bind_reusable(port: u16) -> io::Result<UdpSocket> {
+    // Close-on-exec like the sockets of the standard library, so that the descriptor doesn't
+    // leak into the programs spawned by the app, e.g., the confirm program.
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    #[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd == -1 {
+        return Err(Error::last_os_error());
+    }
+    // Owned right away, so that the descriptor is closed if anything below fails.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    #[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    set_option(
+        &socket,
+        libc::SOL_SOCKET,
+        libc::SO_REUSEADDR,
+        1 as libc::c_int,
+    )?;
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+    set_option(
+        &socket,
+        libc::SOL_SOCKET,
+        libc::SO_REUSEPORT,
+        1 as libc::c_int,
+    )?;
+
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_port = port.to_be();
+    addr.sin_addr.s_addr = u32::from(Ipv4Addr::UNSPECIFIED).to_be();
+    let ret = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+
+    if ret == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// Binds a socket to the `port`, or to an ephemeral one if it is zero, to send multicast
+/// datagrams from.
+///
+/// Multicast loopback is disabled, so that the receiver of the current instance doesn't get
+/// them, and they cross up to `ttl` routers.
+pub fn announce_socket(port: u16, ttl: u32) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    socket.set_multicast_loop_v4(false)?;
+    socket.set_multicast_ttl_v4(ttl)?;
+    Ok(socket)
+}
+
+/// Sends the `pkt` to the `group` out of each of the `interfaces`, or out of the default one
+/// if none are given.
+pub fn send(
+    socket: &UdpSocket,
+    pkt: &[u8],
+    group: Ipv4Addr,
+    port: u16,
+    interfaces: &[Ipv4Addr],
+) -> io::Result<()> {
+    if interfaces.is_empty() {
+        socket.send_to(pkt, (group, port))?;
+        return Ok(());
+    }
+    for &interface in interfaces {
+        set_interface(socket, interface)?;
+        socket.send_to(pkt, (group, port))?;
+    }
+    Ok(())
+}
+
+/// Sets the interface out of which the multicast packets sent on the `socket` leave.
+fn set_interface(socket: &UdpSocket, interface: Ipv4Addr) -> io::Result<()> {
+    let addr = libc::in_addr {
+        s_addr: u32::from(interface).to_be(),
+    };
+    set_option(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, addr)
+}
+
+fn set_option<T>(
+    socket: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: T,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+
+    if ret == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_the_port_between_sockets() {
+        let first = bind_reusable(0).unwrap();
+        let port = first.local_addr().unwrap().port();
+        assert!(bind_reusable(port).is_ok());
+    }
+
+    #[test]
+    fn closes_the_socket_on_exec() {
+        let socket = bind_reusable(0).unwrap();
+        let flags = unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+    }
+}
@@ -0,0 +1,93 @@
+//! Strategies for generating the identifier of a peer.
+//!
+//! The identifier is generated once and then persisted, see [`get_my_id`](super::get_my_id),
+//! so a strategy only decides how a new identifier is derived. Peers are told apart solely by
+//! their identifiers; two peers that end up with the same one overwrite each other in the
+//! peer maps of everyone else.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::{PeerID, DEFAULT_PEER_PORT};
+use crate::interface;
+
+/// A way of generating a new peer identifier.
+pub trait IdStrategy: Send + Sync {
+    fn generate(&self) -> io::Result<PeerID>;
+}
+
+/// Returns the strategy with the given name: `time` for [`TimeBased`], `addr` for
+/// [`AddrBased`] or `random` for [`Random`].
+pub fn strategy_by_name(name: &str) -> Option<Arc<dyn IdStrategy>> {
+    match name {
+        "time" => Some(Arc::new(TimeBased)),
+        "addr" => Some(Arc::new(AddrBased)),
+        "random" => Some(Arc::new(Random)),
+        _ => None,
+    }
+}
+
+/// Hashes the current time.
+///
+/// Peers started at the same instant (e.g., by a script on many hosts) may get the same
+/// identifier, and since `DefaultHasher` isn't guaranteed to be stable across Rust releases,
+/// the same time may hash differently between builds.
+pub struct TimeBased;
+
+impl IdStrategy for TimeBased {
+    fn generate(&self) -> io::Result<PeerID> {
+        let mut hasher = DefaultHasher::new();
+        SystemTime::now().hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+}
+
+/// Hashes the MAC address of the device, or its address when the MAC address isn't available.
+///
+/// The same device always regenerates the same identifier, even after its persisted one is
+/// lost. Cloned virtual machines or containers sharing a MAC address, and devices falling back
+/// to an address that is reused on another network, collide.
+pub struct AddrBased;
+
+impl IdStrategy for AddrBased {
+    fn generate(&self) -> io::Result<PeerID> {
+        let mut hasher = DefaultHasher::new();
+        match interface::mac_address() {
+            Some(mac_addr) => mac_addr.hash(&mut hasher),
+            None => super::get_my_addr(DEFAULT_PEER_PORT).hash(&mut hasher),
+        }
+        Ok(hasher.finish())
+    }
+}
+
+/// Reads 64 random bits from the OS.
+///
+/// Collisions are practically impossible (around one in four billion even among 100 thousand
+/// peers), but a device that loses its persisted identifier gets an unrelated one.
+pub struct Random;
+
+impl IdStrategy for Random {
+    fn generate(&self) -> io::Result<PeerID> {
+        let mut bytes = [0; 8];
+        File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+        Ok(PeerID::from_ne_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_ids_with_each_strategy() {
+        assert_eq!(AddrBased.generate().unwrap(), AddrBased.generate().unwrap());
+        assert_ne!(Random.generate().unwrap(), Random.generate().unwrap());
+        assert!(TimeBased.generate().is_ok());
+        assert!(strategy_by_name("random").is_some());
+        assert!(strategy_by_name("mac").is_none());
+    }
+}
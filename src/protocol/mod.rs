@@ -1,25 +1,116 @@
+pub mod id;
 pub mod packet;
 
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::time::Instant;
+use std::path::Path;
+use std::{fmt, fs, io};
 
-use crate::interface;
+use self::id::{IdStrategy, TimeBased};
+use crate::{elogln, interface};
 
 const DEFAULT_PEER_IP: Ipv4Addr = Ipv4Addr::UNSPECIFIED;
-const DEFAULT_PEER_PORT: u16 = 25802;
+pub const DEFAULT_PEER_PORT: u16 = 25802;
+/// Name of the file, inside the data location, where the peer id is persisted.
+const ID_FILE_NAME: &str = "id";
+/// Name of the file, inside the data location, where the number of launches is persisted.
+const LAUNCHES_FILE_NAME: &str = "launches";
 
 pub type PeerID = u64;
 pub type PeerAddr = SocketAddr;
 
-pub fn get_my_id() -> PeerID {
-    let mut hasher = DefaultHasher::new();
-    Instant::now().hash(&mut hasher);
-    hasher.finish()
+/// Displays a peer address for people: only its IP if it has the default port, which every
+/// peer listens on unless configured otherwise, or the whole address if not.
+///
+/// Machine-readable output keeps the whole address.
+pub struct ShortAddr(pub PeerAddr);
+
+impl fmt::Display for ShortAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.port() {
+            DEFAULT_PEER_PORT => write!(f, "{}", self.0.ip()),
+            _ => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Returns the identifier of the current device.
+///
+/// The identifier is read back from the `data_location` if a previous launch persisted it.
+/// Otherwise, a new one is generated using the `strategy` and persisted, so the device keeps
+/// the same identifier across restarts. Changing the strategy thus only takes effect once the
+/// persisted identifier is removed.
+///
+/// **Migration note:** earlier versions hashed the launch time, so the identifier changed on
+/// every restart. The first launch with this version generates and persists a new stable
+/// identifier; peers that are still running keep the old one in their maps until they restart.
+pub fn get_my_id(data_location: &Path, strategy: &dyn IdStrategy) -> PeerID {
+    let id_path = data_location.join(ID_FILE_NAME);
+
+    if let Some(id) = fs::read_to_string(&id_path)
+        .ok()
+        .and_then(|id| id.trim().parse::<PeerID>().ok())
+    {
+        return id;
+    }
+
+    let id = strategy.generate().unwrap_or_else(|e| {
+        elogln!("Failed to generate the peer id: {e}; falling back to a time based one");
+        // Hashing the time never fails.
+        TimeBased.generate().unwrap()
+    });
+
+    if let Err(e) = persist_id(&id_path, id) {
+        elogln!(
+            "Failed to persist the peer id to `{}`: {e}",
+            id_path.display()
+        );
+    }
+    id
+}
+
+/// Returns the name of the current device, i.e., its hostname.
+pub fn get_my_name() -> String {
+    let mut name = [0u8; 256];
+    let ret = unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) };
+
+    if ret == -1 {
+        return String::from("unknown");
+    }
+    let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..name_len]).into_owned()
 }
 
-pub fn get_my_addr() -> PeerAddr {
+pub fn get_my_addr(port: u16) -> PeerAddr {
     let ip_addr = IpAddr::V4(interface::local_ipv4_address().unwrap_or(DEFAULT_PEER_IP));
-    PeerAddr::new(ip_addr, DEFAULT_PEER_PORT)
+    PeerAddr::new(ip_addr, port)
+}
+
+/// Counts the current launch and returns the number of launches so far, including this one.
+///
+/// Returns `None` if the count can't be persisted, since the next launch would repeat it.
+pub fn count_launch(data_location: &Path) -> Option<u64> {
+    let path = data_location.join(LAUNCHES_FILE_NAME);
+    let launches = fs::read_to_string(&path)
+        .ok()
+        .and_then(|launches| launches.trim().parse::<u64>().ok())
+        .unwrap_or(0)
+        + 1;
+
+    match fs::create_dir_all(data_location).and_then(|_| fs::write(&path, launches.to_string())) {
+        Ok(()) => Some(launches),
+        Err(e) => {
+            elogln!(
+                "Failed to persist the launch count to `{}`: {e}",
+                path.display()
+            );
+            None
+        }
+    }
+}
+
+fn persist_id(id_path: &Path, id: PeerID) -> io::Result<()> {
+    if let Some(parent) = id_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(id_path, id.to_string())
 }
@@ -1,6 +1,8 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fmt::Write;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
 use std::str::{self, Utf8Error};
 
 /// Represents a separator used to distinguish sections, such as headers and payload
@@ -20,20 +22,55 @@ const SECTIONS_SEPARATOR: &[u8; 2] = b"::";
 /// file_name=main.rs
 /// ```
 const HEADER_NAME_VALUE_SEPARATOR: char = '=';
+/// Alphabet used to encode the binary header values as base64 (RFC 4648, with padding).
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64_PADDING: u8 = b'=';
 
-/// Represents an error that can occur when extracting headers from the bytes.
+/// Represents an error that can occur when extracting headers from the bytes, i.e., when they
+/// aren't valid UTF-8.
 ///
 /// This error is returned from the [`Packet::from_bytes`].
-pub type InvalidHeaderSequence = Utf8Error;
+#[derive(Debug)]
+pub struct InvalidHeaderSequence(Utf8Error);
+
+impl fmt::Display for InvalidHeaderSequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "headers aren't valid UTF-8")
+    }
+}
+
+impl Error for InvalidHeaderSequence {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl From<Utf8Error> for InvalidHeaderSequence {
+    fn from(err: Utf8Error) -> InvalidHeaderSequence {
+        InvalidHeaderSequence(err)
+    }
+}
+
+/// Reports a packet that fails to be parsed as [`InvalidData`](io::ErrorKind::InvalidData).
+impl From<InvalidHeaderSequence> for io::Error {
+    fn from(err: InvalidHeaderSequence) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("received data isn't a valid packet; {err}"),
+        )
+    }
+}
 
 /// Represents a packet used for transferring any data along with the additional information.
 ///
 /// The packet is divided into two sections separated by [`SECTIONS_SEPARATOR`]:
 ///
 /// - **Headers** allow the sender and receiver to either pass additional information for the
-/// communication or to pass more information about the data to be transmitted.
+///   communication or to pass more information about the data to be transmitted.
 ///
-/// - **Payload** holds the actual data to be transmitted.
+/// - **Payload** holds the actual data to be transmitted. It is optional; a packet without
+///   any payload bytes (e.g., a request or an ack) carries only the headers.
 pub struct Packet<'p> {
     headers: HashMap<String, String>,
     payload: Option<Cow<'p, [u8]>>,
@@ -52,21 +89,21 @@ impl<'p> Packet<'p> {
     ///
     /// This function attempts to reconstruct a new [`Packet`] from the provided bytes
     /// with the same state as it was originally created using [`Packet::as_bytes`].
-    pub fn from_bytes(bytes: &[u8]) -> Result<Packet, InvalidHeaderSequence> {
-        let separator_len = SECTIONS_SEPARATOR.len();
-        let separator_idx = bytes
-            .windows(separator_len)
-            .position(|bytes| bytes == SECTIONS_SEPARATOR);
+    pub fn from_bytes(bytes: &[u8]) -> Result<Packet<'_>, InvalidHeaderSequence> {
+        let payload_offset = payload_offset(bytes);
 
         // If the sections separator is not present, then it is header only packet.
-        let headers = separator_idx
-            .map_or(str::from_utf8(bytes), |idx| str::from_utf8(&bytes[..idx]))?
+        let headers_len =
+            payload_offset.map_or(bytes.len(), |offset| offset - SECTIONS_SEPARATOR.len());
+        let headers = str::from_utf8(&bytes[..headers_len])?
             .lines()
             .filter_map(|header| header.split_once(HEADER_NAME_VALUE_SEPARATOR))
             .map(|(name, value)| (name.to_string(), value.to_string()))
             .collect::<HashMap<String, String>>();
-        let payload =
-            separator_idx.and_then(|idx| bytes.get(idx + separator_len..).map(Cow::Borrowed));
+        let payload = payload_offset
+            .map(|offset| &bytes[offset..])
+            .filter(|payload| !payload.is_empty())
+            .map(Cow::Borrowed);
 
         Ok(Packet { headers, payload })
     }
@@ -80,9 +117,19 @@ impl<'p> Packet<'p> {
         self.headers.insert(name.to_string(), value.to_string());
     }
 
+    /// Inserts a header with a binary value or updates its value if the header already exists.
+    ///
+    /// Header values are text, so the bytes are stored base64-encoded; use
+    /// [`Packet::get_header_bytes`] to get them back.
+    pub fn set_header_bytes<N: ToString>(&mut self, name: N, value: &[u8]) {
+        self.headers.insert(name.to_string(), encode_base64(value));
+    }
+
     /// Sets the payload to be transmitted.
+    ///
+    /// An empty payload is the same as no payload at all.
     pub fn set_payload(&mut self, payload: Vec<u8>) {
-        self.payload = Some(Cow::Owned(payload));
+        self.payload = (!payload.is_empty()).then_some(Cow::Owned(payload));
     }
 
     /// Returns a reference to the value corresponding to the header.
@@ -90,28 +137,238 @@ impl<'p> Packet<'p> {
         self.headers.get(name).map(|v| v.as_str())
     }
 
-    /// Returns the payload of the packet, if available.
+    /// Returns the binary value of a header set using [`Packet::set_header_bytes`].
+    ///
+    /// Returns `None` if the header is absent or its value isn't valid base64.
+    pub fn get_header_bytes(&self, name: &str) -> Option<Vec<u8>> {
+        decode_base64(self.get_header(name)?)
+    }
+
+    /// Returns the payload of the packet, or `None` if it has no payload bytes.
     pub fn get_payload(&self) -> Option<&[u8]> {
         self.payload.as_deref()
     }
 
+    /// Returns `true` if the packet has no payload, i.e., it carries only headers.
+    pub fn is_empty(&self) -> bool {
+        self.payload.is_none()
+    }
+
     /// Converts the packet into a bytes which can be sent over the network.
     ///
     /// These bytes on the receiver side can then be used to reconstruct a new [`Packet`]
     /// using [`Packet::from_bytes`] with the same state as at the time of sending.
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut headers = String::new();
+        let mut final_bytes = Vec::new();
+        // Writing into a `Vec` never fails.
+        self.write_to(&mut final_bytes).unwrap();
+        final_bytes
+    }
+
+    /// Writes the packet into the given writer in the same format as [`Packet::as_bytes`].
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self.get_payload() {
+            Some(payload) => {
+                self.write_headers_to(writer)?;
+                writer.write_all(payload)
+            }
+            None => self.write_headers(writer),
+        }
+    }
+
+    /// Writes the headers followed by the sections separator into the given writer.
+    ///
+    /// Whatever is written into the writer afterwards becomes the payload of the packet,
+    /// which allows streaming a payload that isn't held in memory.
+    pub fn write_headers_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        self.write_headers(writer)?;
+        writer.write_all(SECTIONS_SEPARATOR)
+    }
 
+    fn write_headers(&self, writer: &mut impl Write) -> io::Result<()> {
         for (name, value) in self.headers.iter() {
-            writeln!(headers, "{name}{HEADER_NAME_VALUE_SEPARATOR}{value}").unwrap();
+            writeln!(writer, "{name}{HEADER_NAME_VALUE_SEPARATOR}{value}")?;
         }
-        let mut final_bytes = Vec::new();
-        final_bytes.extend_from_slice(headers.as_bytes());
+        Ok(())
+    }
+}
+
+/// Returns the index at which the payload of the encoded packet starts, or `None` if the
+/// packet is header only.
+///
+/// Every header ends with a newline, so the sections separator is either at the very start or
+/// right after a newline; a header value containing it (e.g., an IPv6 address) isn't mistaken
+/// for it.
+pub fn payload_offset(bytes: &[u8]) -> Option<usize> {
+    if bytes.starts_with(SECTIONS_SEPARATOR) {
+        return Some(SECTIONS_SEPARATOR.len());
+    }
+    bytes
+        .windows(1 + SECTIONS_SEPARATOR.len())
+        .position(|window| window[0] == b'\n' && window[1..] == SECTIONS_SEPARATOR[..])
+        .map(|idx| idx + 1 + SECTIONS_SEPARATOR.len())
+}
 
-        if let Some(payload) = self.get_payload() {
-            final_bytes.extend_from_slice(SECTIONS_SEPARATOR);
-            final_bytes.extend_from_slice(payload);
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - i * 8)
+        });
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - i * 6)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push(BASE64_PADDING as char);
+            }
+        }
+    }
+    encoded
+}
+
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+
+    for (chunk_idx, chunk) in encoded.chunks(4).enumerate() {
+        let is_last_chunk = chunk_idx == encoded.len() / 4 - 1;
+        let padding_len = chunk
+            .iter()
+            .rev()
+            .take_while(|&&c| c == BASE64_PADDING)
+            .count();
+
+        if padding_len > 2 || (padding_len > 0 && !is_last_chunk) {
+            return None;
+        }
+        let mut group = 0u32;
+        for (i, &c) in chunk[..4 - padding_len].iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+            group |= value << (18 - i * 6);
+        }
+        decoded.extend_from_slice(&group.to_be_bytes()[1..4 - padding_len]);
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_only_packet_has_no_payload() {
+        let packet = Packet::from_bytes(b"kind=ack\nindex=1\n").unwrap();
+        assert_eq!(packet.get_header("index"), Some("1"));
+        assert_eq!(packet.get_payload(), None);
+        assert!(packet.is_empty());
+    }
+
+    #[test]
+    fn empty_payload_is_no_payload() {
+        let packet = Packet::from_bytes(b"kind=message\n::").unwrap();
+        assert_eq!(packet.get_header("kind"), Some("message"));
+        assert_eq!(packet.get_payload(), None);
+        assert!(packet.is_empty());
+
+        let mut packet = Packet::new();
+        packet.set_payload(Vec::new());
+        assert!(packet.is_empty());
+        packet.set_payload(b"::".to_vec());
+        let bytes = packet.as_bytes();
+        assert_eq!(
+            Packet::from_bytes(&bytes).unwrap().get_payload(),
+            Some(&b"::"[..])
+        );
+    }
+
+    #[test]
+    fn tells_the_separator_apart_from_header_values() {
+        let mut packet = Packet::new();
+        packet.set_header("addr", "[::1]:25802");
+        packet.set_header("note", "::");
+        packet.set_payload(b"::data".to_vec());
+
+        let bytes = packet.as_bytes();
+        let parsed = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.get_header("addr"), Some("[::1]:25802"));
+        assert_eq!(parsed.get_header("note"), Some("::"));
+        assert_eq!(parsed.get_payload(), Some(&b"::data"[..]));
+    }
+
+    #[test]
+    fn parses_degenerate_packets() {
+        for bytes in [
+            &b""[..],
+            b"::",
+            b":",
+            b"\n::",
+            b"a=b\n:",
+            b"=\n=",
+            b"\xff::",
+        ] {
+            let _ = Packet::from_bytes(bytes);
+        }
+        let packet = Packet::from_bytes(b"::").unwrap();
+        assert_eq!(packet.get_header("kind"), None);
+        assert!(packet.is_empty());
+        assert_eq!(
+            Packet::from_bytes(b"kind=ack\n::")
+                .unwrap()
+                .get_header("kind"),
+            Some("ack")
+        );
+    }
+
+    #[test]
+    fn reports_invalid_headers_as_invalid_data() {
+        let Err(err) = Packet::from_bytes(b"name=\xff\n::payload") else {
+            panic!("expected invalid headers");
+        };
+        assert!(err.source().is_some_and(|e| e.is::<Utf8Error>()));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn binary_header_round_trips() {
+        for value in [
+            &b""[..],
+            b"f",
+            b"fo",
+            b"foo",
+            b"foob",
+            &[0, 0xff, b'=', b'\n', 0x80],
+        ] {
+            let mut packet = Packet::new();
+            packet.set_header_bytes("nonce", value);
+
+            let bytes = packet.as_bytes();
+            let parsed = Packet::from_bytes(&bytes).unwrap();
+            assert_eq!(parsed.get_header_bytes("nonce").as_deref(), Some(value));
+        }
+    }
+
+    #[test]
+    fn binary_header_matches_standard_base64() {
+        let mut packet = Packet::new();
+        packet.set_header_bytes("hash", b"foobar");
+        assert_eq!(packet.get_header("hash"), Some("Zm9vYmFy"));
+
+        packet.set_header_bytes("hash", b"fooba");
+        assert_eq!(packet.get_header("hash"), Some("Zm9vYmE="));
+    }
+
+    #[test]
+    fn rejects_invalid_binary_header() {
+        let mut packet = Packet::new();
+        for value in ["Zm9", "Zm9v!A==", "Zg==Zg==", "Z==="] {
+            packet.set_header("hash", value);
+            assert_eq!(packet.get_header_bytes("hash"), None);
         }
-        final_bytes
     }
 }
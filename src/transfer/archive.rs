@@ -0,0 +1,322 @@
+//! A minimal reader and writer of ustar archives, so that a directory can be sent as a single
+//! file.
+//!
+//! Only directories and regular files are archived; symbolic links and other special files are
+//! skipped. Archives aren't compressed.
+
+use std::fs::{self, File};
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// MIME type of the contents of a file packet carrying an archive.
+pub const CONTENT_TYPE: &str = "application/x-tar";
+/// Size of a header and the unit the contents of each file are padded to.
+const BLOCK_LEN: usize = 512;
+/// Size of the largest file whose size fits in the header, i.e., 11 octal digits.
+const MAX_FILE_LEN: u64 = 0o77777777777;
+
+/// A directory to be archived, with the entries it had when it was walked.
+///
+/// The archive is never held in memory; it is produced as it is written, see
+/// [`Archive::write_to`].
+pub struct Archive {
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    /// Where the entry is on disk.
+    path: PathBuf,
+    /// Path of the entry in the archive, starting with the name of the archived directory.
+    name: String,
+    /// Size of the contents; always zero for a directory.
+    len: u64,
+    mode: u32,
+    mtime: u64,
+    is_dir: bool,
+}
+
+impl Archive {
+    /// Walks the directory at the `path` to list the entries to archive.
+    pub fn of_dir(path: &Path) -> io::Result<Archive> {
+        let name = path
+            .file_name()
+            .ok_or(Error::new(ErrorKind::InvalidInput, "directory has no name"))?
+            .to_string_lossy()
+            .into_owned();
+        let mut entries = Vec::new();
+        walk(path, name, &mut entries)?;
+        Ok(Archive { entries })
+    }
+
+    /// Returns the number of bytes [`Archive::write_to`] writes.
+    pub fn len(&self) -> u64 {
+        let entries_len = self
+            .entries
+            .iter()
+            .map(|entry| (BLOCK_LEN + padded_len(entry.len)) as u64 + entry.len)
+            .sum::<u64>();
+        // The archive ends with two empty blocks.
+        entries_len + 2 * BLOCK_LEN as u64
+    }
+
+    /// Writes the archive into the given writer, streaming the contents of each file from
+    /// disk.
+    ///
+    /// Fails if a file shrank since the directory was walked; a file that grew is cut to the
+    /// size it had.
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        for entry in &self.entries {
+            writer.write_all(&header(entry)?)?;
+            if entry.is_dir {
+                continue;
+            }
+
+            let file = File::open(&entry.path)?;
+            let copied_len = io::copy(&mut file.take(entry.len), writer)?;
+            if copied_len < entry.len {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("`{}` was truncated while being archived", entry.name),
+                ));
+            }
+            writer.write_all(&[0; BLOCK_LEN][..padded_len(entry.len)])?;
+        }
+        writer.write_all(&[0; 2 * BLOCK_LEN])
+    }
+}
+
+/// Adds the directory at the `path` and everything inside it, sorted by name, to the
+/// `entries`.
+fn walk(path: &Path, name: String, entries: &mut Vec<Entry>) -> io::Result<()> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |mtime| mtime.as_secs());
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o777
+    };
+    #[cfg(not(unix))]
+    let mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+
+    if metadata.is_file() {
+        if metadata.len() > MAX_FILE_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("`{name}` is too large to be archived"),
+            ));
+        }
+        entries.push(Entry {
+            path: path.to_path_buf(),
+            name,
+            len: metadata.len(),
+            mode,
+            mtime,
+            is_dir: false,
+        });
+        return Ok(());
+    }
+
+    entries.push(Entry {
+        path: path.to_path_buf(),
+        name: format!("{name}/"),
+        len: 0,
+        mode,
+        mtime,
+        is_dir: true,
+    });
+    let mut children = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let file_type = child.file_type()?;
+        if !file_type.is_dir() && !file_type.is_file() {
+            continue;
+        }
+        let child_name = format!("{name}/{}", child.file_name().to_string_lossy());
+        walk(&child.path(), child_name, entries)?;
+    }
+    Ok(())
+}
+
+/// Returns the number of zero bytes that pad contents of `len` bytes to a whole block.
+fn padded_len(len: u64) -> usize {
+    (BLOCK_LEN - (len % BLOCK_LEN as u64) as usize) % BLOCK_LEN
+}
+
+/// Encodes the ustar header of the entry.
+fn header(entry: &Entry) -> io::Result<[u8; BLOCK_LEN]> {
+    let mut header = [0; BLOCK_LEN];
+    let (prefix, name) = split_name(&entry.name).ok_or(Error::new(
+        ErrorKind::InvalidInput,
+        format!("`{}` has too long a path to be archived", entry.name),
+    ))?;
+
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], entry.mode as u64);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], entry.len);
+    write_octal(&mut header[136..148], entry.mtime);
+    header[156] = if entry.is_dir { b'5' } else { b'0' };
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is computed as if its own field were filled with spaces.
+    header[148..156].fill(b' ');
+    let checksum = header.iter().map(|&byte| byte as u64).sum();
+    write_octal(&mut header[148..155], checksum);
+    Ok(header)
+}
+
+/// Splits the path into the prefix and name fields of a header, which hold up to 155 and 100
+/// bytes respectively; returns `None` if it can't fit.
+fn split_name(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= 100 {
+        return Some(("", path));
+    }
+    // The split happens at a separator, which is left out of both fields.
+    path.trim_end_matches('/')
+        .match_indices('/')
+        .map(|(pos, _)| (&path[..pos], &path[pos + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100)
+}
+
+/// Writes the value as zero-padded octal digits followed by a NUL byte, filling the field.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+fn read_octal(field: &[u8]) -> Option<u64> {
+    let digits = str_field(field)?;
+    u64::from_str_radix(digits.trim(), 8).ok()
+}
+
+/// Returns the text of a NUL-terminated header field.
+fn str_field(field: &[u8]) -> Option<&str> {
+    let len = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    std::str::from_utf8(&field[..len]).ok()
+}
+
+/// Extracts the directories and regular files of the archive read from the `reader` into the
+/// `destination` and returns the number of files extracted.
+///
+/// The archive is extracted as it is read, so it is never held in memory as a whole. Entries
+/// whose paths are absolute or contain `..` are refused, so that an archive can't write
+/// outside the `destination`; an existing file is replaced.
+pub fn extract(mut reader: impl Read, destination: &Path) -> io::Result<usize> {
+    let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, reason.to_string());
+    let mut file_count = 0;
+    let mut header = [0; BLOCK_LEN];
+
+    loop {
+        // The archive ends with a zero block, or wherever its bytes do.
+        match reader.read_exact(&mut header) {
+            Ok(()) if header != [0; BLOCK_LEN] => {}
+            Ok(()) => break,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let prefix = str_field(&header[345..500]).ok_or(invalid("invalid path in archive"))?;
+        let name = str_field(&header[..100]).ok_or(invalid("invalid path in archive"))?;
+        let len = read_octal(&header[124..136]).ok_or(invalid("invalid size in archive"))?;
+
+        let path = match prefix {
+            "" => PathBuf::from(name),
+            prefix => Path::new(prefix).join(name),
+        };
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(invalid(&format!("refused to extract `{}`", path.display())));
+        }
+        let path = destination.join(path);
+
+        let mut contents = (&mut reader).take(len);
+        let contents_len = match header[156] {
+            b'5' => {
+                fs::create_dir_all(&path)?;
+                io::copy(&mut contents, &mut io::sink())?
+            }
+            b'0' | 0 => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let contents_len = io::copy(&mut contents, &mut File::create(&path)?)?;
+                file_count += 1;
+                contents_len
+            }
+            // Links and special files aren't extracted.
+            _ => io::copy(&mut contents, &mut io::sink())?,
+        };
+        if contents_len < len {
+            return Err(invalid("archive is truncated"));
+        }
+        let padding_len = padded_len(len) as u64;
+        io::copy(&mut (&mut reader).take(padding_len), &mut io::sink())?;
+    }
+    Ok(file_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn archives_and_extracts_a_directory() {
+        let root = env::temp_dir().join(format!("redtooth-archive-{}", process::id()));
+        let source = root.join("photos");
+        let long_name = "n".repeat(90);
+        fs::create_dir_all(source.join("2024").join(&long_name)).unwrap();
+        fs::write(source.join("a.txt"), b"first").unwrap();
+        fs::write(
+            source.join("2024").join(&long_name).join("b.bin"),
+            [7; 1000],
+        )
+        .unwrap();
+
+        let archive = Archive::of_dir(&source).unwrap();
+        let mut bytes = Vec::new();
+        archive.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len() as u64, archive.len());
+
+        let destination = root.join("extracted");
+        assert_eq!(extract(bytes.as_slice(), &destination).unwrap(), 2);
+        let extracted = destination.join("photos");
+        assert_eq!(fs::read(extracted.join("a.txt")).unwrap(), b"first");
+        assert_eq!(
+            fs::read(extracted.join("2024").join(&long_name).join("b.bin")).unwrap(),
+            [7; 1000]
+        );
+    }
+
+    #[test]
+    fn refuses_paths_outside_the_destination() {
+        let entry = Entry {
+            path: PathBuf::new(),
+            name: String::from("../escaped.txt"),
+            len: 0,
+            mode: 0o644,
+            mtime: 0,
+            is_dir: false,
+        };
+        let mut bytes = header(&entry).unwrap().to_vec();
+        bytes.extend([0; 2 * BLOCK_LEN]);
+
+        let destination = env::temp_dir().join(format!("redtooth-escape-{}", process::id()));
+        assert!(extract(bytes.as_slice(), &destination).is_err());
+        assert!(!destination.with_file_name("escaped.txt").exists());
+    }
+}
@@ -0,0 +1,109 @@
+//! Ways of confirming incoming files before they are received.
+//!
+//! A file is confirmed as soon as its headers arrive; the connection is stalled until the
+//! decision is made, so deciding should take less than the `transfer_timeout` of the sender.
+
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::elogln;
+use crate::protocol::{PeerAddr, PeerID};
+
+/// Interval between checks of whether a confirmation command has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What is known about a file waiting to be confirmed.
+pub struct IncomingFile<'a> {
+    /// Identifier the sender claims to have, if it sent one.
+    pub sender_id: Option<PeerID>,
+    pub sender_addr: Option<PeerAddr>,
+    pub file_name: &'a str,
+    /// Size of the file, if the sender announced it.
+    pub len: Option<u64>,
+}
+
+/// A way of deciding whether to receive an incoming file.
+pub trait Confirm: Send + Sync {
+    fn confirm(&self, file: &IncomingFile) -> bool;
+}
+
+/// Any function can decide, e.g., one asking an interactive frontend over a channel and
+/// waiting for the answer with a timeout.
+impl<F> Confirm for F
+where
+    F: Fn(&IncomingFile) -> bool + Send + Sync,
+{
+    fn confirm(&self, file: &IncomingFile) -> bool {
+        self(file)
+    }
+}
+
+/// Runs an external command and receives the file only if it exits successfully.
+///
+/// The command isn't run through a shell: the `template` is split at whitespace into the
+/// program and its arguments, and `{sender}`, `{addr}`, `{name}` and `{size}` are replaced by
+/// the identifier of the sender, its IP address, the name of the file and its size respectively
+/// (`unknown` if not known), e.g., `confirm-file {sender} {name} {size}`. A command still running once the
+/// `timeout` elapses is killed and the file is declined.
+///
+/// A file whose name starts with `-` is declined without running the command, since the
+/// program would take the name for an option.
+pub struct ConfirmCommand {
+    pub template: String,
+    pub timeout: Duration,
+}
+
+impl Confirm for ConfirmCommand {
+    fn confirm(&self, file: &IncomingFile) -> bool {
+        let unknown = || String::from("unknown");
+        let sender = file.sender_id.map_or_else(unknown, |id| id.to_string());
+        let addr = file
+            .sender_addr
+            .map_or_else(unknown, |addr| addr.ip().to_string());
+        let size = file.len.map_or_else(unknown, |len| len.to_string());
+        let mut args = self.template.split_whitespace().map(|arg| {
+            arg.replace("{sender}", &sender)
+                .replace("{addr}", &addr)
+                .replace("{name}", file.file_name)
+                .replace("{size}", &size)
+        });
+        let Some(program) = args.next() else {
+            return false;
+        };
+        if file.file_name.starts_with('-') && self.template.contains("{name}") {
+            elogln!(
+                "Declined `{}`; the confirmation command would take its name for an option",
+                file.file_name
+            );
+            return false;
+        }
+
+        let mut child = match Command::new(&program).args(args).spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                elogln!("Failed to run the confirmation command `{program}`: {e}");
+                return false;
+            }
+        };
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => return status.success(),
+                Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                Ok(None) => {
+                    elogln!("The confirmation command `{program}` timed out; declining the file");
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return false;
+                }
+                Err(e) => {
+                    elogln!("Failed to wait for the confirmation command `{program}`: {e}");
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return false;
+                }
+            }
+        }
+    }
+}
@@ -1,44 +1,1972 @@
+//! Sending and receiving files, and the other packets the peers exchange, over TCP.
+//!
+//! Every packet on a transfer connection is sent as a frame, i.e., prefixed by its length as
+//! a 4-byte big-endian integer, so that multiple packets can share a single connection. A file
+//! packet, i.e., one with the `content_length` header, is the exception: only its headers are
+//! sent as a frame, and its contents follow the frame as that many bytes. Files of any size are
+//! thus streamed from disk on one end to disk on the other, rather than held in memory.
+//!
+//! Contents are sent as they are, never compressed, so there's no `content_encoding` header; a
+//! receiver saves the bytes that follow the frame as the file.
+//!
+//! **NOTE:** The sender of a connection identifies itself with the `sender_id` and
+//! `sender_name` headers of its first packet, which the receiver checks against its allowed and
+//! denied peers. Nothing proves that a peer is who it claims to be, so any peer that can reach
+//! the transfer port can forge them.
+//!
+//! **NOTE:** Transfer connections are plain TCP: nothing is encrypted, and neither end proves
+//! who it is to the other. To send over a network that isn't trusted, tunnel the transfer port
+//! through something that is, e.g., a VPN or an SSH tunnel.
+
+pub mod archive;
+pub mod confirm;
 pub mod receiver;
 pub mod sender;
 
-use std::path::Path;
-use std::{fs, io, str};
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+#[cfg(target_os = "linux")]
+use std::ptr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, io, mem, str};
 
-use crate::protocol::packet::{InvalidHeaderSequence, Packet};
+use self::archive::Archive;
+use crate::config::Config;
+use crate::digest::{self, Sha256};
+use crate::events::{Progress, Transfer};
+use crate::history::Direction;
+use crate::protocol::packet::Packet;
+use crate::protocol::PeerAddr;
 
 /// A wrapper around [`Packet`] specialized for constructing a packet to send or receive files
 /// along with their associated metadata.
-pub struct FilePacket<'data>(Packet<'data>);
+pub struct FilePacket<'data> {
+    packet: Packet<'data>,
+    /// Where the contents streamed as the payload come from and their size, if the packet was
+    /// created from a path, e.g., using [`FilePacket::from_path`].
+    source: Option<(Source, u64)>,
+}
 
-impl<'data> FilePacket<'data> {
-    /// Creates a new file packet by reading a file from the given path.
-    pub fn from_path(path: &Path) -> io::Result<FilePacket<'data>> {
-        let file_name = path.file_name().unwrap_or(path.as_os_str());
-        let file_contents = fs::read(path)?;
+/// Contents that are streamed from disk as the payload of a file packet.
+enum Source {
+    /// The file at the path, from the given offset on.
+    File(PathBuf, u64),
+    /// A directory archived as it is written.
+    Archive(Archive),
+    /// Contents read ahead into memory, to be written to several peers, see
+    /// [`FilePacket::share_contents`].
+    Shared(Arc<[u8]>),
+}
 
-        let mut packet = Packet::new();
-        packet.set_header("file_name", file_name.to_string_lossy());
-        packet.set_payload(file_contents);
-        Ok(FilePacket(packet))
+/// A range of bytes of a file, from `start` up to, but excluding, `end`, or up to the end of
+/// the file if there's no `end`; written as `<start>-<end>` or `<start>-`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+impl ByteRange {
+    /// Returns the offset and length of the range within a file of `file_len` bytes; an `end`
+    /// past the end of the file is cut to it.
+    ///
+    /// Fails if the range starts past the end of the file.
+    fn within(self, file_len: u64) -> io::Result<(u64, u64)> {
+        if self.start > file_len {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("range starts past the end of the file ({file_len} bytes)"),
+            ));
+        }
+        let end = self.end.map_or(file_len, |end| end.min(file_len));
+        Ok((self.start, end - self.start))
+    }
+}
+
+impl str::FromStr for ByteRange {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<ByteRange, Self::Err> {
+        const INVALID: &str = "expected a byte range like `<start>-<end>` or `<start>-`";
+
+        let (start, end) = s.split_once('-').ok_or(INVALID)?;
+        let start = start.parse().map_err(|_| INVALID)?;
+        let end = match end {
+            "" => None,
+            end => Some(end.parse().map_err(|_| INVALID)?),
+        };
+        if end.is_some_and(|end| end < start) {
+            return Err("byte range ends before it starts");
+        }
+        Ok(ByteRange { start, end })
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.end {
+            Some(end) => write!(f, "{}-{end}", self.start),
+            None => write!(f, "{}-", self.start),
+        }
+    }
+}
+
+/// Represents a reason a received file packet is refused, see [`FilePacket::verify`].
+#[derive(Debug, PartialEq)]
+pub enum FileVerifyError {
+    MissingFileName,
+    /// The name is a path rather than a plain file name, e.g., `../notes.txt`, and could
+    /// write outside the save location.
+    UnsafeFileName(String),
+    MissingContentLength,
+    InvalidContentLength(String),
+    /// The contents don't have the length the packet announced.
+    LengthMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    /// The contents don't match the checksum the packet carries.
+    ChecksumMismatch,
+}
+
+impl fmt::Display for FileVerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::FileVerifyError::*;
+
+        match self {
+            MissingFileName => write!(f, "missing file name"),
+            UnsafeFileName(name) => write!(f, "refused the unsafe file name `{name}`"),
+            MissingContentLength => write!(f, "missing content length"),
+            InvalidContentLength(len) => write!(f, "invalid content length `{len}`"),
+            LengthMismatch { expected, actual } => {
+                write!(f, "expected {expected} bytes of contents but got {actual}")
+            }
+            ChecksumMismatch => write!(f, "contents don't match the checksum"),
+        }
     }
+}
+
+impl std::error::Error for FileVerifyError {}
 
-    /// Creates a new file packet by parsing the given bytes.
-    pub fn from_bytes(bytes: &[u8]) -> Result<FilePacket, InvalidHeaderSequence> {
-        Ok(FilePacket(Packet::from_bytes(bytes)?))
+/// Represents an error when the path a file packet is created from isn't a regular file, e.g.,
+/// a directory, see [`FilePacketBuilder::path`].
+///
+/// It is returned as the inner error of an [`ErrorKind::InvalidInput`] error.
+#[derive(Debug)]
+pub struct NotAFile(pub PathBuf);
+
+impl fmt::Display for NotAFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` is not a file", self.0.display())
+    }
+}
+
+impl std::error::Error for NotAFile {}
+
+/// Returns `true` if the `name` is a plain file name rather than a path, e.g., `notes.txt` but
+/// not `../notes.txt`, so that joining it to a directory stays inside of it.
+pub fn is_plain_file_name(name: &str) -> bool {
+    Path::new(name).file_name() == Some(name.as_ref()) && !name.contains('\0')
+}
+
+impl<'data> FilePacket<'data> {
+    /// Creates a new file packet for the file at the given path.
+    ///
+    /// The file contents aren't read until the packet is written using [`FilePacket::write_to`].
+    pub fn from_path(path: &Path) -> io::Result<FilePacket<'data>> {
+        FilePacketBuilder::new().path(path).build()
     }
 
     /// Returns the name of the file, if available; otherwise returns default (`undefined`).
     pub fn get_file_name(&self) -> &str {
-        self.0.get_header("file_name").unwrap_or("undefined")
+        self.packet.get_header("file_name").unwrap_or("undefined")
     }
 
     /// Returns the contents of the file, if available; otherwise returns empty.
+    ///
+    /// The contents of a packet created using [`FilePacket::from_path`] live on disk, and those
+    /// of a received packet follow it on the connection, see [`read_packet`]; so it is always
+    /// empty for such packets.
     pub fn get_contents(&self) -> &[u8] {
-        self.0.get_payload().unwrap_or_default()
+        self.packet.get_payload().unwrap_or_default()
     }
 
-    /// Converts the packet into a bytes which can be sent over the network.
-    pub fn as_owned_bytes(&self) -> Vec<u8> {
-        self.0.as_bytes()
+    /// Returns the size of the file contents.
+    pub fn content_len(&self) -> u64 {
+        match &self.source {
+            Some((_, file_len)) => *file_len,
+            None => self.get_contents().len() as u64,
+        }
+    }
+
+    /// Returns the MIME type of the contents, if the packet carries it.
+    pub fn get_content_type(&self) -> Option<&str> {
+        self.packet.get_header("content_type")
+    }
+
+    /// Returns the permission bits the file had on the sender, if the packet carries them.
+    pub fn get_unix_mode(&self) -> Option<u32> {
+        u32::from_str_radix(self.packet.get_header("unix_mode")?, 8).ok()
+    }
+
+    /// Returns the time the file was last modified on the sender, if the packet carries it.
+    pub fn get_mtime(&self) -> Option<SystemTime> {
+        let secs = self.packet.get_header("mtime")?.parse().ok()?;
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+    }
+
+    /// Returns the offset within the file the contents start at, if the packet carries only
+    /// a range of the file.
+    pub fn get_range_start(&self) -> Option<u64> {
+        self.packet.get_header("range_start")?.parse().ok()
+    }
+
+    /// Returns the SHA-256 digest of the file contents, if the packet carries one.
+    pub fn get_checksum(&self) -> Option<Vec<u8>> {
+        self.packet.get_header_bytes("checksum")
+    }
+
+    /// Sets the position of the file in the manifest it was announced with.
+    pub fn set_manifest_index(&mut self, index: usize) {
+        self.packet.set_header("index", index);
+    }
+
+    /// Returns the position of the file in the manifest it was announced with, if any.
+    pub fn get_manifest_index(&self) -> Option<usize> {
+        self.packet.get_header("index")?.parse().ok()
+    }
+
+    /// Checks that the headers of a received packet are safe to save the file by: the file name
+    /// must be present and a plain file name, and the content length, which every file packet
+    /// carries, must be valid.
+    ///
+    /// The contents, which follow the headers, are checked once received, see
+    /// [`FilePacket::verify_checksum`].
+    pub fn verify(&self) -> Result<(), FileVerifyError> {
+        let file_name = self
+            .packet
+            .get_header("file_name")
+            .ok_or(FileVerifyError::MissingFileName)?;
+        if !is_plain_file_name(file_name) {
+            return Err(FileVerifyError::UnsafeFileName(file_name.to_string()));
+        }
+
+        let len = self
+            .packet
+            .get_header("content_length")
+            .ok_or(FileVerifyError::MissingContentLength)?;
+        len.parse::<u64>()
+            .map_err(|_| FileVerifyError::InvalidContentLength(len.to_string()))?;
+        Ok(())
+    }
+
+    /// Checks that the received contents, whose SHA-256 digest is given, match the checksum,
+    /// if the packet carries one.
+    pub fn verify_checksum(&self, digest: &[u8]) -> Result<(), FileVerifyError> {
+        match self.get_checksum() {
+            Some(checksum) if checksum != digest => Err(FileVerifyError::ChecksumMismatch),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the number of bytes [`FilePacket::write_to`] writes.
+    pub fn encoded_len(&self) -> u64 {
+        let Some((_, file_len)) = &self.source else {
+            return self.packet.as_bytes().len() as u64;
+        };
+        let mut headers = Vec::new();
+        // Writing into a `Vec` never fails.
+        self.packet.write_headers_to(&mut headers).unwrap();
+        headers.len() as u64 + file_len
+    }
+
+    /// Writes the packet into the given writer so it can be sent over the network.
+    ///
+    /// For a packet created using [`FilePacket::from_path`], the file contents are streamed
+    /// from disk in chunks right after the headers instead of being loaded into memory.
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let Some((source, file_len)) = &self.source else {
+            return self.packet.write_to(writer);
+        };
+
+        match source {
+            Source::File(path, offset) => {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(*offset))?;
+                self.packet.write_headers_to(writer)?;
+                let copied_len = io::copy(&mut file.take(*file_len), writer)?;
+                ensure_fully_sent(copied_len, *file_len)
+            }
+            Source::Archive(archive) => {
+                self.packet.write_headers_to(writer)?;
+                archive.write_to(writer)
+            }
+            Source::Shared(contents) => {
+                self.packet.write_headers_to(writer)?;
+                writer.write_all(contents)
+            }
+        }
+    }
+
+    /// Reads the contents that would be streamed from disk into memory, so that writing the
+    /// packet to several peers reads the file, or archives the directory, only once.
+    pub fn share_contents(&mut self) -> io::Result<()> {
+        let Some((source, len)) = &mut self.source else {
+            return Ok(());
+        };
+        let mut contents = Vec::with_capacity(*len as usize);
+        match source {
+            Source::File(path, offset) => {
+                let mut file = File::open(&*path)?;
+                file.seek(SeekFrom::Start(*offset))?;
+                file.take(*len).read_to_end(&mut contents)?;
+            }
+            Source::Archive(archive) => archive.write_to(&mut contents)?,
+            Source::Shared(_) => return Ok(()),
+        }
+        ensure_fully_sent(contents.len() as u64, *len)?;
+        *source = Source::Shared(contents.into());
+        Ok(())
+    }
+
+    /// Writes the packet into the given buffered stream like [`FilePacket::write_to`], where
+    /// the stream reports the bytes written through it to its progress.
+    ///
+    /// On Linux, the file contents of a packet created using [`FilePacket::from_path`] are
+    /// handed from the file to the socket by the kernel using `sendfile(2)`, so they are never
+    /// copied through user space.
+    fn write_to_stream(
+        &self,
+        writer: &mut BufWriter<ProgressWriter<&mut TcpStream>>,
+    ) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if let Some((Source::File(path, offset), file_len)) = &self.source {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(*offset))?;
+            self.packet.write_headers_to(writer)?;
+            // Whatever is buffered has to reach the socket before the kernel sends the file.
+            writer.flush()?;
+
+            let stream = writer.get_mut();
+            let sent_len = sendfile(&file, stream.writer, *file_len, stream.progress);
+            stream.written += sent_len;
+            // Whatever `sendfile` couldn't send, e.g., because the file system doesn't support
+            // it, is copied the usual way from where it stopped.
+            let copied_len = io::copy(&mut (&mut file).take(file_len - sent_len), writer)?;
+            return ensure_fully_sent(sent_len + copied_len, *file_len);
+        }
+        self.write_to(writer)
+    }
+}
+
+fn ensure_fully_sent(sent_len: u64, file_len: u64) -> io::Result<()> {
+    if sent_len < file_len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "file was truncated while being sent",
+        ));
+    }
+    Ok(())
+}
+
+/// Sends up to `len` bytes from the current offset of the `file` into the `stream` and returns
+/// the number of bytes sent, which is less than `len` if `sendfile(2)` failed midway.
+///
+/// The offset of the `file` is advanced past the sent bytes, which are reported to the
+/// `progress` after each chunk.
+#[cfg(target_os = "linux")]
+fn sendfile(file: &File, stream: &TcpStream, len: u64, progress: &mut Progress) -> u64 {
+    // Small enough for the progress to be reported regularly, at the cost of a few more calls.
+    const MAX_CHUNK_LEN: u64 = 1024 * 1024;
+    let mut sent_len = 0;
+
+    while sent_len < len {
+        let chunk_len = (len - sent_len).min(MAX_CHUNK_LEN) as usize;
+        let ret = unsafe {
+            libc::sendfile(
+                stream.as_raw_fd(),
+                file.as_raw_fd(),
+                ptr::null_mut(),
+                chunk_len,
+            )
+        };
+
+        match ret {
+            -1 if Error::last_os_error().kind() == ErrorKind::Interrupted => continue,
+            // Either an error or the end of the file; both are handled by the fallback.
+            ret if ret <= 0 => break,
+            ret => sent_len += ret as u64,
+        }
+        progress.update(sent_len);
+    }
+    sent_len
+}
+
+/// A writer that reports the number of bytes of file contents written through it to a
+/// [`Progress`].
+struct ProgressWriter<'a, W> {
+    writer: W,
+    written: u64,
+    /// Number of bytes written ahead of the file contents, i.e., the length prefix of the frame
+    /// and the headers, which aren't reported.
+    overhead: u64,
+    progress: &'a mut Progress,
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written_len = self.writer.write(buf)?;
+        self.written += written_len as u64;
+        self.progress
+            .update(self.written.saturating_sub(self.overhead));
+        Ok(written_len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<'data> From<Packet<'data>> for FilePacket<'data> {
+    fn from(packet: Packet<'data>) -> FilePacket<'data> {
+        FilePacket {
+            packet,
+            source: None,
+        }
+    }
+}
+
+/// Where the contents of a file packet come from.
+enum Contents {
+    Path(PathBuf),
+    /// A directory, sent as an archive.
+    Archive(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+/// A builder of [`FilePacket`]s, so that the headers describing a file are named and encoded
+/// the same way wherever a packet is created.
+#[derive(Default)]
+pub struct FilePacketBuilder {
+    file_name: Option<String>,
+    contents: Option<Contents>,
+    content_type: Option<String>,
+    checksum: bool,
+    range: Option<ByteRange>,
+}
+
+impl FilePacketBuilder {
+    pub fn new() -> FilePacketBuilder {
+        FilePacketBuilder::default()
+    }
+
+    /// Sets the name of the file; defaults to the last component of the path, if any.
+    pub fn file_name(mut self, file_name: impl Into<String>) -> FilePacketBuilder {
+        self.file_name = Some(file_name.into());
+        self
+    }
+
+    /// Streams the contents from the file at the `path` when the packet is written.
+    ///
+    /// Building the packet fails with [`NotAFile`] if the `path` isn't a regular file.
+    pub fn path(mut self, path: impl Into<PathBuf>) -> FilePacketBuilder {
+        self.contents = Some(Contents::Path(path.into()));
+        self
+    }
+
+    /// Streams the directory at the `path` as an uncompressed tar archive when the packet is
+    /// written; the name defaults to the name of the directory with a `.tar` extension.
+    pub fn archive(mut self, path: impl Into<PathBuf>) -> FilePacketBuilder {
+        self.contents = Some(Contents::Archive(path.into()));
+        self
+    }
+
+    /// Sets the contents from memory.
+    pub fn contents(mut self, contents: Vec<u8>) -> FilePacketBuilder {
+        self.contents = Some(Contents::Bytes(contents));
+        self
+    }
+
+    /// Sets the MIME type of the contents.
+    pub fn content_type(mut self, content_type: impl Into<String>) -> FilePacketBuilder {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Sets whether to include the SHA-256 digest of the contents; computing it for a file on
+    /// disk means reading it in full.
+    pub fn checksum(mut self, checksum: bool) -> FilePacketBuilder {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Sends only the given range of the file set by [`FilePacketBuilder::path`]; the packet
+    /// tells where in the file the contents start.
+    pub fn range(mut self, range: ByteRange) -> FilePacketBuilder {
+        self.range = Some(range);
+        self
+    }
+
+    /// Builds the packet; fails if the contents weren't set, the path isn't of a file or the file
+    /// can't be read, or if the range starts past the end of the file.
+    pub fn build<'data>(self) -> io::Result<FilePacket<'data>> {
+        let contents = self.contents.ok_or(Error::new(
+            ErrorKind::InvalidInput,
+            "file packet needs its contents",
+        ))?;
+        let file_name = match (self.file_name, &contents) {
+            (Some(file_name), _) => file_name,
+            (None, Contents::Path(path)) => path
+                .file_name()
+                .unwrap_or(path.as_os_str())
+                .to_string_lossy()
+                .into_owned(),
+            (None, Contents::Archive(path)) => match path.file_name() {
+                Some(name) => format!("{}.tar", name.to_string_lossy()),
+                None => String::from("undefined.tar"),
+            },
+            (None, Contents::Bytes(_)) => String::from("undefined"),
+        };
+        let content_type = match &contents {
+            Contents::Archive(_) => self.content_type.or(Some(archive::CONTENT_TYPE.into())),
+            _ => self.content_type,
+        };
+
+        let mut packet = Packet::new();
+        packet.set_header("file_name", file_name);
+        if let Some(content_type) = content_type {
+            packet.set_header("content_type", content_type);
+        }
+
+        match contents {
+            Contents::Path(path) => {
+                // Only the size is needed up front; the contents are streamed when written.
+                let metadata = fs::metadata(&path)?;
+                if !metadata.is_file() {
+                    return Err(Error::new(ErrorKind::InvalidInput, NotAFile(path)));
+                }
+                let (offset, file_len) = match self.range {
+                    Some(range) => {
+                        let (offset, len) = range.within(metadata.len())?;
+                        packet.set_header("range_start", offset);
+                        (offset, len)
+                    }
+                    None => (0, metadata.len()),
+                };
+                packet.set_header("content_length", file_len);
+                // Sent along so that the receiver can preserve them if it chooses to.
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mode = metadata.permissions().mode() & 0o7777;
+                    packet.set_header("unix_mode", format!("{mode:o}"));
+                }
+                if let Ok(mtime) = metadata.modified() {
+                    if let Ok(mtime) = mtime.duration_since(UNIX_EPOCH) {
+                        packet.set_header("mtime", mtime.as_secs());
+                    }
+                }
+                if self.checksum && self.range.is_some() {
+                    let mut file = File::open(&path)?;
+                    file.seek(SeekFrom::Start(offset))?;
+                    let mut hasher = Sha256::new();
+                    io::copy(&mut file.take(file_len), &mut hasher)?;
+                    packet.set_header_bytes("checksum", &hasher.finish());
+                } else if self.checksum {
+                    packet.set_header_bytes("checksum", &digest::sha256_file(&path)?);
+                }
+                Ok(FilePacket {
+                    packet,
+                    source: Some((Source::File(path, offset), file_len)),
+                })
+            }
+            Contents::Archive(path) => {
+                let archive = Archive::of_dir(&path)?;
+                let archive_len = archive.len();
+                packet.set_header("content_length", archive_len);
+                if self.checksum {
+                    let mut hasher = Sha256::new();
+                    archive.write_to(&mut hasher)?;
+                    packet.set_header_bytes("checksum", &hasher.finish());
+                }
+                Ok(FilePacket {
+                    packet,
+                    source: Some((Source::Archive(archive), archive_len)),
+                })
+            }
+            Contents::Bytes(bytes) => {
+                packet.set_header("content_length", bytes.len());
+                if self.checksum {
+                    let mut hasher = Sha256::new();
+                    hasher.update(&bytes);
+                    packet.set_header_bytes("checksum", &hasher.finish());
+                }
+                packet.set_payload(bytes);
+                Ok(FilePacket::from(packet))
+            }
+        }
+    }
+}
+
+/// Reads the packet sent as a reply to an earlier packet into the `buf`.
+fn read_reply<'buf>(reader: &mut impl Read, buf: &'buf mut Vec<u8>) -> io::Result<Packet<'buf>> {
+    read_packet(reader, buf)?.ok_or(Error::new(
+        ErrorKind::UnexpectedEof,
+        "connection closed before the peer replied",
+    ))
+}
+
+/// Writes the length prefix of a frame holding `len` bytes.
+fn write_frame_len(writer: &mut impl Write, len: u64) -> io::Result<()> {
+    let len = u32::try_from(len)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "packet is larger than 4 GiB"))?;
+    writer.write_all(&len.to_be_bytes())
+}
+
+/// Writes the packet as a single frame.
+///
+/// A file packet is written using [`write_file_packet`] instead, so that its contents follow
+/// the frame.
+pub fn write_packet(writer: &mut impl Write, packet: &Packet) -> io::Result<()> {
+    let bytes = packet.as_bytes();
+    write_frame_len(writer, bytes.len() as u64)?;
+    writer.write_all(&bytes)
+}
+
+/// Writes the headers of the file packet as a frame followed by its contents, publishing the
+/// progress of the transfer.
+///
+/// The small writes of the length prefix, the headers and the entries of an archive are
+/// gathered in a buffer of `buffer_size` bytes rather than each costing a syscall, and so
+/// a segment of its own.
+fn write_file_packet(
+    stream: &mut TcpStream,
+    packet: &FilePacket,
+    buffer_size: usize,
+) -> io::Result<()> {
+    let mut progress = start_sending(stream.peer_addr().ok(), packet);
+    write_file_packet_reporting(stream, packet, buffer_size, &mut progress)
+}
+
+/// Publishes that the file packet started to be sent to the peer at the `peer_addr`.
+fn start_sending(peer_addr: Option<PeerAddr>, packet: &FilePacket) -> Progress {
+    Progress::start(Transfer {
+        direction: Direction::Sent,
+        peer_addr,
+        file_name: packet.get_file_name().to_string(),
+        total: packet.content_len(),
+    })
+}
+
+/// Writes the file packet like [`write_file_packet`], reporting to the `progress` of a transfer
+/// that was already started, e.g., by a previous attempt to send the packet.
+fn write_file_packet_reporting(
+    stream: &mut TcpStream,
+    packet: &FilePacket,
+    buffer_size: usize,
+    progress: &mut Progress,
+) -> io::Result<()> {
+    let headers_len = packet.encoded_len() - packet.content_len();
+    let mut writer = BufWriter::with_capacity(
+        buffer_size,
+        ProgressWriter {
+            writer: stream,
+            written: 0,
+            overhead: mem::size_of::<u32>() as u64 + headers_len,
+            progress,
+        },
+    );
+    write_frame_len(&mut writer, headers_len)?;
+    packet.write_to_stream(&mut writer)?;
+    writer.flush()
+}
+
+/// Reads a single packet, sent as a frame by [`write_packet`], into the `buf`.
+///
+/// Returns `None` if the connection was closed before a new packet started, which is how
+/// a conversation of any number of packets ends.
+///
+/// Only the headers of a file packet are read; its contents, [`contents_len`] bytes of them,
+/// are left to be read from the `reader`.
+pub fn read_packet<'buf>(
+    reader: &mut impl Read,
+    buf: &'buf mut Vec<u8>,
+) -> io::Result<Option<Packet<'buf>>> {
+    if !read_frame(reader, buf)? {
+        return Ok(None);
+    }
+    Ok(Some(Packet::from_bytes(buf)?))
+}
+
+/// Returns the number of bytes of contents that follow the frame of the `packet`: the content
+/// length of a file packet, or 0 for any other packet.
+///
+/// Fails with [`ErrorKind::InvalidData`] if the content length is invalid, since the packets
+/// that follow can't be told apart from the contents then.
+fn contents_len(packet: &Packet) -> io::Result<u64> {
+    let Some(len) = packet.get_header("content_length") else {
+        return Ok(0);
+    };
+    len.parse().map_err(|_| {
+        let err = FileVerifyError::InvalidContentLength(len.to_string());
+        Error::new(ErrorKind::InvalidData, err.to_string())
+    })
+}
+
+/// Reads the bytes of a single frame into the `frame`, replacing its contents.
+///
+/// Returns `false` if the connection was closed before a new frame started.
+fn read_frame(reader: &mut impl Read, frame: &mut Vec<u8>) -> io::Result<bool> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as u64;
+
+    // Let the buffer grow with the received bytes rather than trusting the length up front.
+    frame.clear();
+    reader.take(len).read_to_end(frame)?;
+
+    if (frame.len() as u64) < len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "connection closed in the middle of a packet",
+        ));
+    }
+    Ok(true)
+}
+
+/// Opens a new transfer connection to the `addr` configured as per the `config`.
+fn connect(addr: PeerAddr, config: &Config) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    configure_stream(&stream, config)?;
+    Ok(stream)
+}
+
+/// Applies the transfer socket options from the `config` to the given stream.
+fn configure_stream(stream: &TcpStream, config: &Config) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(config.transfer_timeout)?;
+    stream.set_write_timeout(config.transfer_timeout)?;
+    set_socket_option(
+        stream,
+        libc::SO_KEEPALIVE,
+        config.transfer_keepalive as libc::c_int,
+    )?;
+
+    if let Some(linger) = config.transfer_linger {
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: linger.as_secs() as libc::c_int,
+        };
+        set_socket_option(stream, libc::SO_LINGER, linger)?;
+    }
+    Ok(())
+}
+
+fn set_socket_option<T>(stream: &TcpStream, name: libc::c_int, value: T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            name,
+            &value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+
+    if ret == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Formats the rate at which `bytes` were transferred within `elapsed`, e.g., `1.5 MiB/s`.
+fn throughput(bytes: u64, elapsed: Duration) -> String {
+    const UNITS: [&str; 4] = ["B/s", "KiB/s", "MiB/s", "GiB/s"];
+
+    // Transfers that complete in no measurable time are treated as taking a microsecond.
+    let mut rate = bytes as f64 / elapsed.as_secs_f64().max(1e-6);
+    let mut unit = 0;
+    while rate >= 1024.0 && unit < UNITS.len() - 1 {
+        rate /= 1024.0;
+        unit += 1;
+    }
+    format!("{rate:.1} {}", UNITS[unit])
+}
+
+/// Reports a stalled transfer as [`ErrorKind::TimedOut`].
+///
+/// Depending on the platform, an elapsed socket timeout is reported either as `WouldBlock`
+/// or as `TimedOut`; this unifies both so callers can tell a stall apart from other errors.
+fn map_timeout(err: Error, timeout: Option<Duration>) -> Error {
+    match (err.kind(), timeout) {
+        (ErrorKind::WouldBlock | ErrorKind::TimedOut, Some(timeout)) => Error::new(
+            ErrorKind::TimedOut,
+            format!("transfer stalled for more than {}s", timeout.as_secs()),
+        ),
+        _ => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Shutdown, TcpListener};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+    use std::{env, process, thread};
+
+    use super::*;
+    use crate::diagnostics::LastError;
+    use crate::events::{self, Event};
+    use crate::history;
+    use crate::protocol::{self, PeerID};
+
+    /// The ID the tests identify themselves with.
+    const TEST_ID: PeerID = 42;
+
+    fn test_config(name: &str) -> Config {
+        let root = env::temp_dir().join(format!("redtooth-{name}-{}", process::id()));
+        fs::create_dir_all(root.join("files")).unwrap();
+        Config {
+            save_location: root.join("files"),
+            shared_location: root.join("shared"),
+            data_location: root.join("data"),
+            ..Config::default()
+        }
+    }
+
+    fn spawn_receiver(config: &Config) -> PeerAddr {
+        let listener =
+            receiver::bind("127.0.0.1:0".parse().unwrap(), config.transfer_backlog).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = config.clone();
+        thread::spawn(move || {
+            receiver::receive_files(
+                listener,
+                config,
+                TEST_ID,
+                Arc::default(),
+                LastError::default(),
+            )
+        });
+        addr
+    }
+
+    #[test]
+    fn sends_files_with_manifest_and_acks() {
+        let config = test_config("manifest");
+        let addr = spawn_receiver(&config);
+
+        let source = config.data_location.join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a.txt"), b"first").unwrap();
+        fs::write(source.join("b.txt"), b"second").unwrap();
+        let paths = [
+            source.join("a.txt"),
+            source.join("missing.txt"),
+            source.join("b.txt"),
+        ];
+
+        let results = sender::send_files_to(addr, &paths, TEST_ID, &config);
+        let outcomes = results
+            .iter()
+            .map(|(path, result)| (path.clone(), result.is_ok()))
+            .collect::<Vec<(PathBuf, bool)>>();
+        assert_eq!(
+            outcomes,
+            [
+                (paths[0].clone(), true),
+                (paths[1].clone(), false),
+                (paths[2].clone(), true)
+            ]
+        );
+        assert_eq!(
+            fs::read(config.save_location.join("a.txt")).unwrap(),
+            b"first"
+        );
+        assert_eq!(
+            fs::read(config.save_location.join("b.txt")).unwrap(),
+            b"second"
+        );
+    }
+
+    #[test]
+    fn sends_multiple_files_to_all_peers() {
+        let config = test_config("multiple_to_all");
+        let other = test_config("multiple_to_all_other");
+        let source = config.data_location.join("source");
+        fs::create_dir_all(&source).unwrap();
+        let paths = [source.join("a.txt"), source.join("my notes.txt")];
+        fs::write(&paths[0], b"first").unwrap();
+        fs::write(&paths[1], b"second").unwrap();
+
+        let addrs = [spawn_receiver(&config), spawn_receiver(&other)];
+        let mut results = sender::send_files_to_all(&addrs, &paths, TEST_ID, &config);
+        results.sort_by_key(|(addr, _)| addrs.iter().position(|a| a == addr));
+        assert_eq!(results.len(), 2);
+        for ((addr, results), receiver) in results.iter().zip([&config, &other]) {
+            assert!(addrs.contains(addr));
+            assert!(results.iter().all(|(_, result)| result.is_ok()));
+            assert_eq!(
+                fs::read(receiver.save_location.join("my notes.txt")).unwrap(),
+                b"second"
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_files_from_denied_peers() {
+        let config = test_config("denied");
+        let source = config.data_location.join("source");
+        fs::create_dir_all(&source).unwrap();
+        let paths = [source.join("a.txt")];
+        fs::write(&paths[0], b"first").unwrap();
+
+        let denied = Config {
+            denied_peers: vec![TEST_ID.to_string()],
+            ..config.clone()
+        };
+        let results = sender::send_files_to(spawn_receiver(&denied), &paths, TEST_ID, &config);
+        assert!(results[0].1.is_err());
+        assert!(!config.save_location.join("a.txt").exists());
+
+        // Nor can a denied peer list or pull the shared files.
+        fs::create_dir_all(&denied.shared_location).unwrap();
+        fs::write(denied.shared_location.join("shared.txt"), b"shared").unwrap();
+        let addr = spawn_receiver(&denied);
+        let err = sender::list_remote(addr, TEST_ID, &config).unwrap_err();
+        assert!(err.to_string().contains("isn't allowed"));
+        assert!(sender::pull_from(addr, "shared.txt", None, TEST_ID, &config).is_err());
+        assert!(!config.save_location.join("shared.txt").exists());
+
+        let allowed = Config {
+            allowed_peers: vec![TEST_ID.to_string()],
+            ..config.clone()
+        };
+        let results = sender::send_files_to(spawn_receiver(&allowed), &paths, TEST_ID, &config);
+        assert!(results[0].1.is_ok());
+        assert!(!allowed.allows_peer(None, Some("stranger")));
+    }
+
+    #[test]
+    fn receives_only_confirmed_files() {
+        use self::confirm::{Confirm, ConfirmCommand, IncomingFile};
+
+        let config = Config {
+            confirm_incoming: Some(Arc::new(ConfirmCommand {
+                template: String::from("test {size} -lt 10"),
+                timeout: Duration::from_secs(5),
+            })),
+            ..test_config("confirm")
+        };
+        let addr = spawn_receiver(&config);
+        let source = config.data_location.join("source");
+        fs::create_dir_all(&source).unwrap();
+        let paths = [
+            source.join("small.txt"),
+            source.join("large.txt"),
+            source.join("tiny.txt"),
+        ];
+        fs::write(&paths[0], b"small").unwrap();
+        fs::write(&paths[1], b"large enough").unwrap();
+        fs::write(&paths[2], b"tiny").unwrap();
+
+        // Declining a file of a batch doesn't keep the files after it from being received.
+        let results = sender::send_files_to(addr, &paths, TEST_ID, &config);
+        assert!(results[0].1.is_ok());
+        assert!(
+            matches!(&results[1].1, Err(sender::SendError::Rejected(reason)) if reason.contains("declined"))
+        );
+        assert!(results[2].1.is_ok());
+        assert!(config.save_location.join("small.txt").exists());
+        assert!(!config.save_location.join("large.txt").exists());
+        assert!(config.save_location.join("tiny.txt").exists());
+
+        // A file sent on its own is declined with the reason.
+        let err = sender::send_file_to(
+            addr,
+            &paths[1],
+            TEST_ID,
+            &Config {
+                confirm_received_length: true,
+                ..config.clone()
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("declined"));
+        assert!(!config.save_location.join("large.txt").exists());
+
+        let slow = ConfirmCommand {
+            template: String::from("sleep 5"),
+            timeout: Duration::from_millis(100),
+        };
+        let file = IncomingFile {
+            sender_id: None,
+            sender_addr: None,
+            file_name: "notes.txt",
+            len: None,
+        };
+        assert!(!slow.confirm(&file));
+
+        let accept_all = ConfirmCommand {
+            template: String::from("true {name}"),
+            timeout: Duration::from_secs(5),
+        };
+        assert!(accept_all.confirm(&file));
+        let option = IncomingFile {
+            file_name: "-rf",
+            ..file
+        };
+        assert!(!accept_all.confirm(&option));
+    }
+
+    #[test]
+    fn lists_shared_files() {
+        let config = test_config("list");
+        let addr = spawn_receiver(&config);
+        assert_eq!(sender::list_remote(addr, TEST_ID, &config).unwrap(), []);
+
+        fs::create_dir_all(config.shared_location.join("nested")).unwrap();
+        fs::write(config.shared_location.join("b.txt"), b"second").unwrap();
+        fs::write(config.shared_location.join("a b.txt"), b"first").unwrap();
+        assert_eq!(
+            sender::list_remote(addr, TEST_ID, &config).unwrap(),
+            [(String::from("a b.txt"), 5), (String::from("b.txt"), 6)]
+        );
+    }
+
+    #[test]
+    fn pulls_shared_files() {
+        let config = test_config("pull");
+        let addr = spawn_receiver(&config);
+        fs::create_dir_all(&config.shared_location).unwrap();
+        fs::write(config.shared_location.join("shared.txt"), b"shared").unwrap();
+
+        sender::pull_from(addr, "shared.txt", None, TEST_ID, &config).unwrap();
+        assert_eq!(
+            fs::read(config.save_location.join("shared.txt")).unwrap(),
+            b"shared"
+        );
+
+        // Saved apart like the files sent by the peer it was pulled from.
+        let by_peer = Config {
+            save_subdir_by_peer: true,
+            ..config.clone()
+        };
+        sender::pull_from(addr, "shared.txt", None, TEST_ID, &by_peer).unwrap();
+        let mut headers = Packet::new();
+        headers.set_header("sender_name", protocol::get_my_name());
+        let peer_dir = receiver::save_dir(&headers, &by_peer);
+        assert_ne!(peer_dir, by_peer.save_location.join("unknown"));
+        assert_eq!(fs::read(peer_dir.join("shared.txt")).unwrap(), b"shared");
+
+        let err = sender::pull_from(addr, "missing.txt", None, TEST_ID, &config).unwrap_err();
+        assert!(err.to_string().contains("no such shared file"));
+        let err =
+            sender::pull_from(addr, "../files/shared.txt", None, TEST_ID, &config).unwrap_err();
+        assert!(err.to_string().contains("invalid file name"));
+    }
+
+    #[test]
+    fn sends_texts_as_files() {
+        let config = test_config("text");
+        let addr = spawn_receiver(&config);
+
+        sender::send_text_to_all(&[addr], "hello world", TEST_ID, &config).unwrap();
+        sender::send_text_to_all(&[addr], "hello again", TEST_ID, &config).unwrap();
+        // Sends only return once the file is read in full, not once it is saved.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let saved = loop {
+            let saved = fs::read_dir(&config.save_location)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with("message-"))
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>();
+            match saved.len() {
+                2 => break saved,
+                _ if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                _ => panic!("the texts weren't saved apart"),
+            }
+        };
+        let mut texts = saved
+            .iter()
+            .inspect(|path| assert_eq!(path.extension().unwrap(), "txt"))
+            .map(|path| fs::read(path).unwrap())
+            .collect::<Vec<_>>();
+        texts.sort();
+        assert_eq!(texts, [b"hello again", b"hello world"]);
+    }
+
+    #[test]
+    fn refuses_to_send_what_isnt_a_file() {
+        let config = test_config("not_a_file");
+        let addr = spawn_receiver(&config);
+        let dir = config.data_location.join("dir");
+        fs::create_dir_all(&dir).unwrap();
+
+        let err = sender::send_file_to(addr, &dir, TEST_ID, &config).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let not_a_file = err.get_ref().and_then(|e| e.downcast_ref::<NotAFile>());
+        assert_eq!(not_a_file.map(|e| &e.0), Some(&dir));
+        assert_eq!(
+            err.to_string(),
+            format!("`{}` is not a file", dir.display())
+        );
+        let results = sender::send_files_to(addr, &[dir], TEST_ID, &config);
+        assert!(matches!(
+            &results[0].1,
+            Err(sender::SendError::Io(e)) if e.get_ref().is_some_and(|e| e.is::<NotAFile>())
+        ));
+    }
+
+    #[test]
+    fn sends_files_on_the_buffer_boundary() {
+        let buffer_size = 4096;
+        let config = Config {
+            transfer_buffer_size: buffer_size,
+            confirm_received_length: true,
+            ..test_config("buffer_boundary")
+        };
+        let addr = spawn_receiver(&config);
+        let source = config.data_location.join("source");
+        fs::create_dir_all(&source).unwrap();
+
+        for len in [
+            buffer_size - 1,
+            buffer_size,
+            buffer_size + 1,
+            2 * buffer_size,
+        ] {
+            let path = source.join(format!("{len}.bin"));
+            let contents = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            fs::write(&path, &contents).unwrap();
+
+            sender::send_file_to(addr, &path, TEST_ID, &config).unwrap();
+            let saved = fs::read(config.save_location.join(format!("{len}.bin"))).unwrap();
+            assert_eq!(saved, contents);
+        }
+
+        // The whole packet, i.e., the frame of the headers along with the contents that follow it,
+        // fills the buffer exactly; the headers grow along with the contents, so their length is
+        // narrowed down.
+        let frame = |len: usize| {
+            FilePacketBuilder::new()
+                .file_name("frame.bin")
+                .contents(vec![7; len])
+                .build()
+                .unwrap()
+        };
+        let mut len = buffer_size;
+        let packet = loop {
+            let packet = frame(len);
+            match packet.encoded_len() as usize + mem::size_of::<u32>() {
+                frame_len if frame_len == buffer_size => break packet,
+                frame_len => len = len + buffer_size - frame_len,
+            }
+        };
+        let contents = vec![7; len];
+        let mut stream = connect(addr, &config).unwrap();
+        write_file_packet(&mut stream, &packet, buffer_size).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        // The peer closes the connection once it has read the end of the conversation.
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(
+            fs::read(config.save_location.join("frame.bin")).unwrap(),
+            contents
+        );
+    }
+
+    #[test]
+    fn tells_whether_a_file_would_be_overwritten() {
+        let config = test_config("exists");
+        let addr = spawn_receiver(&config);
+        fs::create_dir_all(&config.save_location).unwrap();
+        fs::write(config.save_location.join("notes.txt"), b"notes").unwrap();
+
+        assert!(sender::peer_has_file_named(addr, "notes.txt", TEST_ID, &config).unwrap());
+        assert!(!sender::peer_has_file_named(addr, "other.txt", TEST_ID, &config).unwrap());
+        assert!(
+            !sender::peer_has_file_named(addr, "../files/notes.txt", TEST_ID, &config).unwrap()
+        );
+
+        // Renamed files never replace existing ones.
+        let renaming = Config {
+            rename_template: Some(String::from("{stem} ({n}){ext}")),
+            ..config.clone()
+        };
+        let addr = spawn_receiver(&renaming);
+        assert!(!sender::peer_has_file_named(addr, "notes.txt", TEST_ID, &config).unwrap());
+    }
+
+    #[test]
+    fn resumes_pulls_from_a_range() {
+        let config = test_config("pull-range");
+        let addr = spawn_receiver(&config);
+        fs::create_dir_all(&config.shared_location).unwrap();
+        fs::create_dir_all(&config.save_location).unwrap();
+        fs::write(config.shared_location.join("large.bin"), b"0123456789").unwrap();
+        // The first pull was cut off after 4 bytes.
+        let saved = config.save_location.join("large.bin");
+        fs::write(&saved, b"0123").unwrap();
+
+        let range = |s: &str| s.parse::<ByteRange>().unwrap();
+        sender::pull_from(addr, "large.bin", Some(range("4-")), TEST_ID, &config).unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), b"0123456789");
+        // Only the range is written, the rest of the file is left as is.
+        fs::write(config.shared_location.join("large.bin"), b"abcdefghij").unwrap();
+        sender::pull_from(addr, "large.bin", Some(range("2-5")), TEST_ID, &config).unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), b"01cde56789");
+
+        let err =
+            sender::pull_from(addr, "large.bin", Some(range("11-")), TEST_ID, &config).unwrap_err();
+        assert!(err.to_string().contains("past the end of the file"));
+        assert!("5-2".parse::<ByteRange>().is_err());
+        assert_eq!(range("3-").to_string(), "3-");
+    }
+
+    #[test]
+    fn writes_only_the_pulled_ranges() {
+        let config = test_config("pulled-range");
+        fs::create_dir_all(&config.save_location).unwrap();
+        let saved = config.save_location.join("large.bin");
+        fs::write(&saved, b"0123456789").unwrap();
+        let ranged = |start: u64| {
+            let mut packet = FilePacketBuilder::new()
+                .file_name("large.bin")
+                .contents(b"XY".to_vec())
+                .build()
+                .unwrap();
+            packet.packet.set_header("range_start", start);
+            packet
+        };
+
+        // A range pushed by the sender is refused.
+        let addr = spawn_receiver(&config);
+        let mut packet = ranged(4);
+        packet.packet.set_header("confirm_length", true);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write_file_packet(&mut stream, &packet, config.transfer_buffer_size).unwrap();
+        let mut buf = Vec::new();
+        let reply = read_reply(&mut stream, &mut buf).unwrap();
+        assert!(reply.get_header("error").unwrap().contains("wasn't pulled"));
+
+        // So is a pulled one that isn't the range asked for.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let buffer_size = config.transfer_buffer_size;
+        thread::spawn(move || {
+            for (mut stream, start) in listener.incoming().flatten().zip([1 << 40, 4]) {
+                let mut buf = Vec::new();
+                read_packet(&mut stream, &mut buf).unwrap();
+                write_file_packet(&mut stream, &ranged(start), buffer_size).unwrap();
+            }
+        });
+        let range = "4-".parse::<ByteRange>().ok();
+        let err = sender::pull_from(addr, "large.bin", range, TEST_ID, &config).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = sender::pull_from(addr, "large.bin", None, TEST_ID, &config).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(fs::read(&saved).unwrap(), b"0123456789");
+    }
+
+    #[test]
+    fn streams_contents_past_the_frame_of_their_headers() {
+        let config = test_config("streamed");
+        let source = config.data_location.join("source");
+        fs::create_dir_all(&source).unwrap();
+
+        // More than a frame can hold; the file is sparse, so it takes no space.
+        let huge = source.join("huge.bin");
+        let huge_len = u32::MAX as u64 + 1;
+        File::create(&huge).unwrap().set_len(huge_len).unwrap();
+        let packet = FilePacket::from_path(&huge).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            let headers = read_packet(&mut stream, &mut buf).unwrap().unwrap();
+            let mut contents = [1; 1024];
+            stream.read_exact(&mut contents).unwrap();
+            (contents_len(&headers).unwrap(), contents)
+        });
+        // The peer goes away once it has read the start of the contents.
+        let mut stream = connect(addr, &config).unwrap();
+        let err = write_file_packet(&mut stream, &packet, config.transfer_buffer_size);
+        assert_ne!(err.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(peer.join().unwrap(), (huge_len, [0; 1024]));
+        fs::remove_file(&huge).unwrap();
+
+        // The receiver reads the contents that follow the frame straight into the file; the
+        // confirmation only comes once it is saved.
+        let config = Config {
+            confirm_received_length: true,
+            ..config
+        };
+        let path = source.join("large.bin");
+        let contents = (0..200_000).map(|i| i as u8).collect::<Vec<_>>();
+        fs::write(&path, &contents).unwrap();
+        sender::send_file_to(spawn_receiver(&config), &path, TEST_ID, &config).unwrap();
+        assert_eq!(
+            fs::read(config.save_location.join("large.bin")).unwrap(),
+            contents
+        );
+    }
+
+    #[test]
+    fn handles_multiple_packets_per_connection() {
+        let config = test_config("conversation");
+        let addr = spawn_receiver(&config);
+        let mut stream = connect(addr, &config).unwrap();
+
+        for (name, contents) in [("one.txt", "1"), ("two.txt", "2")] {
+            let packet = FilePacketBuilder::new()
+                .file_name(name)
+                .contents(contents.as_bytes().to_vec())
+                .build()
+                .unwrap();
+            write_file_packet(&mut stream, &packet, config.transfer_buffer_size).unwrap();
+        }
+        let mut request = Packet::new();
+        request.set_header("kind", "list");
+        write_packet(&mut stream, &request).unwrap();
+
+        // The listing is only answered after the files that came first have been handled.
+        let mut buf = Vec::new();
+        let reply = read_reply(&mut stream, &mut buf).unwrap();
+        assert_eq!(reply.get_header("kind"), Some("list_reply"));
+        assert_eq!(
+            fs::read(config.save_location.join("one.txt")).unwrap(),
+            b"1"
+        );
+        assert_eq!(
+            fs::read(config.save_location.join("two.txt")).unwrap(),
+            b"2"
+        );
+    }
+
+    #[test]
+    fn rejects_connections_beyond_the_limit() {
+        let config = Config {
+            max_transfer_connections: 1,
+            ..test_config("limit")
+        };
+        let addr = spawn_receiver(&config);
+
+        let _busy = connect(addr, &config).unwrap();
+        let mut rejected = connect(addr, &config).unwrap();
+        let mut buf = Vec::new();
+        let reply = read_reply(&mut rejected, &mut buf).unwrap();
+        assert_eq!(reply.get_header("kind"), Some("error"));
+    }
+
+    #[test]
+    fn saves_nothing_from_interrupted_or_empty_transfers() {
+        let config = Config {
+            skip_empty_files: true,
+            ..test_config("interrupted")
+        };
+        let listener =
+            receiver::bind("127.0.0.1:0".parse().unwrap(), config.transfer_backlog).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let last_error = LastError::default();
+        let receiver_config = config.clone();
+        let receiver_error = last_error.clone();
+        thread::spawn(move || {
+            receiver::receive_files(
+                listener,
+                receiver_config,
+                TEST_ID,
+                Arc::default(),
+                receiver_error,
+            )
+        });
+
+        let packet = FilePacketBuilder::new()
+            .file_name("partial.txt")
+            .contents(b"complete contents".to_vec())
+            .build()
+            .unwrap();
+        let mut bytes = Vec::new();
+        packet.write_to(&mut bytes).unwrap();
+        // Send the headers but close the connection halfway through the contents.
+        let mut stream = connect(addr, &config).unwrap();
+        let headers_len = packet.encoded_len() - packet.content_len();
+        write_frame_len(&mut stream, headers_len).unwrap();
+        stream.write_all(&bytes[..bytes.len() - 5]).unwrap();
+        drop(stream);
+
+        while last_error.get().is_none() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        // Nor is anything left of the contents received so far.
+        assert_eq!(fs::read_dir(&config.save_location).unwrap().count(), 0);
+
+        let source = config.data_location.join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("empty.txt"), b"").unwrap();
+        let results = sender::send_files_to(addr, &[source.join("empty.txt")], TEST_ID, &config);
+        assert!(results[0].1.is_ok());
+        assert!(!config.save_location.join("empty.txt").exists());
+    }
+
+    #[test]
+    fn retries_sends_until_the_peer_is_up() {
+        let config = Config {
+            send_retries: 10,
+            send_retry_delay: Duration::from_millis(50),
+            ..test_config("retries")
+        };
+        // Reserve a port, but only start listening on it after the first attempts failed.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let receiver_config = config.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let listener = receiver::bind(addr, receiver_config.transfer_backlog).unwrap();
+            receiver::receive_files(
+                listener,
+                receiver_config,
+                TEST_ID,
+                Arc::default(),
+                LastError::default(),
+            )
+        });
+
+        let path = config.data_location.join("retried.txt");
+        fs::create_dir_all(&config.data_location).unwrap();
+        fs::write(&path, b"retried").unwrap();
+        sender::send_file_to(addr, &path, TEST_ID, &config).unwrap();
+
+        let received = config.save_location.join("retried.txt");
+        while fs::read(&received).ok().as_deref() != Some(&b"retried"[..]) {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn doesnt_resend_files_the_peer_may_have_saved() {
+        let config = Config {
+            confirm_received_length: true,
+            transfer_timeout: Some(Duration::from_millis(200)),
+            send_retries: 2,
+            send_retry_delay: Duration::from_millis(10),
+            ..test_config("no-resend")
+        };
+        // Take the file in but never confirm it, as if the confirmation was lost.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let accepted_by_peer = Arc::clone(&accepted);
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                accepted_by_peer.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    let _ = io::copy(&mut stream, &mut io::sink());
+                    thread::sleep(Duration::from_secs(1));
+                });
+            }
+        });
+
+        let path = config.data_location.join("unconfirmed.txt");
+        fs::create_dir_all(&config.data_location).unwrap();
+        fs::write(&path, b"unconfirmed").unwrap();
+        let err = sender::send_file_to(addr, &path, TEST_ID, &config).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn starts_a_retried_transfer_once() {
+        let config = Config {
+            send_retries: 2,
+            send_retry_delay: Duration::from_millis(10),
+            ..test_config("started-once")
+        };
+        // Nothing listens on the port once the listener is dropped, so every attempt fails.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let path = config.data_location.join("retried.txt");
+        fs::create_dir_all(&config.data_location).unwrap();
+        fs::write(&path, b"retried").unwrap();
+
+        let events = events::subscribe();
+        assert!(sender::send_file_to(addr, &path, TEST_ID, &config).is_err());
+        // Other tests may publish events meanwhile.
+        let events: Vec<_> = events
+            .try_iter()
+            .filter_map(|event| match event {
+                Event::Started(transfer) if transfer.file_name == "retried.txt" => Some("started"),
+                Event::Failed(transfer, _) if transfer.file_name == "retried.txt" => Some("failed"),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(events, ["started", "failed"]);
+    }
+
+    #[test]
+    fn confirms_the_bytes_actually_written() {
+        let config = Config {
+            content_addressed: true,
+            ..test_config("confirm-written")
+        };
+        let addr = spawn_receiver(&config);
+        let mut packet = FilePacketBuilder::new()
+            .file_name("stored.txt")
+            .contents(b"stored".to_vec())
+            .build()
+            .unwrap();
+        packet.packet.set_header("confirm_length", true);
+
+        // Identical contents are stored once, so nothing is written the second time.
+        for (length, unchanged) in [("6", None), ("0", Some("true"))] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write_file_packet(&mut stream, &packet, config.transfer_buffer_size).unwrap();
+            let mut buf = Vec::new();
+            let reply = read_reply(&mut stream, &mut buf).unwrap();
+            assert_eq!(reply.get_header("length"), Some(length));
+            assert_eq!(reply.get_header("unchanged"), unchanged);
+        }
+
+        let path = config.data_location.join("stored.txt");
+        fs::write(&path, b"stored").unwrap();
+        let config = Config {
+            confirm_received_length: true,
+            ..config
+        };
+        assert!(sender::send_file_to(addr, &path, TEST_ID, &config).is_ok());
+    }
+
+    #[test]
+    fn confirms_the_saved_length() {
+        let config = Config {
+            confirm_received_length: true,
+            ..test_config("confirm-length")
+        };
+        let path = config.data_location.join("confirmed.txt");
+        fs::create_dir_all(&config.data_location).unwrap();
+        fs::write(&path, b"confirmed").unwrap();
+        let addr = spawn_receiver(&config);
+        sender::send_file_to(addr, &path, TEST_ID, &config).unwrap();
+        // The confirmation only comes once the file is saved.
+        assert_eq!(
+            fs::read(config.save_location.join("confirmed.txt")).unwrap(),
+            b"confirmed"
+        );
+
+        // A peer claiming to have saved less fails the send.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            let packet = read_packet(&mut stream, &mut buf).unwrap().unwrap();
+            let contents_len = contents_len(&packet).unwrap();
+            io::copy(&mut (&mut stream).take(contents_len), &mut io::sink()).unwrap();
+            let mut reply = Packet::new();
+            reply.set_header("kind", "saved");
+            reply.set_header("length", 3);
+            write_packet(&mut stream, &reply).unwrap();
+        });
+        let err = sender::send_file_to(addr, &path, TEST_ID, &config).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "expected 9 bytes of contents but got 3");
+    }
+
+    #[test]
+    fn reads_shared_contents_once() {
+        let config = test_config("shared-contents");
+        fs::create_dir_all(&config.data_location).unwrap();
+        let path = config.data_location.join("shared.txt");
+        fs::write(&path, b"shared").unwrap();
+        let mut packet = FilePacket::from_path(&path).unwrap();
+        let mut streamed = Vec::new();
+        packet.write_to(&mut streamed).unwrap();
+
+        packet.share_contents().unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut shared = Vec::new();
+        packet.write_to(&mut shared).unwrap();
+        assert_eq!(shared, streamed);
+
+        let configs = ["shared-first", "shared-second"].map(test_config);
+        let addrs = configs.each_ref().map(spawn_receiver);
+        fs::write(&path, b"shared").unwrap();
+        let results = sender::send_file_to_all(&addrs, &path, TEST_ID, &config).unwrap();
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        for config in &configs {
+            let saved = config.save_location.join("shared.txt");
+            // Sends only return once the file is read in full, not once it is saved.
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !saved.exists() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(fs::read(saved).unwrap(), b"shared");
+        }
+    }
+
+    /// Compares sending a directory of many small files, i.e., a stream of small writes,
+    /// through a single byte buffer, as good as unbuffered, and through the default buffer.
+    /// Run it with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn benchmarks_buffered_transfers() {
+        let dir = env::temp_dir().join(format!("redtooth-bench-dir-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for i in 0..4000 {
+            fs::write(dir.join(format!("{i}.txt")), [b'x'; 1000]).unwrap();
+        }
+
+        for buffer_size in [1, Config::default().transfer_buffer_size] {
+            let mut config = test_config(&format!("bench-{buffer_size}"));
+            config.transfer_buffer_size = buffer_size;
+            let addr = spawn_receiver(&config);
+
+            let started = Instant::now();
+            sender::send_archive_to_all(&[addr], &dir, TEST_ID, &config).unwrap();
+            let elapsed = started.elapsed();
+            let len = fs::metadata(
+                config
+                    .save_location
+                    .join(format!("redtooth-bench-dir-{}.tar", process::id())),
+            );
+            println!(
+                "{buffer_size}-byte buffer: {:.2}s ({})",
+                elapsed.as_secs_f64(),
+                throughput(len.map_or(0, |m| m.len()), elapsed)
+            );
+        }
+    }
+
+    #[test]
+    fn formats_throughput() {
+        assert_eq!(throughput(512, Duration::from_secs(1)), "512.0 B/s");
+        assert_eq!(throughput(3 << 20, Duration::from_secs(2)), "1.5 MiB/s");
+        assert_eq!(throughput(0, Duration::ZERO), "0.0 B/s");
+    }
+
+    #[test]
+    fn stores_files_by_content_hash() {
+        let config = Config {
+            content_addressed: true,
+            ..test_config("objects")
+        };
+        let addr = spawn_receiver(&config);
+
+        let source = config.data_location.join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a.txt"), b"same").unwrap();
+        fs::write(source.join("b.txt"), b"same").unwrap();
+        let results = sender::send_files_to(
+            addr,
+            &[source.join("a.txt"), source.join("b.txt")],
+            TEST_ID,
+            &config,
+        );
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+
+        let hash = digest::to_hex(&digest::sha256(b"same"));
+        let objects = fs::read_dir(config.save_location.join("objects")).unwrap();
+        assert_eq!(objects.count(), 1);
+        assert_eq!(
+            fs::read(config.save_location.join("objects").join(&hash)).unwrap(),
+            b"same"
+        );
+        assert_eq!(
+            fs::read_to_string(config.save_location.join("index")).unwrap(),
+            format!("{hash} a.txt\n{hash} b.txt\n")
+        );
+    }
+
+    #[test]
+    fn runs_the_post_receive_command() {
+        let config = Config {
+            post_receive_command: Some(String::from("cp {path} {path}.copy")),
+            ..test_config("hook")
+        };
+        let addr = spawn_receiver(&config);
+
+        let path = config.data_location.join("hooked file.txt");
+        fs::create_dir_all(&config.data_location).unwrap();
+        fs::write(&path, b"hooked").unwrap();
+        sender::send_file_to(addr, &path, TEST_ID, &config).unwrap();
+
+        let copy = config.save_location.join("hooked file.txt.copy");
+        while fs::read(&copy).ok().as_deref() != Some(&b"hooked"[..]) {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn sends_directories_as_archives() {
+        let config = Config {
+            auto_extract: true,
+            ..test_config("archive")
+        };
+        let addr = spawn_receiver(&config);
+
+        let source = config.data_location.join("album");
+        fs::create_dir_all(source.join("raw")).unwrap();
+        fs::write(source.join("cover.txt"), b"cover").unwrap();
+        fs::write(source.join("raw").join("track.bin"), [1; 2000]).unwrap();
+        sender::send_archive_to_all(&[addr], &source, TEST_ID, &config).unwrap();
+
+        let extracted = config.save_location.join("album");
+        while fs::read(extracted.join("raw").join("track.bin")).ok() != Some(vec![1; 2000]) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(fs::read(extracted.join("cover.txt")).unwrap(), b"cover");
+        assert!(config.save_location.join("album.tar").is_file());
+    }
+
+    #[test]
+    fn builds_packets_with_consistent_headers() {
+        let packet = FilePacketBuilder::new()
+            .file_name("notes.txt")
+            .contents(b"abc".to_vec())
+            .content_type("text/plain")
+            .checksum(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(packet.get_file_name(), "notes.txt");
+        assert_eq!(packet.get_contents(), b"abc");
+        assert_eq!(packet.packet.get_header("content_type"), Some("text/plain"));
+        assert_eq!(packet.get_checksum().unwrap().len(), 32);
+        assert!(FilePacketBuilder::new().build().is_err());
+    }
+
+    #[test]
+    fn verifies_received_file_packets() {
+        let packet = || {
+            FilePacketBuilder::new()
+                .file_name("notes.txt")
+                .contents(b"abc".to_vec())
+                .checksum(true)
+                .build()
+                .unwrap()
+        };
+        assert_eq!(packet().verify(), Ok(()));
+
+        let mut unnamed = packet();
+        unnamed.packet = Packet::new();
+        unnamed.packet.set_payload(b"abc".to_vec());
+        assert_eq!(unnamed.verify(), Err(FileVerifyError::MissingFileName));
+
+        for name in ["../notes.txt", "/etc/passwd", "a/b", "..", ""] {
+            let mut unsafe_name = packet();
+            unsafe_name.packet.set_header("file_name", name);
+            assert_eq!(
+                unsafe_name.verify(),
+                Err(FileVerifyError::UnsafeFileName(name.to_string()))
+            );
+        }
+
+        let mut unsized_packet = packet();
+        unsized_packet.packet = Packet::new();
+        unsized_packet.packet.set_header("file_name", "notes.txt");
+        unsized_packet.packet.set_payload(b"abc".to_vec());
+        assert_eq!(
+            unsized_packet.verify(),
+            Err(FileVerifyError::MissingContentLength)
+        );
+
+        let mut invalid_len = packet();
+        invalid_len.packet.set_header("content_length", "three");
+        assert_eq!(
+            invalid_len.verify(),
+            Err(FileVerifyError::InvalidContentLength(String::from("three")))
+        );
+
+        assert_eq!(packet().verify_checksum(&digest::sha256(b"abc")), Ok(()));
+        assert_eq!(
+            packet().verify_checksum(&digest::sha256(b"abd")),
+            Err(FileVerifyError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn refuses_to_save_unsafe_file_names() {
+        let config = test_config("unsafe-name");
+        let addr = spawn_receiver(&config);
+
+        let mut packet = FilePacketBuilder::new()
+            .contents(b"escaped".to_vec())
+            .build()
+            .unwrap();
+        packet.packet.set_header("file_name", "../escaped.txt");
+        packet.set_manifest_index(0);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write_file_packet(&mut stream, &packet, config.transfer_buffer_size).unwrap();
+        let mut buf = Vec::new();
+        let ack = read_reply(&mut stream, &mut buf).unwrap();
+        assert!(ack
+            .get_header("error")
+            .unwrap()
+            .contains("unsafe file name"));
+        assert!(!config.save_location.with_file_name("escaped.txt").exists());
+
+        // The refusal is recorded under the save location rather than the path it named.
+        let records = history::last(&config.data_location, 1).unwrap();
+        let recorded_path = records[0].rsplit('\t').next().unwrap();
+        assert_eq!(Path::new(recorded_path), config.save_location);
+    }
+
+    #[test]
+    fn refuses_files_without_a_content_length() {
+        let config = test_config("content-length");
+        let addr = spawn_receiver(&config);
+
+        for len in [None, Some("short")] {
+            let mut packet = Packet::new();
+            packet.set_header("file_name", "short.txt");
+            if let Some(len) = len {
+                packet.set_header("content_length", len);
+            }
+            packet.set_payload(b"short".to_vec());
+
+            // The receiver replies as soon as the headers arrive.
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write_packet(&mut stream, &packet).unwrap();
+            let mut buf = Vec::new();
+            let reply = read_reply(&mut stream, &mut buf).unwrap();
+            assert_eq!(reply.get_header("kind"), Some("error"));
+        }
+        assert!(!config.save_location.join("short.txt").exists());
+    }
+
+    #[test]
+    fn refuses_files_larger_than_the_free_space() {
+        let config = test_config("free-space");
+        let addr = spawn_receiver(&config);
+        assert!(receiver::available_space(&config.save_location.join("missing")).unwrap() > 0);
+
+        let mut packet = FilePacketBuilder::new()
+            .file_name("huge.bin")
+            .contents(b"huge".to_vec())
+            .build()
+            .unwrap();
+        packet.packet.set_header("content_length", u64::MAX);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write_file_packet(&mut stream, &packet, config.transfer_buffer_size).unwrap();
+        let mut buf = Vec::new();
+        let reply = read_reply(&mut stream, &mut buf).unwrap();
+        assert_eq!(reply.get_header("kind"), Some("error"));
+        assert!(reply
+            .get_header("error")
+            .unwrap()
+            .contains("not enough space"));
+        assert!(!config.save_location.join("huge.bin").exists());
+    }
+
+    #[test]
+    fn refuses_packets_of_unknown_kinds() {
+        use self::confirm::ConfirmCommand;
+
+        let config = Config {
+            confirm_incoming: Some(Arc::new(ConfirmCommand {
+                template: String::from("false"),
+                timeout: Duration::from_secs(5),
+            })),
+            ..test_config("unknown-kind")
+        };
+        let addr = spawn_receiver(&config);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut buf = Vec::new();
+
+        // Without contents, the connection is kept for the packets that follow.
+        let mut packet = Packet::new();
+        packet.set_header("kind", "anything");
+        write_packet(&mut stream, &packet).unwrap();
+        let reply = read_reply(&mut stream, &mut buf).unwrap();
+        assert_eq!(reply.get_header("kind"), Some("error"));
+        assert!(reply.get_header("error").unwrap().contains("unknown"));
+
+        // A file under another kind isn't saved, nor is it let past the confirmation.
+        let mut packet = FilePacketBuilder::new()
+            .file_name("unconfirmed.txt")
+            .contents(b"unconfirmed".to_vec())
+            .build()
+            .unwrap();
+        packet.packet.set_header("kind", "anything");
+        write_file_packet(&mut stream, &packet, config.transfer_buffer_size).unwrap();
+        let reply = read_reply(&mut stream, &mut buf).unwrap();
+        assert_eq!(reply.get_header("kind"), Some("error"));
+        assert!(reply.get_header("error").unwrap().contains("unknown"));
+        assert!(!config.save_location.join("unconfirmed.txt").exists());
+    }
+
+    #[test]
+    fn preserves_file_metadata_when_enabled() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let config = Config {
+            preserve_file_metadata: true,
+            ..test_config("metadata")
+        };
+        let addr = spawn_receiver(&config);
+
+        let source = config.data_location.join("source");
+        fs::create_dir_all(&source).unwrap();
+        let path = source.join("script.sh");
+        fs::write(&path, b"echo hi").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        // Acked sends only return once the file has been saved.
+        let results = sender::send_files_to(addr, &[path], TEST_ID, &config);
+        assert!(results[0].1.is_ok());
+
+        let metadata = fs::metadata(config.save_location.join("script.sh")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o750);
+        assert_eq!(metadata.modified().unwrap(), mtime);
+    }
+
+    #[test]
+    fn stops_receiving_once_stopped() {
+        let config = test_config("stop");
+        let listener =
+            receiver::bind("127.0.0.1:0".parse().unwrap(), config.transfer_backlog).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = listener.try_clone().unwrap();
+        let receiver = thread::spawn(move || {
+            receiver::receive_files(
+                listener,
+                config,
+                TEST_ID,
+                Arc::default(),
+                LastError::default(),
+            )
+        });
+
+        receiver::stop(&handle).unwrap();
+        assert!(receiver.join().unwrap().is_ok());
+        drop(handle);
+        // The address can be bound again right away, e.g., to go back to it.
+        receiver::bind(addr, 1).unwrap();
     }
 }
@@ -1,47 +1,1229 @@
-use std::fs;
-use std::io::{self, Read};
-use std::net::TcpListener;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
 
-use super::FilePacket;
-use crate::protocol::PeerAddr;
-use crate::{elogln, logln};
+use super::confirm::IncomingFile;
+use super::{archive, ByteRange, FilePacket, FilePacketBuilder, FileVerifyError};
+use crate::config::Config;
+use crate::diagnostics::LastError;
+use crate::digest::{self, Sha256};
+use crate::events::{Progress, Transfer};
+use crate::history::{self, Direction, Record};
+use crate::protocol::packet::Packet;
+use crate::protocol::{PeerAddr, PeerID};
+use crate::{elogln, logln, net};
 
-/// Starts receiving files on the `addr` and upon successful reception saves them
-/// to the given location.
-pub fn receive_files(addr: PeerAddr, save_location: PathBuf) -> io::Result<()> {
-    let listener = TcpListener::bind(addr)?;
-    logln!("Receiving data on {addr}");
+/// Name of the directory, inside the save location, where the files are stored by their
+/// content hash if `content_addressed` is enabled.
+const OBJECTS_DIR_NAME: &str = "objects";
+/// Name of the file, inside the save location, that maps the names of the files stored by their
+/// content hash to their hashes, one `<hash> <name>` line per received file.
+const INDEX_FILE_NAME: &str = "index";
+/// Maximum length of the name of the subdirectory the files of a sender are saved in.
+const MAX_DIR_NAME_LEN: usize = 64;
 
-    for mut stream in listener.incoming().flatten() {
-        let mut data: Vec<u8> = Vec::new();
+/// Binds a listener on the `addr` from where the files can be received.
+///
+/// The `backlog` limits the number of connections the OS queues until they are accepted.
+pub fn bind(addr: PeerAddr, backlog: u32) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr).map_err(|e| {
+        net::bind_error(e, addr.port(), "try changing `transfer_port` in the config")
+    })?;
+
+    // `std` always listens with its own backlog; listening again replaces it.
+    let backlog = libc::c_int::try_from(backlog).unwrap_or(libc::c_int::MAX);
+    if unsafe { libc::listen(listener.as_raw_fd(), backlog) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+/// Stops a [`receive_files`] loop accepting on the `listener`, or on a clone of it, so that
+/// it returns, e.g., before binding on a new address.
+///
+/// The connections already accepted are still handled.
+pub fn stop(listener: &TcpListener) -> io::Result<()> {
+    // Once shut down, a listener fails to accept with `EINVAL`, which ends the loop.
+    if unsafe { libc::shutdown(listener.as_raw_fd(), libc::SHUT_RD) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Starts receiving files on the `listener` and upon successful reception saves them
+/// to the configured save location.
+///
+/// Packets with the `kind=message` header carry a text message instead of a file;
+/// they are logged rather than saved. Packets with the `kind=list` and `kind=pull_request`
+/// headers ask for the list of files in the configured shared location and for one of those
+/// files respectively. Only packets without a `kind` are files; a packet of a kind that isn't
+/// known is answered with a `kind=error` packet rather than saved.
+///
+/// The sender of each connection is checked against the `allowed_peers` and `denied_peers` by
+/// the first packet it sends, whatever its kind; a sender that isn't allowed is refused and the
+/// connection dropped. Files pulled from this peer are identified with `my_id`.
+///
+/// Each connection is handled on its own thread, up to `max_transfer_connections` at once;
+/// connections beyond that are rejected with a `kind=error` packet. The number of connections
+/// being handled is kept in the `active_connections`.
+///
+/// Errors are logged and recorded into the `last_error`. Returns once the listener is
+/// [stopped](stop).
+pub fn receive_files(
+    listener: TcpListener,
+    config: Config,
+    my_id: PeerID,
+    active_connections: Arc<AtomicUsize>,
+    last_error: LastError,
+) -> io::Result<()> {
+    logln!("Receiving data on {}", listener.local_addr()?);
+    let config = Arc::new(config);
 
-        match stream.read_to_end(&mut data) {
-            Ok(data_len) => logln!("Received data of {data_len} bytes"),
-            Err(e) => elogln!("Couldn't read data from the stream: {e}"),
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) if e.kind() == ErrorKind::InvalidInput => break,
+            Err(_) => continue,
         };
+        let Some(slot) =
+            ConnectionSlot::acquire(&active_connections, config.max_transfer_connections)
+        else {
+            let peer = stream
+                .peer_addr()
+                .map_or(String::from("unknown"), |a| a.to_string());
+            report(
+                &last_error,
+                format!("Rejected a connection from `{peer}`; too many connections"),
+            );
 
-        let file_packet = match FilePacket::from_bytes(&data) {
-            Ok(p) => p,
-            Err(e) => {
-                elogln!("Received data isn't a valid file packet; {e}");
-                continue;
-            }
+            let mut reply = Packet::new();
+            reply.set_header("kind", "error");
+            reply.set_header("error", "too many connections; try again later");
+            let _ = super::write_packet(&mut stream, &reply);
+            continue;
         };
+        if let Err(e) = super::configure_stream(&stream, &config) {
+            report(&last_error, format!("Couldn't configure the stream: {e}"));
+        }
+
+        let config = Arc::clone(&config);
+        let connection_error = last_error.clone();
+        let spawned = thread::Builder::new()
+            .name(String::from("file_receiver_connection"))
+            .spawn(move || {
+                let _slot = slot;
+                if let Err(e) = handle_connection(stream, &config, my_id, &connection_error) {
+                    let e = super::map_timeout(e, config.transfer_timeout);
+                    report(&connection_error, format!("Couldn't receive data: {e}"));
+                }
+            });
 
-        if let Err(e) = write_file(file_packet, &save_location) {
-            let path = save_location.display();
-            elogln!("Failed to create file in `{path}`: {e}");
+        if let Err(e) = spawned {
+            report(&last_error, format!("Couldn't handle a connection: {e}"));
         }
     }
     Ok(())
 }
 
-/// Creates a file based on the provided file packet.
+/// A slot taken by a connection that is being handled, freed once it is dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Takes a slot unless all the `max` slots are already taken.
+    fn acquire(active: &Arc<AtomicUsize>, max: usize) -> Option<ConnectionSlot> {
+        active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(Arc::clone(active)))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Receives the packets sent on the connection and handles each of them based on its kind.
+///
+/// A connection may carry any number of packets; it ends when the sender closes it. If it
+/// fails in the middle of a packet, e.g., because the sender went away, the connection is
+/// dropped and nothing of that packet is saved.
+fn handle_connection(
+    stream: TcpStream,
+    config: &Config,
+    my_id: PeerID,
+    last_error: &LastError,
+) -> io::Result<()> {
+    let mut buf = Vec::new();
+    // Packets are read in chunks of the buffer's size rather than however much each read asks
+    // for, which would cost a syscall for every few bytes of the headers.
+    let mut reader = BufReader::with_capacity(config.transfer_buffer_size, stream);
+    let mut sender_checked = false;
+
+    // Wait for the next packet to start arriving, so that only its transfer is timed.
+    while !reader.fill_buf()?.is_empty() {
+        let started = Instant::now();
+        let Some(packet) = super::read_packet(&mut reader, &mut buf)? else {
+            break;
+        };
+        // The sender is checked once, as soon as the headers of its first packet arrive, so
+        // that nothing it sends is handled unless it's allowed.
+        if !sender_checked {
+            ensure_sender_allowed(&packet, reader.get_mut(), config)?;
+            sender_checked = true;
+        }
+        let contents_len = match super::contents_len(&packet) {
+            Ok(len) => len,
+            Err(e) => return Err(refuse(reader.get_mut(), e)),
+        };
+        handle_packet(
+            packet,
+            contents_len,
+            started,
+            &mut reader,
+            config,
+            my_id,
+            last_error,
+        )?;
+    }
+    Ok(())
+}
+
+/// Tells the sender why the connection is dropped with a `kind=error` packet, rather than
+/// taking in what it sent, and returns the error to drop it with.
+fn refuse(stream: &mut TcpStream, err: Error) -> Error {
+    let mut reply = Packet::new();
+    reply.set_header("kind", "error");
+    reply.set_header("error", &err);
+    let _ = super::write_packet(stream, &reply);
+    err
+}
+
+/// Reads the `len` bytes of contents of a file that isn't saved and throws them away, so that
+/// the packets that follow them can still be received.
+fn skip_contents(contents: &mut impl Read, len: u64) -> io::Result<()> {
+    let skipped_len = io::copy(&mut contents.take(len), &mut io::sink())?;
+    ensure_fully_received(skipped_len, len)
+}
+
+fn ensure_fully_received(received_len: u64, len: u64) -> io::Result<()> {
+    if received_len < len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "connection closed in the middle of a packet",
+        ));
+    }
+    Ok(())
+}
+
+/// Fails with [`ErrorKind::StorageFull`] if a file of `len` bytes is larger than the space
+/// left in the `save_location`.
+///
+/// A save location whose free space can't be found is assumed to have enough of it.
+fn ensure_free_space(len: u64, save_location: &Path) -> io::Result<()> {
+    match available_space(save_location) {
+        Ok(available) if available < len => Err(Error::new(
+            ErrorKind::StorageFull,
+            format!("not enough space for {len} bytes; only {available} bytes are free"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Fails with [`ErrorKind::PermissionDenied`] if `confirm_incoming` declines the file
+/// announced by the `headers`.
+fn ensure_confirmed(
+    headers: &Packet,
+    sender_addr: Option<PeerAddr>,
+    config: &Config,
+) -> io::Result<()> {
+    let Some(confirm) = &config.confirm_incoming else {
+        return Ok(());
+    };
+    let file = IncomingFile {
+        sender_id: headers
+            .get_header("sender_id")
+            .and_then(|id| id.parse().ok()),
+        sender_addr,
+        file_name: headers.get_header("file_name").unwrap_or("undefined"),
+        len: headers
+            .get_header("content_length")
+            .and_then(|len| len.parse().ok()),
+    };
+    match confirm.confirm(&file) {
+        true => Ok(()),
+        false => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("declined to receive `{}`", file.file_name),
+        )),
+    }
+}
+
+/// Returns the number of bytes available to the app on the filesystem of the
+/// `save_location`.
+///
+/// The save location doesn't have to exist yet; the nearest existing ancestor is used instead.
+pub fn available_space(save_location: &Path) -> io::Result<u64> {
+    let path = save_location
+        .ancestors()
+        .find(|path| path.exists())
+        .ok_or(Error::new(
+            ErrorKind::NotFound,
+            "save location doesn't exist",
+        ))?;
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+
+    let mut stats = unsafe { mem::zeroed::<libc::statvfs>() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    // Blocks reserved for the superuser aren't counted.
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// Handles a packet whose transfer `started` at the given instant; the `contents_len` bytes of
+/// contents of a file packet follow it on the `reader`.
+fn handle_packet(
+    packet: Packet,
+    contents_len: u64,
+    started: Instant,
+    reader: &mut BufReader<TcpStream>,
+    config: &Config,
+    my_id: PeerID,
+    last_error: &LastError,
+) -> io::Result<()> {
+    let stream = reader.get_mut();
+    match packet.get_header("kind") {
+        // An empty message has nothing to show.
+        Some("message") if packet.is_empty() => Ok(()),
+        Some("message") => {
+            let text = String::from_utf8_lossy(packet.get_payload().unwrap_or_default());
+            match stream.peer_addr() {
+                Ok(peer_addr) => logln!("Message from `{peer_addr}`: {text}"),
+                Err(_) => logln!("Message from an unknown peer: {text}"),
+            }
+            Ok(())
+        }
+        Some("list") => answer_listing(stream, &config.shared_location),
+        Some("pull_request") => answer_pull(&packet, stream, my_id, config),
+        Some("manifest") => {
+            // The announced files follow as packets of their own.
+            let file_count = packet.get_header("file_count").unwrap_or("unknown");
+            logln!("Receiving {file_count} files");
+            Ok(())
+        }
+        // The file follows if it is wanted.
+        Some("hash_probe") => answer_hash_probe(&packet, stream, config),
+        Some("exists_probe") => answer_exists_probe(&packet, stream, config),
+        Some(kind) => {
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown packet kind `{kind}`"),
+            );
+            // Contents that wouldn't be saved aren't taken in either.
+            if contents_len > 0 {
+                return Err(refuse(stream, err));
+            }
+            let mut reply = Packet::new();
+            reply.set_header("kind", "error");
+            reply.set_header("error", err);
+            super::write_packet(stream, &reply)
+        }
+        None => receive_file(
+            FilePacket::from(packet),
+            contents_len,
+            started,
+            reader,
+            config,
+            last_error,
+        ),
+    }
+}
+
+/// Fails if the sender of the packet isn't allowed as per the `allowed_peers` and
+/// `denied_peers`, telling it so with a `kind=error` packet, so that the connection is dropped
+/// before anything it sent is handled.
+fn ensure_sender_allowed(
+    packet: &Packet,
+    stream: &mut TcpStream,
+    config: &Config,
+) -> io::Result<()> {
+    let id = packet
+        .get_header("sender_id")
+        .and_then(|id| id.parse::<PeerID>().ok());
+    let name = packet.get_header("sender_name");
+    if config.allows_peer(id, name) {
+        return Ok(());
+    }
+
+    let mut reply = Packet::new();
+    reply.set_header("kind", "error");
+    reply.set_header("error", "sender isn't allowed");
+    let _ = super::write_packet(stream, &reply);
+
+    let sender = id.map_or(String::from("unknown"), |id| id.to_string());
+    Err(Error::new(
+        ErrorKind::PermissionDenied,
+        format!("refused a connection from the peer `{sender}`; it isn't allowed"),
+    ))
+}
+
+/// Saves the received file and, if it was announced by a manifest, acknowledges it.
+///
+/// A file that fails to be saved is reported back to the sender through its ack rather than
+/// ending the connection, so the remaining files can still be received. A file sent on its own
+/// with the `confirm_length` header is answered with the number of bytes saved, or the error.
+///
+/// A file without a length, or larger than the space left in the save location, is refused
+/// with a `kind=error` packet before any of its contents are received, and the connection is
+/// dropped. A file declined by `confirm_incoming` is skipped instead, so that the files that
+/// follow it, e.g., in the same batch, can still be received.
+fn receive_file(
+    packet: FilePacket,
+    contents_len: u64,
+    started: Instant,
+    reader: &mut BufReader<TcpStream>,
+    config: &Config,
+    last_error: &LastError,
+) -> io::Result<()> {
+    // Checked as soon as the headers arrive, so that a file that would be refused isn't received
+    // first. Every file carries its length, without which none of its contents would follow.
+    if packet.packet.get_header("content_length").is_none() {
+        let err = Error::new(
+            ErrorKind::InvalidData,
+            FileVerifyError::MissingContentLength,
+        );
+        return Err(refuse(reader.get_mut(), err));
+    }
+    if let Err(e) = ensure_confirmed(&packet.packet, reader.get_ref().peer_addr().ok(), config) {
+        skip_contents(reader, contents_len)?;
+        logln!("{e}");
+        let mut reply = Packet::new();
+        match packet.get_manifest_index() {
+            Some(index) => {
+                reply.set_header("kind", "ack");
+                reply.set_header("index", index);
+            }
+            None => reply.set_header("kind", "error"),
+        }
+        reply.set_header("error", &e);
+        return super::write_packet(reader.get_mut(), &reply);
+    }
+    if let Err(e) = ensure_free_space(contents_len, &config.save_location) {
+        return Err(refuse(reader.get_mut(), e));
+    }
+
+    let index = packet.get_manifest_index();
+    let confirm_length = packet.packet.get_header("confirm_length").is_some();
+    let result = save_pushed_file(packet, contents_len, reader, config);
+    if result.is_ok() {
+        let elapsed = started.elapsed();
+        logln!(
+            "Received data of {contents_len} bytes in {:.2}s ({})",
+            elapsed.as_secs_f64(),
+            super::throughput(contents_len, elapsed)
+        );
+    }
+
+    let stream = reader.get_mut();
+    let Some(index) = index else {
+        if !confirm_length {
+            return result.map(|_| ());
+        }
+        let mut reply = Packet::new();
+        match &result {
+            Ok(saved_len) => {
+                reply.set_header("kind", "saved");
+                reply.set_header("length", saved_len.unwrap_or(0));
+                // Tells the sender that nothing had to be written, rather than too little.
+                if saved_len.is_none() {
+                    reply.set_header("unchanged", true);
+                }
+            }
+            Err(e) => {
+                reply.set_header("kind", "error");
+                reply.set_header("error", e);
+            }
+        }
+        super::write_packet(stream, &reply)?;
+        return result.map(|_| ());
+    };
+
+    let mut ack = Packet::new();
+    ack.set_header("kind", "ack");
+    ack.set_header("index", index);
+
+    if let Err(e) = result {
+        report(last_error, e.to_string());
+        ack.set_header("error", e);
+    }
+    super::write_packet(stream, &ack)
+}
+
+/// Saves a file the sender sent on its own accord, as per [`save_file`].
+///
+/// Only a whole file is accepted: a range is only written in reply to a pull that asked for
+/// it, since the sender could otherwise overwrite any part of an existing file, or grow it
+/// far beyond the space that was checked to be free.
+fn save_pushed_file(
+    packet: FilePacket,
+    contents_len: u64,
+    reader: &mut BufReader<TcpStream>,
+    config: &Config,
+) -> io::Result<Option<u64>> {
+    if packet.get_range_start().is_some() {
+        skip_contents(reader, contents_len)?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "refused a range of a file that wasn't pulled",
+        ));
+    }
+    let peer_addr = reader.get_ref().peer_addr().ok();
+    save_file(packet, contents_len, reader, peer_addr, config)
+}
+
+/// Tells the sender whether a file with the probed name and content hash already exists
+/// in the save location; if `content_addressed` is enabled, only the hash has to match.
+///
+/// A name that isn't a plain file name is never looked up, so that the probe can't tell the
+/// hash of files outside the save location.
+fn answer_hash_probe(probe: &Packet, stream: &mut TcpStream, config: &Config) -> io::Result<()> {
+    let has_file = has_probed_file(probe, config);
+
+    let mut reply = Packet::new();
+    reply.set_header("kind", "hash_probe_reply");
+    reply.set_header("answer", if has_file { "have" } else { "want" });
+    super::write_packet(stream, &reply)
+}
+
+/// Returns whether the file the hash `probe` asks about is saved already, see
+/// [`answer_hash_probe`].
+fn has_probed_file(probe: &Packet, config: &Config) -> bool {
+    let Some(hash) = probe.get_header_bytes("hash") else {
+        return false;
+    };
+    if config.content_addressed {
+        return object_path(&config.save_location, &hash).exists();
+    }
+    let file_name = probe.get_header("file_name").unwrap_or_default();
+    super::is_plain_file_name(file_name)
+        && digest::sha256_file(&save_dir(probe, config).join(file_name))
+            .is_ok_and(|local| local[..] == hash)
+}
+
+/// Replies whether a file sent with the name the `probe` asks about would replace a file
+/// already saved, so that the sender can ask before overwriting it.
+///
+/// The reply's `answer` is `exists` or `missing`; files aren't replaced if they are stored by
+/// their content hash or renamed as per `rename_template`, so nothing ever exists then.
+fn answer_exists_probe(probe: &Packet, stream: &mut TcpStream, config: &Config) -> io::Result<()> {
+    let file_name = probe.get_header("file_name").unwrap_or_default();
+    let exists = super::is_plain_file_name(file_name)
+        && !config.content_addressed
+        && config.rename_template.is_none()
+        && save_dir(probe, config).join(file_name).exists();
+
+    let mut reply = Packet::new();
+    reply.set_header("kind", "exists_probe_reply");
+    reply.set_header("answer", if exists { "exists" } else { "missing" });
+    super::write_packet(stream, &reply)
+}
+
+/// Replies with the size and name of each file in the `shared_location`, one file per line.
+///
+/// A failure to list the files is reported back through the `error` header of the reply.
+fn answer_listing(stream: &mut TcpStream, shared_location: &Path) -> io::Result<()> {
+    let mut reply = Packet::new();
+    reply.set_header("kind", "list_reply");
+
+    match list_shared_files(shared_location) {
+        Ok(files) => {
+            let entries = files
+                .iter()
+                .map(|(name, size)| format!("{size} {name}\n"))
+                .collect::<String>();
+            reply.set_payload(entries.into_bytes());
+        }
+        Err(e) => reply.set_header("error", e),
+    }
+    super::write_packet(stream, &reply)
+}
+
+/// Sends back the file requested by the `request` from the `shared_location`.
+///
+/// If the file can't be sent (e.g., it doesn't exist), a `kind=error` packet carrying the
+/// reason in its `error` header is sent instead.
+fn answer_pull(
+    request: &Packet,
+    stream: &mut TcpStream,
+    my_id: PeerID,
+    config: &Config,
+) -> io::Result<()> {
+    let file_name = request.get_header("file_name").unwrap_or_default();
+    // Only the files right inside the shared location can be pulled.
+    let packet = match Path::new(file_name).file_name() {
+        Some(name) if name == file_name => {
+            let path = config.shared_location.join(file_name);
+            match path.is_file() {
+                true => requested_range(request).and_then(|range| {
+                    let builder = FilePacketBuilder::new().path(&path);
+                    match range {
+                        Some(range) => builder.range(range).build(),
+                        None => builder.build(),
+                    }
+                }),
+                false => Err(Error::new(ErrorKind::NotFound, "no such shared file")),
+            }
+        }
+        _ => Err(Error::new(ErrorKind::InvalidInput, "invalid file name")),
+    };
+
+    match packet {
+        Ok(mut packet) => {
+            // Identified like any sent file, so that the puller saves it apart by its sender.
+            super::sender::identify(&mut packet.packet, my_id);
+            logln!("Sending `{file_name}` as requested");
+            super::write_file_packet(stream, &packet, config.transfer_buffer_size)
+        }
+        Err(e) => {
+            let mut reply = Packet::new();
+            reply.set_header("kind", "error");
+            reply.set_header("error", e);
+            super::write_packet(stream, &reply)
+        }
+    }
+}
+
+/// Returns the range of the file a pull request asks for with its `range_start` and
+/// `range_end` headers, if any.
+fn requested_range(request: &Packet) -> io::Result<Option<ByteRange>> {
+    let Some(start) = request.get_header("range_start") else {
+        return Ok(None);
+    };
+    let range = match request.get_header("range_end") {
+        Some(end) => format!("{start}-{end}"),
+        None => format!("{start}-"),
+    };
+    range
+        .parse()
+        .map(Some)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid range; {e}")))
+}
+
+/// Returns the name and size of each regular file in the `shared_location`, sorted by name.
+///
+/// A missing shared location is treated as one without any files.
+fn list_shared_files(shared_location: &Path) -> io::Result<Vec<(String, u64)>> {
+    let entries = match fs::read_dir(shared_location) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                metadata.len(),
+            ));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Receives the contents of the file packet, the `contents_len` bytes that follow its headers on
+/// the `contents`, saves them and records the transfer from the peer at the `peer_addr` into
+/// the history.
 ///
-/// This function will create a file if it does not exist,
-/// and will entirely replace its contents with a new one if it does.
-fn write_file(packet: FilePacket, save_location: &Path) -> io::Result<()> {
-    let file_path = save_location.join(packet.get_file_name());
-    fs::write(file_path, packet.get_contents())
+/// The contents are received into a part file next to where they are saved, which is only
+/// moved into place once they are received in full and match the checksum, if the packet
+/// carries one; so an interrupted or corrupted transfer leaves nothing behind. The contents of
+/// a file that is refused are still read, so that the packets that follow them can be received.
+///
+/// If `skip_empty_files` is enabled, an empty file is skipped instead.
+///
+/// Returns the number of bytes of contents written, or `None` if nothing had to be: the file
+/// was skipped, or its contents were stored already.
+pub(super) fn save_file(
+    packet: FilePacket,
+    contents_len: u64,
+    contents: &mut impl Read,
+    peer_addr: Option<PeerAddr>,
+    config: &Config,
+) -> io::Result<Option<u64>> {
+    if config.skip_empty_files && contents_len == 0 {
+        logln!("Skipped `{}`; the file is empty", packet.get_file_name());
+        return Ok(None);
+    }
+    let save_dir = save_dir(&packet.packet, config);
+    // Nothing is written for a packet that fails verification, but it is still recorded; under
+    // the directory it was sent to, since its name can't be trusted to stay inside it.
+    let (path, result) = match packet.verify() {
+        Ok(()) => {
+            let mut progress = Progress::start(Transfer {
+                direction: Direction::Received,
+                peer_addr,
+                file_name: packet.get_file_name().to_string(),
+                total: contents_len,
+            });
+            let stored = store_file(
+                &packet,
+                contents_len,
+                contents,
+                &save_dir,
+                &mut progress,
+                config,
+            );
+            match stored {
+                Ok((path, written)) => (path, Ok(written)),
+                Err(e) => (save_dir.join(packet.get_file_name()), Err(e)),
+            }
+        }
+        Err(e) => (
+            save_dir.clone(),
+            skip_contents(contents, contents_len)
+                .and(Err(Error::new(ErrorKind::InvalidData, e.to_string()))),
+        ),
+    };
+
+    history::append(
+        &config.data_location,
+        Record {
+            direction: Direction::Received,
+            peer_addr,
+            path: &path,
+            bytes: contents_len,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        },
+    );
+
+    let is_archive = packet.get_content_type() == Some(archive::CONTENT_TYPE);
+    if result.is_ok() && is_archive && config.auto_extract && !config.content_addressed {
+        let extracted = File::open(&path)
+            .and_then(|archive| archive::extract(BufReader::new(archive), &save_dir));
+        match extracted {
+            Ok(file_count) => logln!("Extracted {file_count} files from `{}`", path.display()),
+            Err(e) => elogln!("Failed to extract `{}`: {e}", path.display()),
+        }
+    }
+    if let (Ok(_), Some(command)) = (&result, &config.post_receive_command) {
+        run_post_receive_command(command, &path);
+    }
+    result
+}
+
+/// Receives the contents of the verified file packet into a part file in the `save_dir` and
+/// moves it into place, as per [`save_file`].
+///
+/// Returns the path the file was saved at, along with the number of bytes written or `None`
+/// if its contents were stored already.
+fn store_file(
+    packet: &FilePacket,
+    contents_len: u64,
+    contents: &mut impl Read,
+    save_dir: &Path,
+    progress: &mut Progress,
+    config: &Config,
+) -> io::Result<(PathBuf, Option<u64>)> {
+    let part = match save_dir == config.save_location {
+        true => Ok(()),
+        false => fs::create_dir_all(save_dir),
+    }
+    .and_then(|_| PartFile::create(save_dir))
+    .map_err(|e| {
+        let path = save_dir.display();
+        Error::new(e.kind(), format!("failed to create file in `{path}`: {e}"))
+    });
+    // The contents are only hashed if there's a use for the digest, since it costs a pass.
+    let hashed = config.content_addressed || packet.get_checksum().is_some();
+    let (part, digest) = receive_part(contents, contents_len, part, hashed, progress)?;
+    if let Some(digest) = &digest {
+        packet
+            .verify_checksum(digest)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    }
+
+    let path = match digest.filter(|_| config.content_addressed) {
+        Some(digest) => object_path(&config.save_location, &digest),
+        None => match (&config.rename_template, packet.get_range_start()) {
+            // A range belongs in the file already saved under the name, e.g., to resume a pull.
+            (Some(template), None) => free_path(save_dir, packet.get_file_name(), template),
+            _ => save_dir.join(packet.get_file_name()),
+        },
+    };
+    let written = write_file(packet, part, &path, config)?;
+    Ok((path, written))
+}
+
+/// Receives the `len` bytes of contents of a file from the `contents` into the `part` file,
+/// reporting them to the `progress`, and returns it along with the SHA-256 digest of the
+/// contents if they are `hashed`.
+///
+/// If the part file couldn't be created or written, the contents are still read, so that the
+/// packets that follow them can be received, and the error is returned once they are.
+fn receive_part(
+    contents: &mut impl Read,
+    len: u64,
+    part: io::Result<PartFile>,
+    hashed: bool,
+    progress: &mut Progress,
+) -> io::Result<(PartFile, Option<[u8; 32]>)> {
+    let mut writer = ContentsWriter {
+        file: part.as_ref().ok().map(|part| &part.file),
+        hasher: hashed.then(Sha256::new),
+        progress,
+        written: 0,
+        error: None,
+    };
+    let received_len = io::copy(&mut contents.take(len), &mut writer)?;
+    ensure_fully_received(received_len, len)?;
+
+    let ContentsWriter { hasher, error, .. } = writer;
+    let part = part?;
+    match error {
+        Some(e) => Err(e),
+        None => Ok((part, hasher.map(Sha256::finish))),
+    }
+}
+
+/// A file the contents of a received file are written into until they are received in full
+/// and verified; it is removed once dropped, unless it was moved into place by then.
+struct PartFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PartFile {
+    /// Creates a part file in the `dir`, under a hidden name no other part file has.
+    fn create(dir: &Path) -> io::Result<PartFile> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!(".redtooth-{}-{id}.part", process::id()));
+        // Never opens a file, or follows a symlink, that is already there.
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(PartFile { path, file })
+    }
+
+    /// Returns the number of bytes written into the file.
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Moves the file to the `path`, replacing the file there, if any.
+    fn persist(mut self, path: &Path) -> io::Result<()> {
+        fs::rename(&self.path, path)?;
+        // There's nothing left to remove.
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// A writer that writes the contents of a received file into its part file, if any, hashing
+/// them and reporting them to the progress along the way.
+///
+/// A failure to write into the file is kept rather than returned, so that the rest of the
+/// contents are still read.
+struct ContentsWriter<'a> {
+    file: Option<&'a File>,
+    hasher: Option<Sha256>,
+    progress: &'a mut Progress,
+    written: u64,
+    error: Option<Error>,
+}
+
+impl Write for ContentsWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let (Some(mut file), None) = (self.file, &self.error) {
+            self.error = file.write_all(buf).err();
+        }
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(buf);
+        }
+        self.written += buf.len() as u64;
+        self.progress.update(self.written);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the directory to save the file sent along with the `headers` in: the save location,
+/// or the subdirectory named after the sender in it if `save_subdir_by_peer` is enabled.
+///
+/// The subdirectory is named after the name the sender claims to have, or its identifier if
+/// the name is missing or has nothing left once sanitized, or `unknown` if neither was sent.
+pub fn save_dir(headers: &Packet, config: &Config) -> PathBuf {
+    if !config.save_subdir_by_peer || config.content_addressed {
+        return config.save_location.clone();
+    }
+    let dir_name = ["sender_name", "sender_id"]
+        .into_iter()
+        .find_map(|header| sanitize_dir_name(headers.get_header(header)?))
+        .unwrap_or_else(|| String::from("unknown"));
+    config.save_location.join(dir_name)
+}
+
+/// Returns the `name` with anything but ASCII letters, digits, `-`, `_` and `.` replaced by
+/// `_`, so that it is a single path component; `None` if it would be empty, `.` or `..`.
+fn sanitize_dir_name(name: &str) -> Option<String> {
+    let name = name
+        .chars()
+        .take(MAX_DIR_NAME_LEN)
+        .map(
+            |c| match c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect::<String>();
+    (!name.trim_matches('.').is_empty()).then_some(name)
+}
+
+/// Returns the path in the `save_location` to save the file named `file_name` under without
+/// replacing an existing file, renaming it as per the `template` if the name is taken.
+fn free_path(save_location: &Path, file_name: &str, template: &str) -> PathBuf {
+    let path = save_location.join(file_name);
+    if !path.exists() {
+        return path;
+    }
+    (1..)
+        .map(|n| save_location.join(render_name(template, file_name, n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Renders the rename `template` for the `n`th file named `file_name`, e.g., `a (2).txt` for
+/// `a.txt` and `{stem} ({n}){ext}`.
+fn render_name(template: &str, file_name: &str, n: usize) -> String {
+    let path = Path::new(file_name);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    template
+        .replace("{stem}", &stem)
+        .replace("{ext}", &ext)
+        .replace("{n}", &n.to_string())
+}
+
+/// Runs the post-receive `command` template for the file saved at the `path`, without waiting
+/// for it to exit.
+///
+/// Since the path is substituted into a single argument and no shell is involved, a file name
+/// can't inject arguments or commands. Failures are only logged.
+fn run_post_receive_command(command: &str, path: &Path) {
+    let path = path.to_string_lossy();
+    let mut args = command
+        .split_whitespace()
+        .map(|arg| arg.replace("{path}", &path));
+    let Some(program) = args.next() else {
+        return;
+    };
+
+    let mut child = match Command::new(&program).args(args).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            elogln!("Failed to run the post-receive command `{program}`: {e}");
+            return;
+        }
+    };
+    // Wait on another thread, so that the exited command doesn't linger as a zombie.
+    let spawned = thread::Builder::new()
+        .name(String::from("post_receive_command"))
+        .spawn(move || match child.wait() {
+            Ok(status) if !status.success() => {
+                elogln!("The post-receive command `{program}` failed with {status}");
+            }
+            Err(e) => elogln!("Failed to wait for the post-receive command `{program}`: {e}"),
+            Ok(_) => {}
+        });
+    if let Err(e) = spawned {
+        elogln!("Failed to wait for the post-receive command: {e}");
+    }
+}
+
+/// Moves the received `part` file into place at the `file_path`.
+///
+/// The file at the path, if any, is replaced as a whole. A packet carrying only a range of the
+/// file is instead written at its offset, leaving the rest of the file as is.
+///
+/// If `content_addressed` is enabled, the file is instead stored as an object, see
+/// [`write_object`].
+///
+/// If `preserve_file_metadata` is enabled, the permissions and modification time sent along
+/// are applied as well; failing to apply them doesn't fail the transfer.
+///
+/// Returns the number of bytes written, or `None` if the contents were stored already.
+fn write_file(
+    packet: &FilePacket,
+    part: PartFile,
+    file_path: &Path,
+    config: &Config,
+) -> io::Result<Option<u64>> {
+    let save_location = &config.save_location;
+    let written = match config.content_addressed {
+        true => write_object(packet, part, file_path, save_location),
+        false => match packet.get_range_start() {
+            Some(offset) => write_range(file_path, offset, part).map(Some),
+            None => part
+                .len()
+                .and_then(|len| part.persist(file_path).map(|_| Some(len))),
+        },
+    };
+    let written = written.map_err(|e| {
+        let path = save_location.display();
+        Error::new(e.kind(), format!("failed to create file in `{path}`: {e}"))
+    })?;
+
+    if config.preserve_file_metadata {
+        if let Err(e) = apply_metadata(packet, file_path) {
+            let path = file_path.display();
+            elogln!("Failed to preserve the permissions or mtime of `{path}`: {e}");
+        }
+    }
+    Ok(written)
+}
+
+/// Writes the contents of the `part` file into the file at the `file_path` from the `offset`
+/// on, creating it if it doesn't exist, and returns the number of bytes written.
+fn write_range(file_path: &Path, offset: u64, part: PartFile) -> io::Result<u64> {
+    let mut file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(file_path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut contents = &part.file;
+    contents.seek(SeekFrom::Start(0))?;
+    io::copy(&mut contents, &mut file)
+}
+
+/// Stores the `part` file as the object at the `object_path`, unless identical contents are
+/// stored already, and maps the name of the file to it in the index.
+///
+/// Since the index holds one name per line, a name with a line break is refused before
+/// anything is stored.
+///
+/// Returns the number of bytes of the object written, or `None` if it was stored already.
+fn write_object(
+    packet: &FilePacket,
+    part: PartFile,
+    object_path: &Path,
+    save_location: &Path,
+) -> io::Result<Option<u64>> {
+    if packet.get_file_name().contains(['\n', '\r']) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "file name contains a line break",
+        ));
+    }
+    let written = match object_path.exists() {
+        true => None,
+        false => {
+            fs::create_dir_all(save_location.join(OBJECTS_DIR_NAME))?;
+            let len = part.len()?;
+            part.persist(object_path)?;
+            Some(len)
+        }
+    };
+    let hash = object_path
+        .file_name()
+        .map(|hash| hash.to_string_lossy())
+        .unwrap_or_default();
+
+    let mut index = File::options()
+        .create(true)
+        .append(true)
+        .open(save_location.join(INDEX_FILE_NAME))?;
+    writeln!(index, "{hash} {}", packet.get_file_name())?;
+    Ok(written)
+}
+
+/// Returns the path of the object that stores the contents with the given hash.
+pub fn object_path(save_location: &Path, hash: &[u8]) -> PathBuf {
+    save_location
+        .join(OBJECTS_DIR_NAME)
+        .join(digest::to_hex(hash))
+}
+
+/// Applies the permissions and modification time the sender included in the `packet`.
+fn apply_metadata(packet: &FilePacket, file_path: &Path) -> io::Result<()> {
+    // Set before the permissions, which may make the file read-only.
+    if let Some(mtime) = packet.get_mtime() {
+        File::options()
+            .write(true)
+            .open(file_path)?
+            .set_modified(mtime)?;
+    }
+    #[cfg(unix)]
+    if let Some(mode) = packet.get_unix_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        // The setuid, setgid and sticky bits are never applied.
+        fs::set_permissions(file_path, fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    Ok(())
+}
+
+/// Logs the error and records it as the last error.
+fn report(last_error: &LastError, err: String) {
+    elogln!("{err}");
+    last_error.record(err);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn names_subdirectories_after_the_sender() {
+        let config = Config {
+            save_location: PathBuf::from("/saved"),
+            save_subdir_by_peer: true,
+            ..Config::default()
+        };
+        let headers = |name: Option<&str>, id: Option<&str>| {
+            let mut headers = Packet::new();
+            if let Some(name) = name {
+                headers.set_header("sender_name", name);
+            }
+            if let Some(id) = id {
+                headers.set_header("sender_id", id);
+            }
+            headers
+        };
+        let dir = |name, id| save_dir(&headers(name, id), &config);
+
+        assert_eq!(dir(Some("laptop"), Some("42")), Path::new("/saved/laptop"));
+        assert_eq!(dir(Some("../etc"), Some("42")), Path::new("/saved/.._etc"));
+        assert_eq!(dir(Some("my pc/é"), None), Path::new("/saved/my_pc__"));
+        assert_eq!(dir(Some(".."), Some("42")), Path::new("/saved/42"));
+        assert_eq!(dir(None, None), Path::new("/saved/unknown"));
+        assert_eq!(
+            sanitize_dir_name(&"a".repeat(100)).map(|name| name.len()),
+            Some(MAX_DIR_NAME_LEN)
+        );
+
+        let flat = Config {
+            save_subdir_by_peer: false,
+            ..config.clone()
+        };
+        let headers = headers(Some("laptop"), None);
+        assert_eq!(save_dir(&headers, &flat), Path::new("/saved"));
+    }
+
+    #[test]
+    fn probes_hashes_only_inside_the_save_location() {
+        let root = env::temp_dir().join(format!("redtooth-hash-probe-{}", process::id()));
+        let config = Config {
+            save_location: root.join("saved"),
+            ..Config::default()
+        };
+        fs::create_dir_all(&config.save_location).unwrap();
+        fs::write(config.save_location.join("notes.txt"), b"notes").unwrap();
+        fs::write(root.join("secret.txt"), b"secret").unwrap();
+        let probe = |file_name: &str, contents: &[u8]| {
+            let mut probe = Packet::new();
+            probe.set_header("file_name", file_name);
+            probe.set_header_bytes("hash", &digest::sha256(contents));
+            probe
+        };
+
+        assert!(has_probed_file(&probe("notes.txt", b"notes"), &config));
+        assert!(!has_probed_file(&probe("notes.txt", b"other"), &config));
+        assert!(!has_probed_file(
+            &probe("../secret.txt", b"secret"),
+            &config
+        ));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_index_one_name_per_line() {
+        let save_location = env::temp_dir().join(format!("redtooth-index-{}", process::id()));
+        let _ = fs::remove_dir_all(&save_location);
+        fs::create_dir_all(&save_location).unwrap();
+        let packet = |file_name: &str| {
+            FilePacketBuilder::new()
+                .file_name(file_name)
+                .contents(b"indexed".to_vec())
+                .build()
+                .unwrap()
+        };
+        let part = || {
+            let part = PartFile::create(&save_location).unwrap();
+            (&part.file).write_all(b"indexed").unwrap();
+            part
+        };
+        let object_path = object_path(&save_location, &digest::sha256(b"indexed"));
+
+        write_object(&packet("a.txt"), part(), &object_path, &save_location).unwrap();
+        let err = write_object(
+            &packet("b.txt\nforged c.txt"),
+            part(),
+            &object_path,
+            &save_location,
+        );
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(fs::read(&object_path).unwrap(), b"indexed");
+        let index = fs::read_to_string(save_location.join(INDEX_FILE_NAME)).unwrap();
+        assert_eq!(index.lines().count(), 1);
+        fs::remove_dir_all(&save_location).unwrap();
+    }
+
+    #[test]
+    fn renames_colliding_files_as_per_the_template() {
+        let save_location = env::temp_dir().join(format!("redtooth-rename-{}", process::id()));
+        let _ = fs::remove_dir_all(&save_location);
+        fs::create_dir_all(&save_location).unwrap();
+
+        let mut names = Vec::new();
+        for _ in 0..4 {
+            let path = free_path(&save_location, "a.txt", "{stem} ({n}){ext}");
+            fs::write(&path, b"").unwrap();
+            names.push(path.file_name().unwrap().to_string_lossy().into_owned());
+        }
+        assert_eq!(names, ["a.txt", "a (1).txt", "a (2).txt", "a (3).txt"]);
+
+        assert_eq!(render_name("{stem}-{n}{ext}", "a.txt", 3), "a-3.txt");
+        assert_eq!(render_name("{stem}-{n}{ext}", "Makefile", 1), "Makefile-1");
+    }
 }
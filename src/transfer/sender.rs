@@ -1,26 +1,714 @@
-use std::io::{self, Write};
-use std::net::TcpStream;
-use std::path::Path;
+use std::fmt;
+use std::io::{self, BufReader, Error, ErrorKind};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use super::FilePacket;
-use crate::logln;
-use crate::protocol::PeerAddr;
+use super::{receiver, ByteRange, FilePacket, FilePacketBuilder, FileVerifyError};
+use crate::config::Config;
+use crate::events::Progress;
+use crate::history::{self, Direction, Record};
+use crate::protocol::packet::Packet;
+use crate::protocol::{self, PeerAddr, PeerID};
+use crate::{elogln, logln};
 
-pub fn send_file_to(addr: PeerAddr, path: impl AsRef<Path>) -> io::Result<()> {
-    send_file_to_all(&[addr], path)
+/// Largest contents read into memory to be shared by the workers sending them to several peers;
+/// larger contents are streamed from disk by each worker instead.
+const MAX_SHARED_CONTENTS_LEN: u64 = 4 * 1024 * 1024;
+
+/// Represents an error that can occur when sending a file as part of a manifest.
+pub enum SendError {
+    /// The file couldn't be read or the connection to the peer failed.
+    Io(io::Error),
+    /// The peer received the file but failed to save it.
+    Rejected(String),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Io(e) => write!(f, "{e}"),
+            SendError::Rejected(reason) => write!(f, "rejected by the peer: {reason}"),
+        }
+    }
 }
 
-pub fn send_file_to_all(addrs: &[PeerAddr], path: impl AsRef<Path>) -> io::Result<()> {
+/// The result of sending a packet to each peer, in the order the sends completed.
+pub type SendResults = Vec<(PeerAddr, io::Result<()>)>;
+
+/// The result of sending each file to a peer over a single connection, in the order of the
+/// paths.
+pub type FileResults = Vec<(PathBuf, Result<(), SendError>)>;
+
+pub fn send_file_to(
+    addr: PeerAddr,
+    path: impl AsRef<Path>,
+    my_id: PeerID,
+    config: &Config,
+) -> io::Result<()> {
+    send_file_to_all(&[addr], path, my_id, config)?
+        .into_iter()
+        .try_for_each(|(_, result)| result)
+}
+
+pub fn send_file_to_all(
+    addrs: &[PeerAddr],
+    path: impl AsRef<Path>,
+    my_id: PeerID,
+    config: &Config,
+) -> io::Result<SendResults> {
+    let path = path.as_ref();
+    // The checksum lets the peer tell whether it already has the file.
+    let mut packet = FilePacketBuilder::new()
+        .path(path)
+        .checksum(config.skip_duplicate_files)
+        .build()?;
+    identify(&mut packet.packet, my_id);
+    Ok(send_packet_to_all(addrs, packet, path, my_id, config))
+}
+
+/// Sends the directory at the `path` to all the peers as a single tar archive, which is
+/// produced while it is sent rather than beforehand.
+pub fn send_archive_to_all(
+    addrs: &[PeerAddr],
+    path: impl AsRef<Path>,
+    my_id: PeerID,
+    config: &Config,
+) -> io::Result<SendResults> {
     let path = path.as_ref();
-    assert!(path.is_file());
+    if !path.is_dir() {
+        return Err(Error::new(ErrorKind::InvalidInput, "not a directory"));
+    }
 
-    let packet = FilePacket::from_path(path)?;
-    let data = packet.as_owned_bytes();
-    logln!("Sending data of {} bytes", data.len());
+    let mut packet = FilePacketBuilder::new()
+        .archive(path)
+        .checksum(config.skip_duplicate_files)
+        .build()?;
+    identify(&mut packet.packet, my_id);
+    Ok(send_packet_to_all(addrs, packet, path, my_id, config))
+}
+
+/// Sends the `text` to all the peers as a text file named `message-<timestamp>.txt`, so that
+/// a snippet can be shared without writing it into a file first.
+///
+/// The timestamp is in nanoseconds, so that texts sent in a row don't replace each other.
+pub fn send_text_to_all(
+    addrs: &[PeerAddr],
+    text: &str,
+    my_id: PeerID,
+    config: &Config,
+) -> io::Result<SendResults> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos());
+    let file_name = format!("message-{timestamp}.txt");
+
+    let mut packet = FilePacketBuilder::new()
+        .file_name(&file_name)
+        .contents(text.as_bytes().to_vec())
+        .content_type("text/plain; charset=utf-8")
+        .checksum(config.skip_duplicate_files)
+        .build()?;
+    identify(&mut packet.packet, my_id);
+    Ok(send_packet_to_all(
+        addrs,
+        packet,
+        Path::new(&file_name),
+        my_id,
+        config,
+    ))
+}
+
+/// Sends the file packet created from the `path` to all the peers, to up to
+/// `max_concurrent_sends` of them at once as per [`for_each_peer`].
+///
+/// The workers share the packet rather than each building its own, so contents held in memory
+/// are never copied and the checksum is computed once. Contents streamed from disk are shared
+/// as per [`share_contents`].
+fn send_packet_to_all(
+    addrs: &[PeerAddr],
+    mut packet: FilePacket,
+    path: &Path,
+    my_id: PeerID,
+    config: &Config,
+) -> SendResults {
+    if config.confirm_received_length {
+        packet.packet.set_header("confirm_length", true);
+    }
+    share_contents(&mut packet, addrs.len());
+    let packet = &packet;
+    let probe = packet
+        .get_checksum()
+        .map(|hash| hash_probe(packet.get_file_name(), &hash, my_id));
+    let probe = probe.as_ref();
+    logln!("Sending data of {} bytes", packet.encoded_len());
+    for_each_peer(addrs, config, |addr| {
+        send_packet_to(addr, packet, probe, path, config)
+    })
+}
+
+/// Reads the contents of the packet streamed from disk into memory once beforehand if they are
+/// sent to more than one peer, rather than by each worker, unless they're larger than
+/// [`MAX_SHARED_CONTENTS_LEN`], so that sending a large file doesn't hold all of it in memory.
+fn share_contents(packet: &mut FilePacket, peer_count: usize) {
+    if peer_count < 2 || packet.content_len() > MAX_SHARED_CONTENTS_LEN {
+        return;
+    }
+    if let Err(e) = packet.share_contents() {
+        // Each worker fails to read them in turn, which is reported per peer.
+        elogln!("Failed to read the contents ahead of sending them: {e}");
+    }
+}
+
+/// Calls `send` for each of the peers, for up to `max_concurrent_sends` of them at once; the
+/// rest are queued until a call returns.
+///
+/// Returns what each call returned, in the order the calls returned.
+fn for_each_peer<T: Send>(
+    addrs: &[PeerAddr],
+    config: &Config,
+    send: impl Fn(PeerAddr) -> T + Sync,
+) -> Vec<(PeerAddr, T)> {
+    let next_addr = AtomicUsize::new(0);
+    let (result_sender, results) = mpsc::channel();
+    let worker_count = config.max_concurrent_sends.clamp(1, addrs.len().max(1));
+    thread::scope(|scope| {
+        for _ in 0..worker_count {
+            let result_sender = result_sender.clone();
+            let (next_addr, send) = (&next_addr, &send);
+            scope.spawn(move || {
+                while let Some(&addr) = addrs.get(next_addr.fetch_add(1, Ordering::Relaxed)) {
+                    let _ = result_sender.send((addr, send(addr)));
+                }
+            });
+        }
+    });
+    drop(result_sender);
+    results.into_iter().collect()
+}
 
-    for addr in addrs {
-        let mut stream = TcpStream::connect(addr)?;
-        stream.write_all(&data)?;
+/// Sends the file packet created from the `path` to the peer listening on `addr`, retrying
+/// as per [`with_retries`], and records the transfer into the history.
+fn send_packet_to(
+    addr: PeerAddr,
+    packet: &FilePacket,
+    probe: Option<&Packet>,
+    path: &Path,
+    config: &Config,
+) -> io::Result<()> {
+    let started = Instant::now();
+    // Started once for all the attempts, which report their progress from scratch.
+    let mut progress = super::start_sending(Some(addr), packet);
+    let result = with_retries(addr, config, |written| {
+        send_file(addr, packet, probe, config, &mut progress, written)
+    })
+    .map_err(|e| super::map_timeout(e, config.transfer_timeout));
+    if result.is_ok() {
+        let elapsed = started.elapsed();
+        logln!(
+            "Sent to `{addr}` in {:.2}s ({})",
+            elapsed.as_secs_f64(),
+            super::throughput(packet.content_len(), elapsed)
+        );
+    }
+
+    history::append(
+        &config.data_location,
+        Record {
+            direction: Direction::Sent,
+            peer_addr: Some(addr),
+            path,
+            bytes: packet.content_len(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        },
+    );
+    result
+}
+
+/// Calls `send` until it succeeds, retrying up to `send_retries` times after a transient
+/// network error.
+///
+/// A packet that isn't received in full is dropped by the peer, so retrying an attempt that
+/// failed while writing it doesn't send the same file twice. Once `send` flags the packet as
+/// `written` in full, though, the peer may have saved it, e.g., when the connection fails while
+/// waiting for the confirmation asked by `confirm_received_length`; that attempt isn't retried.
+fn with_retries(
+    addr: PeerAddr,
+    config: &Config,
+    mut send: impl FnMut(&mut bool) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut retries = 0;
+    loop {
+        let mut written = false;
+        match send(&mut written) {
+            Err(e) if !written && retries < config.send_retries && is_transient(&e) => {
+                retries += 1;
+                elogln!(
+                    "Failed to send to `{addr}`: {e}; retrying ({retries}/{})",
+                    config.send_retries
+                );
+                thread::sleep(config.send_retry_delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns `true` if the error is caused by the network rather than the file or the peer's
+/// answer, so trying again may succeed.
+fn is_transient(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::WouldBlock
+            | ErrorKind::Interrupted
+    )
+}
+
+/// Sends the file packet to the peer listening on `addr`.
+///
+/// If the hash `probe` is given, the file is skipped when the peer already has it, as per
+/// [`peer_has_file`].
+///
+/// Once the packet is flushed, `written` is set and the write side of the connection is shut
+/// down, so that the peer reads the end of the conversation right after the last byte rather
+/// than once the connection is dropped; the confirmation asked by `confirm_received_length` is
+/// still read.
+fn send_file(
+    addr: PeerAddr,
+    packet: &FilePacket,
+    probe: Option<&Packet>,
+    config: &Config,
+    progress: &mut Progress,
+    written: &mut bool,
+) -> io::Result<()> {
+    let mut stream = super::connect(addr, config)?;
+
+    if let Some(probe) = probe {
+        if peer_has_file(&mut stream, probe)? {
+            logln!(
+                "`{addr}` already has `{}`; skipping",
+                packet.get_file_name()
+            );
+            return Ok(());
+        }
+    }
+    super::write_file_packet_reporting(&mut stream, packet, config.transfer_buffer_size, progress)?;
+    *written = true;
+    stream.shutdown(Shutdown::Write)?;
+    match config.confirm_received_length {
+        true => ensure_fully_saved(&mut stream, packet.content_len()),
+        false => Ok(()),
+    }
+}
+
+/// Waits for the peer to tell how many bytes of the file it saved, as asked by the
+/// `confirm_length` header, and fails unless it saved all the `sent_len` bytes or had nothing
+/// to write.
+fn ensure_fully_saved(stream: &mut TcpStream, sent_len: u64) -> io::Result<()> {
+    let mut buf = Vec::new();
+    let reply = super::read_reply(stream, &mut buf)?;
+    if reply.get_header("kind") == Some("error") {
+        let reason = reply.get_header("error").unwrap_or("unknown error");
+        return Err(Error::other(format!(
+            "peer failed to save the file: {reason}"
+        )));
+    }
+    let saved_len = reply
+        .get_header("length")
+        .and_then(|len| len.parse::<u64>().ok())
+        .ok_or(Error::new(
+            ErrorKind::InvalidData,
+            "peer sent a malformed confirmation",
+        ))?;
+    // The peer may have had nothing to write, e.g., identical contents stored already.
+    if saved_len != sent_len && reply.get_header("unchanged").is_none() {
+        let mismatch = FileVerifyError::LengthMismatch {
+            expected: sent_len,
+            actual: saved_len,
+        };
+        return Err(Error::new(ErrorKind::InvalidData, mismatch));
     }
     Ok(())
 }
+
+/// Builds a probe asking whether the peer already has a file with the given name and content
+/// hash.
+///
+/// The probe identifies the sender, since the peer may save the files of each sender apart.
+fn hash_probe<'p>(file_name: &str, hash: &[u8], my_id: PeerID) -> Packet<'p> {
+    let mut probe = Packet::new();
+    identify(&mut probe, my_id);
+    probe.set_header("kind", "hash_probe");
+    probe.set_header("file_name", file_name);
+    probe.set_header_bytes("hash", hash);
+    probe
+}
+
+/// Asks the peer whether it already has the file, with the `probe` built by [`hash_probe`].
+///
+/// The peer replies `have` or `want`; on `want` the file is expected over the same connection.
+fn peer_has_file(stream: &mut TcpStream, probe: &Packet) -> io::Result<bool> {
+    super::write_packet(stream, probe)?;
+
+    let mut buf = Vec::new();
+    let reply = super::read_reply(stream, &mut buf)?;
+    Ok(reply.get_header("answer") == Some("have"))
+}
+
+/// Asks the peer listening on `addr` whether sending a file with the given name would replace
+/// a file it already has.
+pub fn peer_has_file_named(
+    addr: PeerAddr,
+    file_name: &str,
+    my_id: PeerID,
+    config: &Config,
+) -> io::Result<bool> {
+    let mut probe = Packet::new();
+    identify(&mut probe, my_id);
+    probe.set_header("kind", "exists_probe");
+    probe.set_header("file_name", file_name);
+
+    let mut stream = super::connect(addr, config)?;
+    let mut buf = Vec::new();
+    let reply = super::write_packet(&mut stream, &probe)
+        .and_then(|_| super::read_reply(&mut stream, &mut buf))
+        .map_err(|e| super::map_timeout(e, config.transfer_timeout))?;
+    Ok(reply.get_header("answer") == Some("exists"))
+}
+
+/// Sends multiple files to the peer listening on `addr` over a single connection.
+///
+/// A manifest listing the names and sizes of the files is sent first, followed by each file
+/// as its own packet. The peer acknowledges every file it receives, so the result of each
+/// file is reported individually in the same order as the `paths`.
+pub fn send_files_to(
+    addr: PeerAddr,
+    paths: &[PathBuf],
+    my_id: PeerID,
+    config: &Config,
+) -> FileResults {
+    let packets = paths
+        .iter()
+        .map(|path| {
+            let mut packet = FilePacket::from_path(path)?;
+            identify(&mut packet.packet, my_id);
+            Ok(packet)
+        })
+        .collect::<Vec<_>>();
+    let mut manifest = manifest_packet(&packets);
+    identify(&mut manifest, my_id);
+
+    let mut stream = match super::connect(addr, config).and_then(|mut stream| {
+        super::write_packet(&mut stream, &manifest)?;
+        Ok(stream)
+    }) {
+        Ok(stream) => stream,
+        Err(e) => {
+            return paths
+                .iter()
+                .map(|path| (path.clone(), Err(SendError::Io(clone_error(&e)))))
+                .collect()
+        }
+    };
+
+    let mut results = Vec::with_capacity(paths.len());
+    for (index, (path, packet)) in paths.iter().zip(packets).enumerate() {
+        let mut bytes = 0;
+        let result = packet
+            .map_err(SendError::Io)
+            .and_then(|mut packet| {
+                bytes = packet.content_len();
+                packet.set_manifest_index(index);
+                send_acked(&mut stream, &packet, index, config)
+            })
+            .map_err(|e| match e {
+                SendError::Io(e) => SendError::Io(super::map_timeout(e, config.transfer_timeout)),
+                e => e,
+            });
+
+        history::append(
+            &config.data_location,
+            Record {
+                direction: Direction::Sent,
+                peer_addr: Some(addr),
+                path,
+                bytes,
+                error: result.as_ref().err().map(|e| e.to_string()),
+            },
+        );
+        results.push((path.clone(), result));
+    }
+    results
+}
+
+/// Sends multiple files to all the peers as per [`send_files_to`], over one connection per
+/// peer, to up to `max_concurrent_sends` of them at once.
+pub fn send_files_to_all(
+    addrs: &[PeerAddr],
+    paths: &[PathBuf],
+    my_id: PeerID,
+    config: &Config,
+) -> Vec<(PeerAddr, FileResults)> {
+    for_each_peer(addrs, config, |addr| {
+        send_files_to(addr, paths, my_id, config)
+    })
+}
+
+/// Sends a short text message to the peer listening on `addr`.
+pub fn send_message_to(
+    addr: PeerAddr,
+    text: &str,
+    my_id: PeerID,
+    config: &Config,
+) -> io::Result<()> {
+    let mut packet = Packet::new();
+    packet.set_header("kind", "message");
+    packet.set_payload(text.as_bytes().to_vec());
+    identify(&mut packet, my_id);
+
+    with_retries(addr, config, |_| {
+        let mut stream = super::connect(addr, config)?;
+        super::write_packet(&mut stream, &packet)
+    })
+    .map_err(|e| super::map_timeout(e, config.transfer_timeout))
+}
+
+/// Asks the peer listening on `addr` for the files it shares and returns their names and sizes.
+pub fn list_remote(
+    addr: PeerAddr,
+    my_id: PeerID,
+    config: &Config,
+) -> io::Result<Vec<(String, u64)>> {
+    let mut request = Packet::new();
+    request.set_header("kind", "list");
+    identify(&mut request, my_id);
+
+    let mut stream = super::connect(addr, config)?;
+    let mut buf = Vec::new();
+    let reply = super::write_packet(&mut stream, &request)
+        .and_then(|_| super::read_reply(&mut stream, &mut buf))
+        .map_err(|e| super::map_timeout(e, config.transfer_timeout))?;
+
+    if let Some(reason) = reply.get_header("error") {
+        return Err(Error::other(format!(
+            "peer failed to list its files: {reason}"
+        )));
+    }
+    let entries = String::from_utf8_lossy(reply.get_payload().unwrap_or_default());
+    entries
+        .lines()
+        .map(|entry| {
+            let (size, name) = entry.split_once(' ')?;
+            Some((name.to_string(), size.parse().ok()?))
+        })
+        .collect::<Option<_>>()
+        .ok_or(Error::new(
+            ErrorKind::InvalidData,
+            "peer sent a malformed listing",
+        ))
+}
+
+/// Asks the peer listening on `addr` for the shared file with the given name and saves it to
+/// the configured save location.
+///
+/// With a `range`, only that range of the file is pulled and written at its offset into the
+/// file already saved under the name, e.g., to resume a pull that was cut off.
+pub fn pull_from(
+    addr: PeerAddr,
+    file_name: &str,
+    range: Option<ByteRange>,
+    my_id: PeerID,
+    config: &Config,
+) -> io::Result<()> {
+    let mut request = Packet::new();
+    request.set_header("kind", "pull_request");
+    request.set_header("file_name", file_name);
+    identify(&mut request, my_id);
+    if let Some(range) = range {
+        if config.content_addressed {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "ranges can't be pulled into a content-addressed save location",
+            ));
+        }
+        request.set_header("range_start", range.start);
+        if let Some(end) = range.end {
+            request.set_header("range_end", end);
+        }
+    }
+
+    let mut stream = super::connect(addr, config)?;
+    super::write_packet(&mut stream, &request)
+        .map_err(|e| super::map_timeout(e, config.transfer_timeout))?;
+    let mut reader = BufReader::with_capacity(config.transfer_buffer_size, &stream);
+    let mut buf = Vec::new();
+    let reply = super::read_reply(&mut reader, &mut buf)
+        .map_err(|e| super::map_timeout(e, config.transfer_timeout))?;
+
+    if reply.get_header("kind") == Some("error") {
+        let reason = reply.get_header("error").unwrap_or("unknown error");
+        return Err(Error::other(format!(
+            "peer failed to send the file: {reason}"
+        )));
+    }
+    let contents_len = super::contents_len(&reply)?;
+    let packet = FilePacket::from(reply);
+    // A range is written at its offset, so it must be the one asked for.
+    if !is_pulled_range(&packet, contents_len, range) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "peer sent another range of the file than the one pulled",
+        ));
+    }
+    receiver::save_file(packet, contents_len, &mut reader, Some(addr), config)
+        .map(|_| ())
+        .map_err(|e| super::map_timeout(e, config.transfer_timeout))
+}
+
+/// Returns `true` if the `packet`, with `contents_len` bytes of contents, carries the `range`
+/// that was pulled, or less of it if the file ends first, or the whole file if no range was
+/// pulled.
+fn is_pulled_range(packet: &FilePacket, contents_len: u64, range: Option<ByteRange>) -> bool {
+    match (packet.get_range_start(), range) {
+        (None, None) => true,
+        (Some(start), Some(range)) => {
+            start == range.start
+                && range
+                    .end
+                    .is_none_or(|end| contents_len <= end - range.start)
+        }
+        _ => false,
+    }
+}
+
+/// Sets the headers that tell the peer who sent the packet, so that it can check the sender
+/// against its allowed and denied peers.
+pub(super) fn identify(packet: &mut Packet, my_id: PeerID) {
+    packet.set_header("sender_id", my_id);
+    packet.set_header("sender_name", protocol::get_my_name());
+}
+
+/// Builds a manifest listing the index, size and name of each file that can be sent.
+fn manifest_packet<'p>(packets: &[io::Result<FilePacket>]) -> Packet<'p> {
+    let entries = packets
+        .iter()
+        .enumerate()
+        .filter_map(|(index, packet)| {
+            let packet = packet.as_ref().ok()?;
+            Some(format!(
+                "{index} {} {}\n",
+                packet.encoded_len(),
+                packet.get_file_name()
+            ))
+        })
+        .collect::<String>();
+
+    let mut manifest = Packet::new();
+    manifest.set_header("kind", "manifest");
+    manifest.set_header("file_count", packets.len());
+    manifest.set_payload(entries.into_bytes());
+    manifest
+}
+
+/// Sends the file packet and waits for the peer to acknowledge it.
+fn send_acked(
+    stream: &mut TcpStream,
+    packet: &FilePacket,
+    index: usize,
+    config: &Config,
+) -> Result<(), SendError> {
+    super::write_file_packet(stream, packet, config.transfer_buffer_size).map_err(SendError::Io)?;
+
+    let mut buf = Vec::new();
+    let ack = super::read_reply(stream, &mut buf).map_err(SendError::Io)?;
+
+    if ack.get_header("kind") != Some("ack") || ack.get_header("index") != Some(&index.to_string())
+    {
+        return Err(SendError::Io(Error::new(
+            ErrorKind::InvalidData,
+            "peer acknowledged a different file",
+        )));
+    }
+
+    match ack.get_header("error") {
+        Some(reason) => Err(SendError::Rejected(reason.to_string())),
+        None => Ok(()),
+    }
+}
+
+fn clone_error(err: &io::Error) -> io::Error {
+    Error::new(err.kind(), err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::sync::Barrier;
+
+    use super::*;
+
+    #[test]
+    fn shares_only_small_contents_in_memory() {
+        let dir = env::temp_dir().join(format!("redtooth-share-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("contents.bin");
+
+        // Contents that were shared are written even once the file is gone.
+        let shares = |len, peer_count| {
+            File::create(&path).unwrap().set_len(len).unwrap();
+            let mut packet = FilePacket::from_path(&path).unwrap();
+            share_contents(&mut packet, peer_count);
+            fs::remove_file(&path).unwrap();
+            packet.write_to(&mut io::sink()).is_ok()
+        };
+        assert!(shares(MAX_SHARED_CONTENTS_LEN, 2));
+        assert!(!shares(MAX_SHARED_CONTENTS_LEN + 1, 2));
+        assert!(!shares(1, 1));
+    }
+
+    #[test]
+    fn bounds_concurrent_sends_and_reports_in_completion_order() {
+        let addrs = (1..=3)
+            .map(|i| PeerAddr::from(([10, 0, 0, i], 25802)))
+            .collect::<Vec<_>>();
+        let config = |max_concurrent_sends| Config {
+            max_concurrent_sends,
+            ..Config::default()
+        };
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        let track = |send: &dyn Fn()| {
+            let count = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            max_in_flight.fetch_max(count, Ordering::SeqCst);
+            send();
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+        };
+
+        let results = for_each_peer(&addrs, &config(1), |_| track(&|| {}));
+        let order = results.iter().map(|(addr, _)| *addr).collect::<Vec<_>>();
+        assert_eq!(order, addrs);
+        assert_eq!(max_in_flight.swap(0, Ordering::SeqCst), 1);
+
+        // The send to the first peer lasts until the one to the third starts, which the worker
+        // that sent to the second only does once done with it; so the second completes first.
+        let third_started = Barrier::new(2);
+        let results = for_each_peer(&addrs, &config(2), |addr| {
+            track(&|| {
+                if addr != addrs[1] {
+                    third_started.wait();
+                }
+            })
+        });
+        assert_eq!(results[0].0, addrs[1]);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    }
+}
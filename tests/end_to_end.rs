@@ -0,0 +1,167 @@
+//! End-to-end tests that run the app as a separate process and drive it over IPC.
+//!
+//! Only a single instance runs at a time: the transfer port is fixed per host and instances on
+//! the same host never see each other's multicast announcements, so they can't discover each
+//! other yet. Transfers are exercised by having the instance send to itself.
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
+
+/// How long a test waits for the instance to get to the expected state.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Held by the running instance, so that the tests don't run instances at once.
+static INSTANCE_LOCK: Mutex<()> = Mutex::new(());
+
+/// A running instance of the app with its own home directory and IPC socket, killed once
+/// dropped.
+struct Instance {
+    child: Child,
+    home: PathBuf,
+    socket_path: PathBuf,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Instance {
+    fn spawn(name: &str) -> Instance {
+        let lock = INSTANCE_LOCK.lock().unwrap_or_else(|p| p.into_inner());
+        let home = env::temp_dir().join(format!("redtooth-e2e-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(&home).unwrap();
+        let socket_path = home.join("redtooth.sock");
+
+        let child = Command::new(env!("CARGO_BIN_EXE_redtooth"))
+            .env("HOME", &home)
+            .env("REDTOOTH_SOCKET", &socket_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut instance = Instance {
+            child,
+            home,
+            socket_path,
+            _lock: lock,
+        };
+
+        wait_until("the IPC socket to accept connections", || {
+            if let Some(status) = instance.child.try_wait().unwrap() {
+                panic!("the instance exited early with {status}; is another one running?");
+            }
+            UnixStream::connect(&instance.socket_path).is_ok()
+        });
+        instance
+    }
+
+    /// Sends the IPC command and returns the response.
+    fn request(&self, command: &str) -> io::Result<String> {
+        let mut stream = UnixStream::connect(&self.socket_path)?;
+        stream.write_all(command.as_bytes())?;
+        stream.shutdown(Shutdown::Write)?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    fn save_location(&self) -> PathBuf {
+        self.home.join("redtooth")
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.home);
+    }
+}
+
+/// Waits until the `condition` holds, panicking if it doesn't within the [`TIMEOUT`].
+fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn sends_a_file_to_itself() {
+    let instance = Instance::spawn("self");
+
+    let mut addr = instance
+        .request("/myaddr")
+        .unwrap()
+        .parse::<SocketAddr>()
+        .unwrap();
+    // The receiver listens on all the interfaces if it has no address of its own.
+    if addr.ip().is_unspecified() {
+        addr.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+
+    let source = instance.home.join("notes.txt");
+    let contents = b"sent end to end".repeat(1000);
+    fs::write(&source, &contents).unwrap();
+    let response = instance
+        .request(&format!("/send_to_addr {addr} {}", source.display()))
+        .unwrap();
+    assert_eq!(response, "");
+
+    let received = instance.save_location().join("notes.txt");
+    wait_until("the file to be received", || {
+        fs::read(&received).is_ok_and(|received| received == contents)
+    });
+    assert!(instance.request("/history").unwrap().contains("notes.txt"));
+}
+
+#[test]
+fn shuts_down_on_request() {
+    let mut instance = Instance::spawn("shutdown");
+
+    assert_eq!(instance.request("/shutdown").unwrap(), "Shutting down");
+    wait_until("the instance to exit", || {
+        instance.child.try_wait().unwrap().is_some()
+    });
+    assert!(instance.child.wait().unwrap().success());
+    assert!(!instance.socket_path.exists());
+}
+
+#[test]
+fn drives_the_daemon_from_the_shell() {
+    let mut instance = Instance::spawn("shell");
+    let addr = instance.request("/myaddr").unwrap();
+
+    let mut shell = Command::new(env!("CARGO_BIN_EXE_redtooth"))
+        .arg("shell")
+        .env("REDTOOTH_SOCKET", &instance.socket_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    shell
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"myip\nbogus\nsendto\noverwrite off\nsendto 1 notes.txt\nstop\n")
+        .unwrap();
+    let output = shell.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let output = String::from_utf8(output.stdout).unwrap();
+    assert!(output.contains(&addr));
+    assert!(output.contains("Unknown command `bogus`"));
+    assert!(output.contains("missing peer id or name"));
+    // Asking the unknown peer whether it has the file fails, so it isn't sent.
+    assert!(output.contains("Overwriting files on the peers is off"));
+    assert!(output.contains("ERR: No peers found\nNot sent"));
+    assert!(output.contains("Shutting down"));
+    wait_until("the instance to exit", || {
+        instance.child.try_wait().unwrap().is_some()
+    });
+}